@@ -0,0 +1,94 @@
+//! Compares generating the legal moves from a `BitBoard` with reading the Slint model of the
+//! board for every piece, which is what the move generation did before the bitboard existed.
+//!
+//! Run with `cargo bench --bench legal_moves`.
+
+use std::{hint::black_box, time::Instant};
+
+use slint::Model;
+use the_checker_mater::game::{bitboard::BitBoard, board::Board, PieceColor, PieceData};
+
+const ITERATIONS: u32 = 100_000;
+
+const POSITIONS: [(&str, &str); 2] = [
+    (
+        "start",
+        "b.b.b.b.\n.b.b.b.b\nb.b.b.b.\n........\n........\n.w.w.w.w\nw.w.w.w.\n.w.w.w.w",
+    ),
+    (
+        "middle game",
+        "b.b...b.\n...b.B..\nb.b.....\n.w...b..\n..w.b...\n.....w.w\nw.W.....\n...w.w..",
+    ),
+];
+
+/// Builds the Slint model of the pieces, as `Board` keeps it
+fn model(diagram: &str) -> slint::VecModel<PieceData> {
+    let layout = Board::parse_layout(diagram).unwrap();
+    slint::VecModel::from(
+        layout
+            .iter()
+            .map(|tile| match *tile {
+                Some((color, is_king)) => PieceData {
+                    color,
+                    is_king,
+                    is_active: true,
+                },
+                None => PieceData {
+                    color: PieceColor::White,
+                    is_king: false,
+                    is_active: false,
+                },
+            })
+            .collect::<Vec<_>>(),
+    )
+}
+
+/// Copies every row of the model for each piece, then generates that piece's moves
+fn model_legal_moves(pieces: &slint::VecModel<PieceData>) -> usize {
+    let mut count = 0;
+    for index in 0..pieces.row_count() {
+        let piece = pieces.row_data(index).unwrap();
+        if !piece.is_active || piece.color != PieceColor::White {
+            continue;
+        }
+
+        let rows: Vec<PieceData> = pieces.iter().collect();
+        let board = BitBoard::from_pieces(&rows, PieceColor::White);
+        if let Some((moves, _)) = board.legal_moves_piece(index) {
+            count += moves.len();
+        }
+    }
+    count
+}
+
+/// Runs `f` `ITERATIONS` times and returns the average time per call in nanoseconds
+fn time(mut f: impl FnMut() -> usize) -> f64 {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        black_box(f());
+    }
+    start.elapsed().as_nanos() as f64 / ITERATIONS as f64
+}
+
+fn main() {
+    for (name, diagram) in POSITIONS {
+        let pieces = model(diagram);
+        let rows: Vec<PieceData> = pieces.iter().collect();
+        let bitboard = BitBoard::from_pieces(&rows, PieceColor::White);
+
+        let model_ns = time(|| model_legal_moves(black_box(&pieces)));
+        let bitboard_ns = time(|| {
+            black_box(&bitboard)
+                .legal_moves(true)
+                .map_or(0, |moves| moves.len())
+        });
+
+        println!(
+            "{:<12} model: {:>8.0} ns, bitboard: {:>8.0} ns ({:.1}x faster)",
+            name,
+            model_ns,
+            bitboard_ns,
+            model_ns / bitboard_ns
+        );
+    }
+}
@@ -2,24 +2,40 @@ use std::process::exit;
 
 use slint::ComponentHandle;
 
-use the_checker_mater::game::data::Context;
+use the_checker_mater::{game::data::Context, logging, net::interface};
 
 #[tokio::main]
 async fn main() -> Result<(), slint::PlatformError> {
+    logging::init_logging(tracing::Level::INFO);
+
     let gamedata = Context::new()?;
 
     let window = gamedata.get_window();
 
     window.on_clicked(gamedata.on_board_clicked());
+    window.on_move_selection(gamedata.on_move_selection());
+    window.on_confirm_selection(gamedata.on_confirm_selection());
 
     window.on_join_game(gamedata.on_join_game());
     window.on_host_game(gamedata.on_host_game());
     window.on_move_piece(gamedata.on_move_piece());
+    window.on_offer_draw(gamedata.on_offer_draw());
+    window.on_respond_draw(gamedata.on_respond_draw());
+    window.on_resign(gamedata.on_resign());
+    window.on_rematch(gamedata.on_rematch());
+    window.on_request_takeback(gamedata.on_request_takeback());
+    window.on_respond_takeback(gamedata.on_respond_takeback());
 
     window.on_exit(|| {
+        interface::disconnect("The player quit");
         exit(0);
     });
 
+    gamedata.offer_saved_session();
+
     let window = gamedata.get_window();
-    window.run()
+    window.run()?;
+
+    interface::disconnect("The window was closed");
+    Ok(())
 }
@@ -0,0 +1,18 @@
+use std::net::SocketAddr;
+
+use the_checker_mater::{logging, net::relay};
+
+/// The address the relay listens on, unless another one is given as the first argument
+const DEFAULT_RELAY_ADDR: &str = "0.0.0.0:7777";
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    logging::init_logging(tracing::Level::INFO);
+
+    let bind_addr: SocketAddr = std::env::args()
+        .nth(1)
+        .unwrap_or(DEFAULT_RELAY_ADDR.to_owned())
+        .parse()?;
+
+    relay::run_relay_server(bind_addr).await
+}
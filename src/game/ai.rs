@@ -0,0 +1,322 @@
+use std::{cmp::Reverse, collections::HashMap};
+
+use super::{bitboard::BitBoard, zobrist, Move, PieceColor};
+
+/// The value of a piece that isn't a king
+const MAN_VALUE: i32 = 100;
+/// The value of a king
+const KING_VALUE: i32 = 300;
+/// The score of a position where the side to move has no legal moves, and has lost
+const LOSS_SCORE: i32 = -1_000_000;
+
+/// What the score stored in a `TTEntry` says about the real score of the position
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Bound {
+    /// The score is the real score
+    Exact,
+    /// The search was cut off, and the real score is at least the score
+    Lower,
+    /// No move reached alpha, and the real score is at most the score
+    Upper,
+}
+
+/// The result of searching a position, kept so the position isn't searched again when it is
+/// reached through another order of moves
+#[derive(Clone, Debug)]
+struct TTEntry {
+    /// How many plies ahead the position was searched
+    depth: u8,
+    score: i32,
+    bound: Bound,
+    /// The best move found, which is searched first if the position is searched again
+    best_move: Option<Move>,
+}
+
+/// Searched positions, keyed by `position_key`
+type TranspositionTable = HashMap<u64, TTEntry>;
+
+/// The state kept between the positions of one search
+#[derive(Default)]
+struct Search {
+    table: TranspositionTable,
+    /// The number of positions searched, for telling how much the table saves
+    nodes: u64,
+}
+
+/// Returns the Zobrist hash of the position with the player or the enemy to move.
+/// The boards in a search all have the same orientation, so which color the player has doesn't
+/// matter here.
+fn position_key(board: &BitBoard, is_player: bool) -> u64 {
+    let side = if is_player {
+        PieceColor::White
+    } else {
+        PieceColor::Black
+    };
+    board.board_hash(PieceColor::White) ^ zobrist::side_key(side)
+}
+
+/// Sorts `moves` so the moves most likely to be good are searched first, which lets alpha-beta
+/// prune more. Moves capturing more pieces come first, and promotions come before other moves
+/// capturing as many pieces.
+fn order_moves(moves: &mut [Move]) {
+    moves.sort_by_key(|mov| {
+        let captures = mov.captured.as_ref().map_or(0, Vec::len);
+        Reverse((captures, mov.promoted))
+    });
+}
+
+/// Scores the position from the point of view of the player if `is_player` is true, or the
+/// enemy otherwise. Positive scores are good for that side.
+pub fn evaluate(board: &BitBoard, is_player: bool) -> i32 {
+    let enemy = board.enemy();
+
+    let player_score = (board.men & board.player).count_ones() as i32 * MAN_VALUE
+        + (board.kings & board.player).count_ones() as i32 * KING_VALUE;
+    let enemy_score = (board.men & enemy).count_ones() as i32 * MAN_VALUE
+        + (board.kings & enemy).count_ones() as i32 * KING_VALUE;
+
+    if is_player {
+        player_score - enemy_score
+    } else {
+        enemy_score - player_score
+    }
+}
+
+/// Searches `depth` plies ahead with alpha-beta pruning, and returns the score of the position
+/// for the side to move. Positions already in the table of `search` are only searched again if
+/// they were searched less deeply.
+fn negamax(
+    board: &BitBoard,
+    is_player: bool,
+    depth: u8,
+    mut alpha: i32,
+    mut beta: i32,
+    search: &mut Search,
+) -> i32 {
+    search.nodes += 1;
+    let Some(mut moves) = board.legal_moves(is_player) else {
+        // Losing later is better than losing now
+        return LOSS_SCORE - depth as i32;
+    };
+
+    if depth == 0 {
+        return evaluate(board, is_player);
+    }
+
+    let key = position_key(board, is_player);
+    let original_alpha = alpha;
+    let mut table_move = None;
+    if let Some(entry) = search.table.get(&key) {
+        if entry.depth >= depth {
+            match entry.bound {
+                Bound::Exact => return entry.score,
+                Bound::Lower => alpha = alpha.max(entry.score),
+                Bound::Upper => beta = beta.min(entry.score),
+            }
+            if alpha >= beta {
+                return entry.score;
+            }
+        }
+        table_move = entry.best_move.clone();
+    }
+
+    order_moves(&mut moves);
+    // The best move last time is likely to cause a cutoff early
+    if let Some(best) = table_move {
+        if let Some(pos) = moves.iter().position(|mov| *mov == best) {
+            moves[..=pos].rotate_right(1);
+        }
+    }
+
+    let mut best_move = None;
+    for mov in moves {
+        let mut next = *board;
+        next.apply_move(&mov);
+
+        let score = -negamax(&next, !is_player, depth - 1, -beta, -alpha, search);
+        if score >= beta {
+            search.table.insert(
+                key,
+                TTEntry {
+                    depth,
+                    score: beta,
+                    bound: Bound::Lower,
+                    best_move: Some(mov),
+                },
+            );
+            return beta;
+        }
+        if score > alpha {
+            alpha = score;
+            best_move = Some(mov);
+        }
+    }
+
+    let bound = if alpha > original_alpha {
+        Bound::Exact
+    } else {
+        Bound::Upper
+    };
+    search.table.insert(
+        key,
+        TTEntry {
+            depth,
+            score: alpha,
+            bound,
+            best_move,
+        },
+    );
+
+    alpha
+}
+
+/// Returns the best move for the player if `is_player` is true, or for the enemy otherwise,
+/// searching `depth` plies ahead. Returns `None` if that side has no legal moves.
+pub fn best_move(board: &BitBoard, is_player: bool, depth: u8) -> Option<Move> {
+    search_root(board, is_player, depth, &mut Search::default())
+}
+
+/// Searches every move of the side to move, see `best_move`.
+fn search_root(board: &BitBoard, is_player: bool, depth: u8, search: &mut Search) -> Option<Move> {
+    let mut moves = board.legal_moves(is_player)?;
+    order_moves(&mut moves);
+
+    let mut best_move = None;
+    let mut alpha = LOSS_SCORE * 2;
+    let beta = -alpha;
+    for mov in moves {
+        let mut next = *board;
+        next.apply_move(&mov);
+
+        let score = -negamax(
+            &next,
+            !is_player,
+            depth.saturating_sub(1),
+            -beta,
+            -alpha,
+            search,
+        );
+        if best_move.is_none() || score > alpha {
+            alpha = score;
+            best_move = Some(mov);
+        }
+    }
+
+    best_move
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::board::Board;
+
+    /// `negamax` without the transposition table
+    fn plain_negamax(
+        board: &BitBoard,
+        is_player: bool,
+        depth: u8,
+        mut alpha: i32,
+        beta: i32,
+        nodes: &mut u64,
+    ) -> i32 {
+        *nodes += 1;
+        let Some(mut moves) = board.legal_moves(is_player) else {
+            return LOSS_SCORE - depth as i32;
+        };
+        if depth == 0 {
+            return evaluate(board, is_player);
+        }
+
+        order_moves(&mut moves);
+        for mov in moves {
+            let mut next = *board;
+            next.apply_move(&mov);
+            let score = -plain_negamax(&next, !is_player, depth - 1, -beta, -alpha, nodes);
+            if score >= beta {
+                return beta;
+            }
+            alpha = alpha.max(score);
+        }
+        alpha
+    }
+
+    /// `search_root` without the transposition table
+    fn plain_best_move(
+        board: &BitBoard,
+        is_player: bool,
+        depth: u8,
+        nodes: &mut u64,
+    ) -> Option<Move> {
+        let mut moves = board.legal_moves(is_player)?;
+        order_moves(&mut moves);
+
+        let mut best_move = None;
+        let mut alpha = LOSS_SCORE * 2;
+        let beta = -alpha;
+        for mov in moves {
+            let mut next = *board;
+            next.apply_move(&mov);
+            let score = -plain_negamax(&next, !is_player, depth - 1, -beta, -alpha, nodes);
+            if best_move.is_none() || score > alpha {
+                alpha = score;
+                best_move = Some(mov);
+            }
+        }
+        best_move
+    }
+
+    /// A position from the opening, where Black has moved the man from 8 to 12
+    fn position() -> BitBoard {
+        let diagram =
+            "b.b.b.b.\n.b.b.b.b\n..b.b.b.\n.b......\n........\n.w.w.w.w\nw.w.w.w.\n.w.w.w.w";
+        Board::from_diagram(diagram, PieceColor::White)
+            .unwrap()
+            .snapshot()
+    }
+
+    #[test]
+    fn table_finds_same_move_as_plain_search() {
+        let board = position();
+        for depth in 1..=6 {
+            let expected = plain_best_move(&board, true, depth, &mut 0);
+            assert_eq!(best_move(&board, true, depth), expected, "depth {}", depth);
+        }
+    }
+
+    #[test]
+    fn table_searches_fewer_nodes() {
+        let board = position();
+        let mut plain_nodes = 0;
+        plain_best_move(&board, true, 6, &mut plain_nodes);
+        let mut search = Search::default();
+        search_root(&board, true, 6, &mut search);
+
+        assert!(
+            search.nodes < plain_nodes,
+            "{} nodes with the table, {} without",
+            search.nodes,
+            plain_nodes
+        );
+    }
+
+    #[test]
+    fn promotions_are_ordered_before_slides() {
+        // White can crown the man on 5, or slide the man on 25
+        let rows = [
+            "........", ".w......", "........", "........", "....b...", "........", "w.......",
+            "........",
+        ];
+        let board = Board::from_diagram(&rows.join("\n"), PieceColor::White)
+            .unwrap()
+            .snapshot();
+        let mut moves = board.legal_moves(true).unwrap();
+        assert!(moves.iter().any(|mov| !mov.promoted));
+
+        // The slides come first before ordering
+        moves.sort_by_key(|mov| mov.promoted);
+        order_moves(&mut moves);
+        let first_slide = moves.iter().position(|mov| !mov.promoted).unwrap();
+        assert!(first_slide > 0, "{:?}", moves);
+        assert!(moves[first_slide..].iter().all(|mov| !mov.promoted));
+    }
+}
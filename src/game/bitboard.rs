@@ -0,0 +1,645 @@
+use std::fmt;
+
+use super::{tile_index, tile_position, zobrist, Direction, Move, PieceColor, PieceData, Rules};
+
+/// A compact copy of the board, where every tile is a bit in a `u32`.
+/// Bit `n` corresponds to the tile with index `n` in the `pieces` model of `Board`.
+///
+/// Unlike `Board` this doesn't touch any Slint models, so it is cheap to copy and mutate, which
+/// makes it the right type for searching through positions.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct BitBoard {
+    /// The tiles holding a piece that isn't a king
+    pub men: u32,
+    /// The tiles holding a king
+    pub kings: u32,
+    /// The tiles holding a piece owned by the local player
+    pub player: u32,
+    /// The rules used for finding the legal moves
+    pub rules: Rules,
+}
+
+/// Returns the index of the next tile in `direction`, or `None` if `index` is on the edge
+/// of the board in that direction.
+pub(super) fn next_index(index: usize, direction: &Direction) -> Option<usize> {
+    let (row, column) = tile_position(index);
+    let (row_step, column_step) = direction.offset();
+    let (row, column) = (row + row_step, column + column_step);
+
+    // Stepping off a side of the board must not wrap around to the other side
+    if !(0..8).contains(&row) || !(0..8).contains(&column) {
+        return None;
+    }
+    Some(tile_index((row, column)))
+}
+
+/// Returns wether a piece owned by the local player (or not) may move in `direction` without
+/// capturing
+fn can_move_in(direction: &Direction, is_king: bool, is_player: bool) -> bool {
+    // If the piece isn't a king it cant move backwards
+    is_king || (direction.is_up() && is_player) || (direction.is_down() && !is_player)
+}
+
+/// Returns wether a piece landing on `index` gets promoted to a king
+fn is_crown_tile(index: usize, is_player: bool) -> bool {
+    is_player && index < 4 || !is_player && index >= 32 - 4
+}
+
+impl BitBoard {
+    /// Creates a `BitBoard` from the pieces of a `Board`.
+    pub fn from_pieces(pieces: &[PieceData], player_color: PieceColor) -> Self {
+        let mut board = Self::default();
+        for (index, piece) in pieces.iter().enumerate() {
+            if !piece.is_active {
+                continue;
+            }
+
+            let bit = 1 << index;
+            if piece.is_king {
+                board.kings |= bit;
+            } else {
+                board.men |= bit;
+            }
+
+            if piece.color == player_color {
+                board.player |= bit;
+            }
+        }
+        board
+    }
+
+    /// All tiles holding a piece
+    pub const fn occupied(&self) -> u32 {
+        self.men | self.kings
+    }
+
+    /// All tiles holding a piece not owned by the local player
+    pub const fn enemy(&self) -> u32 {
+        self.occupied() & !self.player
+    }
+
+    /// Returns true if the tile at `index` holds a piece
+    pub const fn is_occupied(&self, index: usize) -> bool {
+        self.occupied() & (1 << index) != 0
+    }
+
+    /// Returns true if the tile at `index` holds a piece owned by the local player
+    pub const fn is_player(&self, index: usize) -> bool {
+        self.player & (1 << index) != 0
+    }
+
+    /// Returns true if the tile at `index` holds a king
+    pub const fn is_king(&self, index: usize) -> bool {
+        self.kings & (1 << index) != 0
+    }
+
+    /// Returns the `PieceData` of the tile at `index`, for writing back to the Slint models.
+    pub fn piece_data(&self, index: usize, player_color: PieceColor) -> PieceData {
+        if !self.is_occupied(index) {
+            return PieceData::const_default();
+        }
+
+        PieceData {
+            is_active: true,
+            is_king: self.is_king(index),
+            color: if self.is_player(index) {
+                player_color
+            } else {
+                player_color.get_opposite()
+            },
+        }
+    }
+
+    /// Returns the Zobrist hash of the pieces, where the local player has `player_color`.
+    /// The hash is built from logical indices, so it is the same for both players, and matches
+    /// `Board::board_hash`.
+    pub fn board_hash(&self, player_color: PieceColor) -> u64 {
+        let mut hash = 0;
+        for index in 0..32 {
+            if !self.is_occupied(index) {
+                continue;
+            }
+
+            let logical = match player_color {
+                PieceColor::White => index,
+                PieceColor::Black => 31 - index,
+            };
+            hash ^= zobrist::piece_key(logical, &self.piece_data(index, player_color));
+        }
+        hash
+    }
+
+    /// Empties the tile at `index`
+    fn clear(&mut self, index: usize) {
+        let mask = !(1 << index);
+        self.men &= mask;
+        self.kings &= mask;
+        self.player &= mask;
+    }
+
+    /// Returns wether a piece owned by the local player (or not) may capture in `direction`
+    fn can_capture_in(&self, direction: &Direction, is_king: bool, is_player: bool) -> bool {
+        self.rules.men_capture_backwards || can_move_in(direction, is_king, is_player)
+    }
+
+    /// Performs the move described by `mov`.
+    /// The move isn't validated, so it should be a move returned by `legal_moves`.
+    pub fn apply_move(&mut self, mov: &Move) {
+        let is_king = self.is_king(mov.index) || mov.promoted;
+        let is_player = self.is_player(mov.index);
+
+        self.clear(mov.index);
+        if let Some(captured) = &mov.captured {
+            for piece in captured {
+                self.clear(*piece);
+            }
+        }
+
+        let bit = 1 << mov.end;
+        if is_king {
+            self.kings |= bit;
+        } else {
+            self.men |= bit;
+        }
+        if is_player {
+            self.player |= bit;
+        }
+    }
+
+    /// Returns the tile of the first piece found in `direction` from `index`. Kings can look
+    /// past any number of empty tiles, while other pieces only look at the neighbouring tile.
+    fn find_target(&self, index: usize, direction: &Direction, is_king: bool) -> Option<usize> {
+        let mut next = next_index(index, direction)?;
+        while is_king && !self.is_occupied(next) {
+            next = next_index(next, direction)?;
+        }
+        Some(next)
+    }
+
+    /// Follows every capture sequence that starts by jumping the piece at `over` in
+    /// `direction`. `captured` holds the pieces already jumped earlier in the sequence.
+    ///
+    /// Jumped pieces stay on the board until the whole move is done, so they block the path
+    /// and can't be jumped twice. A king may land on any empty tile behind the jumped piece,
+    /// and from every one of them it may continue in all four directions.
+    fn capture_paths(
+        &self,
+        start: usize,
+        over: usize,
+        is_player: bool,
+        is_king: bool,
+        direction: &Direction,
+        mut captured: Vec<usize>,
+    ) -> Option<Vec<Move>> {
+        let targets = if is_player { self.enemy() } else { self.player };
+        if targets & (1 << over) == 0 || captured.contains(&over) {
+            return None;
+        }
+
+        let mut landings = vec![];
+        let mut landing = next_index(over, direction);
+        while let Some(tile) = landing {
+            if self.is_occupied(tile) {
+                break;
+            }
+            landings.push(tile);
+            if !is_king {
+                break;
+            }
+            landing = next_index(tile, direction);
+        }
+
+        if landings.is_empty() {
+            return None;
+        }
+
+        captured.push(over);
+
+        let mut moves = vec![];
+        let mut ending_moves = vec![];
+        for landing in landings {
+            // A man reaching the crown row is promoted, which ends the turn even if it could
+            // keep capturing
+            let promoting = !is_king && is_crown_tile(landing, is_player);
+            if promoting {
+                ending_moves.push(Move {
+                    index: start,
+                    end: landing,
+                    captured: Some(captured.clone()),
+                    promoted: true,
+                });
+                continue;
+            }
+
+            // Check to see if we can take further pieces from the landing tile
+            let mut further_moves = vec![];
+            for direction in Direction::values() {
+                if !self.can_capture_in(direction, is_king, is_player) {
+                    continue;
+                }
+                let Some(next) = self.find_target(landing, direction, is_king) else {
+                    continue;
+                };
+
+                if let Some(mut moves) =
+                    self.capture_paths(start, next, is_player, is_king, direction, captured.clone())
+                {
+                    further_moves.append(&mut moves);
+                }
+            }
+
+            // No further pieces can be taken, so the sequence ends on the landing tile
+            if further_moves.is_empty() {
+                ending_moves.push(Move {
+                    index: start,
+                    end: landing,
+                    captured: Some(captured.clone()),
+                    promoted: false,
+                });
+            }
+
+            moves.append(&mut further_moves);
+        }
+
+        // A king has to land where it can keep capturing, if there is such a tile
+        if moves.is_empty() {
+            moves = ending_moves;
+        }
+
+        Some(moves)
+    }
+
+    /// Returns the moves the piece starting at `start` can make from `index` in `direction`,
+    /// and wether they are capturing.
+    fn check_move(
+        &self,
+        start: usize,
+        index: usize,
+        is_player: bool,
+        is_king: bool,
+        direction: &Direction,
+    ) -> Option<(Vec<Move>, bool)> {
+        let next = next_index(index, direction)?;
+
+        // If the next piece is an enemy check if the next tile is empty
+        // If so this piece can be taken
+        if self.is_occupied(next) {
+            if !self.can_capture_in(direction, is_king, is_player) {
+                return None;
+            }
+            return self
+                .capture_paths(start, next, is_player, is_king, direction, vec![])
+                .map(|moves| (moves, true));
+        }
+
+        if !can_move_in(direction, is_king, is_player) {
+            return None;
+        }
+
+        let promoting = !is_king && is_crown_tile(next, is_player);
+
+        // If we aren't taking a piece, and this tile is empty
+        // We add this move to a list of possible moves
+        let mut moves = vec![];
+        let mut is_taking = false;
+
+        // If the current piece is a king, it may be able to keep moving
+        if is_king {
+            if let Some(mut next_moves) =
+                self.check_move(start, next, is_player, is_king, direction)
+            {
+                moves.append(&mut next_moves.0);
+                is_taking = next_moves.1;
+            }
+        }
+
+        // If we are capturing pieces
+        // Since this move doesn't capture, it should not be added
+        if !is_taking {
+            moves.push(Move {
+                index: start,
+                end: next,
+                captured: None,
+                promoted: promoting,
+            });
+        }
+
+        // Return all the available moves
+        // 1 move if normal piece, x amount if king piece
+        Some((moves, is_taking))
+    }
+
+    /// Get's all the legal moves for the piece at `index`, and wether they are capturing.
+    /// This works for both enemy pieces and player pieces.
+    ///
+    /// Every capture sequence the piece can make is returned as its own `Move`, with `captured`
+    /// holding the jumped pieces in the order they are taken. Two sequences that branch off from
+    /// each other are never merged, even if they end on the same square.
+    pub fn legal_moves_piece(&self, index: usize) -> Option<(Vec<Move>, bool)> {
+        if index >= 32 || !self.is_occupied(index) {
+            return None;
+        }
+
+        let is_player = self.is_player(index);
+        let is_king = self.is_king(index);
+
+        // The moving piece leaves its tile, so sequences are free to pass back over it
+        let mut board = *self;
+        board.clear(index);
+
+        let mut quiet_moves = vec![];
+        let mut capturing_moves = vec![];
+        for direction in Direction::values() {
+            let Some((mut next_moves, is_taking)) =
+                board.check_move(index, index, is_player, is_king, direction)
+            else {
+                continue;
+            };
+
+            if is_taking {
+                capturing_moves.append(&mut next_moves);
+            } else {
+                quiet_moves.append(&mut next_moves);
+            }
+        }
+
+        // Capturing is mandatory, so quiet moves are only legal if nothing can be taken
+        if !capturing_moves.is_empty() {
+            Some((capturing_moves, true))
+        } else if !quiet_moves.is_empty() {
+            Some((quiet_moves, false))
+        } else {
+            None
+        }
+    }
+
+    /// Returns true if `mov` is one of the legal moves of the side owning the piece it moves.
+    pub fn is_legal_move(&self, mov: &Move) -> bool {
+        if mov.index >= 32 || mov.end >= 32 || !self.is_occupied(mov.index) {
+            return false;
+        }
+
+        self.legal_moves(self.is_player(mov.index))
+            .is_some_and(|moves| moves.contains(mov))
+    }
+
+    /// Returns all legal moves for the local player if `is_player` is true, or for the enemy
+    /// otherwise. Returns `None` if that side can't move at all.
+    pub fn legal_moves(&self, is_player: bool) -> Option<Vec<Move>> {
+        let mut pieces = if is_player { self.player } else { self.enemy() };
+
+        let mut quiet_moves = vec![];
+        let mut capturing_moves = vec![];
+        while pieces != 0 {
+            let index = pieces.trailing_zeros() as usize;
+            pieces &= pieces - 1;
+
+            if let Some((mut moves, is_taking)) = self.legal_moves_piece(index) {
+                if is_taking {
+                    capturing_moves.append(&mut moves);
+                } else if capturing_moves.is_empty() {
+                    quiet_moves.append(&mut moves);
+                }
+            }
+        }
+
+        if !capturing_moves.is_empty() {
+            Some(capturing_moves)
+        } else if !quiet_moves.is_empty() {
+            Some(quiet_moves)
+        } else {
+            None
+        }
+    }
+}
+
+/// Draws the board as an 8x8 grid of characters, one line per row, as it is shown to the player.
+/// `x`/`X` are the player's men/kings, `o`/`O` the enemy's men/kings, `.` is an empty playable
+/// tile, and a space is a tile pieces can't stand on.
+impl fmt::Display for BitBoard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for row in 0..8 {
+            for column in 0..8 {
+                if (row + column) % 2 != 0 {
+                    write!(f, " ")?;
+                    continue;
+                }
+
+                let index = row * 4 + column / 2;
+                let tile = match (
+                    self.is_occupied(index),
+                    self.is_player(index),
+                    self.is_king(index),
+                ) {
+                    (false, _, _) => '.',
+                    (true, true, false) => 'x',
+                    (true, true, true) => 'X',
+                    (true, false, false) => 'o',
+                    (true, false, true) => 'O',
+                };
+                write!(f, "{}", tile)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a board with the given men and kings, where `player` tells which of them are the
+    /// local player's
+    fn board(men: &[usize], kings: &[usize], player: &[usize]) -> BitBoard {
+        let bits = |tiles: &[usize]| tiles.iter().fold(0, |bits, tile| bits | 1 << tile);
+        BitBoard {
+            men: bits(men),
+            kings: bits(kings),
+            player: bits(player),
+            rules: Rules::default(),
+        }
+    }
+
+    #[test]
+    fn branching_captures_are_separate_moves() {
+        // The man on 29 can take 25 and 17 to the left, or 26 and 19 to the right
+        let board = board(&[29, 25, 17, 26, 19], &[], &[29]);
+
+        let (moves, is_taking) = board.legal_moves_piece(29).unwrap();
+        assert!(is_taking);
+        assert_eq!(
+            moves,
+            vec![
+                Move {
+                    index: 29,
+                    end: 15,
+                    promoted: false,
+                    captured: Some(vec![26, 19]),
+                },
+                Move {
+                    index: 29,
+                    end: 13,
+                    promoted: false,
+                    captured: Some(vec![25, 17]),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn king_capture_can_turn() {
+        // The king on 18 takes 13 going up-left, and then 5 going up-right
+        let board = board(&[13, 5], &[18], &[18]);
+
+        let (moves, is_taking) = board.legal_moves_piece(18).unwrap();
+        assert!(is_taking);
+        assert_eq!(
+            moves,
+            vec![Move {
+                index: 18,
+                end: 2,
+                promoted: false,
+                captured: Some(vec![13, 5]),
+            }]
+        );
+    }
+
+    #[test]
+    fn landing_on_the_crown_row_ends_the_jump() {
+        // The man on 9 takes 5 and lands on the crown row at 2, where a backwards capture of 6
+        // would otherwise continue to 11
+        let mut board = board(&[9, 5, 6], &[], &[9]);
+        board.rules.men_capture_backwards = true;
+
+        let (moves, is_taking) = board.legal_moves_piece(9).unwrap();
+        assert!(is_taking);
+        assert_eq!(
+            moves,
+            vec![Move {
+                index: 9,
+                end: 2,
+                promoted: true,
+                captured: Some(vec![5]),
+            }]
+        );
+    }
+
+    #[test]
+    fn men_capture_backwards_when_the_rule_allows_it() {
+        // The man on 9 can take 12 going down-left, landing on 16
+        let mut board = board(&[9, 12], &[], &[9]);
+
+        let (moves, is_taking) = board.legal_moves_piece(9).unwrap();
+        assert!(is_taking);
+        assert_eq!(
+            moves,
+            vec![Move {
+                index: 9,
+                end: 16,
+                promoted: false,
+                captured: Some(vec![12]),
+            }]
+        );
+
+        // Without the rule the man can only step forwards
+        board.rules.men_capture_backwards = false;
+        let (moves, is_taking) = board.legal_moves_piece(9).unwrap();
+        assert!(!is_taking);
+        assert!(moves
+            .iter()
+            .all(|mov| mov.captured.is_none() && mov.end < 9));
+        assert_eq!(moves.len(), 2);
+    }
+
+    #[test]
+    fn no_move_wraps_around_the_edge() {
+        let on_board =
+            |(row, column): (i32, i32)| (0..8).contains(&row) && (0..8).contains(&column);
+        // The number of tiles from `index` to the edge of the board, in `direction`
+        let reach = |index: usize, direction: &Direction| {
+            let (row, column) = tile_position(index);
+            let (row_step, column_step) = direction.offset();
+            (1..8)
+                .take_while(|distance| {
+                    on_board((row + row_step * distance, column + column_step * distance))
+                })
+                .count()
+        };
+
+        for index in 0..32 {
+            let (row, column) = tile_position(index);
+
+            // A lone king slides along every diagonal until it reaches the edge
+            let (moves, _) = board(&[], &[index], &[index])
+                .legal_moves_piece(index)
+                .unwrap();
+            let expected: usize = Direction::values()
+                .iter()
+                .map(|direction| reach(index, direction))
+                .sum();
+            assert_eq!(moves.len(), expected, "tile {}", index);
+            for mov in moves {
+                let (end_row, end_column) = tile_position(mov.end);
+                assert!(on_board((end_row, end_column)));
+                assert_eq!((end_row - row).abs(), (end_column - column).abs());
+            }
+
+            // Surrounded by enemy men a man can jump every one which has room behind it
+            let neighbours: Vec<usize> = Direction::values()
+                .iter()
+                .filter_map(|direction| next_index(index, direction))
+                .collect();
+            let board = board(&[&neighbours[..], &[index]].concat(), &[], &[index]);
+            let moves = match board.legal_moves_piece(index) {
+                Some((moves, true)) => moves,
+                _ => vec![],
+            };
+            let expected = Direction::values()
+                .iter()
+                .filter(|direction| reach(index, direction) >= 2)
+                .count();
+            assert_eq!(moves.len(), expected, "tile {}", index);
+            for mov in moves {
+                let (end_row, end_column) = tile_position(mov.end);
+                assert!(on_board((end_row, end_column)));
+                assert_eq!(((end_row - row).abs(), (end_column - column).abs()), (2, 2));
+                let captured = mov.captured.unwrap();
+                assert_eq!(
+                    tile_position(captured[0]),
+                    ((row + end_row) / 2, (column + end_column) / 2)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn partial_jump_is_illegal() {
+        // The man on 25 must take both 21 and 14, stopping on 18 after the first isn't allowed
+        let board = board(&[25, 21, 14, 0, 31], &[], &[25, 31]);
+        let full = Move {
+            index: 25,
+            end: 11,
+            promoted: false,
+            captured: Some(vec![21, 14]),
+        };
+        let partial = Move {
+            index: 25,
+            end: 18,
+            promoted: false,
+            captured: Some(vec![21]),
+        };
+        // Nor is a second jump on its own, as if the first had been a separate turn
+        let second = Move {
+            index: 18,
+            end: 11,
+            promoted: false,
+            captured: Some(vec![14]),
+        };
+
+        assert_eq!(board.legal_moves(true), Some(vec![full.clone()]));
+        assert!(board.is_legal_move(&full));
+        assert!(!board.is_legal_move(&partial));
+        assert!(!board.is_legal_move(&second));
+    }
+}
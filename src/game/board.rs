@@ -1,12 +1,30 @@
-use super::{BoardSquare, Direction, GameWindow, Move, PieceColor, PieceData};
+use super::{
+    ai,
+    bitboard::{self, BitBoard},
+    clock::MoveClock,
+    zobrist, BoardSquare, Direction, GameResult, GameWindow, Move, PieceColor, PieceColorDef,
+    PieceData, PieceDataDef, Rules,
+};
+use anyhow::{anyhow, Context};
 use futures::executor;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
 use slint::ComponentHandle;
 use slint::{Model, Weak};
-use std::mem::{transmute, MaybeUninit};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::fs;
+use std::path::Path;
 use std::rc::Rc;
+use thiserror::Error;
 use tokio::sync::Mutex;
+use tracing::debug;
 
-pub static mut BOARD_MOVE: Mutex<Move> = Mutex::const_new(Move {
+/// The next move to be performed by `Board::move_piece`, in logical indices.
+/// Moves made by the local player and moves recieved from the other player both go through
+/// here, so they can use the same logical indices.
+pub static BOARD_MOVE: Mutex<Move> = Mutex::const_new(Move {
     index: 0,
     end: 0,
     promoted: false,
@@ -14,28 +32,215 @@ pub static mut BOARD_MOVE: Mutex<Move> = Mutex::const_new(Move {
 });
 
 pub fn set_board_move(mov: &Move) {
-    unsafe { *executor::block_on(BOARD_MOVE.lock()) = mov.clone() };
+    *executor::block_on(BOARD_MOVE.lock()) = mov.clone();
 }
 
 pub fn get_board_move() -> Move {
-    unsafe { executor::block_on(BOARD_MOVE.lock()).clone() }
+    executor::block_on(BOARD_MOVE.lock()).clone()
+}
+
+/// How many plies ahead `Board::suggest_move` searches
+const HINT_DEPTH: u8 = 6;
+
+/// The width and height of a tile in the images made by `Board::to_svg`
+const SVG_TILE_SIZE: i32 = 50;
+
+/// How many moves `Board::start_new_game_random` makes at random, before the players take over
+const RANDOM_OPENING_PLIES: usize = 3;
+
+/// A `PieceData` which can be serialized
+#[derive(Serialize, Deserialize)]
+struct SavedPiece(#[serde(with = "PieceDataDef")] PieceData);
+
+/// Everything needed to resume a game, as it is written to a save file
+#[derive(Serialize, Deserialize)]
+struct SavedGame {
+    /// The pieces in display indices
+    pieces: Vec<SavedPiece>,
+    #[serde(with = "PieceColorDef")]
+    player_color: PieceColor,
+    selected_square: i32,
+    history: Vec<Move>,
+    repetitions: HashMap<u64, u8>,
+}
+
+/// The tiles of a board in logical indices. Every tile is empty, or holds a piece with its color
+/// and wether it is a king.
+pub type Layout = [Option<(PieceColor, bool)>; 32];
+
+/// Why a board can't occur in a game, see `Board::validate`
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum BoardError {
+    #[error("The board has {count} tiles, expected 32")]
+    WrongTileCount { count: usize },
+    #[error("{color} has {count} pieces, but a side starts with only 12")]
+    TooManyPieces { color: PieceColor, count: usize },
+    #[error("A man of {color} is on tile {index} on the crown row, where it should be a king")]
+    UncrownedMan { color: PieceColor, index: usize },
+    #[error("Tile {index} is empty, but holds a king")]
+    KingOnEmptyTile { index: usize },
+}
+
+/// Checks that `pieces`, given in display indices, can occur in a game where the local player
+/// has `player_color`, see `Board::validate`.
+fn validate_pieces(pieces: &[PieceData], player_color: PieceColor) -> Result<(), BoardError> {
+    if pieces.len() != 32 {
+        return Err(BoardError::WrongTileCount {
+            count: pieces.len(),
+        });
+    }
+
+    for color in [PieceColor::White, PieceColor::Black] {
+        let count = pieces
+            .iter()
+            .filter(|piece| piece.is_active && piece.color == color)
+            .count();
+        if count > 12 {
+            return Err(BoardError::TooManyPieces { color, count });
+        }
+    }
+
+    // The local player crowns on the top row, and the other player on the bottom row
+    for (index, piece) in pieces.iter().enumerate() {
+        if !piece.is_active && piece.is_king {
+            return Err(BoardError::KingOnEmptyTile { index });
+        }
+
+        let crown_row = if piece.color == player_color { 0 } else { 7 };
+        if piece.is_active && !piece.is_king && index / 4 == crown_row {
+            return Err(BoardError::UncrownedMan {
+                color: piece.color,
+                index,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// How many pieces each side has captured and crowned since the game started, see `Board::stats`.
+/// Unlike the piece counts these only ever go up, except when moves are taken back.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GameStats {
+    /// How many of the enemy's pieces the local player has captured
+    pub player_captured: u8,
+    /// How many of the local player's pieces the enemy has captured
+    pub enemy_captured: u8,
+    /// How many of the local player's men have been crowned
+    pub player_promotions: u8,
+    /// How many of the enemy's men have been crowned
+    pub enemy_promotions: u8,
+}
+
+impl GameStats {
+    /// Counts a move by the local player if `is_player` is true, or by the enemy otherwise.
+    fn add_move(&mut self, is_player: bool, outcome: &MoveOutcome) {
+        let captured = outcome.captured.len() as u8;
+        let promoted = outcome.promoted_square.is_some() as u8;
+        if is_player {
+            self.player_captured += captured;
+            self.player_promotions += promoted;
+        } else {
+            self.enemy_captured += captured;
+            self.enemy_promotions += promoted;
+        }
+    }
+
+    /// Stops counting a move counted by `add_move`, when it is taken back.
+    fn remove_move(&mut self, is_player: bool, outcome: &MoveOutcome) {
+        let captured = outcome.captured.len() as u8;
+        let promoted = outcome.promoted_square.is_some() as u8;
+        if is_player {
+            self.player_captured = self.player_captured.saturating_sub(captured);
+            self.player_promotions = self.player_promotions.saturating_sub(promoted);
+        } else {
+            self.enemy_captured = self.enemy_captured.saturating_sub(captured);
+            self.enemy_promotions = self.enemy_promotions.saturating_sub(promoted);
+        }
+    }
+}
+
+/// Returns the tiles a capture sequence lands on after its start, and the tiles of the pieces it
+/// captures, which tell it apart from other sequences ending on the same tile.
+fn sequence_tiles(mov: &Move) -> Vec<usize> {
+    let mut tiles = mov.path().split_off(1);
+    tiles.extend(mov.captured.iter().flatten());
+    tiles
+}
+
+/// What choosing a tile for the selected piece leads to, see `Board::choose_move`
+#[derive(Clone, Debug, PartialEq)]
+pub enum MoveChoice {
+    /// The piece has no legal move ending on the tile
+    NoMove,
+    /// Several capture sequences end on the tile, and one of them is shown for the player to
+    /// confirm, or to switch to another one
+    ShowingSequence,
+    /// The legal move the player chose, in display indices
+    Move(Move),
+}
+
+/// What changed on the board when `Board::move_piece` performed a move, in display indices
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MoveOutcome {
+    /// The tiles of the pieces which were captured, in the order they were taken
+    pub captured: Vec<usize>,
+    /// The tile of the moved piece, if it was crowned by the move
+    pub promoted_square: Option<usize>,
 }
 
 /// Struct holding gamestate of the checkers board
+///
+/// The board is stored the way it is shown, with the local player's pieces at the bottom.
+/// Logical indices number the tiles as seen from White's side, so they are the same for both
+/// players, and are used whenever a tile has to be understood by the other player.
+///
+/// The derived `Clone` shares the Slint models with the original, so moving a piece on a clone
+/// also moves it on the original. Use `fork` for a copy which can be changed on its own.
 #[derive(Default, Clone)]
 pub struct Board {
     game: Weak<GameWindow>,
     pieces: Rc<slint::VecModel<PieceData>>,
+    /// Kept in sync with `pieces`, and used for move generation
+    bitboard: BitBoard,
+    /// Zobrist hash of the pieces, without the side to move. Updated by `move_piece`
+    hash: u64,
     player_color: PieceColor,
     squares: Rc<slint::VecModel<BoardSquare>>,
     pub selected_square: i32,
+    /// Every move performed since the game started, in logical indices
+    history: Vec<Move>,
+    /// The color whose turn it is. Passed to the other side by `move_piece`, since a capture
+    /// sequence is a single move
+    side_to_move: PieceColor,
+    /// The board from before each of the latest moves in `history`, used by `undo_move`.
+    /// Moves made before the pieces were replaced, eg. by loading a save, can't be taken back
+    undo_stack: Vec<BitBoard>,
+    /// How many times each position has occurred, keyed by its Zobrist hash with the side to move
+    repetitions: HashMap<u64, u8>,
+    /// The clock of the game, if it is played with a time limit
+    clock: Option<MoveClock>,
+    /// Set when the game was ended by the players, eg. by agreeing to a draw
+    result: Option<GameResult>,
+    /// The legal moves of the side to move, returned by `get_legal_moves` until the pieces change
+    legal_moves: RefCell<Option<Vec<Move>>>,
+    /// A move chosen by the player during the opponent's turn, made once it's the player's turn
+    premove: Option<Move>,
+    /// The piece picked up with `confirm_selection`, whose moves are marked
+    keyboard_piece: Option<usize>,
+    /// The captures and promotions of both sides since the game started
+    stats: GameStats,
+    /// The capture sequence shown by `choose_move`, when several of them end on the same tile
+    shown_sequence: Option<Move>,
+    /// The seed of the random opening the game started with, see `start_new_game_random`
+    opening_seed: Option<u64>,
 }
 
 impl Board {
     pub fn new(game: &GameWindow) -> Board {
         let pieces = Rc::new(slint::VecModel::from(vec![]));
 
-        let squares: Vec<BoardSquare> = vec![BoardSquare { marked: false }; 32];
+        let squares: Vec<BoardSquare> = vec![BoardSquare::default(); 32];
         let squares = Rc::new(slint::VecModel::from(squares));
         game.set_squares(squares.clone().into());
 
@@ -47,6 +252,110 @@ impl Board {
         }
     }
 
+    /// Creates a board with the pieces in `layout`, where the local player has `player_color`,
+    /// and White is the side to move. The board isn't shown in a window, which makes it useful
+    /// for setting up positions in tests.
+    pub fn from_layout(layout: Layout, player_color: PieceColor) -> Board {
+        let squares: Vec<BoardSquare> = vec![BoardSquare::default(); 32];
+        let mut board = Board {
+            player_color,
+            squares: Rc::new(slint::VecModel::from(squares)),
+            ..Default::default()
+        };
+
+        let pieces = (0..32)
+            .map(|index| match layout[board.to_logical_index(index)] {
+                Some((color, is_king)) => PieceData {
+                    color,
+                    is_active: true,
+                    is_king,
+                },
+                None => PieceData::const_default(),
+            })
+            .collect();
+        board.set_pieces(pieces);
+        board
+            .repetitions
+            .insert(board.zobrist(PieceColor::White), 1);
+        board
+    }
+
+    /// Parses a `Layout` from an ASCII diagram of the board, as seen from White's side.
+    /// The diagram has 8 rows of 8 characters, where whitespace around the rows is ignored.
+    /// Only the dark squares are read: `w` and `b` are men, `W` and `B` are kings, and `.` is an
+    /// empty tile. The light squares can hold any character except a piece, since no piece can
+    /// stand there. The starting position is:
+    ///
+    /// ```text
+    /// b.b.b.b.
+    /// .b.b.b.b
+    /// b.b.b.b.
+    /// ........
+    /// ........
+    /// .w.w.w.w
+    /// w.w.w.w.
+    /// .w.w.w.w
+    /// ```
+    pub fn parse_layout(diagram: &str) -> anyhow::Result<Layout> {
+        let rows: Vec<Vec<char>> = diagram
+            .lines()
+            .map(str::trim)
+            .filter(|row| !row.is_empty())
+            .map(|row| row.chars().collect())
+            .collect();
+        if rows.len() != 8 {
+            return Err(anyhow!("Diagram has {} rows, expected 8", rows.len()));
+        }
+
+        let mut layout = [None; 32];
+        for (index, tile) in layout.iter_mut().enumerate() {
+            let (row, column) = super::tile_position(index);
+            let row_chars = &rows[row as usize];
+            if row_chars.len() != 8 {
+                return Err(anyhow!(
+                    "Row {} of the diagram has {} columns, expected 8",
+                    row + 1,
+                    row_chars.len()
+                ));
+            }
+
+            *tile = match row_chars[column as usize] {
+                '.' => None,
+                'w' => Some((PieceColor::White, false)),
+                'W' => Some((PieceColor::White, true)),
+                'b' => Some((PieceColor::Black, false)),
+                'B' => Some((PieceColor::Black, true)),
+                other => {
+                    return Err(anyhow!(
+                        "Unknown piece {:?} in row {} of the diagram",
+                        other,
+                        row + 1
+                    ))
+                }
+            };
+        }
+
+        for (row, row_chars) in rows.iter().enumerate() {
+            let mut light_squares = row_chars.iter().skip(1 - row % 2).step_by(2);
+            if let Some(piece) = light_squares.find(|tile| "wWbB".contains(**tile)) {
+                return Err(anyhow!(
+                    "Piece {:?} on a light square in row {} of the diagram",
+                    piece,
+                    row + 1
+                ));
+            }
+        }
+        Ok(layout)
+    }
+
+    /// Creates a board from an ASCII diagram, see `parse_layout` and `from_layout`.
+    /// Fails if the diagram can't be parsed, or the position can't occur in a game.
+    pub fn from_diagram(diagram: &str, player_color: PieceColor) -> anyhow::Result<Board> {
+        let board = Board::from_layout(Board::parse_layout(diagram)?, player_color);
+        board.validate()?;
+        Ok(board)
+    }
+
     /// Returns the starting setup of a checkers board based off `player_color`
     fn default_setup(player_color: PieceColor) -> Vec<PieceData> {
         let enemy_color = player_color.get_opposite();
@@ -79,50 +388,749 @@ impl Board {
     /// Resets the board to starting state based off `player_color`
     pub fn start_new_game(&mut self, color: PieceColor) {
         self.player_color = color;
-        self.pieces = Rc::new(slint::VecModel::from(Board::default_setup(color)));
+        let setup = Board::default_setup(color);
+        self.bitboard = BitBoard {
+            rules: self.bitboard.rules,
+            ..BitBoard::from_pieces(&setup, color)
+        };
+        self.legal_moves.take();
+        self.hash = self.pieces_hash();
+        self.pieces = Rc::new(slint::VecModel::from(setup));
+
+        // White always moves first
+        self.side_to_move = PieceColor::White;
+        self.clock = None;
+        self.result = None;
+        self.history.clear();
+        self.undo_stack.clear();
+        self.premove = None;
+        self.keyboard_piece = None;
+        self.shown_sequence = None;
+        self.stats = GameStats::default();
+        self.opening_seed = None;
+        self.repetitions.clear();
+        self.repetitions.insert(self.zobrist(PieceColor::White), 1);
+
+        // Boards made with `from_layout` aren't shown anywhere
+        if let Some(game) = self.game.upgrade() {
+            game.set_pieces(self.pieces.clone().into());
+        }
+
+        self.reset_squares();
+    }
+
+    /// Starts a new game like `start_new_game`, and then makes the first `RANDOM_OPENING_PLIES`
+    /// moves of it at random, so the players start from another position every game.
+    /// The moves only depend on `seed`, so both peers get the same opening from the same seed.
+    /// The opening moves can't be taken back.
+    pub fn start_new_game_random(&mut self, color: PieceColor, seed: u64) {
+        self.start_new_game(color);
+        self.play_random_opening(seed);
+    }
+
+    /// Makes the random moves of `start_new_game_random` from the current position
+    fn play_random_opening(&mut self, seed: u64) {
+        let mut rng = StdRng::seed_from_u64(seed);
+        for _ in 0..RANDOM_OPENING_PLIES {
+            let side = self.side_to_move();
+            // The moves are listed from the local player's side, so they are put in the order of
+            // their logical indices, which is the same for both peers
+            let mut moves: Vec<Move> = self
+                .legal_moves_for(side)
+                .iter()
+                .filter(|mov| {
+                    // The game mustn't be over before the players get to move
+                    let mut after = self.bitboard;
+                    after.apply_move(mov);
+                    after.legal_moves(side != self.player_color).is_some()
+                })
+                .map(|mov| self.to_logical_move(mov))
+                .collect();
+            if moves.is_empty() {
+                break;
+            }
+            moves.sort_by_key(|mov| (mov.index, mov.end, mov.captured.clone()));
+
+            let mov = moves.swap_remove(rng.gen_range(0..moves.len()));
+            set_board_move(&mov);
+            self.move_piece();
+        }
+
+        self.undo_stack.clear();
+        self.opening_seed = Some(seed);
+    }
+
+    /// Returns the seed of the random opening the game started with, or `None` if it started
+    /// from the usual position.
+    pub fn opening_seed(&self) -> Option<u64> {
+        self.opening_seed
+    }
+
+    /// Starts a new game where the players have swapped colors, so the player who was Black
+    /// last game plays White. A game with a random opening is followed by the same opening.
+    pub fn start_rematch(&mut self) {
+        let color = self.player_color.get_opposite();
+        match self.opening_seed {
+            Some(seed) => self.start_new_game_random(color, seed),
+            None => self.start_new_game(color),
+        }
+    }
+
+    /// Writes the game to `path` as JSON, so it can be resumed later with `load_from_file`.
+    pub fn save_to_file(&self, path: &Path) -> anyhow::Result<()> {
+        let saved = SavedGame {
+            pieces: self.pieces.iter().map(SavedPiece).collect(),
+            player_color: self.player_color,
+            selected_square: self.selected_square,
+            history: self.history.clone(),
+            repetitions: self.repetitions.clone(),
+        };
+
+        let json = serde_json::to_string_pretty(&saved)?;
+        fs::write(path, json).with_context(|| format!("Failed to write save file {:?}", path))
+    }
+
+    /// Replaces the current game with the game saved in `path` by `save_to_file`.
+    /// The Slint models are rebuilt, and the legal moves of the selected piece are marked again.
+    pub fn load_from_file(&mut self, path: &Path) -> anyhow::Result<()> {
+        let json = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read save file {:?}", path))?;
+        let saved: SavedGame = serde_json::from_str(&json)
+            .with_context(|| format!("Save file {:?} is invalid", path))?;
+
+        let pieces: Vec<PieceData> = saved.pieces.into_iter().map(|piece| piece.0).collect();
+        validate_pieces(&pieces, saved.player_color)
+            .with_context(|| format!("Save file {:?} holds an impossible board", path))?;
+
+        self.player_color = saved.player_color;
+        self.set_pieces(pieces);
+        self.selected_square = saved.selected_square;
+        self.history = saved.history;
+        // White always moves first, so the side to move follows from the number of moves
+        self.side_to_move = if self.history.len().is_multiple_of(2) {
+            PieceColor::White
+        } else {
+            PieceColor::Black
+        };
+        self.repetitions = saved.repetitions;
+        self.result = None;
+        // The save file doesn't hold the stats or the clock, so the loaded game has no time limit
+        self.stats = GameStats::default();
+        self.clock = None;
 
-        let game = self.game.unwrap();
-        game.set_pieces(self.pieces.clone().into());
+        if let Some(mov) = self.history.last() {
+            let mov = self.to_display_move(mov);
+            self.highlight_last_move(&mov);
+        }
+
+        let selected = usize::try_from(self.selected_square).unwrap_or(32);
+        if selected < 32 {
+            let (moves, _) = self.get_legal_moves_piece(selected);
+            let mark_indicies: Vec<usize> = moves.iter().map(|mov| mov.end).collect();
+            self.mark_squares(&mark_indicies);
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the pieces with `pieces`, given in logical indices, eg. the board recieved from
+    /// the host after the boards got out of sync. The history of the game is kept.
+    /// The pieces are left alone if the board can't occur in a game, see `validate`.
+    pub fn set_logical_pieces(&mut self, pieces: &[PieceData]) -> anyhow::Result<()> {
+        if pieces.len() != 32 {
+            return Err(BoardError::WrongTileCount {
+                count: pieces.len(),
+            }
+            .into());
+        }
+
+        let pieces: Vec<PieceData> = (0..32)
+            .map(|index| pieces[self.to_logical_index(index)].clone())
+            .collect();
+        validate_pieces(&pieces, self.player_color)?;
+        self.set_pieces(pieces);
+        Ok(())
+    }
+
+    /// Replaces the pieces with `pieces`, given in display indices, and updates everything
+    /// derived from them.
+    fn set_pieces(&mut self, pieces: Vec<PieceData>) {
+        self.bitboard = BitBoard {
+            rules: self.bitboard.rules,
+            ..BitBoard::from_pieces(&pieces, self.player_color)
+        };
+        self.legal_moves.take();
+        self.hash = self.pieces_hash();
+        self.pieces = Rc::new(slint::VecModel::from(pieces));
+        self.undo_stack.clear();
+        self.premove = None;
+        self.keyboard_piece = None;
+        self.shown_sequence = None;
+
+        // Boards made with `from_layout` aren't shown anywhere
+        if let Some(game) = self.game.upgrade() {
+            game.set_pieces(self.pieces.clone().into());
+        }
 
         self.reset_squares();
     }
 
-    /// Takes a `Move` struct and performs the move described within
-    pub fn move_piece(&mut self) {
-        let mov = get_board_move();
+    /// Checks that the board can occur in a game: neither side has more than the 12 pieces it
+    /// starts with, no man is left uncrowned on the row where it should have become a king, and
+    /// no empty tile is marked as a king.
+    pub fn validate(&self) -> Result<(), BoardError> {
+        validate_pieces(&self.pieces.iter().collect::<Vec<_>>(), self.player_color)
+    }
 
-        println!("\nPerformed move: {:#?}", mov);
+    /// Returns the rules the game is played with
+    pub fn rules(&self) -> Rules {
+        self.bitboard.rules
+    }
 
-        let mut start_data = self.pieces.row_data(mov.index).unwrap();
+    /// Plays the game with `rules`, which are kept when a new game is started.
+    pub fn set_rules(&mut self, rules: Rules) {
+        self.bitboard.rules = rules;
+        self.legal_moves.take();
+    }
+
+    /// Returns the color of the local player
+    pub fn player_color(&self) -> PieceColor {
+        self.player_color
+    }
+
+    /// Returns true if the board is shown mirrored compared to the logical indices
+    fn is_flipped(&self) -> bool {
+        self.player_color == PieceColor::Black
+    }
+
+    /// Turns a logical index into the index of the tile as it is shown to the player.
+    pub fn to_display_index(&self, logical: usize) -> usize {
+        if self.is_flipped() {
+            31 - logical
+        } else {
+            logical
+        }
+    }
 
-        // Promotion to king
-        start_data.is_king |= mov.promoted;
+    /// Turns the index of a tile as it is shown to the player into a logical index.
+    pub fn to_logical_index(&self, display: usize) -> usize {
+        // Mirroring the board is its own inverse
+        self.to_display_index(display)
+    }
 
-        self.pieces.set_row_data(mov.end, start_data);
-        self.pieces
-            .set_row_data(mov.index, PieceData::const_default());
+    /// Turns a `Move` in logical indices into a `Move` in display indices.
+    pub fn to_display_move(&self, mov: &Move) -> Move {
+        if self.is_flipped() {
+            mov.reverse()
+        } else {
+            mov.clone()
+        }
+    }
 
+    /// Turns a `Move` in display indices into a `Move` in logical indices.
+    pub fn to_logical_move(&self, mov: &Move) -> Move {
+        self.to_display_move(mov)
+    }
+
+    /// Returns the Zobrist key of `piece` standing on the tile shown at `index`.
+    fn zobrist_key(&self, index: usize, piece: &PieceData) -> u64 {
+        zobrist::piece_key(self.to_logical_index(index), piece)
+    }
+
+    /// Computes the Zobrist hash of all pieces on the board from scratch.
+    fn pieces_hash(&self) -> u64 {
+        self.bitboard.board_hash(self.player_color)
+    }
+
+    /// Returns the Zobrist hash of the pieces, without the side to move.
+    /// Both players get the same hash when their boards match.
+    pub fn board_hash(&self) -> u64 {
+        self.hash
+    }
+
+    /// Returns the number of moves made since the game started
+    pub fn ply(&self) -> u32 {
+        self.history.len() as u32
+    }
+
+    /// Returns the Zobrist hash of the position, with `side` as the side to move.
+    /// The hash is built from logical indices, so both players get the same hash for the same
+    /// position.
+    pub fn zobrist(&self, side: PieceColor) -> u64 {
+        self.hash ^ zobrist::side_key(side)
+    }
+
+    /// Returns the Zobrist hash of the current position, including the side to move.
+    /// The hash is updated incrementally by `move_piece`, so this is cheap to call.
+    pub fn zobrist_hash(&self) -> u64 {
+        self.zobrist(self.side_to_move())
+    }
+
+    /// Takes the `Move` set with `set_board_move` and performs the move described within.
+    /// Returns the pieces captured by the move, and wether the moved piece was crowned.
+    pub fn move_piece(&mut self) -> MoveOutcome {
+        self.perform_move(get_board_move())
+    }
+
+    /// Performs `logical_move`, given in logical indices, see `move_piece`
+    fn perform_move(&mut self, logical_move: Move) -> MoveOutcome {
+        let mov = self.to_display_move(&logical_move);
+
+        debug!("Performed move: {:?}", mov);
+
+        let before = self.bitboard;
+        self.bitboard.apply_move(&mov);
+        self.legal_moves.take();
+        self.shown_sequence = None;
+
+        // Only write the tiles touched by the move back to the model,
+        // and only update the hash for those tiles
+        let mut changed = vec![mov.index, mov.end];
         if let Some(captured) = &mov.captured {
-            for piece in captured {
-                self.pieces.set_row_data(*piece, PieceData::const_default())
+            changed.extend(captured);
+        }
+        for index in changed {
+            let old_piece = before.piece_data(index, self.player_color);
+            let new_piece = self.bitboard.piece_data(index, self.player_color);
+            self.hash ^= self.zobrist_key(index, &old_piece) ^ self.zobrist_key(index, &new_piece);
+
+            self.pieces.set_row_data(index, new_piece);
+        }
+
+        // After the move it's the other side's turn
+        let mover = self.bitboard.piece_data(mov.end, self.player_color).color;
+        *self
+            .repetitions
+            .entry(self.zobrist(mover.get_opposite()))
+            .or_insert(0) += 1;
+        self.history.push(logical_move);
+        self.undo_stack.push(before);
+        self.side_to_move = mover.get_opposite();
+
+        // Moves are performed both when they are sent and recieved, so this keeps the clocks of
+        // both players in step
+        let side_to_move = self.side_to_move();
+        if let Some(clock) = &mut self.clock {
+            clock.start_turn(side_to_move);
+        }
+
+        self.highlight_last_move(&mov);
+
+        let is_player = self.bitboard.is_player(mov.end);
+        let outcome = Board::move_outcome(&before, &self.bitboard, mov);
+        self.stats.add_move(is_player, &outcome);
+        outcome
+    }
+
+    /// Returns what `mov`, in display indices, changed when it took the board from `before` to
+    /// `after`.
+    fn move_outcome(before: &BitBoard, after: &BitBoard, mov: Move) -> MoveOutcome {
+        let promoted = !before.is_king(mov.index) && after.is_king(mov.end);
+        MoveOutcome {
+            captured: mov.captured.unwrap_or_default(),
+            promoted_square: promoted.then_some(mov.end),
+        }
+    }
+
+    /// Takes back the last move, putting the pieces back where they were before it, and returns
+    /// the move in logical indices.
+    /// Returns `None` if there is no move which can be taken back.
+    pub fn undo_move(&mut self) -> Option<Move> {
+        let before = self.undo_stack.pop()?;
+
+        // The position after the move has occurred once less
+        let after = self.zobrist(self.side_to_move());
+        if let Some(count) = self.repetitions.get_mut(&after) {
+            *count -= 1;
+            if *count == 0 {
+                self.repetitions.remove(&after);
             }
         }
+        let mov = self.history.pop()?;
+
+        let display_move = self.to_display_move(&mov);
+        let is_player = self.bitboard.is_player(display_move.end);
+        let mover = self
+            .bitboard
+            .piece_data(display_move.end, self.player_color)
+            .color;
+        let outcome = Board::move_outcome(&before, &self.bitboard, display_move);
+        self.stats.remove_move(is_player, &outcome);
+
+        self.side_to_move = mover;
+        self.bitboard = before;
+        self.legal_moves.take();
+        self.premove = None;
+        self.keyboard_piece = None;
+        self.shown_sequence = None;
+        self.hash = self.pieces_hash();
+        for index in 0..32 {
+            let piece = self.bitboard.piece_data(index, self.player_color);
+            self.pieces.set_row_data(index, piece);
+        }
+
+        let side_to_move = self.side_to_move();
+        if let Some(clock) = &mut self.clock {
+            clock.start_turn(side_to_move);
+        }
+
+        self.reset_squares();
+        if let Some(last) = self.history.last() {
+            let last = self.to_display_move(last);
+            self.highlight_last_move(&last);
+        }
+        Some(mov)
+    }
+
+    /// Keeps `mov`, one of the player's moves in display indices, to be made once it's the
+    /// player's turn, see `take_premove`. Replaces the premove made before, if any.
+    pub fn set_premove(&mut self, mov: Move) {
+        self.clear_premove();
+        for index in [mov.index, mov.end] {
+            let mut square = self.squares.row_data(index).unwrap();
+            square.premove = true;
+            self.squares.set_row_data(index, square);
+        }
+        self.premove = Some(mov);
+    }
+
+    /// Returns the premove set with `set_premove`, if there is one
+    pub fn premove(&self) -> Option<&Move> {
+        self.premove.as_ref()
+    }
+
+    /// Forgets the premove, if there is one
+    pub fn clear_premove(&mut self) {
+        self.premove = None;
+        for index in 0..32 {
+            let mut square = self.squares.row_data(index).unwrap();
+            square.premove = false;
+            self.squares.set_row_data(index, square);
+        }
+    }
+
+    /// Takes the premove, and returns it if it's still one of the player's legal moves.
+    /// Returns `None` if there is no premove, or if it isn't legal anymore, in which case it is
+    /// discarded.
+    pub fn take_premove(&mut self) -> Option<Move> {
+        let premove = self.premove.take()?;
+        self.clear_premove();
+
+        if !self.bitboard.is_legal_move(&premove) {
+            debug!(
+                "Discarded premove, which isn't legal anymore: {:?}",
+                premove
+            );
+            return None;
+        }
+        Some(premove)
+    }
+
+    /// Chooses the legal move of the player's piece on `index` which ends on `tile`, eg. when
+    /// the tile is clicked.
+    ///
+    /// Capture sequences which capture different pieces are separate moves, even when they end
+    /// on the same tile. Choosing such a tile shows the first of the sequences, by marking the
+    /// tiles it lands on and the pieces it captures, and the tile has to be chosen again to
+    /// confirm it. Choosing a tile only another of the sequences lands on or captures, shows
+    /// that sequence instead.
+    pub fn choose_move(&mut self, index: usize, tile: usize) -> MoveChoice {
+        let moves: Vec<Move> = self
+            .player_legal_moves()
+            .into_iter()
+            .filter(|mov| mov.index == index)
+            .collect();
+
+        if let Some(shown) = self.shown_sequence.take() {
+            let shown_tiles = sequence_tiles(&shown);
+            let other = moves.iter().find(|mov| {
+                mov.end == shown.end
+                    && **mov != shown
+                    && sequence_tiles(mov).contains(&tile)
+                    && !shown_tiles.contains(&tile)
+            });
+            if let Some(other) = other.cloned() {
+                self.show_sequence(other);
+                return MoveChoice::ShowingSequence;
+            }
+            if shown.end == tile && moves.contains(&shown) {
+                return MoveChoice::Move(shown);
+            }
+        }
+
+        let mut ending = moves.into_iter().filter(|mov| mov.end == tile);
+        match (ending.next(), ending.next()) {
+            (None, _) => MoveChoice::NoMove,
+            (Some(mov), None) => MoveChoice::Move(mov),
+            (Some(first), Some(_)) => {
+                self.show_sequence(first);
+                MoveChoice::ShowingSequence
+            }
+        }
+    }
+
+    /// Marks the tiles `mov` lands on and the pieces it captures, see `choose_move`.
+    fn show_sequence(&mut self, mov: Move) {
+        self.unmark_squares();
+        self.mark_squares(&sequence_tiles(&mov));
+        self.shown_sequence = Some(mov);
+    }
+
+    /// Moves `selected_square` to the next tile in `direction`, for choosing moves with the
+    /// keyboard. The selection stays where it is at the edge of the board, and starts on the
+    /// leftmost tile of the bottom row if nothing is selected.
+    pub fn move_selection(&mut self, direction: Direction) {
+        let next = match usize::try_from(self.selected_square) {
+            Ok(index) if index < 32 => bitboard::next_index(index, &direction).unwrap_or(index),
+            _ => 28,
+        };
+        self.selected_square = next as i32;
+
+        for index in 0..32 {
+            let mut square = self.squares.row_data(index).unwrap();
+            square.cursor = index == next;
+            self.squares.set_row_data(index, square);
+        }
+    }
+
+    /// Confirms the tile chosen with `move_selection`.
+    /// Returns the player's legal move from the piece picked up earlier to the selected tile, if
+    /// there is one, see `choose_move`. Otherwise the player's piece on the selected tile is
+    /// picked up, and its moves are marked. The move isn't performed, so the caller can send it
+    /// first.
+    pub fn confirm_selection(&mut self) -> Option<Move> {
+        let selected = usize::try_from(self.selected_square).ok()?;
+        if let Some(piece) = self.keyboard_piece.take() {
+            match self.choose_move(piece, selected) {
+                MoveChoice::Move(mov) => {
+                    self.unmark_squares();
+                    return Some(mov);
+                }
+                MoveChoice::ShowingSequence => {
+                    self.keyboard_piece = Some(piece);
+                    return None;
+                }
+                MoveChoice::NoMove => (),
+            }
+        }
+
+        self.unmark_squares();
+        let ends: Vec<usize> = self
+            .player_legal_moves()
+            .iter()
+            .filter(|mov| mov.index == selected)
+            .map(|mov| mov.end)
+            .collect();
+        if !ends.is_empty() {
+            self.keyboard_piece = Some(selected);
+            self.mark_squares(&ends);
+        }
+        None
+    }
+
+    /// Hides the tile chosen with `move_selection`, eg. when a tile is clicked with the mouse.
+    pub fn clear_cursor(&mut self) {
+        self.keyboard_piece = None;
+        for index in 0..32 {
+            let mut square = self.squares.row_data(index).unwrap();
+            square.cursor = false;
+            self.squares.set_row_data(index, square);
+        }
+    }
+
+    /// Makes the premove on the board, if it's still legal, see `take_premove`. Either way the
+    /// premove is cleared. Returns false if there was no premove, or it wasn't legal anymore.
+    /// Only the board is changed, so in a networked game the move still has to be sent.
+    /// The move is set with `set_board_move` once it has been made.
+    pub fn try_apply_premove(&mut self) -> bool {
+        let Some(mov) = self.take_premove() else {
+            return false;
+        };
+        let logical_move = self.to_logical_move(&mov);
+        self.perform_move(logical_move.clone());
+        set_board_move(&logical_move);
+        true
+    }
+
+    /// Returns how many moves have to be taken back to undo the last move of `color`. That is
+    /// just the move if it's the other side's turn, and otherwise also the answer to it.
+    /// Returns `None` if that many moves can't be taken back, or if the game was ended by the
+    /// players.
+    pub fn takeback_plies(&self, color: PieceColor) -> Option<usize> {
+        if self.result.is_some() {
+            return None;
+        }
+
+        let plies = if self.side_to_move() == color { 2 } else { 1 };
+        (plies <= self.undo_stack.len()).then_some(plies)
+    }
+
+    /// Returns the color whose turn it is
+    pub fn side_to_move(&self) -> PieceColor {
+        self.side_to_move
+    }
+
+    /// Plays the rest of the game with `clock`, and starts the time of the side to move.
+    pub fn set_clock(&mut self, mut clock: MoveClock) {
+        clock.start_turn(self.side_to_move());
+        self.clock = Some(clock);
+    }
+
+    /// Returns the clock of the game, if it is played with a time limit.
+    pub fn clock(&self) -> Option<&MoveClock> {
+        self.clock.as_ref()
+    }
+
+    /// Updates the clock of the game, see `MoveClock::tick`.
+    /// Returns the result of the game if a side has run out of time.
+    pub fn tick_clock(&mut self) -> Option<GameResult> {
+        self.clock.as_mut()?.tick()
+    }
+
+    /// Ends the game with `result`, when it wasn't decided on the board, eg. by a draw agreement.
+    pub fn end_game(&mut self, result: GameResult) {
+        if let Some(clock) = &mut self.clock {
+            clock.stop_turn();
+        }
+        self.result = Some(result);
+    }
+
+    /// Ends the game with `color` surrendering, so the other side wins.
+    pub fn resign(&mut self, color: PieceColor) {
+        self.end_game(GameResult::Resigned { loser: color });
+    }
+
+    /// Returns the result of the game if it has ended, or `None` if it is still being played.
+    pub fn get_game_result(&self) -> Option<GameResult> {
+        if self.result.is_some() {
+            return self.result;
+        }
+
+        if let Some(result) = self.clock.as_ref().and_then(MoveClock::timeout) {
+            return Some(result);
+        }
+
+        // A board without any pieces hasn't had a game set up yet, so nobody has lost on it
+        if self.get_empty_piece_count() == 32 {
+            return None;
+        }
+
+        // Being unable to move is a loss, whether the side to move is blocked or has no pieces left
+        let side_to_move = self.side_to_move();
+        if self.legal_moves_for(side_to_move).is_empty() {
+            return Some(GameResult::NoMoves {
+                loser: side_to_move,
+            });
+        }
+
+        if self.is_insufficient_material() {
+            return Some(GameResult::Draw);
+        }
+
+        None
+    }
+
+    /// Returns every move performed since the game started, in logical indices.
+    pub fn history(&self) -> &[Move] {
+        &self.history
+    }
+
+    /// Returns how many pieces each side has captured and crowned since the game started.
+    /// Games loaded from a file start counting when they are loaded.
+    pub fn stats(&self) -> GameStats {
+        self.stats
+    }
+
+    /// Returns how many of the other side's pieces `color` has captured since the game started,
+    /// eg. for showing the pieces each side has lost. Moves taken back aren't counted.
+    pub fn captures_by(&self, color: PieceColor) -> u8 {
+        if color == self.player_color {
+            self.stats.player_captured
+        } else {
+            self.stats.enemy_captured
+        }
+    }
+
+    /// Returns how many times the current position has occurred with `side` to move.
+    pub fn repetition_count(&self, side: PieceColor) -> u8 {
+        self.repetitions
+            .get(&self.zobrist(side))
+            .copied()
+            .unwrap_or(0)
     }
 
     /// Gives all the squares in `indices` the "marked" color
     pub fn mark_squares(&mut self, indices: &[usize]) {
         for index in indices {
-            self.squares
-                .set_row_data(*index, BoardSquare { marked: true });
+            let mut square = self.squares.row_data(*index).unwrap();
+            square.marked = true;
+            self.squares.set_row_data(*index, square);
+        }
+    }
+
+    /// Removes the "marked" color from all squares, but keeps the last move highlighted
+    pub fn unmark_squares(&mut self) {
+        for index in 0..32 {
+            let mut square = self.squares.row_data(index).unwrap();
+            square.marked = false;
+            self.squares.set_row_data(index, square);
+        }
+    }
+
+    /// Highlights the start and end square of `mov`, as the last move made on the board.
+    /// The highlight is removed from the squares of the previous move.
+    pub fn highlight_last_move(&mut self, mov: &Move) {
+        for index in 0..32 {
+            let mut square = self.squares.row_data(index).unwrap();
+            square.last_move = index == mov.index || index == mov.end;
+            self.squares.set_row_data(index, square);
         }
     }
 
     /// Turns all squares back to their original color
     pub fn reset_squares(&mut self) {
         for index in 0..32 {
-            self.squares
-                .set_row_data(index, BoardSquare { marked: false });
+            self.squares.set_row_data(index, BoardSquare::default());
+        }
+    }
+
+    /// Returns the piece at `index`, or `None` if `index` is outside the board.
+    /// Empty tiles give an inactive `PieceData`.
+    pub fn piece_at(&self, index: usize) -> Option<PieceData> {
+        if index >= 32 {
+            return None;
         }
+        Some(self.bitboard.piece_data(index, self.player_color))
+    }
+
+    /// Returns all 32 tiles of the board, in logical indices.
+    pub fn logical_pieces(&self) -> Vec<PieceData> {
+        (0..32)
+            .map(|index| {
+                self.bitboard
+                    .piece_data(self.to_display_index(index), self.player_color)
+            })
+            .collect()
+    }
+
+    /// Iterates over all active pieces on the board, together with their index.
+    pub fn iter_pieces(&self) -> impl Iterator<Item = (usize, PieceData)> + '_ {
+        (0..32).filter_map(|index| {
+            let piece = self.piece_at(index)?;
+            piece.is_active.then_some((index, piece))
+        })
+    }
+
+    /// Iterates over the local player's pieces, together with their index.
+    pub fn iter_player_pieces(&self) -> impl Iterator<Item = (usize, PieceData)> + '_ {
+        self.iter_pieces()
+            .filter(|(_, piece)| piece.color == self.player_color)
+    }
+
+    /// Iterates over the other player's pieces, together with their index.
+    pub fn iter_enemy_pieces(&self) -> impl Iterator<Item = (usize, PieceData)> + '_ {
+        self.iter_pieces()
+            .filter(|(_, piece)| piece.color != self.player_color)
     }
 
     /// Returns true if the `index` corresponds to an active piece on the board
@@ -155,277 +1163,880 @@ impl Board {
         piece.color != self.player_color && piece.is_active
     }
 
+    /// Like `piece_is_empty`, but returns `None` instead of panicking if `index` is outside the
+    /// board, eg. when it comes from the network.
+    pub fn try_piece_is_empty(&self, index: usize) -> Option<bool> {
+        self.piece_at(index).map(|piece| !piece.is_active)
+    }
+
+    /// Like `piece_is_player`, but returns `None` instead of panicking if `index` is outside the
+    /// board.
+    pub fn try_piece_is_player(&self, index: usize) -> Option<bool> {
+        self.piece_at(index)
+            .map(|piece| piece.is_active && piece.color == self.player_color)
+    }
+
+    /// Like `piece_is_enemy`, but returns `None` instead of panicking if `index` is outside the
+    /// board.
+    pub fn try_piece_is_enemy(&self, index: usize) -> Option<bool> {
+        self.piece_at(index)
+            .map(|piece| piece.is_active && piece.color != self.player_color)
+    }
+
     pub fn get_player_piece_count(&self) -> u8 {
-        let mut count = 0;
-        for i in 0..32 {
-            count += self.piece_is_player(i) as u8;
-        }
-        count
+        self.iter_player_pieces().count() as u8
     }
 
     pub fn get_enemy_piece_count(&self) -> u8 {
-        let mut count = 0;
-        for i in 0..32 {
-            count += self.piece_is_enemy(i) as u8;
-        }
-        count
+        self.iter_enemy_pieces().count() as u8
+    }
+
+    pub fn get_player_king_count(&self) -> u8 {
+        self.iter_player_pieces()
+            .filter(|(_, piece)| piece.is_king)
+            .count() as u8
+    }
+
+    pub fn get_enemy_king_count(&self) -> u8 {
+        self.iter_enemy_pieces()
+            .filter(|(_, piece)| piece.is_king)
+            .count() as u8
+    }
+
+    pub fn get_player_man_count(&self) -> u8 {
+        self.iter_player_pieces()
+            .filter(|(_, piece)| !piece.is_king)
+            .count() as u8
+    }
+
+    pub fn get_enemy_man_count(&self) -> u8 {
+        self.iter_enemy_pieces()
+            .filter(|(_, piece)| !piece.is_king)
+            .count() as u8
+    }
+
+    /// Returns true if neither side can force a win with the pieces left, which is when each side
+    /// has a single king and nothing else.
+    /// A side with a man can still crown it and win with two kings against one, so positions with
+    /// men are never flagged.
+    pub fn is_insufficient_material(&self) -> bool {
+        self.get_player_man_count() == 0
+            && self.get_enemy_man_count() == 0
+            && self.get_player_king_count() == 1
+            && self.get_enemy_king_count() == 1
     }
 
     pub fn get_empty_piece_count(&self) -> u8 {
-        let mut count = 0;
-        for i in 0..32 {
-            count += self.piece_is_empty(i) as u8;
-        }
-        count
+        32 - self.iter_pieces().count() as u8
     }
 
     /// Get's all the legal moves for the given piece
     /// This works for both enemy pieces and player pieces
-    pub fn get_legal_moves_piece(&self, index: usize) -> Option<(Vec<Move>, bool)> {
+    ///
+    /// Every capture sequence the piece can make is returned as its own `Move`, with `captured`
+    /// holding the jumped pieces in the order they are taken. Two sequences that branch off from
+    /// each other are never merged, even if they end on the same square.
+    ///
+    /// Also returns wether the moves are capturing. The moves are empty if the tile is empty, or
+    /// the piece can't move.
+    pub fn get_legal_moves_piece(&self, index: usize) -> (Vec<Move>, bool) {
         assert!(index < self.pieces.row_count());
-        let piece = self.pieces.row_data(index)?;
-        if !piece.is_active {
-            return None;
+        self.bitboard.legal_moves_piece(index).unwrap_or_default()
+    }
+
+    /// Returns all legal moves for the side to move, which are empty if it can't move.
+    /// The moves are only generated again after the pieces have changed.
+    pub fn get_legal_moves(&self) -> Vec<Move> {
+        self.legal_moves
+            .borrow_mut()
+            .get_or_insert_with(|| self.legal_moves_for(self.side_to_move))
+            .clone()
+    }
+
+    /// Returns all legal moves for the `player_color`, also during the opponent's turn, when
+    /// they are chosen as a premove.
+    fn player_legal_moves(&self) -> Vec<Move> {
+        if self.side_to_move == self.player_color {
+            self.get_legal_moves()
+        } else {
+            self.legal_moves_for(self.player_color)
         }
+    }
 
-        #[allow(clippy::too_many_arguments)]
-        fn check_move(
-            mut pieces: [PieceData; 32],
-            start: usize,
-            index: usize,
-            local_player_color: PieceColor,
-            enemy_color: PieceColor,
-            is_king: bool,
-            direction: &Direction,
-            is_taking: bool,
-        ) -> Option<(Vec<Move>, bool)> {
-            // Check if the piece is on the edge of the direction
-            let row_left_shifted = index % 8 < 4;
-            let piece_left_side = index % 4 == 0;
-            let peice_right_side = index % 4 == 3;
-            if row_left_shifted && direction.is_left() && piece_left_side {
-                return None;
-            }
+    /// Returns all legal moves for the pieces of `color`, which are empty if they can't move at
+    /// all, in which case `get_game_result()` tells if the game is lost.
+    /// Capturing is only mandatory when a piece of `color` can capture, so the moves of one side
+    /// never depend on the captures available to the other.
+    pub fn legal_moves_for(&self, color: PieceColor) -> Vec<Move> {
+        self.bitboard
+            .legal_moves(color == self.player_color)
+            .unwrap_or_default()
+    }
 
-            if !row_left_shifted && direction.is_right() && peice_right_side {
-                return None;
-            }
+    /// Suggests a move for the player, for showing as a hint.
+    /// Returns `None` if the player has no legal moves. The board itself isn't changed.
+    pub fn suggest_move(&self) -> Option<Move> {
+        ai::best_move(&self.bitboard, true, HINT_DEPTH)
+    }
 
-            let is_local_player = local_player_color != enemy_color;
-            // If the piece isn't a king it cant move backwards
-            if !is_king {
-                if direction.is_down() && is_local_player {
-                    return None;
-                }
+    /// Returns an independent copy of the board, with its own pieces and squares, which isn't
+    /// shown in a window. Unlike `clone`, moves can be made on the copy, eg. while searching
+    /// ahead, without changing this board.
+    pub fn fork(&self) -> Board {
+        let pieces: Vec<PieceData> = self.pieces.iter().collect();
+        let squares: Vec<BoardSquare> = self.squares.iter().collect();
+        Board {
+            game: Weak::default(),
+            pieces: Rc::new(slint::VecModel::from(pieces)),
+            squares: Rc::new(slint::VecModel::from(squares)),
+            ..self.clone()
+        }
+    }
 
-                if direction.is_up() && !is_local_player {
-                    return None;
-                }
-            }
+    /// Draws the board as it is shown, as an SVG image which can be shared in chat or bug reports.
+    /// The colors are the ones used by the game window: marked tiles and the tiles of the last
+    /// move and the premove are tinted, and kings get a crimson ring.
+    pub fn to_svg(&self) -> String {
+        let size = SVG_TILE_SIZE * 8;
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{size}\" height=\"{size}\" \
+             viewBox=\"0 0 {size} {size}\">\n\
+             <rect width=\"{size}\" height=\"{size}\" fill=\"#e3e0a0\"/>\n"
+        );
 
-            let next = index as i32 + direction.get_value(index);
-            if next < 0 || next > pieces.len() as i32 {
-                return None;
+        for (index, square) in self.squares.iter().enumerate() {
+            let (row, column) = super::tile_position(index);
+            let fill = if square.marked {
+                "#ffff41"
+            } else if square.premove {
+                "#3a5d6b"
+            } else if square.last_move {
+                "#6e5f7a"
+            } else {
+                "#352f3b"
+            };
+            let _ = writeln!(
+                svg,
+                "<rect x=\"{}\" y=\"{}\" width=\"{SVG_TILE_SIZE}\" height=\"{SVG_TILE_SIZE}\" \
+                 fill=\"{fill}\"/>",
+                column * SVG_TILE_SIZE,
+                row * SVG_TILE_SIZE,
+            );
+        }
+
+        for (index, piece) in self.pieces.iter().enumerate() {
+            if !piece.is_active {
+                continue;
             }
-            let next_tile = &pieces[next as usize];
 
-            // If the next piece is an enemy check if the next tile is empty
-            // If so this piece can be taken
-            if next_tile.is_active {
-                if next_tile.color != enemy_color || is_taking {
-                    return None;
-                }
+            let (row, column) = super::tile_position(index);
+            let fill = match piece.color {
+                PieceColor::White => "#FFFFFF",
+                PieceColor::Black => "#000000",
+            };
+            // The ring is drawn on the piece itself, so every piece is a single circle
+            let stroke = if piece.is_king {
+                " stroke=\"crimson\" stroke-width=\"4\""
+            } else {
+                ""
+            };
+            let _ = writeln!(
+                svg,
+                "<circle cx=\"{}\" cy=\"{}\" r=\"{}\" fill=\"{fill}\"{stroke}/>",
+                column * SVG_TILE_SIZE + SVG_TILE_SIZE / 2,
+                row * SVG_TILE_SIZE + SVG_TILE_SIZE / 2,
+                SVG_TILE_SIZE / 2 - 5,
+            );
+        }
 
-                return if let Some(mut next_move) = check_move(
-                    pieces,
-                    start,
-                    next as usize,
-                    local_player_color,
-                    enemy_color,
-                    is_king,
-                    direction,
-                    true,
-                ) {
-                    if !next_move.1 {
-                        return Some(next_move);
-                    }
-
-                    // If one of the moves are capturing
-                    // Remove all the moves that aren't capturing
-                    next_move.0 = next_move
-                        .0
-                        .iter()
-                        .filter_map(|mov| mov.captured.as_ref().map(|_| mov.clone()))
-                        .collect();
-
-                    Some(next_move)
-                } else {
-                    None
-                };
-            }
+        svg.push_str("</svg>\n");
+        svg
+    }
 
-            let promoting = is_local_player && next < 4 || !is_local_player && next > 32 - 4;
-
-            // If we are taking a piece, since the next tile is empty
-            // We need to return this move, but also check if we can take more pieces
-            if is_taking {
-                // Check to see if we can take further pieces
-                let mut further_moves = None;
-
-                pieces[index] = PieceData::const_default();
-                for direction in Direction::values() {
-                    let moves = check_move(
-                        pieces.clone(),
-                        start,
-                        next as usize,
-                        local_player_color,
-                        enemy_color,
-                        is_king || promoting,
-                        direction,
-                        false,
-                    );
-
-                    if let Some(mut moves) = moves {
-                        // Discard moves that don't capture
-                        if !moves.1 {
-                            continue;
-                        }
-                        // Append the current piece to the captured vector
-                        for mov in &mut moves.0 {
-                            unsafe { mov.captured.as_mut().unwrap_unchecked().push(index) };
-                            mov.promoted |= promoting;
-                        }
-                        // Add to list of possible moves
-                        further_moves.get_or_insert(vec![]).append(&mut moves.0);
-                    }
-                }
+    /// Returns a copy of the board as a `BitBoard`, which can be searched and mutated without
+    /// touching the Slint models.
+    pub fn snapshot(&self) -> BitBoard {
+        self.bitboard
+    }
+}
 
-                return Some((
-                    further_moves.unwrap_or(vec![Move {
-                        index: start,
-                        end: next as usize,
-                        captured: Some(vec![index]),
-                        promoted: promoting,
-                    }]),
-                    true,
-                ));
-            }
+/// Draws the board as text, see the `Display` implementation of `BitBoard`.
+impl fmt::Display for Board {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.bitboard.fmt(f)
+    }
+}
 
-            // If we aren't taking a piece, and this tile is empty
-            // We add this move to a list of possible moves
-            let mut moves = vec![];
-            let mut is_taking = false;
-
-            // If the current piece is a king, it may be able to keep moving
-            if is_king {
-                if let Some(mut next_moves) = check_move(
-                    pieces,
-                    start,
-                    next as usize,
-                    local_player_color,
-                    enemy_color,
-                    is_king,
-                    direction,
-                    false,
-                ) {
-                    moves.append(&mut next_moves.0);
-                    is_taking = next_moves.1;
-                }
-            }
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{sync::MutexGuard, time::Duration};
 
-            // If we are capturing pieces
-            // Since this move doesn't capture, it should not be added
-            if !is_taking {
-                moves.push(Move {
-                    index: start,
-                    end: next as usize,
-                    captured: None,
-                    promoted: promoting,
-                });
-            }
+    use super::*;
+
+    /// The starting position, with White at the bottom
+    pub(crate) const START: &str =
+        "b.b.b.b.\n.b.b.b.b\nb.b.b.b.\n........\n........\n.w.w.w.w\nw.w.w.w.\n.w.w.w.w";
+
+    static BOARD_MOVE_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    /// Held by the tests which make moves, since `BOARD_MOVE` is shared by every board
+    pub(crate) fn lock_board_move() -> MutexGuard<'static, ()> {
+        BOARD_MOVE_LOCK
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Makes `mov`, given in display indices, on `board`
+    pub(crate) fn play(board: &mut Board, mov: &Move) -> MoveOutcome {
+        set_board_move(&board.to_logical_move(mov));
+        board.move_piece()
+    }
 
-            // Return all the available moves
-            // 1 move if normal piece, x amount if king piece
-            Some((moves, is_taking))
+    #[test]
+    fn save_and_load_round_trip() {
+        let _lock = lock_board_move();
+        let mut board = Board::from_diagram(START, PieceColor::White).unwrap();
+        for _ in 0..2 {
+            let mov = board.legal_moves_for(board.side_to_move())[0].clone();
+            play(&mut board, &mov);
         }
+        let path = std::env::temp_dir().join(format!("checker_mater_{}.json", std::process::id()));
+        board.save_to_file(&path).unwrap();
+
+        let mut loaded = Board::from_diagram(START, PieceColor::White).unwrap();
+        loaded.set_clock(MoveClock::new(
+            PieceColor::White,
+            Duration::from_secs(60),
+            Duration::ZERO,
+        ));
+        loaded.load_from_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.logical_pieces(), board.logical_pieces());
+        assert_eq!(loaded.history(), board.history());
+        assert_eq!(loaded.side_to_move(), board.side_to_move());
+        assert!(loaded.clock().is_none());
+    }
 
-        let mut moves: Option<Vec<Move>> = None;
-        let mut is_taking = false;
-        let mut pieces: [MaybeUninit<PieceData>; 32] =
-            unsafe { MaybeUninit::uninit().assume_init() };
+    #[test]
+    fn impossible_save_is_rejected() {
+        let board = Board::from_diagram(START, PieceColor::White).unwrap();
+        let path = std::env::temp_dir().join(format!(
+            "checker_mater_impossible_{}.json",
+            std::process::id()
+        ));
+        board.save_to_file(&path).unwrap();
+        let saved: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
 
-        for (i, element) in pieces.iter_mut().enumerate() {
-            let piece = self.pieces.row_data(i)?;
-            *element = MaybeUninit::new(piece);
+        // A king on an empty tile in the middle of the board
+        let mut king_on_empty = saved.clone();
+        king_on_empty["pieces"][14]["is_king"] = true.into();
+        // The first tile written twice, which pushes every other tile along
+        let mut duplicate = saved.clone();
+        let first = duplicate["pieces"][0].clone();
+        duplicate["pieces"].as_array_mut().unwrap().insert(0, first);
+
+        let mut loaded = Board::from_diagram(START, PieceColor::White).unwrap();
+        for (save, expected) in [
+            (king_on_empty, BoardError::KingOnEmptyTile { index: 14 }),
+            (duplicate, BoardError::WrongTileCount { count: 33 }),
+        ] {
+            fs::write(&path, save.to_string()).unwrap();
+            let err = loaded.load_from_file(&path).unwrap_err();
+            assert_eq!(err.downcast_ref::<BoardError>(), Some(&expected));
         }
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.logical_pieces(), board.logical_pieces());
+    }
+
+    #[test]
+    fn piece_on_a_light_square_is_rejected() {
+        let light_square =
+            "bbb.b.b.\n.b.b.b.b\nb.b.b.b.\n........\n........\n.w.w.w.w\nw.w.w.w.\n.w.w.w.w";
+        assert!(Board::parse_layout(light_square).is_err());
+        assert!(Board::parse_layout(START).is_ok());
+    }
 
-        let pieces: [PieceData; 32] = unsafe { transmute(pieces) };
+    /// Returns the squares highlighted as the last move
+    fn last_move_squares(board: &Board) -> Vec<usize> {
+        (0..32)
+            .filter(|index| board.squares.row_data(*index).unwrap().last_move)
+            .collect()
+    }
 
-        for direction in Direction::values() {
-            // Since the direction is valid, run the check move algorithm
-            let next_moves = check_move(
-                pieces.clone(),
-                index,
-                index,
-                self.player_color,
-                piece.color.get_opposite(),
-                piece.is_king,
-                direction,
-                false,
-            );
+    #[test]
+    fn last_move_highlights_two_squares() {
+        let _lock = lock_board_move();
+        let mut board = Board::from_diagram(START, PieceColor::White).unwrap();
+        for _ in 0..2 {
+            let mov = board.legal_moves_for(board.side_to_move())[0].clone();
+            play(&mut board, &mov);
+            // Marking the legal moves keeps the highlight
+            board.mark_squares(&[16, 17, 18, 19]);
 
-            if next_moves.is_none() {
-                continue;
+            let mut squares = vec![mov.index, mov.end];
+            squares.sort();
+            assert_eq!(last_move_squares(&board), squares);
+        }
+    }
+
+    #[test]
+    fn display_index_mapping_is_an_involution() {
+        for color in [PieceColor::White, PieceColor::Black] {
+            let board = Board::from_diagram(START, color).unwrap();
+            for index in 0..32 {
+                let display = board.to_display_index(index);
+                assert_eq!(board.to_display_index(display), index);
+                assert_eq!(board.to_logical_index(display), index);
             }
+        }
 
-            let mut next_moves = unsafe { next_moves.unwrap_unchecked() };
+        let flipped = Board::from_diagram(START, PieceColor::Black).unwrap();
+        assert_eq!(flipped.to_display_index(0), 31);
+        let unflipped = Board::from_diagram(START, PieceColor::White).unwrap();
+        assert_eq!(unflipped.to_display_index(0), 0);
+    }
 
-            is_taking |= next_moves.1;
+    #[test]
+    fn opening_is_rendered_with_player_at_bottom() {
+        let rows = [
+            "o o o o ", " o o o o", "o o o o ", " . . . .", ". . . . ", " x x x x", "x x x x ",
+            " x x x x",
+        ];
+        let expected: String = rows.iter().map(|row| format!("{}\n", row)).collect();
+        for color in [PieceColor::White, PieceColor::Black] {
+            let board = Board::from_diagram(START, color).unwrap();
+            assert_eq!(board.to_string(), expected);
+        }
+    }
 
-            if next_moves.1 == is_taking {
-                moves.get_or_insert(vec![]).append(&mut next_moves.0);
-            }
+    #[test]
+    fn rematch_swaps_colors() {
+        let _lock = lock_board_move();
+        let mut host = Board::from_diagram(START, PieceColor::White).unwrap();
+        let mut client = Board::from_diagram(START, PieceColor::Black).unwrap();
+        let opening = host.legal_moves_for(PieceColor::White)[0].clone();
+        play(&mut host, &opening);
+        host.resign(PieceColor::Black);
+        client.resign(PieceColor::Black);
+
+        host.start_rematch();
+        client.start_rematch();
+        assert_eq!(host.player_color(), PieceColor::Black);
+        assert_eq!(client.player_color(), PieceColor::White);
+        for board in [&host, &client] {
+            assert_eq!(board.side_to_move(), PieceColor::White);
+            assert_eq!(board.get_player_piece_count(), 12);
+            assert_eq!(board.get_enemy_piece_count(), 12);
+            assert!(board.history().is_empty());
+            assert!(board.get_game_result().is_none());
         }
+    }
 
-        moves.map(|moves| {
-            if !is_taking {
-                return (moves, is_taking);
-            }
-            // Remove all non-capturing moves
-            let filtered: Vec<Move> = moves
-                .iter()
-                .filter_map(|mov| mov.captured.as_ref().map(|_| mov.clone()))
-                .collect();
+    #[test]
+    fn player_iterator_yields_twelve_pieces_at_start() {
+        for color in [PieceColor::White, PieceColor::Black] {
+            let mut board = Board::from_diagram(START, PieceColor::White).unwrap();
+            board.start_new_game(color);
+            let player: Vec<usize> = board.iter_player_pieces().map(|(index, _)| index).collect();
+            assert_eq!(player, (20..32).collect::<Vec<_>>());
+            assert!(board
+                .iter_player_pieces()
+                .all(|(_, piece)| piece.color == color && !piece.is_king));
+            assert_eq!(board.iter_enemy_pieces().count(), 12);
+            assert_eq!(board.iter_pieces().count(), 24);
+        }
+    }
 
-            (filtered, is_taking)
-        })
+    #[test]
+    fn blocked_side_has_no_legal_moves() {
+        let rows = [
+            "........", "........", "........", "........", "........", "...b....", "b.b.....",
+            ".w......",
+        ];
+        let board = Board::from_diagram(&rows.join("\n"), PieceColor::White).unwrap();
+
+        assert!(board.get_legal_moves().is_empty());
+        assert!(board.legal_moves_for(PieceColor::White).is_empty());
+        assert!(board.piece_is_player(28));
+        assert_eq!(board.get_legal_moves_piece(28), (vec![], false));
+        assert!(!board.legal_moves_for(PieceColor::Black).is_empty());
+        assert_eq!(
+            board.get_game_result(),
+            Some(GameResult::NoMoves {
+                loser: PieceColor::White
+            })
+        );
     }
 
-    /// Returns all legal moves for the `player_color`
-    pub fn get_legal_moves(&self) -> Option<Vec<Move>> {
-        let mut moves = None;
-        let mut is_taking = false;
-        for index in 0..self.pieces.row_count() {
-            if self.pieces.row_data(index)?.color != self.player_color {
-                continue;
+    #[test]
+    fn index_outside_the_board_is_none() {
+        let board = Board::from_diagram(START, PieceColor::White).unwrap();
+        for index in [32, usize::MAX] {
+            assert_eq!(board.try_piece_is_empty(index), None);
+            assert_eq!(board.try_piece_is_player(index), None);
+            assert_eq!(board.try_piece_is_enemy(index), None);
+            assert!(board.piece_at(index).is_none());
+        }
+        assert_eq!(board.try_piece_is_player(31), Some(true));
+        assert_eq!(board.try_piece_is_enemy(0), Some(true));
+        assert_eq!(board.try_piece_is_empty(15), Some(true));
+    }
+
+    #[test]
+    fn resigning_loses_the_game() {
+        let mut board = Board::from_diagram(START, PieceColor::White).unwrap();
+        assert_eq!(board.get_game_result(), None);
+        board.resign(PieceColor::Black);
+        assert_eq!(
+            board.get_game_result().and_then(|result| result.loser()),
+            Some(PieceColor::Black)
+        );
+    }
+
+    #[test]
+    fn mid_game_fixture_has_expected_pieces() {
+        let rows = [
+            "b...b...", ".b.....b", "..b.....", "...W.b..", "........", ".w...B..", "w...w...",
+            ".w......",
+        ];
+        let diagram = rows.join("\n");
+        let board = Board::from_diagram(&diagram, PieceColor::White).unwrap();
+        assert_eq!(board.get_player_piece_count(), 5);
+        assert_eq!(board.get_player_king_count(), 1);
+        assert_eq!(board.get_player_man_count(), 4);
+        assert_eq!(board.get_enemy_piece_count(), 7);
+        assert_eq!(board.get_enemy_king_count(), 1);
+        assert_eq!(board.get_enemy_man_count(), 6);
+        assert_eq!(board.get_empty_piece_count(), 20);
+
+        // The same position from a layout, seen by Black
+        let layout = Board::parse_layout(&diagram).unwrap();
+        let black = Board::from_layout(layout, PieceColor::Black);
+        assert_eq!(black.get_player_piece_count(), 7);
+        assert_eq!(black.get_enemy_king_count(), 1);
+        assert_eq!(black.logical_pieces(), board.logical_pieces());
+    }
+
+    #[test]
+    fn accepted_takeback_restores_both_boards() {
+        let _lock = lock_board_move();
+        let mut host = Board::from_diagram(START, PieceColor::White).unwrap();
+        let mut client = Board::from_diagram(START, PieceColor::Black).unwrap();
+        for _ in 0..2 {
+            let mov = host.legal_moves_for(host.side_to_move())[0].clone();
+            let logical = host.to_logical_move(&mov);
+            for board in [&mut host, &mut client] {
+                set_board_move(&logical);
+                board.move_piece();
             }
+        }
 
-            if let Some(mut legal_moves) = self.get_legal_moves_piece(index) {
-                is_taking |= legal_moves.1;
-                if legal_moves.1 == is_taking {
-                    moves.get_or_insert(vec![]).append(&mut legal_moves.0);
-                }
+        // White asks to take back its move on its own turn, so Black's answer goes too
+        assert_eq!(host.takeback_plies(PieceColor::White), Some(2));
+        assert_eq!(client.takeback_plies(PieceColor::White), Some(2));
+        for board in [&mut host, &mut client] {
+            for _ in 0..2 {
+                board.undo_move().unwrap();
             }
         }
-        moves.map(|moves| {
-            if !is_taking {
-                return moves;
+
+        let start = Board::from_diagram(START, PieceColor::White).unwrap();
+        for board in [&host, &client] {
+            assert_eq!(board.logical_pieces(), start.logical_pieces());
+            assert_eq!(board.side_to_move(), PieceColor::White);
+            assert_eq!(board.ply(), 0);
+            assert_eq!(board.takeback_plies(PieceColor::White), None);
+        }
+        assert_eq!(host.zobrist_hash(), client.zobrist_hash());
+    }
+
+    #[test]
+    fn lone_kings_are_a_draw() {
+        let kings =
+            "......B.\n........\n........\n........\n........\n........\n........\n.W......";
+        let board = Board::from_diagram(kings, PieceColor::White).unwrap();
+        assert!(board.is_insufficient_material());
+        assert_eq!(board.get_game_result(), Some(GameResult::Draw));
+
+        // The man can still be crowned, so White may win
+        let with_man =
+            "......B.\n........\n........\n........\n........\n...w....\n........\n.W......";
+        let board = Board::from_diagram(with_man, PieceColor::White).unwrap();
+        assert!(!board.is_insufficient_material());
+        assert_eq!(board.get_game_result(), None);
+    }
+
+    #[test]
+    fn legal_move_cache_matches_a_recompute() {
+        let _lock = lock_board_move();
+        let mut board = Board::from_diagram(START, PieceColor::White).unwrap();
+        let cached = board.get_legal_moves();
+        assert_eq!(cached, board.legal_moves_for(PieceColor::White));
+        assert_eq!(board.legal_moves.borrow().as_ref(), Some(&cached));
+        assert_eq!(board.get_legal_moves(), cached);
+
+        // White's move changes the pieces, so the cache is dropped
+        play(&mut board, &cached[0]);
+        assert!(board.legal_moves.borrow().is_none());
+        let reply = board.legal_moves_for(PieceColor::Black)[0].clone();
+        play(&mut board, &reply);
+        assert!(board.legal_moves.borrow().is_none());
+        let recomputed = board.legal_moves_for(PieceColor::White);
+        assert_ne!(recomputed, cached);
+        assert_eq!(board.get_legal_moves(), recomputed);
+
+        // Taking the reply back drops the cache too, and it's Black's move again
+        board.undo_move().unwrap();
+        assert!(board.legal_moves.borrow().is_none());
+        assert_eq!(
+            board.get_legal_moves(),
+            board.legal_moves_for(PieceColor::Black)
+        );
+    }
+
+    #[test]
+    fn legal_moves_belong_to_the_side_to_move() {
+        let _lock = lock_board_move();
+        let mut board = Board::from_diagram(START, PieceColor::White).unwrap();
+        let first = board.get_legal_moves()[0].clone();
+        play(&mut board, &first);
+
+        let moves = board.get_legal_moves();
+        assert_eq!(moves, board.legal_moves_for(PieceColor::Black));
+        assert!(moves
+            .iter()
+            .all(|mov| board.try_piece_is_player(mov.index) == Some(false)));
+    }
+
+    #[test]
+    fn kings_and_men_add_up_to_the_pieces() {
+        let mixed =
+            "b...B...\n.b.....b\n..W.....\n...W.b..\n........\n.w...B..\nw...w...\n.W......";
+        for color in [PieceColor::White, PieceColor::Black] {
+            let board = Board::from_diagram(mixed, color).unwrap();
+            assert_eq!(
+                board.get_player_king_count() + board.get_player_man_count(),
+                board.get_player_piece_count()
+            );
+            assert_eq!(
+                board.get_enemy_king_count() + board.get_enemy_man_count(),
+                board.get_enemy_piece_count()
+            );
+        }
+
+        let board = Board::from_diagram(mixed, PieceColor::White).unwrap();
+        assert_eq!(
+            (board.get_player_king_count(), board.get_player_man_count()),
+            (3, 3)
+        );
+        assert_eq!(
+            (board.get_enemy_king_count(), board.get_enemy_man_count()),
+            (2, 4)
+        );
+    }
+
+    /// Returns a move of a man from `index` to `end` without captures
+    fn slide(index: usize, end: usize) -> Move {
+        Move {
+            index,
+            end,
+            promoted: false,
+            captured: None,
+        }
+    }
+
+    #[test]
+    fn premove_is_kept_only_while_legal() {
+        let _lock = lock_board_move();
+        for (reply, kept) in [(slide(10, 14), true), (slide(9, 12), false)] {
+            let mut board = Board::from_diagram(START, PieceColor::White).unwrap();
+            play(&mut board, &slide(20, 16));
+            board.set_premove(slide(22, 18));
+            assert_eq!(board.premove(), Some(&slide(22, 18)));
+
+            // Moving to 12 lets the man on 16 take it, and captures can't be skipped
+            play(&mut board, &reply);
+            let taken = board.take_premove();
+            assert_eq!(taken, kept.then(|| slide(22, 18)), "after {:?}", reply);
+            assert_eq!(board.premove(), None);
+        }
+    }
+
+    #[test]
+    fn moves_on_a_fork_leave_the_parent_unchanged() {
+        let _lock = lock_board_move();
+        let board = Board::from_diagram(START, PieceColor::White).unwrap();
+        let pieces = board.logical_pieces();
+
+        let mut fork = board.fork();
+        play(&mut fork, &slide(20, 16));
+        assert_ne!(fork.logical_pieces(), pieces);
+        assert_eq!(fork.side_to_move(), PieceColor::Black);
+
+        assert_eq!(board.logical_pieces(), pieces);
+        assert_eq!(board.side_to_move(), PieceColor::White);
+        assert_eq!(board.ply(), 0);
+        assert!(last_move_squares(&board).is_empty());
+        assert_eq!(board.get_legal_moves().len(), 7);
+    }
+
+    #[test]
+    fn promoting_move_reports_the_crowned_square() {
+        let _lock = lock_board_move();
+        let diagram =
+            "........\n...w....\n......b.\n........\n........\n........\n........\n........";
+        let mut board = Board::from_diagram(diagram, PieceColor::White).unwrap();
+
+        let mov = Move {
+            index: 5,
+            end: 1,
+            promoted: true,
+            captured: None,
+        };
+        let outcome = play(&mut board, &mov);
+        assert_eq!(outcome.promoted_square, Some(1));
+        assert!(outcome.captured.is_empty());
+        assert!(board.logical_pieces()[1].is_king);
+
+        // The reply doesn't crown anything
+        let reply = board.legal_moves_for(PieceColor::Black)[0].clone();
+        assert_eq!(play(&mut board, &reply).promoted_square, None);
+    }
+
+    #[test]
+    fn over_full_side_is_rejected() {
+        // The starting position with an extra white man in the middle
+        let over_full =
+            "b.b.b.b.\n.b.b.b.b\nb.b.b.b.\n........\nw.......\n.w.w.w.w\nw.w.w.w.\n.w.w.w.w";
+        assert!(Board::from_diagram(over_full, PieceColor::White).is_err());
+        let board = Board::from_layout(Board::parse_layout(over_full).unwrap(), PieceColor::Black);
+        assert_eq!(
+            board.validate(),
+            Err(BoardError::TooManyPieces {
+                color: PieceColor::White,
+                count: 13
+            })
+        );
+
+        for color in [PieceColor::White, PieceColor::Black] {
+            let board = Board::from_diagram(START, color).unwrap();
+            assert!(board.validate().is_ok());
+        }
+    }
+
+    #[test]
+    fn svg_has_a_circle_per_piece() {
+        let rows = [
+            "b...b...", ".b.....b", "..b.....", "...W.b..", "........", ".w...B..", "w...w...",
+            ".w......",
+        ];
+        for diagram in [START.to_owned(), rows.join("\n")] {
+            let board = Board::from_diagram(&diagram, PieceColor::White).unwrap();
+            let svg = board.to_svg();
+            let pieces = board.get_player_piece_count() + board.get_enemy_piece_count();
+            assert_eq!(svg.matches("<circle").count(), pieces as usize);
+            let kings = board.get_player_king_count() + board.get_enemy_king_count();
+            assert_eq!(svg.matches("stroke=\"crimson\"").count(), kings as usize);
+            assert!(svg.starts_with("<svg") && svg.ends_with("</svg>\n"));
+        }
+    }
+
+    /// Returns the tiles showing the keyboard cursor
+    fn cursor_squares(board: &Board) -> Vec<usize> {
+        (0..32)
+            .filter(|index| board.squares.row_data(*index).unwrap().cursor)
+            .collect()
+    }
+
+    #[test]
+    fn selection_stays_on_the_board_from_a_corner() {
+        let mut board = Board::from_diagram(START, PieceColor::White).unwrap();
+        // The top left corner
+        board.selected_square = 0;
+        for direction in [Direction::UpLeft, Direction::UpRight, Direction::DownLeft] {
+            board.move_selection(direction);
+            assert_eq!(board.selected_square, 0);
+            assert_eq!(cursor_squares(&board), vec![0]);
+        }
+
+        // Without a selection the cursor starts in the bottom left corner, and can't leave the
+        // board from there
+        board.selected_square = -1;
+        for direction in [
+            Direction::DownLeft,
+            Direction::DownLeft,
+            Direction::DownRight,
+        ] {
+            board.move_selection(direction);
+            assert_eq!(board.selected_square, 28);
+            assert_eq!(cursor_squares(&board), vec![28]);
+        }
+        board.move_selection(Direction::UpLeft);
+        board.move_selection(Direction::UpLeft);
+        assert_eq!(board.selected_square, 24);
+
+        // Going up right from the corner crosses the board and stops at the right edge
+        board.selected_square = 28;
+        for _ in 0..10 {
+            board.move_selection(Direction::UpRight);
+        }
+        assert_eq!(board.selected_square, 7);
+        assert_eq!(cursor_squares(&board), vec![7]);
+        board.move_selection(Direction::UpLeft);
+        board.move_selection(Direction::UpLeft);
+        assert_eq!(board.selected_square, 3);
+        board.move_selection(Direction::UpRight);
+        assert_eq!(board.selected_square, 3);
+    }
+
+    #[test]
+    fn confirming_the_selection_twice_chooses_a_move() {
+        let mut board = Board::from_diagram(START, PieceColor::White).unwrap();
+        board.selected_square = 28;
+        board.move_selection(Direction::UpLeft);
+        board.move_selection(Direction::UpRight);
+        assert_eq!(board.selected_square, 20);
+        assert_eq!(board.confirm_selection(), None);
+
+        board.move_selection(Direction::UpLeft);
+        assert_eq!(board.confirm_selection(), Some(slide(20, 16)));
+    }
+
+    /// White's man on 20 can take 17 and 10, landing on 6
+    const DOUBLE_CAPTURE: &str =
+        "b.......\n........\n....b...\n........\n..b.....\n.w......\n........\n........";
+
+    #[test]
+    fn double_capture_counts_two_captured() {
+        let _lock = lock_board_move();
+        let mut board = Board::from_diagram(DOUBLE_CAPTURE, PieceColor::White).unwrap();
+        let moves = board.get_legal_moves();
+        assert_eq!(moves.len(), 1);
+        assert_eq!(moves[0].captured, Some(vec![17, 10]));
+
+        let outcome = play(&mut board, &moves[0]);
+        assert_eq!(outcome.captured.len(), 2);
+        assert_eq!(
+            board.stats(),
+            GameStats {
+                player_captured: 2,
+                ..GameStats::default()
             }
+        );
+    }
 
-            moves
-                .iter()
-                .filter_map(|mov| mov.captured.as_ref().map(|_| mov.clone()))
-                .collect()
-        })
+    #[test]
+    fn capture_sequences_to_the_same_tile_stay_separate() {
+        // The man on 25 reaches 9 by taking 20 and 12, or by taking 21 and 13. Capturing
+        // backwards it could go on to take the other two as well
+        let diagram =
+            "........\n........\n........\n.b.b....\n........\n.b.b....\n..w.....\n........";
+        let mut board = Board::from_diagram(diagram, PieceColor::White).unwrap();
+        board.set_rules(Rules {
+            men_capture_backwards: false,
+        });
+        let (mut moves, is_taking) = board.get_legal_moves_piece(25);
+        assert!(is_taking);
+        moves.sort_by_key(|mov| mov.captured.clone());
+        let captured: Vec<_> = moves
+            .iter()
+            .map(|mov| (mov.end, mov.captured.clone()))
+            .collect();
+        assert_eq!(
+            captured,
+            vec![(9, Some(vec![20, 12])), (9, Some(vec![21, 13]))]
+        );
+
+        // Choosing the tile shows one sequence, and a tile of the other one switches to it
+        assert_eq!(board.choose_move(25, 9), MoveChoice::ShowingSequence);
+        let shown = board.shown_sequence.clone().unwrap();
+        let other = moves.into_iter().find(|mov| *mov != shown).unwrap();
+        let other_tile = other.captured.as_ref().unwrap()[0];
+        assert_eq!(
+            board.choose_move(25, other_tile),
+            MoveChoice::ShowingSequence
+        );
+        assert_eq!(board.choose_move(25, 9), MoveChoice::Move(other));
+    }
+
+    #[test]
+    fn illegal_premove_is_discarded() {
+        let _lock = lock_board_move();
+        let mut board = Board::from_diagram(START, PieceColor::White).unwrap();
+        play(&mut board, &slide(20, 16));
+        board.set_premove(slide(22, 18));
+        // Moving to 12 lets the man on 16 take it, and captures can't be skipped
+        play(&mut board, &slide(9, 12));
+        let pieces = board.logical_pieces();
+        let published = get_board_move();
+
+        assert!(!board.try_apply_premove());
+        assert_eq!(board.premove(), None);
+        assert_eq!(board.logical_pieces(), pieces);
+        assert_eq!(board.side_to_move(), PieceColor::White);
+        assert_eq!(get_board_move(), published);
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_opening() {
+        let _lock = lock_board_move();
+        let opening = |color, seed| {
+            let mut board = Board::from_diagram(START, PieceColor::White).unwrap();
+            board.start_new_game_random(color, seed);
+            assert_eq!(board.ply(), RANDOM_OPENING_PLIES as u32);
+            assert_eq!(board.get_game_result(), None);
+            (board.logical_pieces(), board.history().to_vec())
+        };
+
+        // Both peers get the same position, whichever color they play
+        let host = opening(PieceColor::White, 1234);
+        assert_eq!(opening(PieceColor::White, 1234), host);
+        assert_eq!(opening(PieceColor::Black, 1234), host);
+        assert!((0..8).any(|seed| opening(PieceColor::White, seed) != host));
+    }
+
+    #[test]
+    fn sides_alternate_and_a_multi_jump_is_one_turn() {
+        let _lock = lock_board_move();
+        let mut board = Board::from_diagram(START, PieceColor::Black).unwrap();
+        let mut side = PieceColor::White;
+        for _ in 0..4 {
+            assert_eq!(board.side_to_move(), side);
+            let mov = board.legal_moves_for(side)[0].clone();
+            play(&mut board, &mov);
+            side = side.get_opposite();
+        }
+
+        let mut board = Board::from_diagram(DOUBLE_CAPTURE, PieceColor::White).unwrap();
+        let jump = board.get_legal_moves().remove(0);
+        assert_eq!(jump.captured.as_ref().map(Vec::len), Some(2));
+        play(&mut board, &jump);
+        assert_eq!(board.side_to_move(), PieceColor::Black);
+        let reply = board.legal_moves_for(PieceColor::Black)[0].clone();
+        play(&mut board, &reply);
+        assert_eq!(board.side_to_move(), PieceColor::White);
+        assert_eq!(board.ply(), 2);
+    }
+
+    #[test]
+    fn capture_tally_follows_undo() {
+        let _lock = lock_board_move();
+        // Seen by Black, so White's captures are the enemy's
+        let mut board = Board::from_diagram(DOUBLE_CAPTURE, PieceColor::Black).unwrap();
+        let jump = board.legal_moves_for(PieceColor::White).remove(0);
+        play(&mut board, &jump);
+        assert_eq!(board.captures_by(PieceColor::White), 2);
+        assert_eq!(board.captures_by(PieceColor::Black), 0);
+        assert_eq!(board.stats().enemy_captured, 2);
+
+        board.undo_move().unwrap();
+        assert_eq!(board.captures_by(PieceColor::White), 0);
+        assert_eq!(board.stats(), GameStats::default());
+
+        play(&mut board, &jump);
+        board.start_new_game(PieceColor::Black);
+        assert_eq!(board.captures_by(PieceColor::White), 0);
     }
 }
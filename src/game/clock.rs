@@ -0,0 +1,162 @@
+use std::time::{Duration, Instant};
+
+use super::{GameResult, PieceColor};
+
+/// The time limits of a game, agreed on when the client joins
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeControl {
+    /// The time each side starts with
+    pub initial: Duration,
+    /// The time added to a side's clock after it has moved
+    pub increment: Duration,
+}
+
+impl TimeControl {
+    /// Creates a time control from whole seconds
+    pub const fn from_secs(initial: u64, increment: u64) -> Self {
+        Self {
+            initial: Duration::from_secs(initial),
+            increment: Duration::from_secs(increment),
+        }
+    }
+
+    /// Creates a stopped clock with this time control, see `MoveClock::new`
+    pub fn clock(&self, player_color: PieceColor) -> MoveClock {
+        MoveClock::new(player_color, self.initial, self.increment)
+    }
+}
+
+/// A chess-clock, holding the time each side has left.
+/// Only one side's time runs at once, and a side gets `increment` added when its turn ends.
+#[derive(Clone, Debug)]
+pub struct MoveClock {
+    /// The time the local player has left
+    player: Duration,
+    /// The time the other player has left
+    enemy: Duration,
+    /// The time added to a side's clock after it has moved
+    increment: Duration,
+    player_color: PieceColor,
+    /// The side whose time is running, and when its time was last updated
+    running: Option<(PieceColor, Instant)>,
+}
+
+impl MoveClock {
+    /// Creates a stopped clock, where both sides start with `time`.
+    pub fn new(player_color: PieceColor, time: Duration, increment: Duration) -> Self {
+        Self {
+            player: time,
+            enemy: time,
+            increment,
+            player_color,
+            running: None,
+        }
+    }
+
+    fn time_mut(&mut self, color: PieceColor) -> &mut Duration {
+        if color == self.player_color {
+            &mut self.player
+        } else {
+            &mut self.enemy
+        }
+    }
+
+    /// Stops the running side's clock, and starts the clock of `color`.
+    pub fn start_turn(&mut self, color: PieceColor) {
+        self.stop_turn();
+        self.running = Some((color, Instant::now()));
+    }
+
+    /// Stops the running side's clock, and gives it the increment if it hasn't run out of time.
+    pub fn stop_turn(&mut self) {
+        self.tick();
+        if let Some((color, _)) = self.running.take() {
+            let increment = self.increment;
+            let time = self.time_mut(color);
+            if !time.is_zero() {
+                *time += increment;
+            }
+        }
+    }
+
+    /// Subtracts the time passed since the last tick from the running side.
+    /// Should be called regularly, eg. from a timer callback.
+    /// Returns the result of the game if the running side has run out of time.
+    pub fn tick(&mut self) -> Option<GameResult> {
+        let (color, last_tick) = self.running?;
+        let now = Instant::now();
+        let time = self.time_mut(color);
+        *time = time.saturating_sub(now - last_tick);
+        self.running = Some((color, now));
+
+        self.timeout()
+    }
+
+    /// Returns the time `color` has left.
+    pub fn remaining(&self, color: PieceColor) -> Duration {
+        let time = if color == self.player_color {
+            self.player
+        } else {
+            self.enemy
+        };
+
+        match self.running {
+            Some((running, last_tick)) if running == color => {
+                time.saturating_sub(last_tick.elapsed())
+            }
+            _ => time,
+        }
+    }
+
+    /// Returns the side whose turn is currently being timed
+    pub fn running(&self) -> Option<PieceColor> {
+        self.running.map(|(color, _)| color)
+    }
+
+    /// Returns the result of the game if a side has run out of time.
+    pub fn timeout(&self) -> Option<GameResult> {
+        [PieceColor::White, PieceColor::Black]
+            .into_iter()
+            .find(|color| self.remaining(*color).is_zero())
+            .map(|loser| GameResult::Timeout { loser })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn increment_is_added_after_a_move() {
+        let control = TimeControl::from_secs(60, 5);
+        let mut clock = control.clock(PieceColor::White);
+        clock.start_turn(PieceColor::White);
+        clock.start_turn(PieceColor::Black);
+
+        let white = clock.remaining(PieceColor::White);
+        assert!(white > Duration::from_secs(64) && white <= Duration::from_secs(65));
+        assert!(clock.remaining(PieceColor::Black) <= Duration::from_secs(60));
+        assert_eq!(clock.running(), Some(PieceColor::Black));
+    }
+
+    #[test]
+    fn running_out_of_time_ends_the_game() {
+        let mut clock = MoveClock::new(
+            PieceColor::White,
+            Duration::from_millis(20),
+            Duration::from_secs(5),
+        );
+        clock.start_turn(PieceColor::Black);
+        assert_eq!(clock.tick(), None);
+
+        std::thread::sleep(Duration::from_millis(30));
+        let result = Some(GameResult::Timeout {
+            loser: PieceColor::Black,
+        });
+        assert_eq!(clock.tick(), result);
+        // A side which ran out of time doesn't get the increment
+        clock.stop_turn();
+        assert!(clock.remaining(PieceColor::Black).is_zero());
+        assert_eq!(clock.timeout(), result);
+    }
+}
@@ -1,18 +1,25 @@
 use arboard::Clipboard;
-use slint::ComponentHandle;
+use slint::{ComponentHandle, Timer, TimerMode};
+use tracing::{debug, error, info, warn};
 
 use crate::net::interface;
 
 use super::{
-    board::{set_board_move, Board},
-    GameAction, GameWindow, PieceColor, WindowType,
+    board::{set_board_move, Board, MoveChoice},
+    clock::MoveClock,
+    Direction, GameAction, GameResult, GameWindow, Move, PieceColor, PieceData, WindowType,
 };
 use std::cell::RefCell;
 use std::ops::{Deref, DerefMut};
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread::sleep;
 use std::time::Duration;
 
+/// The username used when the player hasn't typed one
+const DEFAULT_USERNAME: &str = "Player";
+
 pub struct Context {
     gamedata: Rc<RefCell<GameData>>,
 }
@@ -68,20 +75,47 @@ impl Context {
                     let mut join_code: String = gamedata.window.get_lan_code().into();
                     join_code = join_code.trim().to_owned();
 
-                    println!("Code was: \"{}\"", &join_code);
+                    debug!("Code was: \"{}\"", &join_code);
+                    // Stay on the prompt, so the code can be fixed
+                    if let Err(err) = interface::validate_join_code(&join_code) {
+                        error!("Couldn't join game: {:#}", err);
+                        return;
+                    }
 
                     gamedata.load_connecting_window(join_code.clone(), false);
 
-                    interface::start_lan_client();
+                    if let Err(err) =
+                        interface::start_lan_client(interface::ClientConfig::default())
+                    {
+                        error!("Couldn't start client: {:#}", err);
+                        gamedata.load_start_window();
+                        return;
+                    }
 
-                    let username: String = gamedata.window.get_username().into();
+                    let username = gamedata.username();
 
                     let handle_weak = gamedata.window.as_weak();
                     tokio::spawn(async move {
-                        let (color, host_username) =
-                            interface::connect_to_host_loop(&join_code, &username).unwrap();
-
-                        println!("Joined {}'s game. You are {:?}", host_username, color);
+                        let connection = interface::connect_to_host_async(
+                            &join_code,
+                            &username,
+                            interface::DEFAULT_JOIN_ATTEMPTS,
+                            interface::DEFAULT_CONNECT_TIMEOUT,
+                        )
+                        .await;
+                        let (color, host_username) = match connection {
+                            Ok(connection) => connection,
+                            Err(err) => {
+                                error!("Couldn't join game: {:#}", err);
+                                slint::invoke_from_event_loop(move || {
+                                    handle_weak.unwrap().set_window_state(WindowType::Start);
+                                })
+                                .unwrap();
+                                return;
+                            }
+                        };
+
+                        info!("Joined {}'s game. You are {:?}", host_username, color);
 
                         let handle_copy = handle_weak.clone();
                         slint::invoke_from_event_loop(move || {
@@ -98,7 +132,10 @@ impl Context {
                         .unwrap();
                     });
 
-                    gamedata.get_board_mut().start_new_game(PieceColor::Black);
+                    gamedata.start_new_game(PieceColor::Black);
+                    gamedata.share_board_state();
+                    gamedata.is_host = Some(false);
+                    gamedata.start_action_timer();
                     gamedata.wait_for_opponent();
                 }
             });
@@ -110,16 +147,27 @@ impl Context {
 
         move || {
             let mut gamedata = try_get_static_self().unwrap();
-            let join_code = interface::start_lan_host();
+            let username = gamedata.username();
+            if let Err(err) = interface::set_my_username(&username) {
+                error!("Couldn't host game: {:#}", err);
+                gamedata.load_start_window();
+                return;
+            }
+
+            let join_code = match interface::start_lan_host(interface::ColorAssignment::default()) {
+                Ok(join_code) => join_code,
+                Err(err) => {
+                    error!("Couldn't host game: {:#}", err);
+                    gamedata.load_start_window();
+                    return;
+                }
+            };
 
             gamedata.load_connecting_window(join_code.clone(), true);
 
             let mut clipboard = Clipboard::new().unwrap();
             clipboard.set_text(join_code).unwrap();
 
-            let username: String = gamedata.window.get_username().into();
-            interface::set_my_username(&username);
-
             let handle_weak = gamedata.window.as_weak();
             std::thread::spawn(move || {
                 loop {
@@ -147,8 +195,12 @@ impl Context {
                 })
                 .unwrap();
             });
-            gamedata.get_board_mut().start_new_game(PieceColor::White);
-            gamedata.is_player_turn = true;
+            let color = interface::get_my_color().unwrap_or(PieceColor::White);
+            gamedata.start_new_game(color);
+            gamedata.share_board_state();
+            gamedata.is_host = Some(true);
+            gamedata.is_player_turn = gamedata.board.side_to_move() == color;
+            gamedata.start_action_timer();
         }
         // self.on_join_game()
     }
@@ -164,84 +216,557 @@ impl Context {
 
             let selected_piece = board.selected_square as usize;
 
-            if !gamedata.is_player_turn {
+            if !gamedata.can_choose_move() {
                 return;
             }
-
-            if board.piece_is_player(selected_piece) {
-                let legal_moves = board.get_legal_moves();
-                if let Some(moves) = legal_moves {
-                    for mov in &moves {
-                        let input_matches_move =
-                            mov.end == index as usize && mov.index == selected_piece;
-
+            board.clear_cursor();
+            let is_premove = gamedata.is_premove();
+
+            // Nothing is selected if `selected_square` is negative
+            if board.try_piece_is_player(selected_piece) == Some(true) {
+                match board.choose_move(selected_piece, index as usize) {
+                    // The piece stays selected, so the player can confirm the capture sequence
+                    MoveChoice::ShowingSequence => return,
+                    MoveChoice::Move(mov) if is_premove => {
+                        board.set_premove(mov);
+                        board.unmark_squares();
+                        board.selected_square = -1;
+                        return;
+                    }
+                    MoveChoice::Move(mov) => {
                         board.selected_square = index;
-
-                        if input_matches_move {
-                            set_board_move(mov);
-                            gamedata.window.invoke_move_piece();
-                            interface::send_game_action(GameAction::MovePiece(mov.clone()), |_| ());
-                            gamedata.wait_for_opponent();
-                            break;
-                        }
+                        gamedata.send_move(board.to_logical_move(&mov));
                     }
+                    MoveChoice::NoMove => {}
                 }
             }
-            // If there was no move with the input
-            board.reset_squares();
-            if let Some(moves) = board.get_legal_moves_piece(index as usize) {
-                let mark_indicies: Vec<usize> = moves.0.iter().map(|mov| mov.end).collect();
-                board.mark_squares(mark_indicies.as_slice());
+            // Clicking anywhere else cancels the premove
+            if is_premove {
+                board.clear_premove();
             }
+            // If there was no move with the input
+            board.unmark_squares();
+            let (moves, _) = board.get_legal_moves_piece(index as usize);
+            let mark_indicies: Vec<usize> = moves.iter().map(|mov| mov.end).collect();
+            board.mark_squares(mark_indicies.as_slice());
             board.selected_square = index;
         }
     }
 
+    pub fn on_move_selection(&self) -> impl FnMut(i32) + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move |direction: i32| {
+            let mut gamedata = try_get_static_self().unwrap();
+            let direction = match direction {
+                0 => Direction::UpLeft,
+                1 => Direction::UpRight,
+                2 => Direction::DownLeft,
+                _ => Direction::DownRight,
+            };
+            gamedata.get_board_mut().move_selection(direction);
+        }
+    }
+
+    pub fn on_confirm_selection(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let mut gamedata = try_get_static_self().unwrap();
+            if !gamedata.can_choose_move() {
+                return;
+            }
+
+            let is_premove = gamedata.is_premove();
+            let Some(mov) = gamedata.get_board_mut().confirm_selection() else {
+                // Like clicking elsewhere, picking up a piece cancels the premove
+                if is_premove {
+                    gamedata.get_board_mut().clear_premove();
+                }
+                return;
+            };
+            if is_premove {
+                gamedata.get_board_mut().set_premove(mov);
+            } else {
+                gamedata.send_move(gamedata.board.to_logical_move(&mov));
+            }
+        }
+    }
+
     pub fn on_move_piece(&self) -> impl FnMut() + 'static {
         let mut try_get_static_self = self.try_get_static_func();
 
         move || {
             let mut gamedata = try_get_static_self().unwrap();
-            gamedata.get_board_mut().move_piece();
+            gamedata.perform_board_move();
 
             gamedata.is_player_turn = true;
+            gamedata.check_game_result();
+        }
+    }
+
+    pub fn on_offer_draw(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let gamedata = try_get_static_self().unwrap();
+            if gamedata.board.get_game_result().is_some() {
+                return;
+            }
+
+            interface::send_game_action(GameAction::OfferDraw, |res| {
+                if let Err(err) = res {
+                    warn!("Draw offer failed: {:#}", err);
+                }
+            });
+        }
+    }
+
+    pub fn on_resign(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let mut gamedata = try_get_static_self().unwrap();
+            if gamedata.board.get_game_result().is_some() {
+                return;
+            }
+
+            interface::send_game_action(GameAction::Surrender, |res| {
+                if let Err(err) = res {
+                    warn!("The opponent didn't get the surrender: {:#}", err);
+                }
+            });
+            let loser = gamedata.board.player_color();
+            gamedata.end_game(GameResult::Resigned { loser });
+        }
+    }
+
+    pub fn on_respond_draw(&self) -> impl FnMut(bool) + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move |accepted: bool| {
+            let mut gamedata = try_get_static_self().unwrap();
+            interface::send_game_action(GameAction::RespondDraw(accepted), |_| ());
+            if accepted {
+                gamedata.end_game(GameResult::Draw);
+            }
+        }
+    }
+
+    pub fn on_request_takeback(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let mut gamedata = try_get_static_self().unwrap();
+            if gamedata.takeback_requested.is_some() {
+                return;
+            }
+            if gamedata
+                .board
+                .takeback_plies(gamedata.board.player_color())
+                .is_none()
+            {
+                info!("There is no move to take back");
+                return;
+            }
+
+            let ply = gamedata.board.ply();
+            interface::request_takeback(ply, |res| {
+                if let Err(err) = res {
+                    warn!("Takeback request failed: {:#}", err);
+                }
+            });
+            gamedata.takeback_requested = Some(ply);
+            gamedata.window.set_takeback_requested(true);
+        }
+    }
+
+    pub fn on_respond_takeback(&self) -> impl FnMut(bool) + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move |accepted: bool| {
+            let mut gamedata = try_get_static_self().unwrap();
+            gamedata.answer_takeback(accepted);
+        }
+    }
+
+    pub fn on_rematch(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let mut gamedata = try_get_static_self().unwrap();
+            if gamedata.board.get_game_result().is_none() {
+                return;
+            }
+
+            if interface::is_rematch_offered() {
+                interface::accept_rematch(|_| ());
+                gamedata.start_rematch();
+                return;
+            }
+
+            interface::send_rematch_request(|res| {
+                if let Err(err) = res {
+                    warn!("Rematch request failed: {:#}", err);
+                }
+            });
+            gamedata.rematch_requested = true;
+            gamedata.window.set_rematch_requested(true);
         }
     }
 
+    /// Handles the actions of the opponent, and watches the connection to them.
+    pub fn start_action_timer(&mut self) {
+        let mut try_get_static_self = self.try_get_static_func();
+        self.action_timer
+            .start(TimerMode::Repeated, Duration::from_millis(50), move || {
+                let Some(mut gamedata) = try_get_static_self() else {
+                    return;
+                };
+
+                gamedata.check_connection();
+                if let Some(color) = interface::take_assigned_color() {
+                    gamedata.play_assigned_color(color);
+                }
+                gamedata.check_time_control();
+                if let Some(pieces) = interface::take_resynced_board() {
+                    gamedata.load_resynced_board(&pieces);
+                }
+                while let Some(action) = interface::get_next_game_action() {
+                    gamedata.handle_game_action(action);
+                }
+            });
+    }
+
     pub fn wait_for_opponent(&mut self) {
         self.is_player_turn = false;
-        let weak_window = self.window.as_weak();
-        tokio::spawn(async move {
-            let mut action;
-            loop {
-                action = interface::get_next_game_action();
-                if action.is_none() {
-                    tokio::time::sleep(Duration::from_millis(50)).await;
-                    continue;
+    }
+
+    /// Plays the rest of the game with `clock`, and shows the time left for both players until
+    /// a side runs out of time.
+    pub fn start_clock(&mut self, clock: MoveClock) {
+        self.get_board_mut().set_clock(clock);
+        self.share_board_state();
+        self.show_clock();
+
+        let mut try_get_static_self = self.try_get_static_func();
+        self.clock_timer
+            .start(TimerMode::Repeated, Duration::from_millis(100), move || {
+                let Some(mut gamedata) = try_get_static_self() else {
+                    return;
+                };
+
+                let result = gamedata.get_board_mut().tick_clock();
+                gamedata.show_clock();
+
+                if let Some(result) = result {
+                    // Each player tells the other when their own time runs out
+                    if result.loser() == Some(gamedata.board.player_color()) {
+                        interface::send_game_action(GameAction::TimeExpired, |_| ());
+                    }
+                    gamedata.end_game(result);
                 }
-                break;
-            }
+            });
+    }
 
-            let action = unsafe { action.unwrap_unchecked() };
-            match action {
-                GameAction::MovePiece(mov) => {
-                    println!("Recieved move: {:#?}", mov);
-                    set_board_move(&mov.reverse());
-                    slint::invoke_from_event_loop(move || {
-                        weak_window.unwrap().invoke_move_piece();
-                    })
-                    .unwrap();
+    /// Handles an action made by the opponent
+    fn handle_game_action(&mut self, action: GameAction) {
+        let opponent = self.board.player_color().get_opposite();
+        match action {
+            GameAction::MovePiece(mov) => {
+                if self.is_player_turn || self.board.get_game_result().is_some() {
+                    warn!("Got a move from the opponent out of turn: {:?}", mov);
+                    return;
+                }
+                // A capture sequence is only legal as a whole, so a sequence can't be made by
+                // sending its jumps one at a time
+                let opponent = self.board.player_color().get_opposite();
+                let display_move = self.board.to_display_move(&mov);
+                if !self.board.legal_moves_for(opponent).contains(&display_move) {
+                    warn!("Got an illegal move from the opponent: {:?}", mov);
+                    return;
+                }
+                info!("Recieved move: {:?}", mov);
+                set_board_move(&mov);
+                self.window.invoke_move_piece();
+                self.play_premove();
+            }
+            GameAction::OfferDraw => self.window.set_draw_offered(true),
+            GameAction::RespondDraw(true) => self.end_game(GameResult::Draw),
+            GameAction::RespondDraw(false) => info!("The opponent declined the draw offer"),
+            // Both players asked for a rematch at the same time
+            GameAction::Rematch if self.rematch_requested => self.start_rematch(),
+            GameAction::Rematch => self.window.set_rematch_offered(true),
+            GameAction::RematchAccept if self.rematch_requested => self.start_rematch(),
+            GameAction::RematchAccept => warn!("The opponent accepted a rematch nobody asked for"),
+            GameAction::TimeExpired => {
+                if self.board.get_game_result().is_none() {
+                    let loser = self.board.player_color().get_opposite();
+                    self.end_game(GameResult::Timeout { loser });
+                }
+            }
+            GameAction::RequestTakeback(ply) => {
+                self.takeback_offered = Some(ply);
+                self.window.set_takeback_offered(true);
+                // The board has moved on, or there is nothing to take back
+                if ply != self.board.ply() || self.board.takeback_plies(opponent).is_none() {
+                    info!("Declined a takeback request made after {} moves", ply);
+                    self.answer_takeback(false);
                 }
-                _ => {
-                    println!(
-                        "Got GameAction {:?} while waiting for opponent,
-                                     this is not implemented yet",
-                        action
-                    );
+            }
+            GameAction::RespondTakeback(accepted) => {
+                let Some(ply) = self.takeback_requested.take() else {
+                    warn!("The opponent answered a takeback nobody asked for");
+                    return;
+                };
+                self.window.set_takeback_requested(false);
+                if !accepted {
+                    info!("The opponent declined the takeback");
+                } else if ply != self.board.ply() {
+                    // Only happens if the boards are already out of sync
+                    warn!("The opponent accepted a takeback the board has moved on from");
+                } else {
+                    self.take_back(self.board.player_color());
+                }
+            }
+            GameAction::Surrender => {
+                if self.board.get_game_result().is_none() {
+                    let loser = self.board.player_color().get_opposite();
+                    self.end_game(GameResult::Resigned { loser });
                 }
             }
+        }
+    }
+
+    /// Makes the player's move `mov`, given in logical indices, and sends it to the opponent.
+    /// The move is sent before the game is checked for a result, since no game actions can be
+    /// sent once the game is over.
+    fn send_move(&mut self, mov: Move) {
+        set_board_move(&mov);
+        self.perform_board_move();
+        interface::send_game_action(GameAction::MovePiece(mov), |res| {
+            if let Err(err) = res {
+                warn!("The opponent didn't accept the move: {:#}", err);
+            }
         });
+        // Moving declines the opponent's takeback request
+        self.answer_takeback(false);
+        self.wait_for_opponent();
+        self.check_game_result();
+    }
+
+    /// Returns wether the player may choose a move on the board
+    fn can_choose_move(&self) -> bool {
+        // While a takeback is requested, the board may be about to change
+        self.board.get_game_result().is_none() && self.takeback_requested.is_none()
+    }
+
+    /// Returns wether a chosen move should be kept as a premove, because it's the opponent's turn
+    fn is_premove(&self) -> bool {
+        !self.is_player_turn || interface::whose_turn() != self.board.player_color()
+    }
+
+    /// Makes the move the player chose during the opponent's turn, if it's still legal
+    fn play_premove(&mut self) {
+        // The opponent's move may have ended the game
+        if !self.is_player_turn {
+            return;
+        }
+        let Some(mov) = self.get_board_mut().take_premove() else {
+            return;
+        };
+        info!("Playing premove: {:?}", mov);
+        self.send_move(self.board.to_logical_move(&mov));
+    }
+
+    /// Performs the move set with `set_board_move`, and shares the new board with the network
+    fn perform_board_move(&mut self) {
+        let outcome = self.get_board_mut().move_piece();
+        if let Some(square) = outcome.promoted_square {
+            debug!("Piece on square {} was crowned", square);
+        }
+        self.share_board_state();
+        interface::send_sync_check(self.board.board_hash(), self.board.ply());
+    }
+
+    /// Ends the game if the board has decided it, eg. because a side can't move
+    fn check_game_result(&mut self) {
+        if let Some(result) = self.board.get_game_result() {
+            self.end_game(result);
+        }
+    }
+
+    /// Answers the opponent's takeback request, if there is one. The moves are only taken back if
+    /// the board hasn't moved on since the request was made.
+    fn answer_takeback(&mut self, accepted: bool) {
+        let Some(ply) = self.takeback_offered.take() else {
+            return;
+        };
+        self.window.set_takeback_offered(false);
+
+        let accepted = accepted && ply == self.board.ply();
+        if accepted {
+            // The board is shared before answering, so the opponent's next move is checked
+            // against the board with the moves taken back
+            self.take_back(self.board.player_color().get_opposite());
+        }
+        interface::respond_takeback(accepted, |_| ());
+    }
+
+    /// Takes back the last move of `color`, and the answer to it if there is one, after both
+    /// players agreed to it.
+    fn take_back(&mut self, color: PieceColor) {
+        let Some(plies) = self.board.takeback_plies(color) else {
+            warn!("There is no move of {} to take back", color);
+            return;
+        };
+        for _ in 0..plies {
+            self.get_board_mut().undo_move();
+        }
+        info!("Took back {} moves", plies);
+
+        interface::clear_sync_checks();
+        self.share_board_state();
+        self.is_player_turn = self.board.side_to_move() == self.board.player_color();
+    }
+
+    /// Starts the game over as `color`, if the host assigned the player another color or
+    /// opening than the board was set up with when joining
+    fn play_assigned_color(&mut self, color: PieceColor) {
+        if color == self.board.player_color()
+            && interface::get_opening_seed() == self.board.opening_seed()
+        {
+            return;
+        }
+        info!("The host assigned you {:?}", color);
+        self.start_new_game(color);
+        self.share_board_state();
+        self.is_player_turn = self.board.side_to_move() == color;
+    }
+
+    /// Starts the clock once both players are connected, if the game has a time control
+    fn check_time_control(&mut self) {
+        if self.board.clock().is_some() || !interface::is_connected() {
+            return;
+        }
+        let Some(time_control) = interface::get_time_control() else {
+            return;
+        };
+        if self.board.get_game_result().is_some() {
+            return;
+        }
+
+        info!("Starting the clock with {:?}", time_control);
+        let clock = time_control.clock(self.board.player_color());
+        self.start_clock(clock);
+    }
+
+    /// Shows if the connection to the opponent is lost. The client tries to reconnect, while the
+    /// host waits for it to come back.
+    fn check_connection(&mut self) {
+        let left = interface::get_disconnect_reason();
+        let lost = left.is_some()
+            || interface::connection_timed_out()
+            || self.reconnecting.load(Ordering::Relaxed);
+        if lost == self.connection_lost {
+            return;
+        }
+        self.connection_lost = lost;
+        self.window.set_connection_lost(lost);
+        if !lost {
+            return;
+        }
+
+        self.cancel_rematch();
+        if let Some(reason) = left {
+            info!("The opponent left the game: {}", reason);
+            self.clock_timer.stop();
+            self.is_player_turn = false;
+            self.window.set_game_over_text("Opponent left the game".into());
+            return;
+        }
+        warn!("Lost connection to the opponent");
+
+        if self.is_host != Some(true) {
+            let reconnecting = self.reconnecting.clone();
+            reconnecting.store(true, Ordering::Relaxed);
+            tokio::spawn(async move {
+                match interface::reconnect_async(interface::DEFAULT_RECONNECT_ATTEMPTS).await {
+                    Ok(()) => reconnecting.store(false, Ordering::Relaxed),
+                    // The connection stays lost
+                    Err(err) => error!("Couldn't reconnect to the host: {}", err),
+                }
+            });
+        }
+    }
+
+    /// Replaces the board with the host's board, after reconnecting or getting out of sync
+    fn load_resynced_board(&mut self, pieces: &[PieceData]) {
+        match self.get_board_mut().set_logical_pieces(pieces) {
+            Ok(()) => {
+                info!("Loaded the board of the host");
+                self.share_board_state();
+            }
+            Err(err) => error!("Couldn't load the board of the host: {:#}", err),
+        }
     }
+
+    /// Starts a new game over the same connection, where the players have swapped colors
+    fn start_rematch(&mut self) {
+        self.cancel_rematch();
+        self.cancel_takeback();
+        interface::clear_draw_offer();
+        interface::clear_sync_checks();
+        interface::set_game_result(None);
+        self.window.set_draw_offered(false);
+        self.window.set_game_over_text("".into());
+
+        self.get_board_mut().start_rematch();
+        self.share_board_state();
+        let color = self.board.player_color();
+        info!("Started a rematch as {:?}", color);
+        self.is_player_turn = self.board.side_to_move() == color;
+    }
+
+    /// Forgets the rematch requests of both players
+    fn cancel_rematch(&mut self) {
+        self.rematch_requested = false;
+        self.window.set_rematch_requested(false);
+        self.window.set_rematch_offered(false);
+    }
+
+    /// Ends the game with `result`, and shows it to the player
+    pub fn end_game(&mut self, result: GameResult) {
+        info!("Game over: {:?}", result);
+        self.get_board_mut().end_game(result);
+        // Stops both players from sending any more game actions, except for a rematch
+        interface::set_game_result(Some(result));
+        self.clock_timer.stop();
+        self.is_player_turn = false;
+
+        let text = match result.loser() {
+            None => "Draw",
+            Some(loser) if loser == self.board.player_color() => "You lost",
+            Some(_) => "You won",
+        };
+        self.window.set_draw_offered(false);
+        self.cancel_takeback();
+        self.window.set_game_over_text(text.into());
+    }
+
+    /// Forgets the takeback requests of both players
+    fn cancel_takeback(&mut self) {
+        self.takeback_requested = None;
+        self.takeback_offered = None;
+        self.window.set_takeback_requested(false);
+        self.window.set_takeback_offered(false);
+    }
+}
+
+/// Formats the time left on a clock as minutes and seconds
+fn format_clock(time: Duration) -> String {
+    let seconds = time.as_secs();
+    format!("{}:{:02}", seconds / 60, seconds % 60)
 }
 
 pub struct GameData {
@@ -249,6 +774,22 @@ pub struct GameData {
     board: Board,
     is_host: Option<bool>,
     is_player_turn: bool,
+    /// Updates the clock of the game, if it has one
+    clock_timer: Timer,
+    /// Handles the opponent's actions, see `Context::start_action_timer`
+    action_timer: Timer,
+    /// Set when the player has asked for a rematch, which the opponent hasn't accepted yet
+    rematch_requested: bool,
+    /// The number of moves made when the player asked for a takeback, while the opponent hasn't
+    /// answered
+    takeback_requested: Option<u32>,
+    /// The number of moves made when the opponent asked for a takeback, while the player hasn't
+    /// answered
+    takeback_offered: Option<u32>,
+    /// Set while the connection to the opponent is shown as lost
+    connection_lost: bool,
+    /// Set while the client reconnects. Stays set if reconnecting failed
+    reconnecting: Arc<AtomicBool>,
 }
 
 impl GameData {
@@ -261,6 +802,13 @@ impl GameData {
             board,
             is_host: None,
             is_player_turn: false,
+            clock_timer: Timer::default(),
+            action_timer: Timer::default(),
+            rematch_requested: false,
+            takeback_requested: None,
+            takeback_offered: None,
+            connection_lost: false,
+            reconnecting: Arc::new(AtomicBool::new(false)),
         })
     }
 
@@ -273,8 +821,67 @@ impl GameData {
         &mut self.board
     }
 
+    /// Starts a new game as `your_color`, with the random opening set by the host, if any
     pub fn start_new_game(&mut self, your_color: PieceColor) {
-        self.get_board_mut().start_new_game(your_color);
+        interface::reset_net_stats();
+        match interface::get_opening_seed() {
+            Some(seed) => self
+                .get_board_mut()
+                .start_new_game_random(your_color, seed),
+            None => self.get_board_mut().start_new_game(your_color),
+        }
+    }
+
+    /// Shares the board with the network, so it can be sent to the client if it reconnects
+    fn share_board_state(&self) {
+        interface::set_board_state(self.board.logical_pieces());
+        interface::set_my_color(self.board.player_color());
+        interface::set_whose_turn(self.board.side_to_move());
+        interface::set_turn_timer(self.board.clock().cloned());
+    }
+
+    /// Shows the time left on the clock of the game, if it has one
+    fn show_clock(&self) {
+        let Some(clock) = self.board.clock() else {
+            return;
+        };
+
+        let player_color = self.board.player_color();
+        self.window
+            .set_my_time(format_clock(clock.remaining(player_color)).into());
+        self.window
+            .set_other_time(format_clock(clock.remaining(player_color.get_opposite())).into());
+    }
+
+    /// Returns the username typed in the start window, or `DEFAULT_USERNAME` if it was left empty
+    fn username(&self) -> String {
+        let username = self.window.get_username();
+        match username.trim() {
+            "" => DEFAULT_USERNAME.to_owned(),
+            username => username.to_owned(),
+        }
+    }
+
+    /// Fills in the join code and username of the session saved before the game was closed, eg.
+    /// by a crash, so the player can join the host again, see `interface::restore_session`.
+    /// Only a client's session is offered, since a restarted host listens on another port.
+    pub fn offer_saved_session(&self) {
+        let Some(session) = interface::restore_session() else {
+            return;
+        };
+        // The host saves its own join code, which doesn't point at the other peer
+        let join_code = session.join_code.filter(|join_code| {
+            interface::validate_join_code(join_code).ok() == Some(session.other_addr)
+        });
+        let Some(join_code) = join_code else {
+            return;
+        };
+
+        info!("Offering to join the host at {:?} again", session.other_addr);
+        self.window.set_lan_code(join_code.into());
+        if let Some(username) = session.my_username {
+            self.window.set_username(username.into());
+        }
     }
 
     pub fn load_start_window(&self) {
@@ -1,11 +1,20 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
 slint::include_modules!();
 
-mod board;
+pub mod ai;
+pub mod bitboard;
+pub mod board;
+pub mod clock;
 pub mod data;
+pub mod zobrist;
 
 impl PieceColor {
     /// Get the opposite color
-    const fn get_opposite(&self) -> Self {
+    pub const fn get_opposite(&self) -> Self {
         match self {
             Self::White => Self::Black,
             Self::Black => Self::White,
@@ -13,6 +22,28 @@ impl PieceColor {
     }
 }
 
+impl fmt::Display for PieceColor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::White => write!(f, "White"),
+            Self::Black => write!(f, "Black"),
+        }
+    }
+}
+
+impl FromStr for PieceColor {
+    type Err = anyhow::Error;
+
+    /// Parses the name of a color, as written by `Display`, ignoring case
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "white" => Ok(Self::White),
+            "black" => Ok(Self::Black),
+            _ => Err(anyhow!("{:?} isn't a piece color", s)),
+        }
+    }
+}
+
 impl PieceData {
     const fn const_default() -> Self {
         PieceData {
@@ -23,7 +54,25 @@ impl PieceData {
     }
 }
 
-#[derive(Clone, Debug)]
+/// Serde definition of the Slint generated `PieceColor`, for use with `#[serde(with)]`
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "PieceColor")]
+enum PieceColorDef {
+    White,
+    Black,
+}
+
+/// Serde definition of the Slint generated `PieceData`, for use with `#[serde(with)]`
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "PieceData")]
+struct PieceDataDef {
+    is_active: bool,
+    #[serde(with = "PieceColorDef")]
+    color: PieceColor,
+    is_king: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Move {
     pub index: usize,
     pub end: usize,
@@ -47,6 +96,114 @@ impl Move {
             captured,
         }
     }
+
+    /// Returns the tiles the piece stands on during the move, from `index` to `end`.
+    /// The landing tiles between jumps aren't stored, so they are found from the captured
+    /// pieces: each one is the first tile behind a captured piece, which lines up diagonally with
+    /// the next captured piece.
+    pub fn path(&self) -> Vec<usize> {
+        let mut path = vec![self.index];
+        let Some(captured) = &self.captured else {
+            path.push(self.end);
+            return path;
+        };
+
+        for pair in captured.windows(2) {
+            let from = tile_position(*path.last().unwrap_or(&self.index));
+            let (over, next) = (tile_position(pair[0]), tile_position(pair[1]));
+            let step = ((over.0 - from.0).signum(), (over.1 - from.1).signum());
+
+            let landing = (1..8)
+                .map(|distance| (over.0 + step.0 * distance, over.1 + step.1 * distance))
+                .take_while(|(row, column)| (0..8).contains(row) && (0..8).contains(column))
+                .find(|(row, column)| (row - next.0).abs() == (column - next.1).abs());
+            match landing {
+                Some(landing) => path.push(tile_index(landing)),
+                // Not a valid capture sequence, so there is no path to show
+                None => break,
+            }
+        }
+        path.push(self.end);
+        path
+    }
+}
+
+/// Returns the row and column on the 8x8 board of the tile with `index`
+fn tile_position(index: usize) -> (i32, i32) {
+    let row = (index / 4) as i32;
+    (row, (index % 4) as i32 * 2 + row % 2)
+}
+
+/// Returns the index of the tile at `position`, see `tile_position`
+fn tile_index((row, column): (i32, i32)) -> usize {
+    (row * 4 + column / 2) as usize
+}
+
+/// Writes the move in draughts notation, with the tiles numbered 1 to 32: `9-13` for a move
+/// without captures, and `9x18x27` for a capture sequence. A `K` is added if the piece is crowned.
+impl fmt::Display for Move {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let separator = if self.captured.is_some() { "x" } else { "-" };
+        let path = self
+            .path()
+            .iter()
+            .map(|tile| (tile + 1).to_string())
+            .collect::<Vec<_>>()
+            .join(separator);
+        write!(f, "{}", path)?;
+
+        if self.promoted {
+            write!(f, "K")?;
+        }
+        Ok(())
+    }
+}
+
+/// How a game has ended
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GameResult {
+    /// `loser` has no legal moves left on their turn, either because every piece is blocked or
+    /// because none are left
+    NoMoves { loser: PieceColor },
+    /// `loser` ran out of time on their clock
+    Timeout { loser: PieceColor },
+    /// `loser` surrendered the game
+    Resigned { loser: PieceColor },
+    /// The players agreed to a draw, or neither side has the pieces left to win, see
+    /// `Board::is_insufficient_material`
+    Draw,
+}
+
+impl GameResult {
+    /// Returns the side that lost the game, or `None` if nobody did
+    pub fn loser(&self) -> Option<PieceColor> {
+        match self {
+            Self::NoMoves { loser } | Self::Timeout { loser } | Self::Resigned { loser } => {
+                Some(*loser)
+            }
+            Self::Draw => None,
+        }
+    }
+}
+
+/// The rules which differ between variants of checkers
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Rules {
+    /// Wether men may capture backwards. Men can never move backwards without capturing
+    pub men_capture_backwards: bool,
+}
+
+impl Rules {
+    /// Men may capture backwards, like in international and russian draughts
+    pub const DEFAULT: Self = Self {
+        men_capture_backwards: true,
+    };
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
 }
 
 /// An enum which holds the possible actions a user can make in the game.
@@ -55,10 +212,25 @@ pub enum GameAction {
     /// Move a piece, by its current position, and its target position.
     /// It is not guarenteed that this move is valid yet, so it should be validated before use.
     MovePiece(Move),
-    /// Indicated that the player want's to suggest a stalemate
-    Stalemate,
+    /// Indicates that the player want's to end the game in a draw.
+    /// Only one offer can be pending at a time, and it is answered with `RespondDraw`.
+    OfferDraw,
     /// Indicates that the player want's to end the game by surrender
     Surrender,
+    /// The answer to an `OfferDraw`. The game ends in a draw if the offer is accepted.
+    RespondDraw(bool),
+    /// Asks to play another game when the current one is over, answered with `RematchAccept`
+    Rematch,
+    /// Accepts a `Rematch`. The new game is played over the same session, with swapped colors.
+    RematchAccept,
+    /// Indicates that the player's own clock has run out, so they lost the game
+    TimeExpired,
+    /// Asks to take back the sender's last move, and the answer to it if the sender is to move.
+    /// Holds the number of moves made when it was sent, so it can be declined if the board has
+    /// moved on since. Answered with `RespondTakeback`.
+    RequestTakeback(u32),
+    /// The answer to a `RequestTakeback`. If it is accepted, both players take back the moves.
+    RespondTakeback(bool),
 }
 
 impl GameAction {
@@ -79,14 +251,21 @@ impl GameAction {
             promoted,
         })
     }
+
+    /// Returns true for `Rematch` and `RematchAccept`, which are the only actions that can be
+    /// sent after the game has ended.
+    pub fn is_rematch(&self) -> bool {
+        matches!(self, Self::Rematch | Self::RematchAccept)
+    }
 }
 
+/// A diagonal direction on the board, as it is shown to the local player
 #[derive(Clone, Copy, Debug)]
-enum Direction {
-    UpLeft = -5,
-    UpRight = -4,
-    DownLeft = 3,
-    DownRight = 4,
+pub enum Direction {
+    UpLeft,
+    UpRight,
+    DownLeft,
+    DownRight,
 }
 
 impl Direction {
@@ -96,23 +275,15 @@ impl Direction {
         &[UpRight, UpLeft, DownLeft, DownRight]
     }
 
-    /// Get's the value used to traverse the array of tiles in
-    /// the Board struct, based off the index of the piece
-    const fn get_value(&self, index: usize) -> i32 {
-        let row_type: i32 = ((index % 8) / 4) as i32;
-        *self as i32 + row_type
-    }
-
-    /// Returns wether the direction is left
-    const fn is_left(&self) -> bool {
-        use Direction::*;
-        matches!(self, UpLeft | DownLeft)
-    }
-
-    /// Returns wether the direction is right
-    const fn is_right(&self) -> bool {
+    /// Returns how many rows and columns a step in this direction moves, see `tile_position`
+    const fn offset(&self) -> (i32, i32) {
         use Direction::*;
-        matches!(self, UpRight | DownRight)
+        match self {
+            UpLeft => (-1, -1),
+            UpRight => (-1, 1),
+            DownLeft => (1, -1),
+            DownRight => (1, 1),
+        }
     }
 
     /// Returns wether the direction is down
@@ -127,3 +298,53 @@ impl Direction {
         matches!(self, UpRight | UpLeft)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn piece_color_parses_its_display() {
+        for color in [PieceColor::White, PieceColor::Black] {
+            assert_eq!(color.to_string().parse::<PieceColor>().unwrap(), color);
+        }
+        assert_eq!("BLACK".parse::<PieceColor>().unwrap(), PieceColor::Black);
+        assert!("red".parse::<PieceColor>().is_err());
+        assert!("".parse::<PieceColor>().is_err());
+    }
+
+    #[test]
+    fn moves_are_written_in_draughts_notation() {
+        let quiet = Move {
+            index: 8,
+            end: 12,
+            promoted: false,
+            captured: None,
+        };
+        assert_eq!(quiet.to_string(), "9-13");
+
+        let jump = Move {
+            index: 8,
+            end: 17,
+            promoted: false,
+            captured: Some(vec![12]),
+        };
+        assert_eq!(jump.to_string(), "9x18");
+
+        let double_jump = Move {
+            index: 8,
+            end: 26,
+            promoted: false,
+            captured: Some(vec![12, 21]),
+        };
+        assert_eq!(double_jump.to_string(), "9x18x27");
+
+        let crowning = Move {
+            index: 24,
+            end: 28,
+            promoted: true,
+            captured: None,
+        };
+        assert_eq!(crowning.to_string(), "25-29K");
+    }
+}
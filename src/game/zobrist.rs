@@ -0,0 +1,166 @@
+//! Zobrist hashing of board positions.
+//!
+//! Every combination of logical tile, piece color and king-ness has a fixed random key, and the
+//! hash of a position is the XOR of the keys of all its pieces. Moving a piece then only has to
+//! XOR out the keys of the tiles it changed and XOR in the new ones.
+
+use super::{PieceColor, PieceData};
+
+/// The seed used to generate `KEYS`. Changing it changes every position hash.
+const SEED: u64 = 0x636b_6d61_7465_7221;
+
+/// Random keys, indexed by logical tile, piece color and wether the piece is a king.
+const KEYS: [[[u64; 2]; 2]; 32] = generate_keys().0;
+
+/// Random key XOR'ed into the hash when Black is the side to move.
+pub const SIDE_KEY: u64 = generate_keys().1;
+
+/// One step of the SplitMix64 generator. Returns the next state and the generated number.
+const fn splitmix64(state: u64) -> (u64, u64) {
+    let state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    (state, z ^ (z >> 31))
+}
+
+/// Generates the keys from `SEED` at compile time, so hashes are the same across runs and on
+/// both players' machines.
+const fn generate_keys() -> ([[[u64; 2]; 2]; 32], u64) {
+    let mut keys = [[[0; 2]; 2]; 32];
+    let mut state = SEED;
+
+    let mut tile = 0;
+    while tile < 32 {
+        let mut color = 0;
+        while color < 2 {
+            let mut king = 0;
+            while king < 2 {
+                let (next_state, key) = splitmix64(state);
+                state = next_state;
+                keys[tile][color][king] = key;
+                king += 1;
+            }
+            color += 1;
+        }
+        tile += 1;
+    }
+
+    let (_, side_key) = splitmix64(state);
+    (keys, side_key)
+}
+
+/// Returns the key of `piece` standing on the tile with the logical index `logical`.
+/// Empty tiles have the key 0, so they don't change the hash.
+pub fn piece_key(logical: usize, piece: &PieceData) -> u64 {
+    if !piece.is_active {
+        return 0;
+    }
+
+    let color = match piece.color {
+        PieceColor::White => 0,
+        PieceColor::Black => 1,
+    };
+    KEYS[logical][color][piece.is_king as usize]
+}
+
+/// Returns the key to XOR into a hash when `side` is the side to move.
+pub const fn side_key(side: PieceColor) -> u64 {
+    match side {
+        PieceColor::White => 0,
+        PieceColor::Black => SIDE_KEY,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::game::{
+        bitboard::BitBoard,
+        board::{
+            tests::{lock_board_move, play, START},
+            Board,
+        },
+        Move, PieceColor,
+    };
+
+    /// Returns a move without captures from `index` to `end`
+    fn step(index: usize, end: usize) -> Move {
+        Move {
+            index,
+            end,
+            promoted: false,
+            captured: None,
+        }
+    }
+
+    #[test]
+    fn move_order_doesnt_change_hash() {
+        let _lock = lock_board_move();
+        let mut first = Board::from_diagram(START, PieceColor::White).unwrap();
+        for mov in [step(20, 16), step(8, 12), step(23, 19), step(11, 15)] {
+            play(&mut first, &mov);
+        }
+        let mut second = Board::from_diagram(START, PieceColor::White).unwrap();
+        for mov in [step(23, 19), step(11, 15), step(20, 16), step(8, 12)] {
+            play(&mut second, &mov);
+        }
+
+        assert_eq!(first.zobrist_hash(), second.zobrist_hash());
+    }
+
+    #[test]
+    fn promotion_changes_hash() {
+        let _lock = lock_board_move();
+        let diagram =
+            "........\n.w......\n........\n.b......\n........\n........\n........\n........";
+        let mut board = Board::from_diagram(diagram, PieceColor::White).unwrap();
+        play(
+            &mut board,
+            &Move {
+                promoted: true,
+                ..step(4, 0)
+            },
+        );
+
+        let crowned = BitBoard {
+            men: 1 << 12,
+            kings: 1 << 0,
+            player: 1 << 0,
+            rules: board.rules(),
+        };
+        // The same position, but with the man left uncrowned
+        let uncrowned = BitBoard {
+            men: 1 << 0 | 1 << 12,
+            kings: 0,
+            ..crowned
+        };
+        assert_eq!(board.board_hash(), crowned.board_hash(PieceColor::White));
+        assert_ne!(board.board_hash(), uncrowned.board_hash(PieceColor::White));
+    }
+
+    #[test]
+    fn undoing_a_move_restores_hash() {
+        let _lock = lock_board_move();
+        let mut board = Board::from_diagram(START, PieceColor::White).unwrap();
+        let before = board.zobrist_hash();
+
+        play(&mut board, &step(21, 17));
+        assert_ne!(board.zobrist_hash(), before);
+        board.undo_move().unwrap();
+        assert_eq!(board.zobrist_hash(), before);
+    }
+
+    #[test]
+    fn identical_positions_hash_alike() {
+        let _lock = lock_board_move();
+        let mut played = Board::from_diagram(START, PieceColor::White).unwrap();
+        for mov in [step(20, 16), step(8, 12), step(23, 19), step(11, 15)] {
+            play(&mut played, &mov);
+        }
+        let diagram =
+            "b.b.b.b.\n.b.b.b.b\n..b.b...\n.b.....b\nw.....w.\n...w.w..\nw.w.w.w.\n.w.w.w.w";
+        let built = Board::from_diagram(diagram, PieceColor::White).unwrap();
+
+        assert_eq!(played.zobrist_hash(), built.zobrist_hash());
+    }
+}
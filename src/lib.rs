@@ -1,2 +1,3 @@
 pub mod game;
+pub mod logging;
 pub mod net;
@@ -0,0 +1,72 @@
+use std::fmt::{self, Write};
+
+use tracing::{
+    field::{Field, Visit},
+    level_filters::LevelFilter,
+    span, Event, Level, Metadata, Subscriber,
+};
+
+/// A minimal `tracing` subscriber, which prints every event up to `max_level` to stdout.
+/// Spans are ignored.
+struct StdoutSubscriber {
+    max_level: Level,
+}
+
+/// Collects the fields of an event into a single line of text.
+struct LineVisitor {
+    line: String,
+}
+
+impl Visit for LineVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.line, "{:?}", value);
+        } else {
+            let _ = write!(self.line, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+impl Subscriber for StdoutSubscriber {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= &self.max_level
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(LevelFilter::from_level(self.max_level))
+    }
+
+    fn new_span(&self, _span: &span::Attributes<'_>) -> span::Id {
+        span::Id::from_u64(1)
+    }
+
+    fn record(&self, _span: &span::Id, _values: &span::Record<'_>) {}
+
+    fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut visitor = LineVisitor {
+            line: String::new(),
+        };
+        event.record(&mut visitor);
+
+        let metadata = event.metadata();
+        println!(
+            "{:>5} {}: {}",
+            metadata.level(),
+            metadata.target(),
+            visitor.line
+        );
+    }
+
+    fn enter(&self, _span: &span::Id) {}
+
+    fn exit(&self, _span: &span::Id) {}
+}
+
+/// Prints all log events up to `max_level` to stdout.
+/// Nothing is logged until this is called, so library users and tests can choose to opt in, or
+/// install their own `tracing` subscriber instead. Does nothing if a subscriber is already set.
+pub fn init_logging(max_level: Level) {
+    let _ = tracing::subscriber::set_global_default(StdoutSubscriber { max_level });
+}
@@ -1,5 +1,6 @@
 use std::{
     net::{IpAddr, SocketAddr},
+    path::PathBuf,
     sync::Arc,
     time::Duration,
 };
@@ -8,54 +9,216 @@ use anyhow::anyhow;
 use chrono::Utc;
 use futures::executor;
 use tokio::sync::Mutex;
+use tracing::{info, trace, warn};
 
 use crate::{
-    game::{GameAction, PieceColor},
+    game::{
+        clock::{MoveClock, TimeControl},
+        GameAction, GameResult, PieceColor, PieceData,
+    },
     net::{
-        net_utils::{get_available_port, get_local_ip, hex_decode_ip, hex_encode_ip},
+        net_utils::{self, get_available_port, get_local_ip, hex_encode_ip, unspecified_addr},
         p2p::{
             net_loop::{client_network_loop, host_network_loop},
             queue::{
-                check_for_response, get_outgoing_queue_len, new_transaction_id,
-                pop_incoming_gameaction, push_outgoing_queue,
+                self, check_for_response, get_outgoing_queue_len, new_transaction_id,
+                pop_incoming_gameaction, push_outgoing_queue, DEFAULT_MAX_OUTGOING_LEN,
             },
             P2pPacket, P2pRequest, P2pRequestPacket, P2pResponse, P2pResponsePacket,
         },
-        status,
+        relay, status,
+    },
+};
+
+pub use crate::net::{
+    net_utils::{validate_join_code, validate_username, NetworkError},
+    p2p::{
+        queue::{NetStats, DEFAULT_MAX_INCOMING_LEN},
+        P2pError, MAX_CHAT_LEN, MAX_USERNAME_LEN, PROTOCOL_VERSION,
+    },
+    status::{
+        subscribe, subscribe_status, ClientConfig, ColorAssignment, ConnectionStatus, SessionInfo,
     },
 };
 
-/// Start the host network peer on a LAN connection.
-/// Returns the join code for the client
-pub fn start_lan_host() -> String {
-    let port = executor::block_on(get_available_port()).unwrap();
-    let socket = executor::block_on(tokio::net::UdpSocket::bind(("0.0.0.0", port))).unwrap();
+/// The number of join requests `connect_to_host` should send before giving up, when nothing
+/// else is needed.
+pub const DEFAULT_JOIN_ATTEMPTS: u32 = 8;
+/// The number of times `reconnect` should try to reconnect before giving up, when nothing
+/// else is needed.
+pub const DEFAULT_RECONNECT_ATTEMPTS: u32 = 5;
+/// The longest time `connect_to_host` should wait for the host, when nothing else is needed.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// The time waited for a response to the first join request, before sending another one
+const JOIN_RETRY_START_MS: u64 = 500;
+/// The longest time waited for a response to a join request, before sending another one
+const JOIN_RETRY_MAX_MS: u64 = 8_000;
+/// How often to check for a response to the join requests
+const JOIN_POLL_MS: u64 = 50;
+/// The longest time `disconnect()` waits for the disconnect to be acknowledged
+const DISCONNECT_WAIT_MS: u64 = 300;
+/// How often `attempt_reconnect()` and `start_spectating()` check for the hosts response
+const HOST_POLL_MS: u64 = 100;
+/// How many times `attempt_reconnect()` and `start_spectating()` check for the hosts response,
+/// before giving up
+const HOST_POLLS: u32 = 50;
+
+/// Start the host network peer on a LAN connection, where the players get their colors as set in
+/// `colors`. The host's color can be read with `get_my_color()` afterwards.
+/// Returns the join code for the client, or an error if no socket could be opened or the local
+/// IP address couldn't be found.
+pub fn start_lan_host(colors: ColorAssignment) -> Result<String, NetworkError> {
+    executor::block_on(start_lan_host_async(colors))
+}
+
+/// The async version of `start_lan_host()`.
+pub async fn start_lan_host_async(colors: ColorAssignment) -> Result<String, NetworkError> {
+    let local_ip = get_local_ip()?;
+    let (socket, port) = bind_socket(local_ip).await?;
+    assign_host_color(colors).await;
+
+    let encoded_ip = hex_encode_ip(SocketAddr::new(local_ip, port));
+    status::set_join_code(&encoded_ip).await;
+    status::set_relay(None).await;
+
+    status::set_connection_status(status::ConnectionStatus::PendingConnection).await;
+
+    host_network_loop(socket, DEFAULT_MAX_OUTGOING_LEN).await;
+
+    Ok(encoded_ip)
+}
+
+/// Start the client network peer on a LAN connection, which pings the host as set in `config`.
+/// The socket uses the same address family as the local IP, like the host does.
+/// Returns an error if no socket could be opened.
+pub fn start_lan_client(config: ClientConfig) -> Result<(), NetworkError> {
+    executor::block_on(start_lan_client_async(config))
+}
+
+/// The async version of `start_lan_client()`.
+pub async fn start_lan_client_async(config: ClientConfig) -> Result<(), NetworkError> {
+    status::set_client_config(config).await;
 
-    let local_ip = get_local_ip().unwrap();
+    let local_ip = get_local_ip()?;
+    status::set_relay(None).await;
+    start_client_loop(local_ip).await
+}
+
+/// Looks for LAN hosts waiting for a client for `timeout`, see `net_utils::discover_hosts`.
+/// Returns the username and the join code of every host found, so the user can pick a game
+/// instead of typing its join code.
+pub fn discover_lan_hosts(timeout: Duration) -> Vec<(String, String)> {
+    executor::block_on(discover_lan_hosts_async(timeout))
+}
+
+/// The async version of `discover_lan_hosts()`.
+pub async fn discover_lan_hosts_async(timeout: Duration) -> Vec<(String, String)> {
+    net_utils::discover_hosts(timeout)
+        .await
+        .into_iter()
+        .map(|(username, addr)| (username, hex_encode_ip(addr)))
+        .collect()
+}
+
+/// Start the host network peer, sending all packets through the relay server at `relay_addr`.
+/// Works when the peers can't reach each other directly, eg. over the internet behind NATs, as
+/// long as both can reach the relay, see `relay::run_relay_server`.
+/// The players get their colors as set in `colors`, like with `start_lan_host()`.
+/// Returns the join code for the client, which is the code the host is registered under on the
+/// relay, or an error if no socket could be opened.
+pub fn start_relay_host(
+    relay_addr: SocketAddr,
+    colors: ColorAssignment,
+) -> Result<String, NetworkError> {
+    executor::block_on(start_relay_host_async(relay_addr, colors))
+}
+
+/// The async version of `start_relay_host()`.
+pub async fn start_relay_host_async(
+    relay_addr: SocketAddr,
+    colors: ColorAssignment,
+) -> Result<String, NetworkError> {
+    let (socket, _) = bind_socket(relay_addr.ip()).await?;
+    assign_host_color(colors).await;
 
-    let encoded_ip = hex_encode_ip(SocketAddr::new(IpAddr::V4(local_ip), port)).unwrap();
-    executor::block_on(status::set_join_code(&encoded_ip));
+    let code = relay::new_relay_code();
+    socket
+        .send_to(&relay::wrap_frame(&code, &[]), relay_addr)
+        .await
+        .map_err(|err| {
+            NetworkError::send_error(&format!("Failed to register with the relay: {}", err))
+        })?;
+    info!(
+        "Registered with relay at {} under code {}",
+        relay_addr, code
+    );
+
+    status::set_join_code(&code).await;
+    status::set_relay(Some((relay_addr, code.clone()))).await;
+    status::set_connection_status(status::ConnectionStatus::PendingConnection).await;
+
+    host_network_loop(socket, DEFAULT_MAX_OUTGOING_LEN).await;
+
+    Ok(code)
+}
+
+/// Picks the host's color with `colors`, and sets it as the color of the local player.
+async fn assign_host_color(colors: ColorAssignment) {
+    let color = colors.host_color(&mut rand::thread_rng());
+    info!("Hosting the game as {:?}", color);
+    status::set_my_color(color).await;
+}
 
-    executor::block_on(status::set_connection_status(
-        status::ConnectionStatus::PendingConnection,
-    ));
+/// Start the client network peer, sending all packets through the relay server at `relay_addr`
+/// to the host registered under `join_code`, see `start_relay_host()`.
+/// The game is then joined with `connect_to_host()`, like on LAN.
+/// Returns an error if no socket could be opened.
+pub fn start_relay_client(relay_addr: SocketAddr, join_code: &str) -> Result<(), NetworkError> {
+    executor::block_on(start_relay_client_async(relay_addr, join_code))
+}
 
-    host_network_loop(socket);
+/// The async version of `start_relay_client()`.
+pub async fn start_relay_client_async(
+    relay_addr: SocketAddr,
+    join_code: &str,
+) -> Result<(), NetworkError> {
+    status::set_relay(Some((relay_addr, join_code.to_owned()))).await;
+    status::set_other_addr(relay_addr).await;
+    start_client_loop(relay_addr.ip()).await
+}
 
-    encoded_ip
+/// Binds a UDP socket to a free port, on the unspecified address of the same family as `ip`.
+/// Returns the socket and its port.
+async fn bind_socket(ip: IpAddr) -> Result<(tokio::net::UdpSocket, u16), NetworkError> {
+    let port = get_available_port().await?;
+    let bind_addr = SocketAddr::new(unspecified_addr(ip), port);
+    let socket = tokio::net::UdpSocket::bind(bind_addr)
+        .await
+        .map_err(|source| NetworkError::BindFailed { port, source })?;
+    Ok((socket, port))
 }
 
-/// Start the client network peer on a LAN connection.
-pub fn start_lan_client() {
-    let port = executor::block_on(get_available_port()).unwrap();
-    let socket = executor::block_on(tokio::net::UdpSocket::bind(("0.0.0.0", port))).unwrap();
+/// Opens a client socket, with the same address family as `ip`, and starts the client network
+/// loop with it, using the config the client was last started with.
+async fn start_client_loop(ip: IpAddr) -> Result<(), NetworkError> {
+    let (socket, _) = bind_socket(ip).await?;
+
+    status::set_connection_status(status::ConnectionStatus::PendingConnection).await;
+
+    let config = status::get_client_config().await;
+    client_network_loop(socket, config, DEFAULT_MAX_OUTGOING_LEN).await;
 
-    executor::block_on(status::set_connection_status(
-        status::ConnectionStatus::PendingConnection,
-    ));
+    Ok(())
+}
 
-    // Start client network loop, with 10 pings pr. second
-    client_network_loop(socket, 1);
+/// Finds the address to send packets for the host with `join_code` to. This is the relay in
+/// relay mode, and otherwise the address encoded in the join code.
+/// Returns an error if the join code isn't valid, see `validate_join_code`.
+async fn host_addr(join_code: &str) -> Result<SocketAddr, NetworkError> {
+    match status::get_relay().await {
+        Some((relay_addr, _)) => Ok(relay_addr),
+        None => validate_join_code(join_code),
+    }
 }
 
 /// Sends a join request to the host.
@@ -64,108 +227,558 @@ pub fn start_lan_client() {
 ///
 /// ## Params
 /// * `join_code` - The join code sent by the host.
-/// * `username` - The clients username.
-pub fn send_join_request(join_code: &str, username: &str) -> u16 {
+/// * `username` - The clients username, see `validate_username`.
+///
+/// Returns the transaction id of the request, or an error if the outgoing queue is full, or the
+/// join code or the username is invalid, see `validate_join_code` and `validate_username`.
+pub fn send_join_request(join_code: &str, username: &str) -> Result<u16, NetworkError> {
+    executor::block_on(send_join_request_async(join_code, username))
+}
+
+/// The async version of `send_join_request()`.
+pub async fn send_join_request_async(join_code: &str, username: &str) -> Result<u16, NetworkError> {
+    validate_username(username)?;
+    host_addr(join_code).await?;
     let join_request = P2pRequest::new(
         status::CONNECT_SESSION_ID,
-        executor::block_on(new_transaction_id()),
-        P2pRequestPacket::Connect {
-            join_code: join_code.to_owned(),
-            username: username.to_owned(),
-        },
+        new_transaction_id().await,
+        P2pRequestPacket::connect(join_code, username),
     );
-    let host_addr = hex_decode_ip(join_code).unwrap();
-    println!("Asking to join Host at {:?}", host_addr);
-
-    println!("Pushing to queue");
+    trace!("Asking to join host with join code {}", join_code);
 
-    executor::block_on(push_outgoing_queue(
-        P2pPacket::Request(join_request.clone()),
-        None,
-    ))
+    push_outgoing_queue(P2pPacket::Request(join_request.clone()), None).await
 }
 
 /// Check if the connection request sent with `send_join_request()` has gotten an response.
 /// If a packet has been recieved, and if that packet is a correct response, the function will
 /// return the clients assigned piece color, as well as the hosts username.
 ///
+/// If the host uses another `PROTOCOL_VERSION`, the error is a `NetworkError::IncompatibleVersion`,
+/// so the UI can tell the user to update.
+///
 /// ## Params
 /// * `transaction_id` - The id of the join request
 pub fn check_for_connection_resp(
     transaction_id: u16,
-) -> Option<anyhow::Result<(PieceColor, String)>> {
-    println!("Checking for resp");
-    match executor::block_on(check_for_response(transaction_id)) {
+) -> Option<Result<(PieceColor, String), NetworkError>> {
+    executor::block_on(check_for_connection_resp_async(transaction_id))
+}
+
+/// The async version of `check_for_connection_resp()`.
+pub async fn check_for_connection_resp_async(
+    transaction_id: u16,
+) -> Option<Result<(PieceColor, String), NetworkError>> {
+    trace!("Checking for connection response");
+    match check_for_response(transaction_id).await {
         Some(resp) => match resp {
             P2pPacket::Response(resp) => match resp.packet {
+                P2pResponsePacket::Connect {
+                    protocol_version, ..
+                } if protocol_version != PROTOCOL_VERSION => {
+                    warn!(
+                        "Host uses protocol version {}, but this game uses {}",
+                        protocol_version, PROTOCOL_VERSION
+                    );
+                    Some(Err(NetworkError::IncompatibleVersion {
+                        local: PROTOCOL_VERSION,
+                        host: Some(protocol_version),
+                    }))
+                }
                 P2pResponsePacket::Connect {
                     client_color,
                     host_username,
+                    time_control,
+                    opening_seed,
+                    ..
                 } => {
-                    println!("Got resp");
-                    executor::block_on(status::set_connection_status(
-                        status::ConnectionStatus::connected(),
-                    ));
-                    println!("Set connection status");
-                    executor::block_on(status::set_session_id(resp.session_id));
-                    println!("Set session id");
-                    executor::block_on(status::set_other_username(&host_username));
-                    println!("Set username");
+                    status::set_connection_status(status::ConnectionStatus::connected()).await;
+                    status::set_disconnect_reason(None).await;
+                    status::set_game_result(None).await;
+                    queue::reset_sequences().await;
+                    queue::reset_net_stats();
+                    status::set_time_control(time_control).await;
+                    status::set_opening_seed(opening_seed).await;
+                    status::set_session_id(resp.session_id).await;
+                    status::set_other_username(&host_username).await;
+                    status::set_assigned_color(client_color).await;
+                    status::save_session().await;
+                    info!(
+                        "Connected to {}'s game with session id {}",
+                        host_username, resp.session_id
+                    );
                     Some(Ok((client_color, host_username)))
                 }
+                P2pResponsePacket::Error {
+                    kind: P2pError::IncompatibleVersion,
+                } => {
+                    warn!("Join request was refused, since the protocol versions don't match");
+                    Some(Err(NetworkError::IncompatibleVersion {
+                        local: PROTOCOL_VERSION,
+                        host: None,
+                    }))
+                }
                 P2pResponsePacket::Error { kind } => {
-                    Some(Err(anyhow!("Got Error response: {:?}", kind)))
+                    warn!("Join request was refused: {:?}", kind);
+                    Some(Err(NetworkError::Refused { kind }))
+                }
+                _ => {
+                    warn!("Got wrong response packet for join request");
+                    Some(Err(NetworkError::ResponseTypeError))
                 }
-                _ => Some(Err(anyhow!("Got wrong response Packet"))),
             },
-            _ => Some(Err(anyhow!("Got request packet instead of response"))),
+            _ => {
+                warn!("Got request packet instead of response for join request");
+                Some(Err(NetworkError::ResponseTypeError))
+            }
         },
         None => {
-            println!("Got no resp :(");
+            trace!("No connection response yet");
             None
         }
     }
 }
 
-/// A blocking function which sends a join request to the host, and waits for a response. The
-/// function is in a loop, so if a packet goes lost, it will send a new one after 5 seconds.
+/// A blocking function which sends a join request to the host, and waits for a response. If a
+/// packet goes lost, a new request is sent, waiting twice as long between each request, starting
+/// at `JOIN_RETRY_START_MS` and up to `JOIN_RETRY_MAX_MS`. A response to any of the sent requests
+/// is returned right away.
 ///
 /// ## Params
 /// * `join_code` - The join code sent by the host.
-/// * `username` - The clients username.
-pub fn connect_to_host_loop(
+/// * `username` - The clients username, see `validate_username`.
+/// * `max_attempts` - How many join requests to send before giving up and returning an error.
+/// * `timeout` - How long to wait for the host in total before giving up and returning an error,
+///   eg. because the join code is wrong.
+pub fn connect_to_host(
+    join_code: &str,
+    username: &str,
+    max_attempts: u32,
+    timeout: Duration,
+) -> Result<(PieceColor, String), NetworkError> {
+    executor::block_on(connect_to_host_async(
+        join_code,
+        username,
+        max_attempts,
+        timeout,
+    ))
+}
+
+/// The async version of `connect_to_host()`.
+pub async fn connect_to_host_async(
     join_code: &str,
     username: &str,
-) -> anyhow::Result<(PieceColor, String)> {
-    executor::block_on(status::set_join_code(join_code));
-    let host_addr = hex_decode_ip(join_code).unwrap();
-    executor::block_on(status::set_other_addr(host_addr));
-    set_my_username(username);
-    println!("Starting to connect...");
-    let mut connection_tick = tokio::time::interval(Duration::from_millis(500));
-    loop {
-        let join_id = send_join_request(join_code, username);
-
-        let time = Utc::now();
-        println!("Request sent at {:?}", time.to_string());
-        print!(
-            "Queue len: {}",
-            executor::block_on(get_outgoing_queue_len())
-        );
-        println!("!!!");
+    max_attempts: u32,
+    timeout: Duration,
+) -> Result<(PieceColor, String), NetworkError> {
+    validate_username(username)?;
+    let deadline = tokio::time::Instant::now() + timeout;
+    status::set_join_code(join_code).await;
+    let host_addr = host_addr(join_code).await?;
+    status::set_other_addr(host_addr).await;
+    status::set_my_username(username).await;
+    info!("Connecting to host at {:?}", host_addr);
 
-        for _ in 0..10 {
-            executor::block_on(connection_tick.tick());
-            if let Some(resp) = check_for_connection_resp(join_id) {
-                return resp;
+    let mut join_ids = vec![];
+    let mut delay = Duration::from_millis(JOIN_RETRY_START_MS);
+    let mut connection_tick = tokio::time::interval(Duration::from_millis(JOIN_POLL_MS));
+    for attempt in 1..=max_attempts {
+        join_ids.push(send_join_request_async(join_code, username).await?);
+
+        trace!(
+            "Join request {}/{} sent at {}, outgoing queue length: {}",
+            attempt,
+            max_attempts,
+            Utc::now(),
+            get_outgoing_queue_len().await
+        );
+
+        let retry_at = tokio::time::Instant::now() + delay;
+        while tokio::time::Instant::now() < retry_at {
+            if tokio::time::Instant::now() >= deadline {
+                return Err(NetworkError::Timeout { timeout });
+            }
+            connection_tick.tick().await;
+            for join_id in &join_ids {
+                if let Some(resp) = check_for_connection_resp_async(*join_id).await {
+                    return resp;
+                }
+            }
+        }
+
+        delay = (delay * 2).min(Duration::from_millis(JOIN_RETRY_MAX_MS));
+    }
+
+    Err(NetworkError::HostUnreachable {
+        attempts: max_attempts,
+    })
+}
+
+/// Tries to continue the lost session, eg. after the connection timed out, using the session id
+/// and host address of the old connection. Blocks until the host responds, or gives up.
+/// On success the hosts version of the board can be read with `get_board_state()`.
+///
+/// Returns an error if there is no session to continue, if the host doesn't know the session, or
+/// if the host doesn't respond. The UI should then fall back to joining a new game.
+pub fn attempt_reconnect() -> Result<(), NetworkError> {
+    executor::block_on(attempt_reconnect_async())
+}
+
+/// The async version of `attempt_reconnect()`.
+pub async fn attempt_reconnect_async() -> Result<(), NetworkError> {
+    let session_id = status::get_session_id().await;
+    let host_addr = status::get_other_addr().await;
+    if session_id == status::CONNECT_SESSION_ID || host_addr.is_none() {
+        return Err(NetworkError::NoSession);
+    }
+
+    info!(
+        "Reconnecting to host at {:?} with session id {}",
+        host_addr, session_id
+    );
+    status::set_connection_status(status::ConnectionStatus::PendingConnection).await;
+
+    let request = P2pRequest::new(
+        session_id,
+        new_transaction_id().await,
+        P2pRequestPacket::Reconnect,
+    );
+    let transaction_id = push_outgoing_queue(P2pPacket::Request(request), None).await?;
+
+    let mut reconnect_tick = tokio::time::interval(Duration::from_millis(HOST_POLL_MS));
+    for _ in 0..HOST_POLLS {
+        reconnect_tick.tick().await;
+        let Some(resp) = check_for_response(transaction_id).await else {
+            continue;
+        };
+
+        let error = match resp {
+            P2pPacket::Response(P2pResponse {
+                packet: P2pResponsePacket::Resync { board },
+                ..
+            }) => {
+                status::set_resynced_board(board).await;
+                status::set_connection_status(status::ConnectionStatus::connected()).await;
+                info!("Reconnected to host with session id {}", session_id);
+                return Ok(());
+            }
+            P2pPacket::Response(P2pResponse {
+                packet: P2pResponsePacket::Error { kind },
+                ..
+            }) => {
+                warn!("Reconnect request was refused: {:?}", kind);
+                NetworkError::Refused { kind }
+            }
+            _ => {
+                warn!("Got wrong response packet for reconnect request");
+                NetworkError::ResponseTypeError
+            }
+        };
+
+        status::set_connection_status(status::ConnectionStatus::Disconnected).await;
+        return Err(error);
+    }
+
+    status::set_connection_status(status::ConnectionStatus::Disconnected).await;
+    warn!("Host didn't respond to reconnect request");
+    Err(NetworkError::Timeout {
+        timeout: Duration::from_millis(HOST_POLL_MS) * HOST_POLLS,
+    })
+}
+
+/// A blocking function which keeps trying to continue the lost session, eg. after the connection
+/// timed out or was dropped. Every attempt opens a new socket, like `start_lan_client()`, and
+/// sends a reconnect request with the session id of the old connection to the host, found with
+/// the stored join code or through the relay. The host keeps its game, so nothing has to be reset
+/// on this side either.
+///
+/// Waits twice as long after every failed attempt, like `connect_to_host()`, and returns the
+/// last error after `max_attempts` attempts.
+pub fn reconnect(max_attempts: u32) -> Result<(), NetworkError> {
+    executor::block_on(reconnect_async(max_attempts))
+}
+
+/// The async version of `reconnect()`.
+pub async fn reconnect_async(max_attempts: u32) -> Result<(), NetworkError> {
+    let mut delay = Duration::from_millis(JOIN_RETRY_START_MS);
+    let mut last_error = NetworkError::HostUnreachable { attempts: 0 };
+    for attempt in 1..=max_attempts {
+        if attempt > 1 {
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(Duration::from_millis(JOIN_RETRY_MAX_MS));
+        }
+        info!("Reconnect attempt {}/{}", attempt, max_attempts);
+
+        // The host address is forgotten when the client gives up on the connection
+        let other_addr = match status::get_other_addr().await {
+            Some(addr) => addr,
+            None => {
+                let join_code = status::get_join_code()
+                    .await
+                    .ok_or(NetworkError::NoSession)?;
+                let addr = host_addr(&join_code).await?;
+                status::set_other_addr(addr).await;
+                addr
+            }
+        };
+
+        let result = match start_client_loop(other_addr.ip()).await {
+            Ok(()) => attempt_reconnect_async().await,
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(()) => return Ok(()),
+            Err(err) => {
+                warn!("Reconnect attempt failed: {}", err);
+                last_error = err;
+            }
+        }
+    }
+
+    Err(last_error)
+}
+
+/// Starts watching the game of the host with `join_code`, without playing. Opens a client socket
+/// with `start_lan_client()`, and blocks until the host has accepted the spectator.
+/// On success the hosts board can be read with `get_board_state()`, which is kept up to date with
+/// the moves the host forwards. The moves can also be read with `get_next_game_action()`, but
+/// the spectator can't send any game actions of its own. Chat messages from the spectator are
+/// shown to the host as sent by "Spectator".
+///
+/// Returns an error if the join code is wrong, or if the host doesn't respond.
+pub fn start_spectating(join_code: &str) -> Result<(), NetworkError> {
+    executor::block_on(start_spectating_async(join_code))
+}
+
+/// The async version of `start_spectating()`.
+pub async fn start_spectating_async(join_code: &str) -> Result<(), NetworkError> {
+    let host_addr = validate_join_code(join_code)?;
+    status::set_join_code(join_code).await;
+    status::set_other_addr(host_addr).await;
+    start_lan_client_async(ClientConfig::default()).await?;
+    info!("Asking to spectate the game of host at {:?}", host_addr);
+
+    let request = P2pRequest::new(
+        status::CONNECT_SESSION_ID,
+        new_transaction_id().await,
+        P2pRequestPacket::Spectate {
+            join_code: join_code.to_owned(),
+        },
+    );
+    let transaction_id = push_outgoing_queue(P2pPacket::Request(request), None).await?;
+
+    let mut spectate_tick = tokio::time::interval(Duration::from_millis(HOST_POLL_MS));
+    for _ in 0..HOST_POLLS {
+        spectate_tick.tick().await;
+        let Some(resp) = check_for_response(transaction_id).await else {
+            continue;
+        };
+
+        let error = match resp {
+            P2pPacket::Response(P2pResponse {
+                session_id,
+                packet: P2pResponsePacket::Resync { board },
+                ..
+            }) => {
+                status::set_session_id(session_id).await;
+                queue::reset_sequences().await;
+                queue::reset_net_stats();
+                status::set_board_state(board).await;
+                status::set_spectating(true).await;
+                status::set_connection_status(status::ConnectionStatus::connected()).await;
+                info!("Spectating the game of host at {:?}", host_addr);
+                return Ok(());
+            }
+            P2pPacket::Response(P2pResponse {
+                packet: P2pResponsePacket::Error { kind },
+                ..
+            }) => {
+                warn!("Spectate request was refused: {:?}", kind);
+                NetworkError::Refused { kind }
+            }
+            _ => {
+                warn!("Got wrong response packet for spectate request");
+                NetworkError::ResponseTypeError
+            }
+        };
+
+        status::set_connection_status(status::ConnectionStatus::Disconnected).await;
+        return Err(error);
+    }
+
+    status::set_connection_status(status::ConnectionStatus::Disconnected).await;
+    warn!("Host didn't respond to spectate request");
+    Err(NetworkError::Timeout {
+        timeout: Duration::from_millis(HOST_POLL_MS) * HOST_POLLS,
+    })
+}
+
+/// Check if the local peer is watching a game, started with `start_spectating()`.
+pub fn is_spectating() -> bool {
+    executor::block_on(is_spectating_async())
+}
+
+/// The async version of `is_spectating()`.
+pub async fn is_spectating_async() -> bool {
+    status::is_spectating().await
+}
+
+/// Returns how many spectators are watching the hosts game.
+pub fn spectator_count() -> usize {
+    executor::block_on(spectator_count_async())
+}
+
+/// The async version of `spectator_count()`.
+pub async fn spectator_count_async() -> usize {
+    status::get_spectator_count().await
+}
+
+/// Closes the connection, and tells the other user why, so they know right away that this user
+/// left. The disconnect packet is sent once, and its acknowledgement is waited for a short while.
+/// If either goes lost, the connection is closed anyway, and the other user finds out when the
+/// connection times out.
+/// Should be called before the game is closed.
+///
+/// Returns true if the other user acknowledged the disconnect.
+pub fn disconnect(reason: &str) -> bool {
+    executor::block_on(disconnect_async(reason))
+}
+
+/// The async version of `disconnect()`.
+pub async fn disconnect_async(reason: &str) -> bool {
+    let mut acknowledged = false;
+    if status::get_connection_status().await.can_send() && status::get_other_addr().await.is_some()
+    {
+        let request = P2pRequest::new(
+            status::get_session_id().await,
+            new_transaction_id().await,
+            P2pRequestPacket::Disconnect {
+                reason: reason.to_owned(),
+            },
+        );
+        if let Ok(transaction_id) = push_outgoing_queue(P2pPacket::Request(request), None).await {
+            let mut disconnect_tick = tokio::time::interval(Duration::from_millis(10));
+            for _ in 0..DISCONNECT_WAIT_MS / 10 {
+                disconnect_tick.tick().await;
+                if check_for_response(transaction_id).await.is_some() {
+                    acknowledged = true;
+                    break;
+                }
             }
         }
     }
+    info!("Disconnected: {}", reason);
+
+    // Stops the tasks of the network loop, which drops the socket
+    status::new_loop_generation().await;
+    status::set_connection_status(status::ConnectionStatus::Disconnected).await;
+    status::remove_other_addr().await;
+    status::remove_other_username().await;
+    status::clear_session().await;
+    queue::clear_outgoing().await;
+    acknowledged
+}
+
+/// Reads the session saved when the connection to the other user was made, so the game can offer
+/// to reconnect after a crash or restart. The session is forgotten when the game ends, or the
+/// connection is closed with `disconnect()`.
+/// Returns `None` if there is no session to reconnect to.
+pub fn restore_session() -> Option<SessionInfo> {
+    executor::block_on(restore_session_async())
+}
+
+/// The async version of `restore_session()`.
+pub async fn restore_session_async() -> Option<SessionInfo> {
+    status::restore_session().await
+}
+
+/// Saves the session to `path`, instead of a file in the temporary directory, see
+/// `restore_session()`.
+pub fn set_session_file(path: PathBuf) {
+    executor::block_on(set_session_file_async(path))
+}
+
+/// The async version of `set_session_file()`.
+pub async fn set_session_file_async(path: PathBuf) {
+    status::set_session_file(path).await
+}
+
+/// Gets the reason the other user gave when they closed the connection, see `disconnect()`.
+/// `None` if they haven't left.
+pub fn get_disconnect_reason() -> Option<String> {
+    executor::block_on(get_disconnect_reason_async())
+}
+
+/// The async version of `get_disconnect_reason()`.
+pub async fn get_disconnect_reason_async() -> Option<String> {
+    status::get_disconnect_reason().await
+}
+
+/// Send a chat message to the other user.
+/// Returns an error if the message is longer than `MAX_CHAT_LEN` bytes, instead of cutting it, or
+/// if the outgoing queue is full.
+pub fn send_chat_message(text: &str) -> Result<(), NetworkError> {
+    executor::block_on(send_chat_message_async(text))
+}
+
+/// The async version of `send_chat_message()`.
+pub async fn send_chat_message_async(text: &str) -> Result<(), NetworkError> {
+    if text.len() > MAX_CHAT_LEN {
+        return Err(NetworkError::ChatTooLong {
+            len: text.len(),
+            max: MAX_CHAT_LEN,
+        });
+    }
+
+    let request = P2pRequest::new(
+        status::get_session_id().await,
+        new_transaction_id().await,
+        P2pRequestPacket::Chat {
+            message: text.to_owned(),
+        },
+    );
+    // Nothing is done with the acknowledgement, except clearing the transaction
+    let closure = Arc::new(Mutex::new(|_: anyhow::Result<P2pResponse>| ()));
+    push_outgoing_queue(P2pPacket::Request(request), Some(closure)).await?;
+    Ok(())
+}
+
+/// Get the next chat message from the other user, as their username and the text of the message.
+pub fn get_next_chat_message() -> Option<(String, String)> {
+    executor::block_on(get_next_chat_message_async())
+}
+
+/// The async version of `get_next_chat_message()`.
+pub async fn get_next_chat_message_async() -> Option<(String, String)> {
+    queue::pop_incoming_chat().await
 }
 
 /// Get the next game action from the other user.
 pub fn get_next_game_action() -> Option<GameAction> {
-    executor::block_on(pop_incoming_gameaction())
+    executor::block_on(get_next_game_action_async())
+}
+
+/// The async version of `get_next_game_action()`.
+pub async fn get_next_game_action_async() -> Option<GameAction> {
+    pop_incoming_gameaction().await
+}
+
+/// Returns how many game actions and chat messages from the other user haven't been read with
+/// `get_next_game_action()` and `get_next_chat_message()` yet.
+pub fn get_incoming_queue_len() -> usize {
+    executor::block_on(get_incoming_queue_len_async())
+}
+
+/// The async version of `get_incoming_queue_len()`.
+pub async fn get_incoming_queue_len_async() -> usize {
+    queue::get_incoming_queue_len().await
+}
+
+/// Sets how many game actions, and how many chat messages, from the other user can wait to be
+/// read. While the game actions are full, new ones are refused with `P2pError::QueueFull`, and
+/// the oldest chat message is thrown away for a new one.
+/// Defaults to `DEFAULT_MAX_INCOMING_LEN`.
+pub fn set_max_incoming_len(len: usize) {
+    queue::set_max_incoming_len(len)
 }
 
 /// Send a game action to the other user.
@@ -176,8 +789,16 @@ pub fn get_next_game_action() -> Option<GameAction> {
 /// * `on_response` - The closure that will be called when the `GameAction` request gets a
 /// response.
 ///
+/// Returns the transaction id of the request, which can be withdrawn with `cancel_request()`, or
+/// `None` if the action failed right away.
+///
+/// If the other user refuses the action, the error given to `on_response` is a
+/// `NetworkError::Refused`, which can be matched on after downcasting it, to find out why.
+///
 /// ## Examples:
-/// ```
+/// ```no_run
+/// use the_checker_mater::{game::GameAction, net::interface::send_game_action};
+///
 /// let action = GameAction::Surrender;
 ///
 /// let callback = |res: anyhow::Result<()>| {
@@ -185,44 +806,1627 @@ pub fn get_next_game_action() -> Option<GameAction> {
 ///         Ok(_) => println!("Hell yea!!"),
 ///         Err(_) => println!("Hell no!!"),
 ///     };
-/// }
+/// };
 ///
 /// send_game_action(action, callback);
 /// ```
-pub fn send_game_action<F>(action: GameAction, mut on_response: F)
+pub fn send_game_action<F>(action: GameAction, on_response: F) -> Option<u16>
+where
+    F: FnMut(anyhow::Result<()>) + Send + Sync + 'static,
+{
+    executor::block_on(send_game_action_async(action, on_response))
+}
+
+/// The async version of `send_game_action()`.
+/// Returns once the request is queued, without waiting for a response.
+///
+/// A `GameAction::OfferDraw` fails right away if a draw offer from either user is pending, and a
+/// `GameAction::RespondDraw` fails right away if none is.
+/// Once the game is over, see `get_game_result()`, every action except `GameAction::Rematch` and
+/// `GameAction::RematchAccept` fails right away. A `GameAction::Surrender` ends the game as soon
+/// as it is queued, without waiting for the other user.
+pub async fn send_game_action_async<F>(action: GameAction, mut on_response: F) -> Option<u16>
 where
     F: FnMut(anyhow::Result<()>) + Send + Sync + 'static,
 {
-    let closure = Arc::new(Mutex::new(move |resp: P2pResponse| {
-        if let P2pResponsePacket::Error { kind: _ } = resp.packet {
-            on_response(Err(anyhow::anyhow!("Recieved error")));
-        } else {
-            on_response(Ok(()));
+    if let Some(result) = status::get_game_result().await {
+        if !action.is_rematch() {
+            on_response(Err(anyhow!("The game is already over: {:?}", result)));
+            return None;
+        }
+    }
+
+    let is_draw_offer = matches!(action, GameAction::OfferDraw);
+    let is_surrender = matches!(action, GameAction::Surrender);
+    // The turn before a move, for when the move can't be sent after all
+    let mut turn_before = None;
+    match action {
+        GameAction::OfferDraw => {
+            if status::is_draw_offer_pending().await {
+                on_response(Err(anyhow!("A draw offer is already pending")));
+                return None;
+            }
+            status::set_draw_offer_pending(true).await;
+        }
+        GameAction::RespondDraw(_) => {
+            if !status::is_draw_offer_pending().await {
+                on_response(Err(anyhow!("There is no draw offer to answer")));
+                return None;
+            }
+            status::set_draw_offer_pending(false).await;
+        }
+        GameAction::Surrender => {
+            if let Some(loser) = status::get_my_color().await {
+                status::set_game_result(Some(GameResult::Resigned { loser })).await;
+            }
+        }
+        GameAction::RematchAccept => status::set_game_result(None).await,
+        // The other user may move once this move is recieved
+        GameAction::MovePiece(_) => {
+            if let Some(my_color) = status::get_my_color().await {
+                turn_before = Some(status::whose_turn().await);
+                status::set_whose_turn(my_color.get_opposite()).await;
+            }
+        }
+        _ => {}
+    }
+
+    // The board is shared after every move, so this is the hash of the board after the action
+    let board_hash = status::get_shared_bitboard()
+        .await
+        .map_or(0, |board| board.board_hash(PieceColor::White));
+
+    // Shared with the response closure, so a request which can't be queued is answered here
+    let on_response = Arc::new(std::sync::Mutex::new(on_response));
+    let respond = on_response.clone();
+    let closure = Arc::new(Mutex::new(move |resp: anyhow::Result<P2pResponse>| {
+        let result = match resp {
+            Ok(resp) => match resp.packet {
+                P2pResponsePacket::Error { kind } => Err(NetworkError::Refused { kind }.into()),
+                _ => Ok(()),
+            },
+            Err(err) => Err(err),
+        };
+
+        // The offer never made it, so another one may be made. The closure is run by the network
+        // loop, so the flag is cleared in a task of its own instead of blocking the loop.
+        if is_draw_offer && result.is_err() {
+            tokio::spawn(async {
+                status::set_draw_offer_pending(false).await;
+            });
         }
+        (respond.lock().unwrap())(result);
     }));
 
-    let request = P2pRequest {
-        session_id: executor::block_on(status::get_session_id()),
-        transaction_id: executor::block_on(new_transaction_id()),
-        packet: P2pRequestPacket::game_action(action),
-    };
-    executor::block_on(push_outgoing_queue(
-        P2pPacket::Request(request),
-        Some(closure),
-    ));
+    let request = P2pRequest::new(
+        status::get_session_id().await,
+        new_transaction_id().await,
+        P2pRequestPacket::game_action(action, board_hash),
+    );
+    match push_outgoing_queue(P2pPacket::Request(request), Some(closure)).await {
+        Ok(transaction_id) => Some(transaction_id),
+        Err(err) => {
+            if is_draw_offer {
+                status::set_draw_offer_pending(false).await;
+            }
+            // The surrender was never sent, so the game goes on
+            if is_surrender {
+                status::set_game_result(None).await;
+            }
+            if let Some(turn) = turn_before {
+                status::set_whose_turn(turn).await;
+            }
+            (on_response.lock().unwrap())(Err(err.into()));
+            None
+        }
+    }
 }
 
-/// Check if there is an established connection between the host and client.
-pub fn is_connected() -> bool {
-    executor::block_on(status::get_connection_status()).is_connected()
+/// Withdraws the request with `transaction_id`, eg. a draw offer the user changed their mind
+/// about, if it hasn't gotten a response yet. Its `on_response` closure is never called.
+/// Returns false if there was no such request.
+///
+/// A request which has already been sent may still reach the other user. A withdrawn draw offer
+/// is no longer pending on this side.
+pub fn cancel_request(transaction_id: u16) -> bool {
+    executor::block_on(cancel_request_async(transaction_id))
 }
 
-/// Gets the other users username.
-pub fn get_other_username() -> Option<String> {
-    executor::block_on(status::get_other_username())
+/// The async version of `cancel_request()`.
+pub async fn cancel_request_async(transaction_id: u16) -> bool {
+    let packet = queue::get_outgoing_request(transaction_id).await;
+    if !queue::cancel_outgoing(transaction_id).await {
+        return false;
+    }
+
+    if let Some(P2pRequestPacket::GameAction {
+        action: GameAction::OfferDraw,
+        ..
+    }) = packet
+    {
+        status::set_draw_offer_pending(false).await;
+    }
+    true
+}
+
+/// Ask the other user for a rematch, once the game is over.
+/// If they accept, a `GameAction::RematchAccept` is recieved, see `get_next_game_action`.
+pub fn send_rematch_request<F>(on_response: F)
+where
+    F: FnMut(anyhow::Result<()>) + Send + Sync + 'static,
+{
+    executor::block_on(send_rematch_request_async(on_response))
+}
+
+/// The async version of `send_rematch_request()`.
+pub async fn send_rematch_request_async<F>(on_response: F)
+where
+    F: FnMut(anyhow::Result<()>) + Send + Sync + 'static,
+{
+    send_game_action_async(GameAction::Rematch, on_response).await;
+}
+
+/// Accept the rematch the other user asked for.
+pub fn accept_rematch<F>(on_response: F)
+where
+    F: FnMut(anyhow::Result<()>) + Send + Sync + 'static,
+{
+    executor::block_on(accept_rematch_async(on_response))
+}
+
+/// The async version of `accept_rematch()`.
+pub async fn accept_rematch_async<F>(on_response: F)
+where
+    F: FnMut(anyhow::Result<()>) + Send + Sync + 'static,
+{
+    status::set_rematch_offered(false).await;
+    send_game_action_async(GameAction::RematchAccept, on_response).await;
+}
+
+/// Ask the other user to take back your last move, and their answer to it if it's your turn.
+/// `ply` is the number of moves made on your board, so the other user can decline if their board
+/// has moved on since.
+/// The answer is recieved as a `GameAction::RespondTakeback`, see `get_next_game_action`.
+pub fn request_takeback<F>(ply: u32, on_response: F)
+where
+    F: FnMut(anyhow::Result<()>) + Send + Sync + 'static,
+{
+    executor::block_on(request_takeback_async(ply, on_response))
+}
+
+/// The async version of `request_takeback()`.
+pub async fn request_takeback_async<F>(ply: u32, on_response: F)
+where
+    F: FnMut(anyhow::Result<()>) + Send + Sync + 'static,
+{
+    send_game_action_async(GameAction::RequestTakeback(ply), on_response).await;
+}
+
+/// Answer the takeback the other user asked for. When accepting, the moves should be taken back
+/// and the board shared first, so the other user's board is compared with the new board.
+pub fn respond_takeback<F>(accepted: bool, on_response: F)
+where
+    F: FnMut(anyhow::Result<()>) + Send + Sync + 'static,
+{
+    executor::block_on(respond_takeback_async(accepted, on_response))
+}
+
+/// The async version of `respond_takeback()`.
+pub async fn respond_takeback_async<F>(accepted: bool, on_response: F)
+where
+    F: FnMut(anyhow::Result<()>) + Send + Sync + 'static,
+{
+    send_game_action_async(GameAction::RespondTakeback(accepted), on_response).await;
+}
+
+/// Check if the other user has asked for a rematch, which hasn't been accepted yet.
+pub fn is_rematch_offered() -> bool {
+    executor::block_on(is_rematch_offered_async())
+}
+
+/// The async version of `is_rematch_offered()`.
+pub async fn is_rematch_offered_async() -> bool {
+    status::is_rematch_offered().await
 }
 
-/// Sets your username.
-pub fn set_my_username(name: &str) {
-    executor::block_on(status::set_my_username(name))
+/// Check if there is an established connection between the host and client.
+pub fn is_connected() -> bool {
+    executor::block_on(is_connected_async())
+}
+
+/// The async version of `is_connected()`.
+pub async fn is_connected_async() -> bool {
+    status::get_connection_status().await.is_connected()
+}
+
+/// Gets the average round-trip time to the other user in milliseconds, over the latest pings.
+/// `None` if there is no connection, or no ping has been answered yet.
+pub fn get_ping_ms() -> Option<u64> {
+    executor::block_on(get_ping_ms_async())
+}
+
+/// The async version of `get_ping_ms()`.
+pub async fn get_ping_ms_async() -> Option<u64> {
+    status::get_latency()
+        .await
+        .map(|latency| latency.as_millis() as u64)
+}
+
+/// Get the statistics about the packets sent to and recieved from the other user, eg. how many had
+/// to be sent again and the estimated round-trip time, for diagnosing a bad connection.
+/// They are counted from zero when a session starts, and after `reset_net_stats()`.
+pub fn get_net_stats() -> NetStats {
+    status::net_stats()
+}
+
+/// The async version of `get_net_stats()`.
+pub async fn get_net_stats_async() -> NetStats {
+    status::net_stats()
+}
+
+/// Counts the statistics returned by `get_net_stats()` from zero, eg. when a new game starts.
+pub fn reset_net_stats() {
+    queue::reset_net_stats()
+}
+
+/// Check if the connection was lost, because nothing was recieved from the other peer for longer
+/// than the heartbeat timeout.
+pub fn connection_timed_out() -> bool {
+    executor::block_on(connection_timed_out_async())
+}
+
+/// The async version of `connection_timed_out()`.
+pub async fn connection_timed_out_async() -> bool {
+    status::get_connection_status().await.is_timed_out()
+}
+
+/// Sets how long to wait for a response before a request is sent again, and how many times it is
+/// sent again before it is given up on. Defaults to 500 ms and 5 times.
+pub fn set_retransmit_policy(interval: Duration, max_retransmits: u32) {
+    executor::block_on(set_retransmit_policy_async(interval, max_retransmits));
+}
+
+/// The async version of `set_retransmit_policy()`.
+pub async fn set_retransmit_policy_async(interval: Duration, max_retransmits: u32) {
+    queue::set_retransmit_policy(interval, max_retransmits).await;
+}
+
+/// Sets how long the other peer may be silent before the connection times out.
+/// Defaults to 15 seconds.
+pub fn set_heartbeat_timeout(timeout: Duration) {
+    executor::block_on(set_heartbeat_timeout_async(timeout));
+}
+
+/// The async version of `set_heartbeat_timeout()`.
+pub async fn set_heartbeat_timeout_async(timeout: Duration) {
+    status::set_heartbeat_timeout(timeout).await;
+}
+
+/// Shares the current game board, in logical indices, so the host can send it to a client that
+/// resyncs or reconnects.
+pub fn set_board_state(board: Vec<PieceData>) {
+    executor::block_on(set_board_state_async(board));
+}
+
+/// The async version of `set_board_state()`.
+pub async fn set_board_state_async(board: Vec<PieceData>) {
+    status::set_board_state(board).await;
+}
+
+/// Sets the color of the local player's pieces, so moves recieved from the other user can be
+/// checked against the board shared with `set_board_state()`.
+pub fn set_my_color(color: PieceColor) {
+    executor::block_on(set_my_color_async(color));
+}
+
+/// The async version of `set_my_color()`.
+pub async fn set_my_color_async(color: PieceColor) {
+    status::set_my_color(color).await;
+}
+
+/// Returns the color whose turn it is. Moves recieved from the other user on the local player's
+/// turn are refused with `P2pError::OutOfTurn`, so the UI should only take moves when this is the
+/// local player's color.
+pub fn whose_turn() -> PieceColor {
+    executor::block_on(whose_turn_async())
+}
+
+/// The async version of `whose_turn()`.
+pub async fn whose_turn_async() -> PieceColor {
+    status::whose_turn().await
+}
+
+/// Sets the color whose turn it is, eg. when a new game is started or moves are taken back. The
+/// turn is passed on by the moves sent and recieved, so this isn't needed after every move.
+pub fn set_whose_turn(color: PieceColor) {
+    executor::block_on(set_whose_turn_async(color))
+}
+
+/// The async version of `set_whose_turn()`.
+pub async fn set_whose_turn_async(color: PieceColor) {
+    status::set_whose_turn(color).await
+}
+
+/// Returns the color of the local player's pieces. For the host this is set when it starts, see
+/// `ColorAssignment`.
+pub fn get_my_color() -> Option<PieceColor> {
+    executor::block_on(get_my_color_async())
+}
+
+/// The async version of `get_my_color()`.
+pub async fn get_my_color_async() -> Option<PieceColor> {
+    status::get_my_color().await
+}
+
+/// Takes the color the host assigned the client when it joined. The game should set up its board
+/// with it, if it doesn't already play that color.
+pub fn take_assigned_color() -> Option<PieceColor> {
+    executor::block_on(take_assigned_color_async())
+}
+
+/// The async version of `take_assigned_color()`.
+pub async fn take_assigned_color_async() -> Option<PieceColor> {
+    status::take_assigned_color().await
+}
+
+/// Sends the hash of the local board after `ply` moves to the other user, who compares it with
+/// the hash of their own board. Should be called after every move.
+///
+/// If the hashes differ, the connection status becomes `ConnectionStatus::Desynced`, see
+/// `is_desynced()`.
+pub fn send_sync_check(hash: u64, ply: u32) {
+    executor::block_on(send_sync_check_async(hash, ply))
+}
+
+/// The async version of `send_sync_check()`.
+pub async fn send_sync_check_async(hash: u64, ply: u32) {
+    status::record_board_hash(ply, hash, true).await;
+
+    let request = P2pRequest::new(
+        status::get_session_id().await,
+        new_transaction_id().await,
+        P2pRequestPacket::SyncCheck { hash, ply },
+    );
+    // Nothing is done with the acknowledgement, except clearing the transaction
+    let closure = Arc::new(Mutex::new(|_: anyhow::Result<P2pResponse>| ()));
+    if let Err(err) = push_outgoing_queue(P2pPacket::Request(request), Some(closure)).await {
+        warn!("Failed to queue sync check: {}", err);
+    }
+}
+
+/// Check if the boards of the users have stopped matching, see `send_sync_check()`.
+pub fn is_desynced() -> bool {
+    executor::block_on(is_desynced_async())
+}
+
+/// The async version of `is_desynced()`.
+pub async fn is_desynced_async() -> bool {
+    status::get_connection_status().await.is_desynced()
+}
+
+/// Takes the board recieved from the host when reconnecting or resyncing after a desync, in
+/// logical indices. The game should replace its own board with it.
+pub fn take_resynced_board() -> Option<Vec<PieceData>> {
+    executor::block_on(take_resynced_board_async())
+}
+
+/// The async version of `take_resynced_board()`.
+pub async fn take_resynced_board_async() -> Option<Vec<PieceData>> {
+    status::take_resynced_board().await
+}
+
+/// Sets the time limits of the game. Must be called by the host before the client joins, since
+/// the time control is sent to the client along with its color. Games have no time limit unless
+/// this is called.
+pub fn set_time_control(time_control: Option<TimeControl>) {
+    executor::block_on(set_time_control_async(time_control));
+}
+
+/// The async version of `set_time_control()`.
+pub async fn set_time_control_async(time_control: Option<TimeControl>) {
+    status::set_time_control(time_control).await;
+}
+
+/// Gets the time limits of the game, which the client recieves when it joins.
+pub fn get_time_control() -> Option<TimeControl> {
+    executor::block_on(get_time_control_async())
+}
+
+/// The async version of `get_time_control()`.
+pub async fn get_time_control_async() -> Option<TimeControl> {
+    status::get_time_control().await
+}
+
+/// Sets the seed of the game's random opening, see `Board::start_new_game_random`, or `None` to
+/// start from the usual position. Must be called by the host before the client joins, since the
+/// seed is sent to the client along with its color.
+pub fn set_opening_seed(seed: Option<u64>) {
+    executor::block_on(set_opening_seed_async(seed));
+}
+
+/// The async version of `set_opening_seed()`.
+pub async fn set_opening_seed_async(seed: Option<u64>) {
+    status::set_opening_seed(seed).await;
+}
+
+/// Gets the seed of the game's random opening, which the client recieves when it joins.
+pub fn get_opening_seed() -> Option<u64> {
+    executor::block_on(get_opening_seed_async())
+}
+
+/// The async version of `get_opening_seed()`.
+pub async fn get_opening_seed_async() -> Option<u64> {
+    status::get_opening_seed().await
+}
+
+/// Shares the clock of the game, so the time left can be read with `remaining_time()`.
+/// Should be called whenever a turn starts.
+pub fn set_turn_timer(timer: Option<MoveClock>) {
+    executor::block_on(set_turn_timer_async(timer));
+}
+
+/// The async version of `set_turn_timer()`.
+pub async fn set_turn_timer_async(timer: Option<MoveClock>) {
+    status::set_turn_timer(timer).await;
+}
+
+/// Gets the time `color` has left on the clock shared with `set_turn_timer()`, or `None` if the
+/// game has no time limit.
+pub fn remaining_time(color: PieceColor) -> Option<Duration> {
+    executor::block_on(remaining_time_async(color))
+}
+
+/// The async version of `remaining_time()`.
+pub async fn remaining_time_async(color: PieceColor) -> Option<Duration> {
+    status::get_turn_timer()
+        .await
+        .map(|timer| timer.remaining(color))
+}
+
+/// Gets the last game board shared with `set_board_state()`, or recieved from the host when
+/// reconnecting.
+pub fn get_board_state() -> Vec<PieceData> {
+    executor::block_on(get_board_state_async())
+}
+
+/// The async version of `get_board_state()`.
+pub async fn get_board_state_async() -> Vec<PieceData> {
+    status::get_board_state().await
+}
+
+/// Check if a draw offer from either user hasn't been answered yet.
+pub fn is_draw_offer_pending() -> bool {
+    executor::block_on(is_draw_offer_pending_async())
+}
+
+/// The async version of `is_draw_offer_pending()`.
+pub async fn is_draw_offer_pending_async() -> bool {
+    status::is_draw_offer_pending().await
+}
+
+/// Returns how the game ended, or `None` while it is being played. Set by `set_game_result()`, and
+/// when either user surrenders or runs out of time.
+pub fn get_game_result() -> Option<GameResult> {
+    executor::block_on(get_game_result_async())
+}
+
+/// The async version of `get_game_result()`.
+pub async fn get_game_result_async() -> Option<GameResult> {
+    status::get_game_result().await
+}
+
+/// Sets how the game ended, after which only a rematch can be asked for, see
+/// `send_game_action()`. Set it to `None` when a new game is started.
+/// The saved session is forgotten while there is no game to reconnect to, see
+/// `restore_session()`.
+pub fn set_game_result(result: Option<GameResult>) {
+    executor::block_on(set_game_result_async(result))
+}
+
+/// The async version of `set_game_result()`.
+pub async fn set_game_result_async(result: Option<GameResult>) {
+    status::set_game_result(result).await;
+    if result.is_some() {
+        status::clear_session().await;
+    } else {
+        status::save_session().await;
+    }
+}
+
+/// Forgets a pending draw offer, eg. when a new game is started.
+pub fn clear_draw_offer() {
+    executor::block_on(clear_draw_offer_async())
+}
+
+/// The async version of `clear_draw_offer()`.
+pub async fn clear_draw_offer_async() {
+    status::set_draw_offer_pending(false).await
+}
+
+/// Forgets the board hashes which haven't been compared with the other user's yet, so they
+/// aren't compared with the hashes of a new game.
+pub fn clear_sync_checks() {
+    executor::block_on(clear_sync_checks_async())
+}
+
+/// The async version of `clear_sync_checks()`.
+pub async fn clear_sync_checks_async() {
+    status::clear_board_hashes().await
+}
+
+/// Gets the other users username.
+pub fn get_other_username() -> Option<String> {
+    executor::block_on(get_other_username_async())
+}
+
+/// The async version of `get_other_username()`.
+pub async fn get_other_username_async() -> Option<String> {
+    status::get_other_username().await
+}
+
+/// Sets your username. Returns an error, and keeps the old username, if `name` is invalid, see
+/// `validate_username`.
+pub fn set_my_username(name: &str) -> Result<(), NetworkError> {
+    executor::block_on(set_my_username_async(name))
+}
+
+/// The async version of `set_my_username()`.
+pub async fn set_my_username_async(name: &str) -> Result<(), NetworkError> {
+    validate_username(name)?;
+    status::set_my_username(name).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::UdpSocket;
+
+    use super::*;
+    use crate::game::{
+        bitboard::BitBoard,
+        board::{tests::START, Board},
+        Move,
+    };
+    use crate::net::p2p::communicate::send_p2p_packet;
+    use crate::net::test_utils::{reset_state, MockPeer, CLIENT_WITHOUT_PINGS, TEST_LOCK};
+
+    /// Starts a client network loop talking to `host`, on a loopback socket
+    async fn start_test_client(host: &MockPeer) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        status::set_other_addr(host.addr()).await;
+        client_network_loop(socket, CLIENT_WITHOUT_PINGS, DEFAULT_MAX_OUTGOING_LEN).await;
+    }
+
+    /// Starts a host network loop on a loopback socket, hosting as White, and returns its join code
+    async fn start_test_host() -> String {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let join_code = hex_encode_ip(socket.local_addr().unwrap());
+        status::set_join_code(&join_code).await;
+        status::set_my_username("alice").await;
+        status::set_my_color(PieceColor::White).await;
+        status::set_connection_status(ConnectionStatus::PendingConnection).await;
+        host_network_loop(socket, DEFAULT_MAX_OUTGOING_LEN).await;
+        join_code
+    }
+
+    /// Joins the host started by `start_test_host` as `client`, and returns the host's address and
+    /// the session id
+    async fn join_test_host(join_code: &str, client: &MockPeer) -> (SocketAddr, u16) {
+        let host = validate_join_code(join_code).unwrap();
+        let join = P2pRequest::new(
+            status::CONNECT_SESSION_ID,
+            0,
+            P2pRequestPacket::connect(join_code, "bob"),
+        );
+        let response = client.request(join, host).await;
+        assert!(matches!(response.packet, P2pResponsePacket::Connect { .. }));
+        (host, response.session_id)
+    }
+
+    /// Returns the address of the client talking to `host`, which is only known once the client
+    /// has sent something
+    async fn client_addr(host: &MockPeer) -> SocketAddr {
+        send_chat_message_async("hi").await.unwrap();
+        let (request, client) = host.next_request().await.unwrap();
+        host.respond(&request, P2pResponsePacket::Acknowledge, client)
+            .await;
+        client
+    }
+
+    /// Returns the pieces of the starting position, in logical indices
+    fn start_pieces() -> Vec<PieceData> {
+        Board::from_diagram(START, PieceColor::White)
+            .unwrap()
+            .logical_pieces()
+    }
+
+    /// Returns a request from the host with `action`
+    fn game_action_request(transaction_id: u16, action: GameAction) -> P2pRequest {
+        P2pRequest::new(0, transaction_id, P2pRequestPacket::game_action(action, 0))
+    }
+
+    /// Sends `action` like the game does, and returns a reciever for wether it succeeded
+    async fn send_action(action: GameAction) -> tokio::sync::mpsc::UnboundedReceiver<bool> {
+        let (sender, reciever) = tokio::sync::mpsc::unbounded_channel();
+        send_game_action_async(action, move |result| {
+            let _ = sender.send(result.is_ok());
+        })
+        .await;
+        reciever
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reconnect_continues_session() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        let host = MockPeer::bind().await;
+        start_test_client(&host).await;
+        status::set_session_id(42).await;
+        status::set_connection_status(ConnectionStatus::TimedOut).await;
+
+        let reconnect = tokio::spawn(attempt_reconnect_async());
+        let (request, client) = host.next_request().await.unwrap();
+        assert_eq!(request.session_id, 42);
+        assert!(matches!(request.packet, P2pRequestPacket::Reconnect));
+        let board = vec![PieceData::default(); 32];
+        let packet = P2pResponsePacket::Resync {
+            board: board.clone(),
+        };
+        host.respond(&request, packet, client).await;
+
+        reconnect.await.unwrap().unwrap();
+        assert!(status::get_connection_status().await.is_connected());
+        assert_eq!(status::take_resynced_board().await, Some(board));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn join_requests_back_off_until_answered() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        let host = MockPeer::bind().await;
+        start_test_client(&host).await;
+
+        let join_code = hex_encode_ip(host.addr());
+        let connect = tokio::spawn(async move {
+            connect_to_host_async(
+                &join_code,
+                "bob",
+                DEFAULT_JOIN_ATTEMPTS,
+                DEFAULT_CONNECT_TIMEOUT,
+            )
+            .await
+        });
+
+        // The host only answers the 4th join request
+        let mut arrivals = vec![];
+        let (request, client) = loop {
+            let (request, client) = host.next_request().await.unwrap();
+            assert!(matches!(request.packet, P2pRequestPacket::Connect { .. }));
+            arrivals.push(tokio::time::Instant::now());
+            if arrivals.len() == 4 {
+                break (request, client);
+            }
+        };
+        let packet = P2pResponsePacket::connect(PieceColor::Black, "alice".to_owned(), None, None);
+        host.respond(&request, packet, client).await;
+
+        let (color, host_username) = connect.await.unwrap().unwrap();
+        assert_eq!(color, PieceColor::Black);
+        assert_eq!(host_username, "alice");
+        let delays: Vec<Duration> = arrivals.windows(2).map(|pair| pair[1] - pair[0]).collect();
+        assert!(
+            delays.windows(2).all(|pair| pair[0] < pair[1]),
+            "{:?}",
+            delays
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn chat_messages_reach_the_other_peer() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        let host = MockPeer::bind().await;
+        start_test_client(&host).await;
+        status::set_other_username("alice").await;
+
+        send_chat_message_async("hello").await.unwrap();
+        let (request, client) = host.next_request().await.unwrap();
+        assert!(matches!(
+            request.packet,
+            P2pRequestPacket::Chat { ref message } if message == "hello"
+        ));
+        host.respond(&request, P2pResponsePacket::Acknowledge, client)
+            .await;
+
+        let chat = P2pRequest::new(
+            0,
+            1,
+            P2pRequestPacket::Chat {
+                message: "hi back".to_owned(),
+            },
+        );
+        let response = host.request(chat, client).await;
+        assert_eq!(response.packet, P2pResponsePacket::Acknowledge);
+        assert_eq!(
+            get_next_chat_message_async().await,
+            Some(("alice".to_owned(), "hi back".to_owned()))
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn draw_offers_are_accepted_or_declined() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        let host = MockPeer::bind().await;
+        start_test_client(&host).await;
+        let client = client_addr(&host).await;
+
+        // The host offers a draw, which the client accepts
+        let offer = game_action_request(1, GameAction::OfferDraw);
+        assert_eq!(
+            host.request(offer, client).await.packet,
+            P2pResponsePacket::Acknowledge
+        );
+        assert!(is_draw_offer_pending_async().await);
+        assert!(matches!(
+            get_next_game_action_async().await,
+            Some(GameAction::OfferDraw)
+        ));
+        let second_offer = game_action_request(2, GameAction::OfferDraw);
+        assert_eq!(
+            host.request(second_offer, client).await.packet,
+            P2pResponsePacket::error(P2pError::DrawOfferPending)
+        );
+
+        let mut accepted = send_action(GameAction::RespondDraw(true)).await;
+        let (answer, _) = host.next_request().await.unwrap();
+        assert!(matches!(
+            answer.packet,
+            P2pRequestPacket::GameAction {
+                action: GameAction::RespondDraw(true),
+                ..
+            }
+        ));
+        host.respond(&answer, P2pResponsePacket::Acknowledge, client)
+            .await;
+        assert_eq!(accepted.recv().await, Some(true));
+        assert!(!is_draw_offer_pending_async().await);
+
+        // The client offers a draw, which the host declines
+        let mut offered = send_action(GameAction::OfferDraw).await;
+        let (offer, _) = host.next_request().await.unwrap();
+        host.respond(&offer, P2pResponsePacket::Acknowledge, client)
+            .await;
+        assert_eq!(offered.recv().await, Some(true));
+        assert!(is_draw_offer_pending_async().await);
+
+        let decline = game_action_request(3, GameAction::RespondDraw(false));
+        assert_eq!(
+            host.request(decline, client).await.packet,
+            P2pResponsePacket::Acknowledge
+        );
+        assert!(!is_draw_offer_pending_async().await);
+        assert!(matches!(
+            get_next_game_action_async().await,
+            Some(GameAction::RespondDraw(false))
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn failed_draw_offer_is_no_longer_pending() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+
+        // The offer doesn't fit in the outgoing queue
+        queue::set_max_outgoing_len(1);
+        let resync = P2pRequest::new(0, 1, P2pRequestPacket::Resync);
+        push_outgoing_queue(P2pPacket::Request(resync), None)
+            .await
+            .unwrap();
+        let mut offered = send_action(GameAction::OfferDraw).await;
+        assert_eq!(offered.recv().await, Some(false));
+        assert!(!is_draw_offer_pending_async().await);
+        queue::set_max_outgoing_len(DEFAULT_MAX_OUTGOING_LEN);
+
+        // The host refuses the offer
+        reset_state().await;
+        let host = MockPeer::bind().await;
+        start_test_client(&host).await;
+        let mut offered = send_action(GameAction::OfferDraw).await;
+        assert!(is_draw_offer_pending_async().await);
+        let (offer, client) = host.next_request().await.unwrap();
+        let refusal = P2pResponsePacket::error(P2pError::DrawOfferPending);
+        host.respond(&offer, refusal, client).await;
+        assert_eq!(offered.recv().await, Some(false));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!is_draw_offer_pending_async().await);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn illegal_move_is_rejected() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        let host = MockPeer::bind().await;
+        start_test_client(&host).await;
+        let client = client_addr(&host).await;
+        let pieces = start_pieces();
+        set_board_state_async(pieces.clone()).await;
+        set_my_color_async(PieceColor::Black).await;
+
+        // A White man jumping two rows up without capturing anything
+        let teleport = Move {
+            index: 22,
+            end: 13,
+            promoted: false,
+            captured: None,
+        };
+        let request = game_action_request(1, GameAction::MovePiece(teleport));
+        assert_eq!(
+            host.request(request, client).await.packet,
+            P2pResponsePacket::error(P2pError::IllegalMove)
+        );
+        assert!(get_next_game_action_async().await.is_none());
+        assert_eq!(get_board_state_async().await, pieces);
+        assert_eq!(whose_turn_async().await, PieceColor::White);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn diverged_boards_are_flagged() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        let host = MockPeer::bind().await;
+        start_test_client(&host).await;
+        let client = client_addr(&host).await;
+        status::set_connection_status(ConnectionStatus::Connected { ping: 0 }).await;
+
+        // Both boards start alike, but each applies a different first move
+        let mut host_board = BitBoard::from_pieces(&start_pieces(), PieceColor::White);
+        let mut client_board = host_board;
+        let moves = host_board.legal_moves(true).unwrap();
+        host_board.apply_move(&moves[0]);
+        client_board.apply_move(&moves[1]);
+
+        send_sync_check_async(client_board.board_hash(PieceColor::White), 1).await;
+        let (check, _) = host.next_request().await.unwrap();
+        host.respond(&check, P2pResponsePacket::Acknowledge, client)
+            .await;
+        assert!(!is_desynced_async().await);
+
+        let packet = P2pRequestPacket::SyncCheck {
+            hash: host_board.board_hash(PieceColor::White),
+            ply: 1,
+        };
+        assert_eq!(
+            host.request(P2pRequest::new(0, 1, packet), client)
+                .await
+                .packet,
+            P2pResponsePacket::Acknowledge
+        );
+        assert!(is_desynced_async().await);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn tampered_move_triggers_resync() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        let host = MockPeer::bind().await;
+        start_test_client(&host).await;
+        let client = client_addr(&host).await;
+        status::set_connection_status(ConnectionStatus::Connected { ping: 0 }).await;
+        set_board_state_async(start_pieces()).await;
+        set_my_color_async(PieceColor::Black).await;
+
+        // A legal move, but with a hash which doesn't match the board after it
+        let board = BitBoard::from_pieces(&start_pieces(), PieceColor::White);
+        let mov = board.legal_moves(true).unwrap().remove(0);
+        let request = P2pRequest::new(
+            0,
+            1,
+            P2pRequestPacket::game_action(GameAction::MovePiece(mov), 0xdead),
+        );
+        send_p2p_packet(&host.socket, request, client)
+            .await
+            .unwrap();
+
+        // The client acknowledges the move, and asks for the host's board
+        let mut acknowledged = false;
+        let mut resync = None;
+        while !acknowledged || resync.is_none() {
+            match host.recieve().await.expect("No packet recieved").0 {
+                P2pPacket::Response(response) => {
+                    assert_eq!(response.transaction_id, 1);
+                    assert_eq!(response.packet, P2pResponsePacket::Acknowledge);
+                    acknowledged = true;
+                }
+                P2pPacket::Request(request) => {
+                    assert!(matches!(request.packet, P2pRequestPacket::Resync));
+                    resync = Some(request);
+                }
+            }
+        }
+        assert!(is_desynced_async().await);
+
+        let packet = P2pResponsePacket::Resync {
+            board: start_pieces(),
+        };
+        host.respond(&resync.unwrap(), packet, client).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!is_desynced_async().await);
+        assert_eq!(take_resynced_board_async().await, Some(start_pieces()));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn expired_time_ends_the_game() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        let host = MockPeer::bind().await;
+        start_test_client(&host).await;
+        let client = client_addr(&host).await;
+        set_my_color_async(PieceColor::Black).await;
+
+        let expired = game_action_request(1, GameAction::TimeExpired);
+        assert_eq!(
+            host.request(expired, client).await.packet,
+            P2pResponsePacket::Acknowledge
+        );
+        assert_eq!(
+            get_game_result_async().await,
+            Some(GameResult::Timeout {
+                loser: PieceColor::White
+            })
+        );
+        assert!(matches!(
+            get_next_game_action_async().await,
+            Some(GameAction::TimeExpired)
+        ));
+
+        // The game is over, so the host can't move anymore
+        let mov = BitBoard::from_pieces(&start_pieces(), PieceColor::White)
+            .legal_moves(true)
+            .unwrap()
+            .remove(0);
+        let request = game_action_request(2, GameAction::MovePiece(mov));
+        assert_eq!(
+            host.request(request, client).await.packet,
+            P2pResponsePacket::error(P2pError::GameOver)
+        );
+    }
+
+    /// Set when the test binary is started again by `client_joins_host_and_chats`, to run
+    /// `lan_test_host` in a process of its own
+    const TEST_HOST_ENV: &str = "CHECKER_MATER_TEST_HOST";
+
+    /// The host of `client_joins_host_and_chats`, which needs a process of its own, since the
+    /// network state is shared by everything in a process. Prints its join code, answers the
+    /// first chat message, and stops once the client has left.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "started by client_joins_host_and_chats"]
+    async fn lan_test_host() {
+        if std::env::var_os(TEST_HOST_ENV).is_none() {
+            return;
+        }
+        reset_state().await;
+        status::set_my_username("alice").await;
+        let join_code = start_lan_host_async(ColorAssignment::HostWhite)
+            .await
+            .unwrap();
+        println!("join code: {}", join_code);
+
+        let mut tick = tokio::time::interval(Duration::from_millis(20));
+        let mut answered = false;
+        for _ in 0..500 {
+            tick.tick().await;
+            if let Some((sender, message)) = get_next_chat_message_async().await {
+                assert_eq!((sender.as_str(), message.as_str()), ("bob", "hi"));
+                send_chat_message_async("hello").await.unwrap();
+                answered = true;
+            }
+            if get_disconnect_reason_async().await.is_some() {
+                break;
+            }
+        }
+        assert!(answered);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn client_joins_host_and_chats() {
+        use tokio::io::{AsyncBufReadExt, BufReader};
+
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        let mut host = tokio::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "net::interface::tests::lan_test_host"])
+            .args(["--ignored", "--nocapture", "--test-threads=1"])
+            .env(TEST_HOST_ENV, "1")
+            .stdout(std::process::Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .unwrap();
+        let mut lines = BufReader::new(host.stdout.take().unwrap()).lines();
+        let join_code = loop {
+            let line = tokio::time::timeout(Duration::from_secs(30), lines.next_line())
+                .await
+                .expect("the host didn't print its join code")
+                .unwrap()
+                .expect("the host stopped before printing its join code");
+            // The test harness prints the name of the test on the same line
+            if let Some((_, join_code)) = line.split_once("join code: ") {
+                break join_code.to_owned();
+            }
+        };
+
+        start_lan_client_async(CLIENT_WITHOUT_PINGS).await.unwrap();
+        let (color, host_username) = connect_to_host_async(
+            &join_code,
+            "bob",
+            DEFAULT_JOIN_ATTEMPTS,
+            Duration::from_secs(10),
+        )
+        .await
+        .unwrap();
+        assert_eq!(color, PieceColor::Black);
+        assert_eq!(host_username, "alice");
+        assert!(is_connected_async().await);
+
+        send_chat_message_async("hi").await.unwrap();
+        let mut tick = tokio::time::interval(Duration::from_millis(20));
+        let mut chat = None;
+        for _ in 0..250 {
+            tick.tick().await;
+            chat = get_next_chat_message_async().await;
+            if chat.is_some() {
+                break;
+            }
+        }
+        assert_eq!(chat, Some(("alice".to_owned(), "hello".to_owned())));
+
+        assert!(disconnect_async("the test is done").await);
+        let exit = tokio::time::timeout(Duration::from_secs(30), host.wait())
+            .await
+            .expect("the host didn't stop after the client left")
+            .unwrap();
+        assert!(exit.success());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn mismatched_protocol_version_is_rejected() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        let join_code = start_test_host().await;
+        let host = validate_join_code(&join_code).unwrap();
+        let client = MockPeer::bind().await;
+
+        let packet = P2pRequestPacket::Connect {
+            join_code,
+            username: "bob".to_owned(),
+            protocol_version: PROTOCOL_VERSION + 1,
+        };
+        let join = P2pRequest::new(status::CONNECT_SESSION_ID, 1, packet);
+        assert_eq!(
+            client.request(join, host).await.packet,
+            P2pResponsePacket::error(P2pError::IncompatibleVersion)
+        );
+        assert!(!is_connected_async().await);
+        assert_eq!(status::get_other_addr().await, None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn second_client_is_refused() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        let join_code = start_test_host().await;
+        let host = validate_join_code(&join_code).unwrap();
+        let first = MockPeer::bind().await;
+        let second = MockPeer::bind().await;
+        let join = |transaction_id, username| {
+            P2pRequest::new(
+                status::CONNECT_SESSION_ID,
+                transaction_id,
+                P2pRequestPacket::connect(&join_code, username),
+            )
+        };
+
+        let joined = first.request(join(1, "bob"), host).await;
+        assert!(matches!(joined.packet, P2pResponsePacket::Connect { .. }));
+        assert_eq!(
+            second.request(join(2, "eve"), host).await.packet,
+            P2pResponsePacket::error(P2pError::FullGameSession)
+        );
+
+        // The joined client asking again is answered like the first time
+        let rejoined = first.request(join(3, "bob"), host).await;
+        assert_eq!(rejoined.packet, joined.packet);
+        assert_eq!(rejoined.session_id, joined.session_id);
+        assert_eq!(status::get_other_addr().await, Some(first.addr()));
+        assert_eq!(status::get_other_username().await.as_deref(), Some("bob"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn full_game_is_a_typed_error() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        let host = MockPeer::bind().await;
+        start_test_client(&host).await;
+
+        let join_code = hex_encode_ip(host.addr());
+        let connect = tokio::spawn(async move {
+            connect_to_host_async(
+                &join_code,
+                "eve",
+                DEFAULT_JOIN_ATTEMPTS,
+                DEFAULT_CONNECT_TIMEOUT,
+            )
+            .await
+        });
+        let (request, client) = host.next_request().await.unwrap();
+        assert!(matches!(request.packet, P2pRequestPacket::Connect { .. }));
+        let packet = P2pResponsePacket::error(P2pError::FullGameSession);
+        host.respond(&request, packet, client).await;
+
+        let result = connect.await.unwrap();
+        assert!(
+            matches!(
+                result,
+                Err(NetworkError::Refused {
+                    kind: P2pError::FullGameSession
+                })
+            ),
+            "{:?}",
+            result
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn duplicate_move_is_applied_once() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        let join_code = start_test_host().await;
+        let client = MockPeer::bind().await;
+        let (host, session_id) = join_test_host(&join_code, &client).await;
+        set_board_state_async(start_pieces()).await;
+        set_whose_turn_async(PieceColor::Black).await;
+
+        let mov = BitBoard::from_pieces(&start_pieces(), PieceColor::White)
+            .legal_moves(false)
+            .unwrap()
+            .remove(0);
+        let packet = P2pRequestPacket::game_action(GameAction::MovePiece(mov), 0);
+        let request = P2pRequest::new(session_id, 1, packet);
+        // The same packet twice, and then again under another transaction id
+        let mut resent = request.clone();
+        resent.transaction_id = 2;
+        for request in [request.clone(), request, resent] {
+            let response = client.request(request, host).await;
+            assert_eq!(response.packet, P2pResponsePacket::Acknowledge);
+        }
+
+        assert!(matches!(
+            get_next_game_action_async().await,
+            Some(GameAction::MovePiece(_))
+        ));
+        assert!(get_next_game_action_async().await.is_none());
+        assert_eq!(whose_turn_async().await, PieceColor::White);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn second_move_in_a_row_is_out_of_turn() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        let join_code = start_test_host().await;
+        let client = MockPeer::bind().await;
+        let (host, session_id) = join_test_host(&join_code, &client).await;
+        set_board_state_async(start_pieces()).await;
+        set_whose_turn_async(PieceColor::Black).await;
+
+        let mut moves = BitBoard::from_pieces(&start_pieces(), PieceColor::White)
+            .legal_moves(false)
+            .unwrap();
+        let first = P2pRequestPacket::game_action(GameAction::MovePiece(moves.remove(0)), 0);
+        let response = client
+            .request(P2pRequest::new(session_id, 1, first), host)
+            .await;
+        assert_eq!(response.packet, P2pResponsePacket::Acknowledge);
+        assert_eq!(whose_turn_async().await, PieceColor::White);
+
+        let second = P2pRequestPacket::game_action(GameAction::MovePiece(moves.remove(0)), 0);
+        let response = client
+            .request(P2pRequest::new(session_id, 2, second), host)
+            .await;
+        assert_eq!(
+            response.packet,
+            P2pResponsePacket::error(P2pError::OutOfTurn)
+        );
+        assert_eq!(whose_turn_async().await, PieceColor::White);
+
+        // Only the first move reaches the game
+        assert!(matches!(
+            get_next_game_action_async().await,
+            Some(GameAction::MovePiece(_))
+        ));
+        assert!(get_next_game_action_async().await.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn disconnect_from_client_closes_host() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        let join_code = start_test_host().await;
+        let client = MockPeer::bind().await;
+        let (host, session_id) = join_test_host(&join_code, &client).await;
+
+        let packet = P2pRequestPacket::Disconnect {
+            reason: "bye".to_owned(),
+        };
+        let response = client
+            .request(P2pRequest::new(session_id, 1, packet), host)
+            .await;
+        assert_eq!(response.packet, P2pResponsePacket::Acknowledge);
+        assert!(matches!(
+            status::get_connection_status().await,
+            ConnectionStatus::Disconnected
+        ));
+        assert_eq!(get_disconnect_reason_async().await.as_deref(), Some("bye"));
+        assert_eq!(status::get_other_addr().await, None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn disconnect_from_host_is_acknowledged() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        let join_code = start_test_host().await;
+        let client = MockPeer::bind().await;
+        let (host, _) = join_test_host(&join_code, &client).await;
+
+        let started = tokio::time::Instant::now();
+        let disconnect = tokio::spawn(disconnect_async("closing"));
+        let (request, _) = client.next_request().await.unwrap();
+        assert!(matches!(
+            request.packet,
+            P2pRequestPacket::Disconnect { ref reason } if reason == "closing"
+        ));
+        client
+            .respond(&request, P2pResponsePacket::Acknowledge, host)
+            .await;
+
+        assert!(disconnect.await.unwrap());
+        assert!(started.elapsed() < Duration::from_millis(DISCONNECT_WAIT_MS));
+        assert!(matches!(
+            status::get_connection_status().await,
+            ConnectionStatus::Disconnected
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn client_pings_at_the_configured_rate() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        let host = MockPeer::bind().await;
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        status::set_other_addr(host.addr()).await;
+        status::set_connection_status(ConnectionStatus::connected()).await;
+        let config = ClientConfig {
+            pings_per_second: 10,
+            ..ClientConfig::DEFAULT
+        };
+        client_network_loop(socket, config, DEFAULT_MAX_OUTGOING_LEN).await;
+
+        let mut arrivals = vec![];
+        while arrivals.len() < 5 {
+            let (packet, client) = host.recieve().await.expect("No ping recieved");
+            if let P2pPacket::Request(request) = packet {
+                assert!(matches!(request.packet, P2pRequestPacket::Ping));
+                arrivals.push(tokio::time::Instant::now());
+                host.respond(&request, P2pResponsePacket::Pong, client)
+                    .await;
+            }
+        }
+        let average = (arrivals[4] - arrivals[0]) / 4;
+        assert!(
+            average > Duration::from_millis(60) && average < Duration::from_millis(160),
+            "{:?}",
+            average
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn connecting_to_silent_host_times_out() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        let host = MockPeer::bind().await;
+        start_test_client(&host).await;
+
+        let timeout = Duration::from_millis(400);
+        let started = tokio::time::Instant::now();
+        let result =
+            connect_to_host_async(&hex_encode_ip(host.addr()), "bob", u32::MAX, timeout).await;
+        let elapsed = started.elapsed();
+
+        assert!(matches!(result, Err(NetworkError::Timeout { timeout: t }) if t == timeout));
+        assert!(elapsed >= timeout, "{:?}", elapsed);
+        assert!(
+            elapsed < timeout + Duration::from_millis(JOIN_POLL_MS * 4),
+            "{:?}",
+            elapsed
+        );
+    }
+
+    #[tokio::test]
+    async fn malformed_join_code_is_an_error() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+
+        let result = send_join_request_async("not a code", "bob").await;
+        assert!(matches!(result, Err(NetworkError::InvalidJoinCode { .. })));
+        let result = connect_to_host_async("7f00", "bob", 1, DEFAULT_CONNECT_TIMEOUT).await;
+        assert!(matches!(result, Err(NetworkError::InvalidJoinCode { .. })));
+        assert_eq!(get_outgoing_queue_len().await, 0);
+    }
+
+    #[tokio::test]
+    async fn refused_local_requests_are_typed_errors() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+
+        let result = send_chat_message_async(&"a".repeat(MAX_CHAT_LEN + 1)).await;
+        assert!(matches!(
+            result,
+            Err(NetworkError::ChatTooLong {
+                max: MAX_CHAT_LEN,
+                ..
+            })
+        ));
+        assert!(matches!(
+            attempt_reconnect_async().await,
+            Err(NetworkError::NoSession)
+        ));
+        assert!(matches!(
+            reconnect_async(1).await,
+            Err(NetworkError::NoSession)
+        ));
+        assert!(matches!(
+            start_spectating_async("not a code").await,
+            Err(NetworkError::InvalidJoinCode { .. })
+        ));
+        assert_eq!(get_outgoing_queue_len().await, 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn recieved_surrender_wins_the_game() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        let host = MockPeer::bind().await;
+        start_test_client(&host).await;
+        let client = client_addr(&host).await;
+        set_my_color_async(PieceColor::Black).await;
+
+        let surrender = game_action_request(1, GameAction::Surrender);
+        assert_eq!(
+            host.request(surrender, client).await.packet,
+            P2pResponsePacket::Acknowledge
+        );
+        let result = get_game_result_async().await.unwrap();
+        assert_eq!(
+            result,
+            GameResult::Resigned {
+                loser: PieceColor::White
+            }
+        );
+        assert_eq!(result.loser(), Some(PieceColor::White));
+        assert!(matches!(
+            get_next_game_action_async().await,
+            Some(GameAction::Surrender)
+        ));
+
+        // Neither side plays on after the game is over
+        let mut offered = send_action(GameAction::OfferDraw).await;
+        assert_eq!(offered.recv().await, Some(false));
+        let offer = game_action_request(2, GameAction::OfferDraw);
+        assert_eq!(
+            host.request(offer, client).await.packet,
+            P2pResponsePacket::error(P2pError::GameOver)
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sent_surrender_loses_the_game_at_once() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        let host = MockPeer::bind().await;
+        start_test_client(&host).await;
+        set_my_color_async(PieceColor::Black).await;
+
+        // The result is set before the host has answered
+        let mut surrendered = send_action(GameAction::Surrender).await;
+        assert_eq!(
+            get_game_result_async().await,
+            Some(GameResult::Resigned {
+                loser: PieceColor::Black
+            })
+        );
+
+        let (request, client) = host.next_request().await.unwrap();
+        assert!(matches!(
+            request.packet,
+            P2pRequestPacket::GameAction {
+                action: GameAction::Surrender,
+                ..
+            }
+        ));
+        host.respond(&request, P2pResponsePacket::Acknowledge, client)
+            .await;
+        assert_eq!(surrendered.recv().await, Some(true));
+
+        let mut offered = send_action(GameAction::OfferDraw).await;
+        assert_eq!(offered.recv().await, Some(false));
+    }
+
+    #[tokio::test]
+    async fn surrender_is_undone_when_the_queue_is_full() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        set_my_color_async(PieceColor::Black).await;
+        queue::set_max_outgoing_len(1);
+        let resync = P2pRequest::new(0, 1, P2pRequestPacket::Resync);
+        push_outgoing_queue(P2pPacket::Request(resync), None)
+            .await
+            .unwrap();
+
+        let mut surrendered = send_action(GameAction::Surrender).await;
+        assert_eq!(surrendered.recv().await, Some(false));
+        assert_eq!(get_game_result_async().await, None);
+
+        queue::set_max_outgoing_len(DEFAULT_MAX_OUTGOING_LEN);
+    }
+
+    #[tokio::test]
+    async fn move_keeps_the_turn_when_the_queue_is_full() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        set_my_color_async(PieceColor::White).await;
+        queue::set_max_outgoing_len(1);
+        let resync = P2pRequest::new(0, 1, P2pRequestPacket::Resync);
+        push_outgoing_queue(P2pPacket::Request(resync), None)
+            .await
+            .unwrap();
+
+        let mov = BitBoard::from_pieces(&start_pieces(), PieceColor::White)
+            .legal_moves(true)
+            .unwrap()
+            .remove(0);
+        let mut moved = send_action(GameAction::MovePiece(mov)).await;
+        assert_eq!(moved.recv().await, Some(false));
+        assert_eq!(whose_turn_async().await, PieceColor::White);
+
+        queue::set_max_outgoing_len(DEFAULT_MAX_OUTGOING_LEN);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn spectator_recieves_the_players_move() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        let join_code = start_test_host().await;
+        let client = MockPeer::bind().await;
+        let (host, session_id) = join_test_host(&join_code, &client).await;
+        set_board_state_async(start_pieces()).await;
+        set_whose_turn_async(PieceColor::Black).await;
+
+        let spectator = MockPeer::bind().await;
+        let packet = P2pRequestPacket::Spectate {
+            join_code: join_code.clone(),
+        };
+        let response = spectator
+            .request(P2pRequest::new(status::CONNECT_SESSION_ID, 0, packet), host)
+            .await;
+        assert!(matches!(response.packet, P2pResponsePacket::Resync { .. }));
+
+        let mov = BitBoard::from_pieces(&start_pieces(), PieceColor::White)
+            .legal_moves(false)
+            .unwrap()
+            .remove(0);
+        let packet = P2pRequestPacket::game_action(GameAction::MovePiece(mov.clone()), 0);
+        let response = client
+            .request(P2pRequest::new(session_id, 1, packet), host)
+            .await;
+        assert_eq!(response.packet, P2pResponsePacket::Acknowledge);
+
+        let (forwarded, from) = spectator.next_request().await.unwrap();
+        assert_eq!(from, host);
+        match forwarded.packet {
+            P2pRequestPacket::GameAction {
+                action: GameAction::MovePiece(forwarded_move),
+                ..
+            } => assert_eq!(forwarded_move, mov),
+            other => panic!("the spectator recieved {:?}", other),
+        }
+
+        // The spectator can watch, but not play
+        let packet = P2pRequestPacket::game_action(GameAction::Surrender, 0);
+        let response = spectator
+            .request(P2pRequest::new(status::CONNECT_SESSION_ID, 1, packet), host)
+            .await;
+        assert_eq!(
+            response.packet,
+            P2pResponsePacket::error(P2pError::Spectator)
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn host_without_a_join_code_refuses_spectators() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let host = socket.local_addr().unwrap();
+        status::set_my_color(PieceColor::White).await;
+        host_network_loop(socket, DEFAULT_MAX_OUTGOING_LEN).await;
+
+        let spectator = MockPeer::bind().await;
+        for transaction_id in [0, 1] {
+            let packet = P2pRequestPacket::Spectate {
+                join_code: hex_encode_ip(host),
+            };
+            let response = spectator
+                .request(
+                    P2pRequest::new(status::CONNECT_SESSION_ID, transaction_id, packet),
+                    host,
+                )
+                .await;
+            assert_eq!(
+                response.packet,
+                P2pResponsePacket::error(P2pError::InvalidJoinCode)
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn waiting_host_answers_discovery_probes() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        let join_code = start_test_host().await;
+        let host = validate_join_code(&join_code).unwrap();
+        let target = SocketAddr::new(host.ip(), net_utils::DISCOVERY_PORT);
+
+        // The responder of an earlier test may still hold the discovery port for a moment
+        let mut found = vec![];
+        for _ in 0..10 {
+            found = net_utils::probe_hosts(target, Duration::from_millis(300)).await;
+            if !found.is_empty() {
+                break;
+            }
+        }
+        assert_eq!(found, vec![("alice".to_owned(), host)]);
+
+        // Once the client has joined, the game isn't open anymore
+        let client = MockPeer::bind().await;
+        join_test_host(&join_code, &client).await;
+        let found = net_utils::probe_hosts(target, Duration::from_millis(300)).await;
+        assert!(found.is_empty(), "{:?}", found);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn acked_request_is_counted() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        let host = MockPeer::bind().await;
+        start_test_client(&host).await;
+        reset_net_stats();
+
+        send_chat_message_async("hi").await.unwrap();
+        let (request, client) = host.next_request().await.unwrap();
+        host.respond(&request, P2pResponsePacket::Acknowledge, client)
+            .await;
+
+        // The loop counts the response once it has handled it
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(2);
+        while get_net_stats_async().await.packets_acked == 0 {
+            assert!(
+                tokio::time::Instant::now() < deadline,
+                "the ack wasn't counted"
+            );
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let stats = get_net_stats_async().await;
+        assert!(stats.packets_sent >= 1, "{:?}", stats);
+        assert!(stats.packets_recieved >= 1, "{:?}", stats);
+        assert_eq!(stats.timeouts, 0);
+    }
 }
@@ -1,4 +1,7 @@
 pub mod interface;
 mod net_utils;
 mod p2p;
+pub mod relay;
 mod status;
+#[cfg(test)]
+mod test_utils;
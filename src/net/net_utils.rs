@@ -1,8 +1,16 @@
-use std::{hint, net::{IpAddr, Ipv4Addr, SocketAddr}};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::RangeInclusive,
+    time::Duration,
+};
 
 use anyhow::anyhow;
-use local_ip_address::local_ip;
+use local_ip_address::{local_ip, local_ipv6};
 use thiserror::Error;
+use tracing::{debug, info, warn};
+
+use super::p2p::{P2pError, MAX_USERNAME_LEN, PACKET_MAGIC, PROTOCOL_VERSION};
 
 /// Turn the data into bytes ready to be sent over the network. The packet is in BE (Big Endian)
 /// order.
@@ -30,6 +38,10 @@ pub enum PacketError {
     Empty,
     #[error("Data error. Reason: {reason:?}")]
     DataError { reason: String },
+    #[error("Invalid packet header, the packet isn't from this game")]
+    InvalidMagic,
+    #[error("Unknown packet format version {version}")]
+    UnknownVersion { version: u8 },
 }
 impl PacketError {
     pub fn invalid_length(expected: usize, got: usize) -> Self {
@@ -46,14 +58,44 @@ impl PacketError {
 pub enum NetworkError {
     #[error("Couldn't find an available port in range 6000..=7000")]
     PortBindingError,
-    #[error("Failed to get local IPv4 address")]
-    GetIpV4Error,
+    #[error("Failed to get local IP address")]
+    GetIpError,
     #[error("Error occured while sending data: {details:?}")]
     SendError { details: String },
     #[error("Error occured while recieving data: {details:?}")]
     RecieveError { details: String },
     #[error("Response Type Error: Got wrong data type in return")]
     ResponseTypeError,
+    #[error("Got no response to request {transaction_id} after sending it {tries} times")]
+    NoResponse { transaction_id: u16, tries: u32 },
+    #[error(
+        "The host uses another protocol version than this game ({local}), one of you has to update"
+    )]
+    IncompatibleVersion { local: u16, host: Option<u16> },
+    #[error("The outgoing queue is full, it can hold at most {capacity} packets")]
+    QueueFull { capacity: usize },
+    #[error("Failed to bind socket to port {port}: {source}")]
+    BindFailed { port: u16, source: io::Error },
+    #[error("Invalid join code {join_code:?}: {reason}")]
+    InvalidJoinCode {
+        join_code: String,
+        reason: &'static str,
+    },
+    #[error("Connection timed out after {timeout:?}")]
+    Timeout { timeout: Duration },
+    #[error("The host didn't respond to {attempts} join requests")]
+    HostUnreachable { attempts: u32 },
+    #[error("The request was refused by the other peer: {kind:?}")]
+    Refused { kind: P2pError },
+    #[error("Invalid username {username:?}: {reason}")]
+    InvalidUsername {
+        username: String,
+        reason: &'static str,
+    },
+    #[error("There is no session to reconnect to")]
+    NoSession,
+    #[error("Chat message is {len} bytes long, but can at most be {max} bytes")]
+    ChatTooLong { len: usize, max: usize },
 }
 impl NetworkError {
     pub fn send_error(details: &str) -> Self {
@@ -68,62 +110,372 @@ impl NetworkError {
     }
 }
 
-pub async fn get_available_port() -> anyhow::Result<u16> {
-    for port_id in 6000..=7000 {
+/// Checks that `name` can be used as a username. It must be 1 to `MAX_USERNAME_LEN` characters
+/// long, and can't contain control characters, like newlines.
+pub fn validate_username(name: &str) -> Result<(), NetworkError> {
+    let reason = if name.is_empty() {
+        "it is empty"
+    } else if name.chars().count() > MAX_USERNAME_LEN {
+        "it is too long"
+    } else if name.chars().any(char::is_control) {
+        "it contains control characters"
+    } else {
+        return Ok(());
+    };
+
+    // A long username is cut, so it doesn't fill the logs
+    Err(NetworkError::InvalidUsername {
+        username: name.chars().take(MAX_USERNAME_LEN).collect(),
+        reason,
+    })
+}
+
+/// The ports the peers bind their sockets to
+const PEER_PORTS: RangeInclusive<u16> = 6000..=7000;
+
+pub async fn get_available_port() -> Result<u16, NetworkError> {
+    first_free_port(PEER_PORTS).await
+}
+
+/// Returns the first port in `ports` a UDP socket can be bound to
+async fn first_free_port(ports: RangeInclusive<u16>) -> Result<u16, NetworkError> {
+    for port_id in ports {
         if (tokio::net::UdpSocket::bind(("0.0.0.0", port_id)).await).is_ok() {
             return Ok(port_id);
         }
     }
-    Err(NetworkError::PortBindingError.into())
+    Err(NetworkError::PortBindingError)
 }
 
-pub fn get_local_ip() -> anyhow::Result<Ipv4Addr> {
+/// Returns the local IP address other peers can reach this computer on.
+/// A Hamachi address is preferred, then any IPv4 address, and an IPv6 address is only used if
+/// there is no IPv4 address.
+pub fn get_local_ip() -> Result<IpAddr, NetworkError> {
     let hamachi_netifas: Option<(String, IpAddr)> = local_ip_address::list_afinet_netifas()
-        .unwrap()
+        .map_err(|_| NetworkError::GetIpError)?
         .into_iter()
         .filter(|netifas| matches!(netifas.1, IpAddr::V4(_)))
         .find(|x| x.0.to_lowercase().trim() == "hamachi");
 
-        if let Some(netifas) = hamachi_netifas {
-            print!("Found Hamachi IP!!");
-            return match netifas.1 {
-                IpAddr::V4(ip) => Ok(ip),
-                _ => unsafe { hint::unreachable_unchecked(); }
-            }
-        }
+    if let Some((_, ip)) = hamachi_netifas {
+        info!("Using Hamachi address {}", ip);
+        return Ok(ip);
+    }
 
-    if let Ok(IpAddr::V4(ip)) = local_ip() {
-        Ok(ip)
-    } else {
-        Err(NetworkError::GetIpV4Error.into())
+    if let Ok(ip @ IpAddr::V4(_)) = local_ip() {
+        return Ok(ip);
+    }
+
+    match local_ipv6() {
+        Ok(ip @ IpAddr::V6(_)) => Ok(ip),
+        _ => Err(NetworkError::GetIpError),
+    }
+}
+
+/// Returns the unspecified address of the same family as `ip`, for binding a socket which can
+/// recieve packets sent to `ip`.
+pub fn unspecified_addr(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    }
+}
+
+/// Address family tag of a join code holding an IPv4 address
+const JOIN_CODE_V4_TAG: u8 = 4;
+/// Address family tag of a join code holding an IPv6 address
+const JOIN_CODE_V6_TAG: u8 = 6;
+
+/// Encodes `addr` as a HEX string, which is used as the join code.
+/// The first byte tags the address family, followed by the address and the port.
+/// The flow info and scope id of IPv6 addresses are not included.
+pub fn hex_encode_ip(addr: SocketAddr) -> String {
+    let mut bytes = vec![];
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            bytes.push(JOIN_CODE_V4_TAG);
+            bytes.append(&mut ip.octets().to_vec());
+        }
+        IpAddr::V6(ip) => {
+            bytes.push(JOIN_CODE_V6_TAG);
+            bytes.append(&mut ip.octets().to_vec());
+        }
     }
+    bytes.append(&mut addr.port().to_be_bytes().to_vec());
+    hex::encode(bytes)
 }
 
-pub fn hex_encode_ip(addr: SocketAddr) -> anyhow::Result<String> {
-    if let IpAddr::V4(ip) = addr.ip() {
-        let ip_u32: u32 = ip.into();
+/// The lengths of the join codes made by `hex_encode_ip`, for untagged IPv4, IPv4 and IPv6
+const JOIN_CODE_LENS: [usize; 3] = [12, 14, 38];
 
-        let mut bytes = vec![];
-        bytes.append(&mut ip_u32.to_be_bytes().to_vec());
-        bytes.append(&mut addr.port().to_be_bytes().to_vec());
-        Ok(hex::encode(bytes))
+/// Checks that `code` looks like a join code made by `hex_encode_ip`, and decodes it.
+/// Unlike `hex_decode_ip`, the error tells what is wrong with the code, so it can be shown to
+/// the user right away.
+pub fn validate_join_code(code: &str) -> Result<SocketAddr, NetworkError> {
+    let reason = if code.is_empty() {
+        "it is empty"
+    } else if !code.chars().all(|c| c.is_ascii_hexdigit()) {
+        "it can only contain the digits 0-9 and the letters a-f"
+    } else if !JOIN_CODE_LENS.contains(&code.len()) {
+        "it has the wrong length, part of it may be missing"
     } else {
-        Err(NetworkError::GetIpV4Error.into())
+        return hex_decode_ip(code);
+    };
+
+    Err(NetworkError::InvalidJoinCode {
+        join_code: code.to_owned(),
+        reason,
+    })
+}
+
+/// Decodes a join code made by `hex_encode_ip`.
+/// Old join codes without the address family tag are read as IPv4.
+/// Returns `NetworkError::InvalidJoinCode` if `data` isn't a join code.
+pub fn hex_decode_ip(data: &str) -> Result<SocketAddr, NetworkError> {
+    let invalid_join_code = || NetworkError::InvalidJoinCode {
+        join_code: data.to_owned(),
+        reason: "it isn't a join code",
+    };
+    let bytes = hex::decode(data).map_err(|_| invalid_join_code())?;
+
+    let (ip, port): (IpAddr, &[u8]) = match (bytes.len(), bytes.first()) {
+        // Untagged IPv4
+        (6, _) => {
+            let ip: [u8; 4] = bytes[..4].try_into().unwrap();
+            (ip.into(), &bytes[4..])
+        }
+        (7, Some(&JOIN_CODE_V4_TAG)) => {
+            let ip: [u8; 4] = bytes[1..5].try_into().unwrap();
+            (ip.into(), &bytes[5..])
+        }
+        (19, Some(&JOIN_CODE_V6_TAG)) => {
+            let ip: [u8; 16] = bytes[1..17].try_into().unwrap();
+            (ip.into(), &bytes[17..])
+        }
+        _ => return Err(invalid_join_code()),
+    };
+    let port = u16::from_be_bytes(port.try_into().unwrap());
+
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// The port LAN hosts listen on for the probes of `discover_hosts`. It is outside the range of
+/// `get_available_port`, so a host never plays on it.
+pub const DISCOVERY_PORT: u16 = 7100;
+/// Sent by `discover_hosts` to ask the hosts on the LAN for their join info
+const DISCOVERY_PROBE: [u8; 3] = [PACKET_MAGIC[0], PACKET_MAGIC[1], b'?'];
+/// The start of the answer to a `DISCOVERY_PROBE`, see `encode_discovery_reply`
+const DISCOVERY_REPLY: [u8; 3] = [PACKET_MAGIC[0], PACKET_MAGIC[1], b'!'];
+
+/// Returns true if `data` is a probe sent by `discover_hosts`.
+pub fn is_discovery_probe(data: &[u8]) -> bool {
+    data == DISCOVERY_PROBE
+}
+
+/// The answer of a host to a discovery probe. It is `DISCOVERY_REPLY`, followed by the
+/// `PROTOCOL_VERSION` of the host, the length of the join code as one byte, the join code and the
+/// username of the host.
+pub fn encode_discovery_reply(username: &str, join_code: &str) -> Vec<u8> {
+    let mut bytes = DISCOVERY_REPLY.to_vec();
+    bytes.append(&mut PROTOCOL_VERSION.to_be_bytes().to_vec());
+    bytes.push(join_code.len() as u8);
+    bytes.append(&mut join_code.as_bytes().to_vec());
+    bytes.append(&mut username.as_bytes().to_vec());
+    bytes
+}
+
+/// Reads a reply made by `encode_discovery_reply`, and returns the username of the host and the
+/// address its game can be joined on.
+/// Fails if `data` isn't a reply, or the host uses another `PROTOCOL_VERSION`, since it can't be
+/// joined then.
+fn decode_discovery_reply(data: &[u8]) -> anyhow::Result<(String, SocketAddr)> {
+    let header_len = DISCOVERY_REPLY.len() + 3;
+    if data.len() < header_len {
+        return Err(PacketError::invalid_length(header_len, data.len()).into());
+    }
+    if data[..DISCOVERY_REPLY.len()] != DISCOVERY_REPLY {
+        return Err(PacketError::InvalidMagic.into());
+    }
+
+    let version = u16::from_be_bytes(data[3..5].try_into().unwrap());
+    if version != PROTOCOL_VERSION {
+        return Err(anyhow!(
+            "Host uses protocol version {}, but this game uses {}",
+            version,
+            PROTOCOL_VERSION
+        ));
     }
+
+    let code_end = header_len + data[5] as usize;
+    if data.len() < code_end {
+        return Err(PacketError::invalid_length(code_end, data.len()).into());
+    }
+    let join_code = String::from_utf8(data[header_len..code_end].to_vec())
+        .map_err(|_| PacketError::data_error("Invalid UFT8 encoded values for join code"))?;
+    let username = String::from_utf8(data[code_end..].to_vec())
+        .map_err(|_| PacketError::data_error("Invalid UFT8 encoded values for username"))?;
+
+    Ok((username, hex_decode_ip(&join_code)?))
+}
+
+/// Broadcasts a discovery probe on the LAN, and returns the username and address of every host
+/// which answers within `timeout`. Only hosts waiting for a client answer, so every host returned
+/// can be joined, eg. with the join code `hex_encode_ip` makes from its address.
+pub async fn discover_hosts(timeout: Duration) -> Vec<(String, SocketAddr)> {
+    probe_hosts(
+        SocketAddr::new(Ipv4Addr::BROADCAST.into(), DISCOVERY_PORT),
+        timeout,
+    )
+    .await
 }
 
-pub fn hex_decode_ip(data: &str) -> anyhow::Result<SocketAddr> {
-    let bytes = match hex::decode(data) {
-        Ok(bytes) => bytes,
-        Err(_) => return Err(anyhow!("Couldn't decode hex data")),
+/// Sends a discovery probe to `target`, which may be a broadcast address, and returns the
+/// username and address of every host which answers within `timeout`, see `discover_hosts`.
+pub async fn probe_hosts(target: SocketAddr, timeout: Duration) -> Vec<(String, SocketAddr)> {
+    let mut hosts = vec![];
+    let socket = match tokio::net::UdpSocket::bind(SocketAddr::new(
+        unspecified_addr(target.ip()),
+        0,
+    ))
+    .await
+    {
+        Ok(socket) => socket,
+        Err(err) => {
+            warn!("Failed to bind the discovery socket: {}", err);
+            return hosts;
+        }
     };
+    if target.is_ipv4() {
+        if let Err(err) = socket.set_broadcast(true) {
+            warn!(
+                "Failed to allow broadcasts on the discovery socket: {}",
+                err
+            );
+        }
+    }
+    if let Err(err) = socket.send_to(&DISCOVERY_PROBE, target).await {
+        warn!("Failed to send the discovery probe to {}: {}", target, err);
+        return hosts;
+    }
+
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut buffer = vec![0; 512];
+    while let Ok(result) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buffer)).await {
+        let (len, addr) = match result {
+            Ok(received) => received,
+            Err(err) => {
+                warn!("Failed to recieve discovery replies: {}", err);
+                break;
+            }
+        };
+        match decode_discovery_reply(&buffer[..len]) {
+            Ok(host) if !hosts.contains(&host) => hosts.push(host),
+            Ok(_) => {}
+            Err(err) => debug!("Ignored discovery reply from {:?}: {:#}", addr, err),
+        }
+    }
+    hosts
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddrV6;
 
-    if bytes.len() != 6 {
-        return Err(anyhow!("Wrong data length"));
+    use super::*;
+
+    #[tokio::test]
+    async fn taken_ports_are_an_error() {
+        let socket = tokio::net::UdpSocket::bind(("0.0.0.0", 0)).await.unwrap();
+        let port = socket.local_addr().unwrap().port();
+
+        assert!(matches!(
+            first_free_port(port..=port).await,
+            Err(NetworkError::PortBindingError)
+        ));
+    }
+
+    #[test]
+    fn join_codes_round_trip() {
+        for addr in ["192.168.1.20:6001", "[2001:db8::7]:6002", "[::1]:7000"] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            assert_eq!(hex_decode_ip(&hex_encode_ip(addr)).unwrap(), addr);
+        }
+
+        // Join codes from before the address family tag are still IPv4
+        assert_eq!(
+            hex_decode_ip("c0a801141771").unwrap(),
+            "192.168.1.20:6001".parse::<SocketAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn join_code_drops_scope_id() {
+        let link_local =
+            SocketAddr::V6(SocketAddrV6::new("fe80::1:2".parse().unwrap(), 6003, 0, 3));
+
+        let decoded = hex_decode_ip(&hex_encode_ip(link_local)).unwrap();
+        assert_eq!(decoded.ip(), link_local.ip());
+        assert_eq!(decoded.port(), 6003);
+        assert!(matches!(decoded, SocketAddr::V6(addr) if addr.scope_id() == 0));
+    }
+
+    #[test]
+    fn ipv6_host_binds_ipv6_socket() {
+        let host_ip: IpAddr = "fe80::1:2".parse().unwrap();
+        assert_eq!(unspecified_addr(host_ip), Ipv6Addr::UNSPECIFIED);
+        let host_ip: IpAddr = "192.168.1.20".parse().unwrap();
+        assert_eq!(unspecified_addr(host_ip), Ipv4Addr::UNSPECIFIED);
+
+        // The client finds the host's IPv6 address in the join code
+        let host = SocketAddr::new("2001:db8::7".parse().unwrap(), 6002);
+        assert_eq!(validate_join_code(&hex_encode_ip(host)).unwrap(), host);
+    }
+
+    #[test]
+    fn bad_usernames_are_rejected() {
+        let too_long = "a".repeat(MAX_USERNAME_LEN + 1);
+        let cases = [
+            ("", "it is empty"),
+            (too_long.as_str(), "it is too long"),
+            ("bob\n", "it contains control characters"),
+            ("b\u{7}ob", "it contains control characters"),
+        ];
+        for (name, expected) in cases {
+            match validate_username(name) {
+                Err(NetworkError::InvalidUsername { username, reason }) => {
+                    assert_eq!(reason, expected);
+                    assert!(username.chars().count() <= MAX_USERNAME_LEN);
+                }
+                other => panic!("{:?} gave {:?}", name, other),
+            }
+        }
+
+        assert!(validate_username("bob").is_ok());
+        assert!(validate_username("Æsir spiller").is_ok());
+        assert!(validate_username(&"ø".repeat(MAX_USERNAME_LEN)).is_ok());
     }
 
-    let ip = u32::from_be_bytes(bytes[..4].try_into().unwrap());
-    let port = u16::from_be_bytes(bytes[4..].try_into().unwrap());
+    #[test]
+    fn bad_join_codes_are_rejected() {
+        let code = hex_encode_ip("192.168.0.10:6000".parse().unwrap());
+        let cases = [
+            ("", "it is empty"),
+            (
+                "c0a8000a17zz",
+                "it can only contain the digits 0-9 and the letters a-f",
+            ),
+            (
+                &code[..code.len() - 4],
+                "it has the wrong length, part of it may be missing",
+            ),
+        ];
+        for (join_code, expected) in cases {
+            match validate_join_code(join_code) {
+                Err(NetworkError::InvalidJoinCode { reason, .. }) => assert_eq!(reason, expected),
+                other => panic!("{:?} gave {:?}", join_code, other),
+            }
+        }
 
-    Ok(SocketAddr::new(IpAddr::V4(ip.into()), port))
+        assert!(validate_join_code(&code).is_ok());
+    }
 }
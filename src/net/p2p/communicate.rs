@@ -1,8 +1,66 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    io::{Read, Write},
+    net::SocketAddr,
+    sync::Arc,
+};
 
-use crate::net::net_utils::{FromPacket, NetworkError, ToPacket};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
 
-use super::P2pPacket;
+use crate::net::{
+    net_utils::{NetworkError, PacketError, ToPacket},
+    relay, status,
+};
+
+use super::{decode_packet, encode_packet, queue, P2pPacket};
+
+/// The flag in front of a packet which is sent as it is
+const UNCOMPRESSED_FLAG: u8 = 0;
+/// The flag in front of a packet which is compressed with deflate
+const DEFLATE_FLAG: u8 = 1;
+/// Packets shorter than this are never compressed, since it wouldn't make them much shorter
+const COMPRESSION_THRESHOLD: usize = 256;
+/// The longest a packet may be after decompressing it, so a small packet can't take up a lot of
+/// memory
+const MAX_DECOMPRESSED_LEN: u64 = 64 * 1024;
+
+/// Puts the compression flag in front of `bytes`, a serialized packet, and compresses it if it's
+/// long enough, and compressing makes it shorter.
+pub fn compress_packet(bytes: &[u8]) -> Vec<u8> {
+    if bytes.len() >= COMPRESSION_THRESHOLD {
+        let mut encoder = DeflateEncoder::new(vec![DEFLATE_FLAG], Compression::fast());
+        if let Ok(compressed) = encoder.write_all(bytes).and_then(|_| encoder.finish()) {
+            if compressed.len() < bytes.len() + 1 {
+                return compressed;
+            }
+        }
+    }
+
+    let mut packet = Vec::with_capacity(bytes.len() + 1);
+    packet.push(UNCOMPRESSED_FLAG);
+    packet.extend_from_slice(bytes);
+    packet
+}
+
+/// Reads the compression flag of `packet`, and returns the serialized packet after it,
+/// decompressed if needed. See `compress_packet`.
+pub fn decompress_packet(packet: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let Some((&flag, bytes)) = packet.split_first() else {
+        return Err(PacketError::Empty.into());
+    };
+
+    match flag {
+        UNCOMPRESSED_FLAG => Ok(bytes.to_vec()),
+        DEFLATE_FLAG => {
+            let mut decompressed = vec![];
+            DeflateDecoder::new(bytes)
+                .take(MAX_DECOMPRESSED_LEN)
+                .read_to_end(&mut decompressed)
+                .map_err(|_| PacketError::data_error("Invalid compressed packet"))?;
+            Ok(decompressed)
+        }
+        _ => Err(PacketError::data_error("Invalid compression flag").into()),
+    }
+}
 
 /// Send a packet to the other machine over a P2P UDP protocol.
 /// # Example:
@@ -20,8 +78,17 @@ pub async fn send_p2p_packet<T: ToPacket>(
     packet: T,
     to: SocketAddr,
 ) -> anyhow::Result<usize> {
-    match socket.send_to(packet.to_packet().as_slice(), to).await {
-        Ok(bytes) => Ok(bytes),
+    let mut bytes = encode_packet(&packet);
+    // In relay mode `to` is the relay, which finds the other peer by the code in the header
+    if let Some((_, code)) = status::get_relay().await {
+        bytes = relay::wrap_frame(&code, &bytes);
+    }
+
+    match socket.send_to(bytes.as_slice(), to).await {
+        Ok(bytes) => {
+            queue::count_sent_packet();
+            Ok(bytes)
+        }
         Err(e) => Err(NetworkError::send_error(&e.to_string()).into()),
     }
 }
@@ -41,7 +108,12 @@ pub async fn recieve_p2p_packet(
     match socket.recv_from(&mut buffer).await {
         Ok((len, addr)) => {
             buffer.resize(len, 0);
-            let response = P2pPacket::from_packet(buffer.to_vec())?;
+            if status::get_relay().await.is_some() {
+                let (_, payload) = relay::unwrap_frame(&buffer)?;
+                buffer = payload.to_vec();
+            }
+            let response = decode_packet(&buffer)?;
+            queue::count_recieved_packet();
             Ok((response, addr))
         }
         Err(e) => {
@@ -49,3 +121,55 @@ pub async fn recieve_p2p_packet(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        game::{PieceColor, PieceData},
+        net::p2p::{P2pResponse, P2pResponsePacket},
+    };
+
+    #[test]
+    fn large_packet_round_trips_compressed() {
+        let bytes: Vec<u8> = (0..4096).map(|i| (i % 7) as u8).collect();
+        let compressed = compress_packet(&bytes);
+        assert_eq!(compressed[0], DEFLATE_FLAG);
+        assert!(compressed.len() < bytes.len());
+        assert_eq!(decompress_packet(&compressed).unwrap(), bytes);
+
+        // Short packets are sent as they are
+        let short = compress_packet(&bytes[..16]);
+        assert_eq!(short[0], UNCOMPRESSED_FLAG);
+        assert_eq!(decompress_packet(&short).unwrap(), &bytes[..16]);
+    }
+
+    #[test]
+    fn resync_packet_round_trips() {
+        let board: Vec<PieceData> = (0..32)
+            .map(|index| PieceData {
+                color: if index < 12 {
+                    PieceColor::Black
+                } else {
+                    PieceColor::White
+                },
+                is_active: !(12..20).contains(&index),
+                ..Default::default()
+            })
+            .collect();
+        let response = P2pResponse::new(7, 3, P2pResponsePacket::resync(board.clone()));
+
+        let P2pPacket::Response(decoded) = decode_packet(&encode_packet(&response)).unwrap() else {
+            panic!("Expected a response");
+        };
+        assert_eq!(decoded.transaction_id, 3);
+        assert_eq!(decoded.packet, P2pResponsePacket::Resync { board });
+    }
+
+    #[test]
+    fn corrupt_compressed_packet_is_an_error() {
+        assert!(decompress_packet(&[]).is_err());
+        assert!(decompress_packet(&[DEFLATE_FLAG, 0xff, 0x00, 0x12]).is_err());
+        assert!(decompress_packet(&[2, 1, 2, 3]).is_err());
+    }
+}
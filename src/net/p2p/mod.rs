@@ -6,7 +6,49 @@ use anyhow::anyhow;
 
 use super::net_utils::{FromPacket, PacketError, ToByte, ToPacket};
 
-use crate::game::{GameAction, Move, PieceColor, PieceData};
+use crate::game::{clock::TimeControl, GameAction, Move, PieceColor, PieceData};
+
+/// The longest chat message that can be sent, in bytes
+pub const MAX_CHAT_LEN: usize = 512;
+/// The longest username that can be used, in characters
+pub const MAX_USERNAME_LEN: usize = 32;
+/// The version of the packet format, sent when the client joins. Peers with different versions
+/// can't play together, so this must be bumped whenever the packet format changes.
+pub const PROTOCOL_VERSION: u16 = 11;
+/// The bytes every packet starts with, so packets from other programs are dropped right away
+pub const PACKET_MAGIC: [u8; 2] = *b"CM";
+/// The version of the packet header. Only bumped when the header itself changes, so a join
+/// request from a client with another `PROTOCOL_VERSION` can still be read and refused.
+pub const HEADER_VERSION: u8 = 1;
+/// The length of the header, see `encode_packet`
+const HEADER_LEN: usize = PACKET_MAGIC.len() + 1;
+
+/// Serializes `packet` for sending, behind the packet header. The header is `PACKET_MAGIC`
+/// followed by `HEADER_VERSION`, and the packet after it may be compressed, see
+/// `communicate::compress_packet`.
+pub fn encode_packet<T: ToPacket>(packet: &T) -> Vec<u8> {
+    let mut bytes = PACKET_MAGIC.to_vec();
+    bytes.push(HEADER_VERSION);
+    bytes.append(&mut communicate::compress_packet(&packet.to_packet()));
+    bytes
+}
+
+/// Reads a packet serialized with `encode_packet`.
+/// Returns a `PacketError` if the header is wrong, or if the packet is cut short or invalid.
+pub fn decode_packet(bytes: &[u8]) -> anyhow::Result<P2pPacket> {
+    if bytes.len() < HEADER_LEN {
+        return Err(PacketError::invalid_length(HEADER_LEN, bytes.len()).into());
+    }
+    if bytes[..PACKET_MAGIC.len()] != PACKET_MAGIC {
+        return Err(PacketError::InvalidMagic.into());
+    }
+    let version = bytes[PACKET_MAGIC.len()];
+    if version != HEADER_VERSION {
+        return Err(PacketError::UnknownVersion { version }.into());
+    }
+
+    P2pPacket::from_packet(communicate::decompress_packet(&bytes[HEADER_LEN..])?)
+}
 
 #[derive(Clone, Debug)]
 pub enum P2pPacket {
@@ -21,6 +63,14 @@ impl P2pPacket {
     pub fn is_response(&self) -> bool {
         matches!(self, Self::Response(_))
     }
+    /// Returns true for pings and their responses, which are the first to be thrown away when the
+    /// outgoing queue is full, since the next ping replaces them.
+    pub fn is_ping(&self) -> bool {
+        match self {
+            Self::Request(req) => matches!(req.packet, P2pRequestPacket::Ping),
+            Self::Response(resp) => resp.packet == P2pResponsePacket::Pong,
+        }
+    }
 }
 
 impl ToPacket for P2pPacket {
@@ -34,7 +84,10 @@ impl ToPacket for P2pPacket {
 
 impl FromPacket for P2pPacket {
     fn from_packet(packet: Vec<u8>) -> anyhow::Result<Self> {
-        match packet[0] {
+        let Some(&packet_type) = packet.first() else {
+            return Err(PacketError::Empty.into());
+        };
+        match packet_type {
             0 => match P2pRequest::from_packet(packet) {
                 Ok(req) => Ok(Self::Request(req)),
                 Err(e) => Err(e),
@@ -57,16 +110,21 @@ pub struct P2pRequest {
     pub session_id: u16,
     /// This specific transactions ID
     pub transaction_id: u16,
+    /// Counts up with every new request from the sender, but stays the same when a request is
+    /// sent again, so the reciever can drop requests it has already handled.
+    pub sequence: u32,
     /// The main packet of the request.
     pub packet: P2pRequestPacket,
 }
 
 impl P2pRequest {
-    /// Create a new `P2pRequest` from the sessions ID and the packet.
+    /// Create a new `P2pRequest` from the sessions ID and the packet, with the next sequence
+    /// number.
     pub fn new(session_id: u16, transaction_id: u16, packet: P2pRequestPacket) -> Self {
         Self {
             session_id,
             transaction_id,
+            sequence: queue::next_sequence(),
             packet,
         }
     }
@@ -78,6 +136,7 @@ impl ToPacket for P2pRequest {
         bytes.append(&mut 0u8.to_be_bytes().to_vec());
         bytes.append(&mut self.session_id.to_be_bytes().to_vec());
         bytes.append(&mut self.transaction_id.to_be_bytes().to_vec());
+        bytes.append(&mut self.sequence.to_be_bytes().to_vec());
         bytes.append(&mut self.packet.to_packet());
 
         bytes
@@ -86,19 +145,21 @@ impl ToPacket for P2pRequest {
 
 impl FromPacket for P2pRequest {
     fn from_packet(packet: Vec<u8>) -> anyhow::Result<Self> {
-        if packet.len() < 6 {
-            return Err(PacketError::invalid_length(6, packet.len()).into());
+        if packet.len() < 10 {
+            return Err(PacketError::invalid_length(10, packet.len()).into());
         }
         if packet[0] != 0 {
             return Err(PacketError::InavlidType.into());
         }
         let session_id = u16::from_be_bytes(packet[1..3].try_into().unwrap());
         let transaction_id = u16::from_be_bytes(packet[3..5].try_into().unwrap());
-        let packet = P2pRequestPacket::from_packet(packet[5..].to_vec())?;
+        let sequence = u32::from_be_bytes(packet[5..9].try_into().unwrap());
+        let packet = P2pRequestPacket::from_packet(packet[9..].to_vec())?;
 
         Ok(Self {
             session_id,
             transaction_id,
+            sequence,
             packet,
         })
     }
@@ -118,11 +179,29 @@ pub enum P2pRequestPacket {
         join_code: String,
         /// The clients username. Set by the clients user.
         username: String,
+        /// The `PROTOCOL_VERSION` of the client.
+        protocol_version: u16,
     },
     /// Ask the host for a copy of the correct board, so the client can resync theirs.
     Resync,
     /// Perform a game action
-    GameAction { action: GameAction },
+    /// `board_hash` is the hash of the senders board after the action, see `Board::board_hash`.
+    GameAction { action: GameAction, board_hash: u64 },
+    /// Ask the host to continue a session after the connection was lost. The session id of the
+    /// request must be the id of the lost session.
+    Reconnect,
+    /// A chat message to the other player, at most `MAX_CHAT_LEN` bytes long.
+    Chat { message: String },
+    /// The hash of the senders board after `ply` moves, see `Board::board_hash`. Sent after
+    /// every move, so the reciever can check that the boards haven't drifted apart.
+    SyncCheck { hash: u64, ply: u32 },
+    /// Ask the host to watch the game without playing. `join_code` is the same code used by
+    /// `P2pRequestPacket::Connect`. The host responds with its board, and then forwards every
+    /// move to the spectator.
+    Spectate { join_code: String },
+    /// Tell the other peer that the connection is closed on purpose, eg. because the player quit.
+    /// `reason` is a short text for the other user.
+    Disconnect { reason: String },
 }
 
 impl P2pRequestPacket {
@@ -133,11 +212,21 @@ impl P2pRequestPacket {
         Self::Connect {
             join_code: join_code.to_owned(),
             username: username.to_owned(),
+            protocol_version: PROTOCOL_VERSION,
         }
     }
     /// Perform a game action
-    pub fn game_action(action: GameAction) -> Self {
-        Self::GameAction { action }
+    pub fn game_action(action: GameAction, board_hash: u64) -> Self {
+        Self::GameAction { action, board_hash }
+    }
+    /// Returns true if the request is sent again when it doesn't get a response.
+    /// Pings and join requests are already sent regularly, so they aren't, and a disconnect is
+    /// only sent on a best-effort basis.
+    pub fn is_retransmitted(&self) -> bool {
+        !matches!(
+            self,
+            Self::Ping | Self::Connect { .. } | Self::Disconnect { .. }
+        )
     }
 }
 
@@ -151,20 +240,49 @@ impl ToPacket for P2pRequestPacket {
             Self::Connect {
                 join_code,
                 username,
+                protocol_version,
             } => {
                 bytes.append(&mut self.to_u8().to_be_bytes().to_vec()); // Packet type code
 
+                bytes.append(&mut protocol_version.to_be_bytes().to_vec());
+                // The join code length depends on the address family, so it's prefixed
+                bytes.push(join_code.len() as u8);
                 bytes.append(&mut join_code.as_bytes().to_vec());
                 bytes.append(&mut username.as_bytes().to_vec());
             }
             Self::Resync => {
                 bytes.append(&mut self.to_u8().to_be_bytes().to_vec()); // Packet type code
             }
-            Self::GameAction { action } => {
+            Self::GameAction { action, board_hash } => {
                 bytes.append(&mut self.to_u8().to_be_bytes().to_vec()); // Packet type code
 
+                bytes.append(&mut board_hash.to_be_bytes().to_vec());
                 bytes.append(&mut action.to_packet());
             }
+            Self::Reconnect => {
+                bytes.append(&mut self.to_u8().to_be_bytes().to_vec()); // Packet type code
+            }
+            Self::Chat { message } => {
+                bytes.append(&mut self.to_u8().to_be_bytes().to_vec()); // Packet type code
+
+                bytes.append(&mut message.as_bytes().to_vec());
+            }
+            Self::SyncCheck { hash, ply } => {
+                bytes.append(&mut self.to_u8().to_be_bytes().to_vec()); // Packet type code
+
+                bytes.append(&mut hash.to_be_bytes().to_vec());
+                bytes.append(&mut ply.to_be_bytes().to_vec());
+            }
+            Self::Spectate { join_code } => {
+                bytes.append(&mut self.to_u8().to_be_bytes().to_vec()); // Packet type code
+
+                bytes.append(&mut join_code.as_bytes().to_vec());
+            }
+            Self::Disconnect { reason } => {
+                bytes.append(&mut self.to_u8().to_be_bytes().to_vec()); // Packet type code
+
+                bytes.append(&mut reason.as_bytes().to_vec());
+            }
         }
         bytes
     }
@@ -180,19 +298,24 @@ impl FromPacket for P2pRequestPacket {
             1 => Ok(Self::Ping),
             // Connect
             2 => {
-                if packet.len() < 14 {
-                    return Err(PacketError::invalid_length(14, packet.len()).into());
+                if packet.len() < 4 {
+                    return Err(PacketError::invalid_length(4, packet.len()).into());
                 }
-                let join_code = match String::from_utf8(packet[1..13].to_vec()) {
+                let protocol_version = u16::from_be_bytes(packet[1..3].try_into().unwrap());
+                let join_code_end = 4 + packet[3] as usize;
+                if packet.len() < join_code_end + 1 {
+                    return Err(PacketError::invalid_length(join_code_end + 1, packet.len()).into());
+                }
+                let join_code = match String::from_utf8(packet[4..join_code_end].to_vec()) {
                     Ok(string) => string,
                     Err(_) => {
                         return Err(PacketError::data_error(
-                            "Invalid UFT8 encoded values for username",
+                            "Invalid UFT8 encoded values for join code",
                         )
                         .into())
                     }
                 };
-                let username = match String::from_utf8(packet[13..].to_vec()) {
+                let username = match String::from_utf8(packet[join_code_end..].to_vec()) {
                     Ok(string) => string,
                     Err(_) => {
                         return Err(PacketError::data_error(
@@ -205,18 +328,81 @@ impl FromPacket for P2pRequestPacket {
                 Ok(Self::Connect {
                     join_code,
                     username,
+                    protocol_version,
                 })
             }
             // Resync
             3 => Ok(Self::Resync),
             // Game Action
             4 => {
-                if packet.len() < 2 {
-                    return Err(PacketError::invalid_length(2, packet.len()).into());
+                if packet.len() < 10 {
+                    return Err(PacketError::invalid_length(10, packet.len()).into());
+                }
+                let board_hash = u64::from_be_bytes(packet[1..9].try_into().unwrap());
+                let action = GameAction::from_packet(packet[9..].to_vec())?;
+
+                Ok(Self::GameAction { action, board_hash })
+            }
+            // Reconnect
+            5 => Ok(Self::Reconnect),
+            // Chat
+            6 => {
+                if packet.len() - 1 > MAX_CHAT_LEN {
+                    return Err(PacketError::data_error(&format!(
+                        "Chat message is longer than {} bytes",
+                        MAX_CHAT_LEN
+                    ))
+                    .into());
                 }
-                let action = GameAction::from_packet(packet[1..].to_vec()).unwrap();
+                let message = match String::from_utf8(packet[1..].to_vec()) {
+                    Ok(string) => string,
+                    Err(_) => {
+                        return Err(PacketError::data_error(
+                            "Invalid UFT8 encoded values for chat message",
+                        )
+                        .into())
+                    }
+                };
+
+                Ok(Self::Chat { message })
+            }
+            // SyncCheck
+            7 => {
+                if packet.len() != 13 {
+                    return Err(PacketError::invalid_length(13, packet.len()).into());
+                }
+                let hash = u64::from_be_bytes(packet[1..9].try_into().unwrap());
+                let ply = u32::from_be_bytes(packet[9..13].try_into().unwrap());
+
+                Ok(Self::SyncCheck { hash, ply })
+            }
+            // Spectate
+            8 => {
+                let join_code = match String::from_utf8(packet[1..].to_vec()) {
+                    Ok(string) => string,
+                    Err(_) => {
+                        return Err(PacketError::data_error(
+                            "Invalid UFT8 encoded values for join code",
+                        )
+                        .into())
+                    }
+                };
 
-                Ok(Self::GameAction { action })
+                Ok(Self::Spectate { join_code })
+            }
+            // Disconnect
+            9 => {
+                let reason = match String::from_utf8(packet[1..].to_vec()) {
+                    Ok(string) => string,
+                    Err(_) => {
+                        return Err(PacketError::data_error(
+                            "Invalid UFT8 encoded values for disconnect reason",
+                        )
+                        .into())
+                    }
+                };
+
+                Ok(Self::Disconnect { reason })
             }
             _ => Err(
                 PacketError::data_error(&format!("Not valid packet type: {}", packet[0])).into(),
@@ -229,12 +415,14 @@ impl ToByte for P2pRequestPacket {
     fn to_u8(&self) -> u8 {
         match self {
             Self::Ping => 1,
-            Self::Connect {
-                join_code: _,
-                username: _,
-            } => 2,
+            Self::Connect { .. } => 2,
             Self::Resync => 3,
-            Self::GameAction { action: _ } => 4,
+            Self::GameAction { .. } => 4,
+            Self::Reconnect => 5,
+            Self::Chat { message: _ } => 6,
+            Self::SyncCheck { hash: _, ply: _ } => 7,
+            Self::Spectate { join_code: _ } => 8,
+            Self::Disconnect { reason: _ } => 9,
         }
     }
 }
@@ -310,8 +498,16 @@ pub enum P2pResponsePacket {
         client_color: PieceColor,
         /// The hosts username, set by the Hosts user.
         host_username: String,
+        /// The time limits of the game, or `None` if it has no time limit. Set by the host.
+        time_control: Option<TimeControl>,
+        /// The seed of the game's random opening, or `None` if it starts from the usual position.
+        /// Set by the host.
+        opening_seed: Option<u64>,
+        /// The `PROTOCOL_VERSION` of the host.
+        protocol_version: u16,
     },
-    /// A response to `P2pRequestPacket::Resync`, features the hosts version of the game board.
+    /// A response to `P2pRequestPacket::Resync` and `P2pRequestPacket::Reconnect`, features the
+    /// hosts version of the game board.
     Resync {
         /// The hosts version of the game board, which the client will copy.
         board: Vec<PieceData>,
@@ -326,10 +522,18 @@ impl P2pResponsePacket {
         Self::Error { kind }
     }
     /// Response to `P2pRequestPacket::Connect`.
-    pub fn connect(client_color: PieceColor, host_username: String) -> Self {
+    pub fn connect(
+        client_color: PieceColor,
+        host_username: String,
+        time_control: Option<TimeControl>,
+        opening_seed: Option<u64>,
+    ) -> Self {
         Self::Connect {
             client_color,
             host_username,
+            time_control,
+            opening_seed,
+            protocol_version: PROTOCOL_VERSION,
         }
     }
     /// A response to `P2pRequestPacket::Resync`, features the hosts version of the game board.
@@ -353,10 +557,27 @@ impl ToPacket for P2pResponsePacket {
             Self::Connect {
                 client_color,
                 host_username,
+                time_control,
+                opening_seed,
+                protocol_version,
             } => {
                 bytes.append(&mut self.to_u8().to_be_bytes().to_vec()); // Packet type code
 
+                bytes.append(&mut protocol_version.to_be_bytes().to_vec());
                 bytes.append(&mut client_color.to_u8().to_be_bytes().to_vec());
+                // Whole seconds, which are all zero if the game has no time limit
+                bytes.push(time_control.is_some() as u8);
+                let (initial, increment) = time_control.map_or((0, 0), |time_control| {
+                    (
+                        time_control.initial.as_secs() as u32,
+                        time_control.increment.as_secs() as u32,
+                    )
+                });
+                bytes.append(&mut initial.to_be_bytes().to_vec());
+                bytes.append(&mut increment.to_be_bytes().to_vec());
+                // Zero if the game has no random opening
+                bytes.push(opening_seed.is_some() as u8);
+                bytes.append(&mut opening_seed.unwrap_or(0).to_be_bytes().to_vec());
                 bytes.append(&mut host_username.as_bytes().to_vec());
             }
             Self::Resync { board } => {
@@ -401,16 +622,24 @@ impl FromPacket for P2pResponsePacket {
             1 => Ok(Self::Pong),
             // Connect
             2 => {
-                if packet.len() < 3 {
-                    return Err(PacketError::invalid_length(2, packet.len()).into());
+                if packet.len() < 23 {
+                    return Err(PacketError::invalid_length(23, packet.len()).into());
                 }
 
-                let client_color = match PieceColor::try_from(packet[1]) {
+                let protocol_version = u16::from_be_bytes(packet[1..3].try_into().unwrap());
+                let client_color = match PieceColor::try_from(packet[3]) {
                     Ok(color) => color,
                     Err(e) => return Err(PacketError::data_error(&e.to_string()).into()),
                 };
 
-                let host_username = match String::from_utf8(packet[2..].to_vec()) {
+                let initial = u32::from_be_bytes(packet[5..9].try_into().unwrap());
+                let increment = u32::from_be_bytes(packet[9..13].try_into().unwrap());
+                let time_control = (packet[4] != 0)
+                    .then(|| TimeControl::from_secs(initial as u64, increment as u64));
+                let opening_seed = (packet[13] != 0)
+                    .then(|| u64::from_be_bytes(packet[14..22].try_into().unwrap()));
+
+                let host_username = match String::from_utf8(packet[22..].to_vec()) {
                     Ok(string) => string,
                     Err(_) => {
                         return Err(PacketError::data_error(
@@ -423,12 +652,16 @@ impl FromPacket for P2pResponsePacket {
                 Ok(Self::Connect {
                     client_color,
                     host_username,
+                    time_control,
+                    opening_seed,
+                    protocol_version,
                 })
             }
             // Resync
             3 => {
-                if packet.len() < 65 {
-                    return Err(PacketError::invalid_length(65, packet.len()).into());
+                // One byte per tile
+                if packet.len() != 33 {
+                    return Err(PacketError::invalid_length(33, packet.len()).into());
                 }
 
                 let mut board = vec![];
@@ -455,10 +688,7 @@ impl ToByte for P2pResponsePacket {
         match self {
             Self::Error { kind: _ } => 0,
             Self::Pong => 1,
-            Self::Connect {
-                client_color: _,
-                host_username: _,
-            } => 2,
+            Self::Connect { .. } => 2,
             Self::Resync { board: _ } => 3,
             Self::Acknowledge => 4,
         }
@@ -479,6 +709,12 @@ impl ToPacket for GameAction {
                 }
             }
         }
+        if let Self::RespondDraw(accepted) | Self::RespondTakeback(accepted) = self {
+            bytes.push(*accepted as u8);
+        }
+        if let Self::RequestTakeback(ply) = self {
+            bytes.extend_from_slice(&ply.to_be_bytes());
+        }
         bytes
     }
 }
@@ -488,23 +724,28 @@ impl FromPacket for GameAction {
         if packet.is_empty() {
             return Err(PacketError::invalid_length(1, 0).into());
         }
-        match Self::from(packet[0]) {
+        match Self::try_from(packet[0])? {
             Self::MovePiece(_) => {
-                if packet.len() < 3 {
+                if packet.len() < 4 {
                     return Err(PacketError::invalid_length(4, packet.len()).into());
                 }
+                // A tile outside the board would make the bitboard shifts overflow
+                let mut tiles = packet[1..3].iter().chain(&packet[4..]);
+                if let Some(tile) = tiles.find(|tile| **tile >= 32) {
+                    return Err(PacketError::data_error(&format!(
+                        "Not valid tile index: {}",
+                        tile
+                    ))
+                    .into());
+                }
                 let index = packet[1] as usize;
                 let end = packet[2] as usize;
                 let promoted = packet[3] != 0;
 
-                let mut captured: Option<Vec<usize>> = None;
-                if packet.len() > 4 {
-                    captured = Some(vec![]);
-
-                    for pack in packet.iter().skip(4) {
-                        unsafe { captured.as_mut().unwrap_unchecked().push(*pack as usize) }
-                    }
-                }
+                // The captured tiles follow in the order they were jumped, and a move without
+                // captures has none
+                let captured = (packet.len() > 4)
+                    .then(|| packet[4..].iter().map(|tile| *tile as usize).collect());
 
                 Ok(Self::move_piece(index, end, captured, promoted))
             }
@@ -514,30 +755,75 @@ impl FromPacket for GameAction {
                 }
                 Ok(Self::Surrender)
             }
-            Self::Stalemate => {
+            Self::OfferDraw => {
+                if packet.len() != 1 {
+                    return Err(PacketError::invalid_length(1, packet.len()).into());
+                }
+                Ok(Self::OfferDraw)
+            }
+            Self::RespondDraw(_) => {
+                if packet.len() != 2 {
+                    return Err(PacketError::invalid_length(2, packet.len()).into());
+                }
+                Ok(Self::RespondDraw(packet[1] != 0))
+            }
+            Self::Rematch => {
+                if packet.len() != 1 {
+                    return Err(PacketError::invalid_length(1, packet.len()).into());
+                }
+                Ok(Self::Rematch)
+            }
+            Self::RematchAccept => {
+                if packet.len() != 1 {
+                    return Err(PacketError::invalid_length(1, packet.len()).into());
+                }
+                Ok(Self::RematchAccept)
+            }
+            Self::TimeExpired => {
                 if packet.len() != 1 {
                     return Err(PacketError::invalid_length(1, packet.len()).into());
                 }
-                Ok(Self::Stalemate)
+                Ok(Self::TimeExpired)
+            }
+            Self::RequestTakeback(_) => {
+                if packet.len() != 5 {
+                    return Err(PacketError::invalid_length(5, packet.len()).into());
+                }
+                let ply = u32::from_be_bytes(packet[1..5].try_into().unwrap());
+                Ok(Self::RequestTakeback(ply))
+            }
+            Self::RespondTakeback(_) => {
+                if packet.len() != 2 {
+                    return Err(PacketError::invalid_length(2, packet.len()).into());
+                }
+                Ok(Self::RespondTakeback(packet[1] != 0))
             }
         }
     }
 }
 
-impl From<u8> for GameAction {
-    fn from(value: u8) -> Self {
+impl TryFrom<u8> for GameAction {
+    type Error = PacketError;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
-            0 => Self::MovePiece(Move {
+            0 => Ok(Self::MovePiece(Move {
                 index: 0,
                 end: 0,
                 captured: None,
                 promoted: false,
-            }),
-            1 => Self::Stalemate,
-            2 => Self::Surrender,
-            _ => {
-                panic!("Not valid Gameaction value in 'From' cast")
-            }
+            })),
+            1 => Ok(Self::OfferDraw),
+            2 => Ok(Self::Surrender),
+            3 => Ok(Self::RespondDraw(false)),
+            4 => Ok(Self::Rematch),
+            5 => Ok(Self::RematchAccept),
+            6 => Ok(Self::TimeExpired),
+            7 => Ok(Self::RequestTakeback(0)),
+            8 => Ok(Self::RespondTakeback(false)),
+            _ => Err(PacketError::data_error(&format!(
+                "Not valid game action type: {}",
+                value
+            ))),
         }
     }
 }
@@ -546,8 +832,14 @@ impl ToByte for GameAction {
     fn to_u8(&self) -> u8 {
         match self {
             Self::MovePiece(_) => 0,
-            Self::Stalemate => 1,
+            Self::OfferDraw => 1,
             Self::Surrender => 2,
+            Self::RespondDraw(_) => 3,
+            Self::Rematch => 4,
+            Self::RematchAccept => 5,
+            Self::TimeExpired => 6,
+            Self::RequestTakeback(_) => 7,
+            Self::RespondTakeback(_) => 8,
         }
     }
 }
@@ -568,6 +860,31 @@ pub enum P2pError {
     /// THis errorkind is caused by data flowing the wrong direction. E.g. when a Host tries to
     /// send a `P2pRequest::Connect` to the client.
     WrongDirection,
+    /// This errorkind is caused by offering a draw while another draw offer hasn't been answered.
+    DrawOfferPending,
+    /// This errorkind is caused by a move which isn't legal on the recievers board, or which
+    /// moves a piece that doesn't belong to the sender.
+    IllegalMove,
+    /// This errorkind is caused by a spectator sending a game action, or another request only the
+    /// players may send.
+    Spectator,
+    /// This errorkind is caused by the client joining with a different `PROTOCOL_VERSION` than
+    /// the host.
+    IncompatibleVersion,
+    /// This errorkind is caused by answering a draw offer when no offer is pending.
+    NoDrawOffer,
+    /// This errorkind is caused by the client joining with an empty or too long username, or one
+    /// with characters that can't be shown, see `validate_username`.
+    InvalidUsername,
+    /// This errorkind is caused by a game action sent after the game has ended, eg. a move after
+    /// the other player surrendered. Only a rematch can be asked for then.
+    GameOver,
+    /// This errorkind is caused by a move sent when it isn't the senders turn, eg. two moves in a
+    /// row.
+    OutOfTurn,
+    /// This errorkind is caused by a game action sent while the reciever hasn't handled the game
+    /// actions it already got, and its incoming queue is full. The action may be sent again later.
+    QueueFull,
 }
 
 impl ToByte for P2pError {
@@ -578,6 +895,15 @@ impl ToByte for P2pError {
             Self::InvalidSessionId => 2,
             Self::FullGameSession => 3,
             Self::WrongDirection => 4,
+            Self::DrawOfferPending => 5,
+            Self::IllegalMove => 6,
+            Self::Spectator => 7,
+            Self::IncompatibleVersion => 8,
+            Self::NoDrawOffer => 9,
+            Self::InvalidUsername => 10,
+            Self::GameOver => 11,
+            Self::OutOfTurn => 12,
+            Self::QueueFull => 13,
         }
     }
 }
@@ -591,8 +917,17 @@ impl TryFrom<u8> for P2pError {
             2 => Ok(Self::InvalidSessionId),
             3 => Ok(Self::FullGameSession),
             4 => Ok(Self::WrongDirection),
+            5 => Ok(Self::DrawOfferPending),
+            6 => Ok(Self::IllegalMove),
+            7 => Ok(Self::Spectator),
+            8 => Ok(Self::IncompatibleVersion),
+            9 => Ok(Self::NoDrawOffer),
+            10 => Ok(Self::InvalidUsername),
+            11 => Ok(Self::GameOver),
+            12 => Ok(Self::OutOfTurn),
+            13 => Ok(Self::QueueFull),
             _ => Err(anyhow!(
-                "Can only take values in range 0..=4 for P2p Error, got {}",
+                "Can only take values in range 0..=13 for P2p Error, got {}",
                 value
             )),
         }
@@ -627,7 +962,7 @@ impl ToByte for PieceData {
     fn to_u8(&self) -> u8 {
         let mut byte: u8 = 0;
 
-        if self.is_active {
+        if !self.is_active {
             return byte;
         }
 
@@ -680,3 +1015,240 @@ impl TryFrom<u8> for PieceData {
         Ok(piece)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn requests() -> Vec<P2pRequestPacket> {
+        let mov = Move {
+            index: 21,
+            end: 14,
+            promoted: false,
+            captured: Some(vec![17]),
+        };
+        let actions = vec![
+            GameAction::MovePiece(mov),
+            GameAction::OfferDraw,
+            GameAction::Surrender,
+            GameAction::RespondDraw(true),
+            GameAction::Rematch,
+            GameAction::RematchAccept,
+            GameAction::TimeExpired,
+            GameAction::RequestTakeback(12),
+            GameAction::RespondTakeback(false),
+        ];
+
+        let mut requests = vec![
+            P2pRequestPacket::Ping,
+            P2pRequestPacket::connect("7f0000011771", "bob"),
+            P2pRequestPacket::Resync,
+            P2pRequestPacket::Reconnect,
+            P2pRequestPacket::Chat {
+                message: "good game".to_owned(),
+            },
+            P2pRequestPacket::SyncCheck {
+                hash: 0x0123_4567_89ab_cdef,
+                ply: 31,
+            },
+            P2pRequestPacket::Spectate {
+                join_code: "7f0000011771".to_owned(),
+            },
+            P2pRequestPacket::Disconnect {
+                reason: "closed the game".to_owned(),
+            },
+        ];
+        requests.extend(
+            actions
+                .into_iter()
+                .map(|action| P2pRequestPacket::game_action(action, u64::MAX)),
+        );
+        requests
+    }
+
+    fn responses() -> Vec<P2pResponsePacket> {
+        let errors = [
+            P2pError::InvalidBoard,
+            P2pError::InvalidJoinCode,
+            P2pError::InvalidSessionId,
+            P2pError::FullGameSession,
+            P2pError::WrongDirection,
+            P2pError::DrawOfferPending,
+            P2pError::IllegalMove,
+            P2pError::Spectator,
+            P2pError::IncompatibleVersion,
+            P2pError::NoDrawOffer,
+            P2pError::InvalidUsername,
+            P2pError::GameOver,
+            P2pError::OutOfTurn,
+            P2pError::QueueFull,
+        ];
+        let board = (0..32)
+            .map(|index| PieceData {
+                color: if index < 12 {
+                    PieceColor::Black
+                } else {
+                    PieceColor::White
+                },
+                is_active: index < 12,
+                is_king: index == 3,
+            })
+            .collect();
+
+        let mut responses = vec![
+            P2pResponsePacket::Pong,
+            P2pResponsePacket::Acknowledge,
+            P2pResponsePacket::connect(PieceColor::Black, "alice".to_owned(), None, None),
+            P2pResponsePacket::connect(
+                PieceColor::White,
+                "alice".to_owned(),
+                Some(TimeControl::from_secs(300, 5)),
+                Some(42),
+            ),
+            P2pResponsePacket::Resync { board },
+        ];
+        responses.extend(errors.into_iter().map(P2pResponsePacket::error));
+        responses
+    }
+
+    /// Returns every packet variant, with the bytes `encode_packet` makes of it
+    fn encoded_packets() -> Vec<(P2pPacket, Vec<u8>)> {
+        let requests = requests().into_iter().map(|packet| {
+            let request = P2pRequest::new(513, 7, packet);
+            let bytes = encode_packet(&request);
+            (P2pPacket::Request(request), bytes)
+        });
+        let responses = responses().into_iter().map(|packet| {
+            let response = P2pResponse::new(513, 7, packet);
+            let bytes = encode_packet(&response);
+            (P2pPacket::Response(response), bytes)
+        });
+        requests.chain(responses).collect()
+    }
+
+    /// Returns true if `packet` ends with text or captured tiles, which take up the rest of the
+    /// packet, so a packet cut short in them still decodes
+    fn ends_without_length(packet: &P2pPacket) -> bool {
+        match packet {
+            P2pPacket::Request(request) => matches!(
+                request.packet,
+                P2pRequestPacket::Connect { .. }
+                    | P2pRequestPacket::Chat { .. }
+                    | P2pRequestPacket::Spectate { .. }
+                    | P2pRequestPacket::Disconnect { .. }
+                    | P2pRequestPacket::GameAction {
+                        action: GameAction::MovePiece(_),
+                        ..
+                    }
+            ),
+            P2pPacket::Response(response) => {
+                matches!(response.packet, P2pResponsePacket::Connect { .. })
+            }
+        }
+    }
+
+    #[test]
+    fn every_packet_round_trips() {
+        for (packet, bytes) in encoded_packets() {
+            let decoded = decode_packet(&bytes).unwrap();
+            assert_eq!(format!("{:?}", decoded), format!("{:?}", packet));
+        }
+    }
+
+    #[test]
+    fn truncated_packets_are_errors() {
+        for (packet, bytes) in encoded_packets() {
+            let expected = format!("{:?}", packet);
+            for len in 0..bytes.len() {
+                match decode_packet(&bytes[..len]) {
+                    Err(_) => {}
+                    Ok(decoded) => {
+                        assert!(
+                            ends_without_length(&packet),
+                            "{} cut to {} bytes",
+                            expected,
+                            len
+                        );
+                        assert_ne!(format!("{:?}", decoded), expected);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn corrupt_headers_are_errors() {
+        let (_, bytes) = encoded_packets().remove(0);
+
+        let mut wrong_magic = bytes.clone();
+        wrong_magic[0] = b'X';
+        assert!(decode_packet(&wrong_magic).is_err());
+
+        let mut wrong_version = bytes.clone();
+        wrong_version[PACKET_MAGIC.len()] = HEADER_VERSION + 1;
+        assert!(decode_packet(&wrong_version).is_err());
+
+        let mut wrong_kind = bytes;
+        // The byte telling requests from responses, after the compression flag
+        wrong_kind[HEADER_LEN + 1] = 9;
+        assert!(decode_packet(&wrong_kind).is_err());
+    }
+
+    #[test]
+    fn other_header_version_is_a_version_error() {
+        let (_, bytes) = encoded_packets().remove(0);
+        for version in [0, HEADER_VERSION + 1, u8::MAX] {
+            let mut other_version = bytes.clone();
+            other_version[PACKET_MAGIC.len()] = version;
+            let err = decode_packet(&other_version).unwrap_err();
+            assert!(
+                matches!(
+                    err.downcast_ref::<PacketError>(),
+                    Some(PacketError::UnknownVersion { version: got }) if *got == version
+                ),
+                "{:?}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn move_off_the_board_is_an_error() {
+        for (end, captured) in [(32, None), (14, Some(vec![40]))] {
+            let packet = GameAction::move_piece(21, end, captured, false).to_packet();
+            assert!(GameAction::from_packet(packet).is_err());
+        }
+    }
+
+    #[test]
+    fn multi_capture_promotion_round_trips() {
+        // The captures are listed in the order they are made, which isn't sorted
+        let mov = Move {
+            index: 20,
+            end: 2,
+            promoted: true,
+            captured: Some(vec![17, 10, 6]),
+        };
+        let request = P2pRequest {
+            session_id: 7,
+            transaction_id: 300,
+            sequence: 12,
+            packet: P2pRequestPacket::game_action(GameAction::MovePiece(mov.clone()), 42),
+        };
+
+        let decoded = P2pRequest::from_packet(request.to_packet()).unwrap();
+        assert_eq!(decoded.session_id, 7);
+        assert_eq!(decoded.transaction_id, 300);
+        assert_eq!(decoded.sequence, 12);
+        match decoded.packet {
+            P2pRequestPacket::GameAction {
+                action: GameAction::MovePiece(decoded_move),
+                board_hash,
+            } => {
+                assert_eq!(decoded_move, mov);
+                assert_eq!(board_hash, 42);
+            }
+            other => panic!("decoded {:?}", other),
+        }
+    }
+}
@@ -1,22 +1,40 @@
 use std::{
+    net::SocketAddr,
     sync::Arc,
     time::{Duration, Instant},
 };
 
+use tokio::sync::Mutex;
+use tracing::{debug, error, info, trace, warn};
+
 use crate::{
-    game::GameAction,
+    game::{bitboard::BitBoard, GameAction, GameResult, Move},
     net::{
+        net_utils::{
+            encode_discovery_reply, is_discovery_probe, validate_username, DISCOVERY_PORT,
+        },
         p2p::{
             communicate::{recieve_p2p_packet, send_p2p_packet},
-            queue::{self, get_incoming_gameaction_len, push_incoming_gameaction},
+            queue::{
+                self, get_incoming_gameaction_len, get_incoming_queue_len, is_incoming_full,
+                push_incoming_chat, push_incoming_gameaction,
+            },
             P2pError, P2pPacket, P2pRequest, P2pRequestPacket, P2pResponse, P2pResponsePacket,
-            PieceColor,
+            PieceColor, PROTOCOL_VERSION,
         },
+        relay,
         status::{
-            get_connection_status, get_join_code, get_my_username, get_other_addr, get_session_id,
-            remove_other_addr, remove_other_username, set_connection_ping, set_connection_status,
-            set_other_addr, set_other_username, set_reconnect_tries, set_session_id,
-            ConnectionStatus, CONNECT_SESSION_ID,
+            add_spectator, apply_shared_move, get_board_state, get_connection_status,
+            get_game_result, get_heartbeat_timeout, get_join_code, get_last_packet_time,
+            get_loop_generation, get_my_color, get_my_username, get_opening_seed, get_other_addr,
+            get_other_username, get_relay, get_session_id, get_shared_bitboard, get_spectators,
+            get_time_control, is_draw_offer_pending, is_spectating, is_spectator,
+            new_loop_generation, record_board_hash, record_latency, remove_other_addr,
+            remove_other_username, remove_spectator, save_session, set_connection_status,
+            set_desynced, set_disconnect_reason, set_draw_offer_pending, set_game_result,
+            set_other_addr, set_other_username, set_reconnect_tries, set_rematch_offered,
+            set_resynced_board, set_session_id, set_whose_turn, update_last_packet_time,
+            whose_turn, ClientConfig, ConnectionStatus, CONNECT_SESSION_ID,
         },
     },
 };
@@ -24,28 +42,469 @@ use crate::{
 use super::queue::{new_transaction_id, push_outgoing_queue, wait_for_response};
 
 pub const REQUEST_TIMEOUT_MS: u128 = 500;
-const DISCONNECT_TIME_MS: u128 = 5_000;
 const RECONNECT_TRIES: u32 = 10;
+/// The sender shown for chat messages from spectators, which don't send a username
+const SPECTATOR_CHAT_NAME: &str = "Spectator";
+/// How often the host pings the client, so the client hears from the host while it waits
+const HOST_PING_INTERVAL_MS: u64 = 1_000;
+/// How often the heartbeat watchdog checks when the last packet was recieved
+const HEARTBEAT_CHECK_MS: u64 = 500;
+/// How long the discovery responder waits before trying to bind `DISCOVERY_PORT` again
+const DISCOVERY_BIND_RETRY_MS: u64 = 1_000;
+
+/// Returns true if a newer network loop has been started since the loop of `generation`, in which
+/// case the tasks of the old loop should stop.
+async fn is_stale(generation: u32) -> bool {
+    get_loop_generation().await != generation
+}
+
+/// Handles an incoming `GameAction::OfferDraw` or `GameAction::RespondDraw`, and returns the
+/// response to it. An offer made while another one is pending is rejected, and so is an answer
+/// when no offer is pending, so a draw is only agreed on when both users want one.
+/// Used by both the host and client network loops.
+async fn recieve_draw_action(action: GameAction) -> P2pResponsePacket {
+    match action {
+        GameAction::OfferDraw => {
+            if is_draw_offer_pending().await {
+                warn!("Rejected draw offer, since another offer is pending");
+                return P2pResponsePacket::error(P2pError::DrawOfferPending);
+            }
+            set_draw_offer_pending(true).await;
+        }
+        GameAction::RespondDraw(_) => {
+            if !is_draw_offer_pending().await {
+                warn!("Rejected answer to a draw offer, since no offer is pending");
+                return P2pResponsePacket::error(P2pError::NoDrawOffer);
+            }
+            set_draw_offer_pending(false).await;
+        }
+        _ => {}
+    }
+    push_incoming_gameaction(action).await;
+    P2pResponsePacket::Acknowledge
+}
+
+/// Returns true if the game has ended, so `action` is refused with `P2pError::GameOver`. Only
+/// the actions starting a rematch are handled then.
+/// Used by both the host and client network loops.
+async fn is_refused_after_game(action: &GameAction) -> bool {
+    let Some(result) = get_game_result().await else {
+        return false;
+    };
+    if action.is_rematch() {
+        return false;
+    }
+    warn!(
+        "Rejected {:?}, since the game is over: {:?}",
+        action, result
+    );
+    true
+}
+
+/// Returns true if the game action in the request with `sequence` was sent before a game action
+/// which has already been handled. The stale action is acknowledged without being handled, so it
+/// can't undo or repeat what the newer one did.
+/// Used by both the host and client network loops.
+async fn is_stale_action(sequence: u32, action: &GameAction) -> bool {
+    if queue::check_action_sequence(sequence).await {
+        return false;
+    }
+    info!(
+        "Ignored {:?} from request {}, since a newer game action was handled",
+        action, sequence
+    );
+    true
+}
+
+/// Refuses `action` with `P2pError::QueueFull`, since the game hasn't handled the game actions it
+/// already got. The other peer may send it again later.
+/// Used by both the host and client network loops.
+async fn refuse_for_full_queue(action: &GameAction) -> P2pResponsePacket {
+    warn!(
+        "Rejected {:?}, since {} game actions and chat messages haven't been handled",
+        action,
+        get_incoming_queue_len().await
+    );
+    P2pResponsePacket::error(P2pError::QueueFull)
+}
+
+/// Handles a `GameAction::Surrender` or a `GameAction::TimeExpired` from the other peer. Both
+/// end the game with the other peer losing, after which neither peer sends any game actions
+/// until a rematch is started.
+/// Used by both the host and client network loops.
+async fn recieve_game_end(action: GameAction) -> P2pResponsePacket {
+    if let Some(my_color) = get_my_color().await {
+        let loser = my_color.get_opposite();
+        let result = match action {
+            GameAction::Surrender => GameResult::Resigned { loser },
+            _ => GameResult::Timeout { loser },
+        };
+        info!("The game is over: {:?}", result);
+        set_game_result(Some(result)).await;
+    }
+    push_incoming_gameaction(action).await;
+    P2pResponsePacket::Acknowledge
+}
+
+/// Handles a `GameAction::Rematch` or a `GameAction::RematchAccept` from the other peer. An
+/// accepted rematch starts a new game, so game actions are handled again.
+/// Used by both the host and client network loops.
+async fn recieve_rematch_action(action: GameAction) -> P2pResponsePacket {
+    let is_request = matches!(action, GameAction::Rematch);
+    set_rematch_offered(is_request).await;
+    if !is_request {
+        set_game_result(None).await;
+    }
+    push_incoming_gameaction(action).await;
+    P2pResponsePacket::Acknowledge
+}
+
+/// Closes the connection after the other peer sent a `P2pRequestPacket::Disconnect`, so the game
+/// knows right away that the other user left, see `get_disconnect_reason`.
+/// Used by both the host and client network loops.
+async fn recieve_disconnect(reason: String) {
+    info!("The other peer closed the connection: {}", reason);
+    set_disconnect_reason(Some(reason)).await;
+    set_connection_status(ConnectionStatus::Disconnected).await;
+    remove_other_addr().await;
+    remove_other_username().await;
+    queue::clear_outgoing().await;
+}
+
+/// Checks that `mov`, recieved from the other peer, moves one of their pieces and is legal on the
+/// board shared with `set_board_state`. Moves can't be checked before a board has been shared,
+/// so they are allowed until then.
+/// Only whole capture sequences are legal, so a jump stopping while the piece can capture more is
+/// rejected, and the rest of the sequence can't follow as another move, since the turn has passed.
+async fn is_legal_remote_move(mov: &Move) -> bool {
+    let pieces = get_board_state().await;
+    let Some(my_color) = get_my_color().await else {
+        return true;
+    };
+    if pieces.len() != 32 {
+        return true;
+    }
+
+    // The board is in logical indices, where White's pieces move up like the player's
+    let board = BitBoard::from_pieces(&pieces, PieceColor::White);
+    let sender_is_white = my_color.get_opposite() == PieceColor::White;
+    mov.index < 32 && board.is_player(mov.index) == sender_is_white && board.is_legal_move(mov)
+}
+
+/// Returns true if it's the other peer's turn, so it may move. Moves can't be checked before the
+/// local player's color is known, so they are allowed until then.
+async fn is_remote_turn() -> bool {
+    let Some(my_color) = get_my_color().await else {
+        return true;
+    };
+    whose_turn().await != my_color
+}
+
+/// Passes the turn to the local player, after a move from the other peer has been accepted
+async fn pass_turn_to_me() {
+    if let Some(my_color) = get_my_color().await {
+        set_whose_turn(my_color).await;
+    }
+}
+
+/// Compares `board_hash`, the hash of the other peer's board after `action`, with the hash of the
+/// shared board after `action`. On a mismatch the connection becomes desynced, and the client asks
+/// the host for its board, which replaces the client's.
+async fn check_board_hash(action: &GameAction, board_hash: u64, is_client: bool) {
+    let Some(mut board) = get_shared_bitboard().await else {
+        return;
+    };
+    if let GameAction::MovePiece(mov) = action {
+        // Illegal moves are rejected, and never make it onto the board
+        if !board.is_legal_move(mov) {
+            return;
+        }
+        board.apply_move(mov);
+    }
+    // The shared board doesn't know the moves before it, so it can't take them back. The boards
+    // are compared again with the next move
+    if let GameAction::RespondTakeback(true) = action {
+        return;
+    }
+
+    let my_hash = board.board_hash(PieceColor::White);
+    if my_hash == board_hash {
+        // The boards match again, eg. after the client resynced
+        set_desynced(false).await;
+        return;
+    }
+
+    error!(
+        "Board is out of sync after {:?}. Local hash: {:#018x}, other hash: {:#018x}",
+        action, my_hash, board_hash
+    );
+    set_desynced(true).await;
+    if !is_client {
+        return;
+    }
+
+    let closure = Arc::new(Mutex::new(|resp: anyhow::Result<P2pResponse>| {
+        match resp.map(|resp| resp.packet) {
+            Ok(P2pResponsePacket::Resync { board }) => {
+                info!("Resynced board with the host");
+                // The closure is run by the network loop, which mustn't block on the status
+                tokio::spawn(async move {
+                    set_resynced_board(board).await;
+                    set_desynced(false).await;
+                });
+            }
+            Ok(packet) => warn!("Resync request failed: {:?}", packet),
+            Err(err) => warn!("Resync request failed: {:#}", err),
+        }
+    }));
+    let request = P2pRequest::new(
+        get_session_id().await,
+        new_transaction_id().await,
+        P2pRequestPacket::Resync,
+    );
+    if let Err(err) = push_outgoing_queue(P2pPacket::Request(request), Some(closure)).await {
+        warn!("Failed to queue resync request: {}", err);
+    }
+}
+
+/// Returns the color the host assigns the client, which is the opposite of the host's color
+async fn client_color() -> PieceColor {
+    get_my_color()
+        .await
+        .map_or(PieceColor::Black, |color| color.get_opposite())
+}
+
+/// Sends `request`, a move applied by the host, to every spectator of the game, without waiting
+/// for a response. A spectator which misses a move notices it with the board hash of the next one,
+/// and resyncs.
+async fn forward_to_spectators(socket: &Arc<tokio::net::UdpSocket>, request: &P2pRequest) {
+    if !matches!(
+        request.packet,
+        P2pRequestPacket::GameAction {
+            action: GameAction::MovePiece(_),
+            ..
+        }
+    ) {
+        return;
+    }
+    for addr in get_spectators().await {
+        if let Err(err) = send_p2p_packet(socket, request.clone(), addr).await {
+            warn!(
+                "Failed to forward move to spectator at {:?}: {:#}",
+                addr, err
+            );
+        }
+    }
+}
+
+/// Sends `response` to `addr`. Responses to the client go through the outgoing queue, while
+/// responses to spectators and refused peers are sent right away, since the outgoing queue only
+/// sends to the client.
+async fn send_host_response(
+    socket: &Arc<tokio::net::UdpSocket>,
+    response: P2pResponse,
+    addr: SocketAddr,
+) {
+    if get_other_addr().await == Some(addr) {
+        if let Err(err) = queue::push_outgoing_queue(P2pPacket::Response(response), None).await {
+            warn!("Failed to queue response to {:?}: {}", addr, err);
+        }
+    } else if let Err(err) = send_p2p_packet(socket, response, addr).await {
+        warn!("Failed to send response to {:?}: {:#}", addr, err);
+    }
+}
+
+/// How often the retransmitter checks for requests without a response
+const RETRANSMIT_CHECK_MS: u64 = 100;
+
+/// Sends requests which haven't gotten a response again, see `queue::retransmit_pending_requests`.
+/// Used by both the host and client network loops.
+fn spawn_retransmitter(generation: u32) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_millis(RETRANSMIT_CHECK_MS));
+        loop {
+            if is_stale(generation).await {
+                break;
+            }
+            interval.tick().await;
+            queue::retransmit_pending_requests().await;
+        }
+    });
+}
+
+/// Watches the time since the last packet was recieved from the other peer, and sets the
+/// connection status to `ConnectionStatus::TimedOut` when it exceeds the heartbeat timeout.
+/// Used by both the host and client network loops.
+fn spawn_heartbeat_watchdog(generation: u32) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_millis(HEARTBEAT_CHECK_MS));
+        loop {
+            if is_stale(generation).await {
+                break;
+            }
+            interval.tick().await;
+
+            let connection_status = get_connection_status().await;
+            if !connection_status.is_connected() && !connection_status.is_reconnecting() {
+                continue;
+            }
+            let Some(last_packet_time) = get_last_packet_time().await else {
+                continue;
+            };
+
+            let timeout = get_heartbeat_timeout().await;
+            if last_packet_time.elapsed() >= timeout {
+                warn!(
+                    "Nothing recieved from the other peer in {:?}, connection timed out",
+                    timeout
+                );
+                set_connection_status(ConnectionStatus::TimedOut).await;
+            }
+        }
+    });
+}
+
+/// Answers the discovery probes of clients on the LAN with the host's join info, see
+/// `net_utils::discover_hosts`, until a newer network loop is started.
+/// Only a LAN host waiting for a client answers, since a relay host can't be joined on its
+/// address.
+fn spawn_discovery_responder(generation: u32) {
+    tokio::spawn(async move {
+        let mut socket = None;
+        let mut buffer = vec![0; 64];
+        loop {
+            if is_stale(generation).await {
+                break;
+            }
+            let Some(discovery_sock) = &socket else {
+                // The responder of the previous loop may still hold the port
+                match tokio::net::UdpSocket::bind(("0.0.0.0", DISCOVERY_PORT)).await {
+                    Ok(new_sock) => socket = Some(new_sock),
+                    Err(err) => {
+                        debug!("Failed to bind the discovery port: {}", err);
+                        tokio::time::sleep(Duration::from_millis(DISCOVERY_BIND_RETRY_MS)).await;
+                    }
+                }
+                continue;
+            };
+
+            let (len, addr) = match tokio::time::timeout(
+                Duration::from_millis(REQUEST_TIMEOUT_MS as u64),
+                discovery_sock.recv_from(&mut buffer),
+            )
+            .await
+            {
+                Ok(Ok(received)) => received,
+                _ => continue,
+            };
+            if !is_discovery_probe(&buffer[..len])
+                || !matches!(
+                    get_connection_status().await,
+                    ConnectionStatus::PendingConnection
+                )
+                || get_relay().await.is_some()
+            {
+                continue;
+            }
+            let Some(join_code) = get_join_code().await else {
+                continue;
+            };
+
+            debug!("Answering discovery probe from {:?}", addr);
+            let username = get_my_username().await.unwrap_or("HOST".to_owned());
+            let reply = encode_discovery_reply(&username, &join_code);
+            if let Err(err) = discovery_sock.send_to(&reply, addr).await {
+                warn!("Failed to answer discovery probe from {:?}: {}", addr, err);
+            }
+        }
+    });
+}
 
 /// The async network loop for the host.
 /// The loop goes though the following points:
 ///     - Check for incoming messages and respond accordingly.
+///     - Answer discovery probes from clients on the LAN, while waiting for a client.
 ///     - If connected with the client:
 ///         - Send the next item in the Outgoing queue to the host.
-pub fn host_network_loop(socket: tokio::net::UdpSocket) {
+///
+/// At most `max_outgoing_len` packets wait in the outgoing queue, see
+/// `queue::push_outgoing_queue`.
+///
+/// Starting a new loop stops the tasks of the running one.
+pub async fn host_network_loop(socket: tokio::net::UdpSocket, max_outgoing_len: usize) {
+    queue::set_max_outgoing_len(max_outgoing_len);
     let socket = Arc::new(socket);
+    let generation = new_loop_generation().await;
+    spawn_heartbeat_watchdog(generation);
+    spawn_retransmitter(generation);
+    spawn_discovery_responder(generation);
+    // Ping client
+    let ping_sock = socket.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_millis(HOST_PING_INTERVAL_MS));
+        loop {
+            if is_stale(generation).await {
+                break;
+            }
+            interval.tick().await;
+
+            if !get_connection_status().await.is_connected() || get_other_addr().await.is_none() {
+                // Keeps the host registered with the relay, and its NAT open, until a client joins
+                if let Some((relay_addr, code)) = get_relay().await {
+                    let frame = relay::wrap_frame(&code, &[]);
+                    if let Err(err) = ping_sock.send_to(&frame, relay_addr).await {
+                        warn!("Failed to register with the relay: {}", err);
+                    }
+                }
+                continue;
+            }
+
+            let time = Instant::now();
+
+            let ping_id = new_transaction_id().await;
+            let ping = P2pRequest::new(get_session_id().await, ping_id, P2pRequestPacket::Ping);
+            // The queue is full of more important packets, so this ping can wait
+            if push_outgoing_queue(P2pPacket::Request(ping), None)
+                .await
+                .is_err()
+            {
+                continue;
+            }
+
+            match tokio::time::timeout(
+                Duration::from_millis(REQUEST_TIMEOUT_MS as u64),
+                wait_for_response(ping_id),
+            )
+            .await
+            {
+                Ok(_) => record_latency(time).await,
+                // A lost ping is fine, the heartbeat watchdog notices if the client is gone
+                Err(_) => {
+                    queue::count_timeout();
+                    queue::remove_transaction(ping_id).await;
+                }
+            }
+        }
+    });
     // Handle outgoing queue
     tokio::spawn({
-        println!("Starting Host Handle outgoing queue");
+        debug!("Starting host outgoing queue task");
         let new_sock = socket.clone();
         async move {
             loop {
+                if is_stale(generation).await {
+                    break;
+                }
                 let client_addr = match get_other_addr().await {
                     Some(addr) => addr,
                     None => continue,
                 };
                 if let Some((data, id)) = queue::pop_outgoing_queue().await {
-                    println!("Sending Packet with ID {}... ({:?})", id, data);
+                    trace!("Sending packet with ID {}... ({:?})", id, data);
+                    queue::track_sent_packet(&data).await;
+                    if let P2pPacket::Request(request) = &data {
+                        forward_to_spectators(&new_sock, request).await;
+                    }
                     send_p2p_packet(&new_sock, data, client_addr).await.unwrap();
                 }
             }
@@ -53,21 +512,12 @@ pub fn host_network_loop(socket: tokio::net::UdpSocket) {
     });
     // Handle incoming responses
     tokio::spawn({
-        println!("Starting Host handle incoming responses");
+        debug!("Starting host incoming packet task");
         let new_sock = socket.clone();
         async move {
-            let mut time_since_ping = Instant::now();
             loop {
-                if time_since_ping.elapsed().as_millis() >= DISCONNECT_TIME_MS
-                    && get_other_addr().await.is_some()
-                {
-                    println!(
-                        "Client at {:?} disconnected!",
-                        get_other_addr().await.unwrap()
-                    );
-                    remove_other_addr().await;
-                    remove_other_username().await;
-                    set_session_id(CONNECT_SESSION_ID).await;
+                if is_stale(generation).await {
+                    break;
                 }
                 // Get incoming
                 let timeout_result = tokio::time::timeout(
@@ -83,71 +533,246 @@ pub fn host_network_loop(socket: tokio::net::UdpSocket) {
                     },
                     Err(_) => continue,
                 };
+                // Spectators don't keep the connection to the client alive
+                let from_spectator = is_spectator(addr).await;
+                if !from_spectator {
+                    update_last_packet_time().await;
+                }
 
                 if let P2pPacket::Request(req) = incoming_packet {
+                    if from_spectator {
+                        let packet = match req.packet {
+                            P2pRequestPacket::Ping => P2pResponsePacket::Pong,
+                            P2pRequestPacket::Resync | P2pRequestPacket::Spectate { .. } => {
+                                P2pResponsePacket::resync(get_board_state().await)
+                            }
+                            // Spectators can't play, but they may still talk to the host
+                            P2pRequestPacket::Chat { message } => {
+                                push_incoming_chat(SPECTATOR_CHAT_NAME.to_owned(), message).await;
+                                P2pResponsePacket::Acknowledge
+                            }
+                            P2pRequestPacket::Disconnect { reason: _ } => {
+                                info!("Spectator at {:?} stopped watching", addr);
+                                remove_spectator(addr).await;
+                                P2pResponsePacket::Acknowledge
+                            }
+                            packet => {
+                                warn!("Rejected {:?} from spectator at {:?}", packet, addr);
+                                P2pResponsePacket::error(P2pError::Spectator)
+                            }
+                        };
+                        let response = P2pResponse::new(req.session_id, req.transaction_id, packet);
+                        send_host_response(&new_sock, response, addr).await;
+                        continue;
+                    }
+
+                    // The response went lost, so the client sent the request again. Spectators
+                    // are answered above, since their transaction ids aren't the client's
+                    if let Some(response) = queue::get_sent_response(req.transaction_id).await {
+                        send_host_response(&new_sock, response, addr).await;
+                        continue;
+                    }
+
+                    // Join requests come from peers which haven't joined yet, so their
+                    // sequence numbers aren't counted with the client's
+                    let is_join = matches!(
+                        req.packet,
+                        P2pRequestPacket::Connect { .. } | P2pRequestPacket::Spectate { .. }
+                    );
+                    if !is_join && !queue::check_sequence(req.sequence).await {
+                        info!(
+                            "Dropped request {} from {:?}, since it was already handled",
+                            req.sequence, addr
+                        );
+                        // The game action isn't handled again, but the client still waits for
+                        // its acknowledgement
+                        if matches!(req.packet, P2pRequestPacket::GameAction { .. }) {
+                            let response = P2pResponse::new(
+                                get_session_id().await,
+                                req.transaction_id,
+                                P2pResponsePacket::Acknowledge,
+                            );
+                            send_host_response(&new_sock, response, addr).await;
+                        }
+                        continue;
+                    }
+
+                    let is_retransmitted = req.packet.is_retransmitted();
                     let packet = match req.packet {
+                        P2pRequestPacket::GameAction { action, .. }
+                            if is_stale_action(req.sequence, &action).await =>
+                        {
+                            P2pResponsePacket::Acknowledge
+                        }
                         P2pRequestPacket::Ping => P2pResponsePacket::Pong,
                         P2pRequestPacket::Connect {
                             join_code,
                             username,
+                            protocol_version,
                         } => {
-                            if get_other_addr().await.is_some() {
-                                println!(
-                                    "Failed join attempt from {:?} - Game session full.",
-                                    addr
+                            let status = get_connection_status().await;
+                            if protocol_version != PROTOCOL_VERSION {
+                                warn!(
+                                    "Failed join attempt from {:?} - Protocol version {} doesn't match {}.",
+                                    addr, protocol_version, PROTOCOL_VERSION
                                 );
+                                P2pResponsePacket::error(P2pError::IncompatibleVersion)
+                            } else if get_other_addr().await == Some(addr) && status.is_connected()
+                            {
+                                // The joined client didn't get the response, and asked again.
+                                // It gets the same answer, without starting a new session.
+                                info!("{} at {:?} asked to join again", username, addr);
+                                let username = get_my_username().await.unwrap_or("HOST".to_owned());
+
+                                P2pResponsePacket::connect(
+                                    client_color().await,
+                                    username,
+                                    get_time_control().await,
+                                    get_opening_seed().await,
+                                )
+                            } else if get_other_addr().await.is_some() && !status.is_timed_out() {
+                                // A client that timed out may be replaced by a new one, but until
+                                // then its session is kept, so it can reconnect
+                                warn!("Failed join attempt from {:?} - Game session full.", addr);
                                 P2pResponsePacket::error(P2pError::FullGameSession)
-                            } else if join_code != get_join_code().await.unwrap() {
-                                println!("Failed join attempt from {:?} - Wrong join code.", addr);
+                            } else if get_join_code().await.as_ref() != Some(&join_code) {
+                                warn!("Failed join attempt from {:?} - Wrong join code.", addr);
                                 P2pResponsePacket::error(P2pError::InvalidJoinCode)
                             } else if req.session_id != CONNECT_SESSION_ID {
-                                println!(
-                                    "Failed join attempt from {:?} - Wrong session code.",
-                                    addr
-                                );
+                                warn!("Failed join attempt from {:?} - Wrong session code.", addr);
                                 P2pResponsePacket::error(P2pError::InvalidSessionId)
+                            } else if let Err(err) = validate_username(&username) {
+                                warn!("Failed join attempt from {:?} - {}", addr, err);
+                                P2pResponsePacket::error(P2pError::InvalidUsername)
                             } else {
-                                println!("{} at {:?} Joined the game!", username, addr);
+                                info!("{} at {:?} Joined the game!", username, addr);
 
                                 set_session_id(rand::random::<u16>()).await;
+                                queue::reset_sequences().await;
+                                queue::reset_net_stats();
+                                set_disconnect_reason(None).await;
+                                set_game_result(None).await;
                                 set_connection_status(ConnectionStatus::connected()).await;
                                 set_other_addr(addr).await;
                                 set_other_username(&username).await;
+                                save_session().await;
                                 let username = get_my_username().await.unwrap_or("HOST".to_owned());
 
-                                P2pResponsePacket::Connect {
-                                    client_color: PieceColor::White,
-                                    host_username: username,
-                                }
+                                P2pResponsePacket::connect(
+                                    client_color().await,
+                                    username,
+                                    get_time_control().await,
+                                    get_opening_seed().await,
+                                )
                             }
                         }
-                        P2pRequestPacket::Resync => P2pResponsePacket::resync(vec![]),
-                        P2pRequestPacket::GameAction { action } => {
+                        P2pRequestPacket::Resync => {
+                            P2pResponsePacket::resync(get_board_state().await)
+                        }
+                        P2pRequestPacket::Chat { message } => {
+                            let sender = get_other_username().await.unwrap_or_default();
+                            push_incoming_chat(sender, message).await;
+                            P2pResponsePacket::Acknowledge
+                        }
+                        P2pRequestPacket::SyncCheck { hash, ply } => {
+                            record_board_hash(ply, hash, false).await;
+                            P2pResponsePacket::Acknowledge
+                        }
+                        P2pRequestPacket::Spectate { join_code } => {
+                            // A host without a join code can't be watched either
+                            if get_join_code().await.as_ref() != Some(&join_code) {
+                                warn!("Failed spectate attempt from {:?} - Wrong join code.", addr);
+                                P2pResponsePacket::error(P2pError::InvalidJoinCode)
+                            } else {
+                                info!("Spectator at {:?} is watching the game", addr);
+                                add_spectator(addr).await;
+                                P2pResponsePacket::resync(get_board_state().await)
+                            }
+                        }
+                        P2pRequestPacket::Disconnect { reason } => {
+                            // Only the client can end its connection
+                            if get_other_addr().await == Some(addr) {
+                                recieve_disconnect(reason).await;
+                            }
+                            P2pResponsePacket::Acknowledge
+                        }
+                        P2pRequestPacket::Reconnect => {
+                            if req.session_id == CONNECT_SESSION_ID
+                                || req.session_id != get_session_id().await
+                            {
+                                warn!(
+                                    "Failed reconnect attempt from {:?} - Unknown session id.",
+                                    addr
+                                );
+                                P2pResponsePacket::error(P2pError::InvalidSessionId)
+                            } else {
+                                info!("Client reconnected from {:?}", addr);
+                                set_other_addr(addr).await;
+                                save_session().await;
+                                set_connection_status(ConnectionStatus::connected()).await;
+                                P2pResponsePacket::resync(get_board_state().await)
+                            }
+                        }
+                        P2pRequestPacket::GameAction { action, .. }
+                            if is_refused_after_game(&action).await =>
+                        {
+                            P2pResponsePacket::error(P2pError::GameOver)
+                        }
+                        P2pRequestPacket::GameAction { action, .. } if is_incoming_full().await => {
+                            refuse_for_full_queue(&action).await
+                        }
+                        P2pRequestPacket::GameAction { action, board_hash } => {
+                            check_board_hash(&action, board_hash, false).await;
                             match action {
-                                GameAction::Surrender => {
-                                    // TODO: Verify Surrender
-                                    push_incoming_gameaction(action).await;
-                                    P2pResponsePacket::Acknowledge
+                                GameAction::Surrender | GameAction::TimeExpired => {
+                                    recieve_game_end(action).await
                                 }
-                                GameAction::Stalemate => {
-                                    // TODO: Verify Stalemate
-                                    push_incoming_gameaction(action).await;
-                                    P2pResponsePacket::Acknowledge
+                                GameAction::Rematch | GameAction::RematchAccept => {
+                                    recieve_rematch_action(action).await
                                 }
-                                GameAction::MovePiece(_) => {
-                                    // TODO: Verify move
+                                // The game checks if the moves can still be taken back
+                                GameAction::RequestTakeback(_) | GameAction::RespondTakeback(_) => {
                                     push_incoming_gameaction(action).await;
                                     P2pResponsePacket::Acknowledge
                                 }
+                                GameAction::OfferDraw | GameAction::RespondDraw(_) => {
+                                    recieve_draw_action(action).await
+                                }
+                                GameAction::MovePiece(ref mov) if !is_remote_turn().await => {
+                                    warn!("Rejected move out of turn from the client: {:?}", mov);
+                                    P2pResponsePacket::error(P2pError::OutOfTurn)
+                                }
+                                GameAction::MovePiece(ref mov) => {
+                                    if is_legal_remote_move(mov).await {
+                                        pass_turn_to_me().await;
+                                        let forwarded = P2pRequest::new(
+                                            get_session_id().await,
+                                            new_transaction_id().await,
+                                            P2pRequestPacket::game_action(
+                                                action.clone(),
+                                                board_hash,
+                                            ),
+                                        );
+                                        forward_to_spectators(&new_sock, &forwarded).await;
+                                        push_incoming_gameaction(action).await;
+                                        P2pResponsePacket::Acknowledge
+                                    } else {
+                                        warn!("Rejected illegal move from the client: {:?}", mov);
+                                        P2pResponsePacket::error(P2pError::IllegalMove)
+                                    }
+                                }
                             }
                         }
                     };
                     let session_id = get_session_id().await;
                     let response = P2pResponse::new(session_id, req.transaction_id, packet);
-                    queue::push_outgoing_queue(P2pPacket::Response(response), None).await;
-                    time_since_ping = Instant::now();
+                    if is_retransmitted {
+                        queue::cache_sent_response(&response).await;
+                    }
+                    send_host_response(&new_sock, response, addr).await;
                 } else if let P2pPacket::Response(resp) = incoming_packet {
-                    if !queue::check_transaction_id(resp.transaction_id).await {
+                    // Spectators only acknowledge forwarded moves, which nothing waits for
+                    if from_spectator || !queue::check_transaction_id(resp.transaction_id).await {
                         continue;
                     }
                     queue::set_response(resp.transaction_id, Some(P2pPacket::Response(resp))).await;
@@ -164,83 +789,107 @@ pub fn host_network_loop(socket: tokio::net::UdpSocket) {
 ///         - Send a ping.
 ///         - Check for incoming messages and respond accordingly.
 ///
-/// When entering, it requires the open  UdpSocket, as well as how many pings pr. second the client
-/// should send.
-pub fn client_network_loop(socket: tokio::net::UdpSocket, pings: usize) {
+/// When entering, it requires the open  UdpSocket, as well as the `ClientConfig` which says how
+/// often the client pings the host, and how long it waits for an answer. At most `max_outgoing_len` packets wait in the outgoing queue, see
+/// `queue::push_outgoing_queue`.
+///
+/// Starting a new loop stops the tasks of the running one, so the client can switch to a new
+/// socket when it reconnects.
+pub async fn client_network_loop(
+    socket: tokio::net::UdpSocket,
+    config: ClientConfig,
+    max_outgoing_len: usize,
+) {
+    queue::set_max_outgoing_len(max_outgoing_len);
     let socket = Arc::new(socket);
-    // Ping host
-    tokio::spawn({
-        println!("Starting Client Ping Host");
-        let mut interval = tokio::time::interval(Duration::from_millis((1000 / pings) as u64));
-        async move {
-            loop {
-                interval.tick().await;
+    let generation = new_loop_generation().await;
+    spawn_heartbeat_watchdog(generation);
+    spawn_retransmitter(generation);
+    // Ping host. Without pings, the connection is only kept track of with the hosts pings, by the
+    // heartbeat watchdog
+    if let Some(ping_interval) = config.ping_interval() {
+        tokio::spawn({
+            debug!("Starting client ping task");
+            let mut interval = tokio::time::interval(ping_interval);
+            async move {
+                loop {
+                    if is_stale(generation).await {
+                        break;
+                    }
+                    interval.tick().await;
 
-                let connection_status = get_connection_status().await;
-                if !connection_status.is_connected() && !connection_status.is_reconnecting() {
-                    continue;
-                }
-                if get_other_addr().await.is_none() {
-                    continue;
-                }
+                    let connection_status = get_connection_status().await;
+                    if !connection_status.is_connected() && !connection_status.is_reconnecting() {
+                        continue;
+                    }
+                    if get_other_addr().await.is_none() {
+                        continue;
+                    }
 
-                let time = Instant::now();
+                    let time = Instant::now();
 
-                let session_id = get_session_id().await;
+                    let session_id = get_session_id().await;
 
-                let ping_id = new_transaction_id().await;
-                let ping = P2pRequest::new(session_id, ping_id, P2pRequestPacket::Ping);
+                    let ping_id = new_transaction_id().await;
+                    let ping = P2pRequest::new(session_id, ping_id, P2pRequestPacket::Ping);
 
-                push_outgoing_queue(P2pPacket::Request(ping), None).await;
+                    // The queue is full of more important packets, so this ping can wait
+                    if push_outgoing_queue(P2pPacket::Request(ping), None)
+                        .await
+                        .is_err()
+                    {
+                        continue;
+                    }
 
-                match tokio::time::timeout(
-                    Duration::from_millis(REQUEST_TIMEOUT_MS as u64),
-                    wait_for_response(ping_id),
-                )
-                .await
-                {
-                    Ok(data) => {
-                        if let P2pPacket::Response(pong) = data {
-                            if pong.packet != P2pResponsePacket::Pong {
-                                println!("Got wrong packet, expected pong, got: ");
-                                dbg!(&pong);
-                            }
-                            let elapsed_ms = time.elapsed().as_millis();
-                            println!("ping: {} ms", elapsed_ms);
-                            if get_connection_status().await.is_reconnecting() {
-                                set_connection_status(ConnectionStatus::connected()).await;
+                    match tokio::time::timeout(config.connect_timeout, wait_for_response(ping_id))
+                        .await
+                    {
+                        Ok(data) => {
+                            if let P2pPacket::Response(pong) = data {
+                                if pong.packet != P2pResponsePacket::Pong {
+                                    warn!("Got wrong packet, expected pong, got: {:?}", pong);
+                                }
+                                trace!("ping: {} ms", time.elapsed().as_millis());
+                                if get_connection_status().await.is_reconnecting() {
+                                    set_connection_status(ConnectionStatus::connected()).await;
+                                }
+                                record_latency(time).await;
                             }
-                            set_connection_ping(elapsed_ms).await;
                         }
-                    }
-                    Err(e) => {
-                        if let ConnectionStatus::Reconnecting { tries } =
-                            get_connection_status().await
-                        {
-                            println!("Trying to reconnect... ({} / {})", tries, RECONNECT_TRIES);
-                            if tries >= RECONNECT_TRIES as u8 {
-                                set_connection_status(ConnectionStatus::Disconnected).await;
-                                remove_other_addr().await;
-                                remove_other_username().await;
-                                println!("Disconnected from host");
+                        Err(e) => {
+                            queue::count_timeout();
+                            queue::remove_transaction(ping_id).await;
+                            if let ConnectionStatus::Reconnecting { tries } =
+                                get_connection_status().await
+                            {
+                                info!("Trying to reconnect... ({} / {})", tries, RECONNECT_TRIES);
+                                if tries >= RECONNECT_TRIES as u8 {
+                                    set_connection_status(ConnectionStatus::Disconnected).await;
+                                    remove_other_addr().await;
+                                    remove_other_username().await;
+                                    warn!("Disconnected from host");
+                                } else {
+                                    set_reconnect_tries(tries + 1).await;
+                                }
                             } else {
-                                set_reconnect_tries(tries + 1).await;
+                                warn!("Ping request time out: {}", e);
+                                set_connection_status(ConnectionStatus::reconnecting()).await;
                             }
-                        } else {
-                            println!("Ping request time out: {}", e.to_string());
-                            set_connection_status(ConnectionStatus::reconnecting()).await;
                         }
                     }
                 }
             }
-        }
-    });
+        });
+    }
     // Handle outgoing queue
     tokio::spawn({
-        println!("Starting Client Handle outgoing queue");
+        debug!("Starting client outgoing queue task");
         let new_sock = socket.clone();
         async move {
             loop {
+                if is_stale(generation).await {
+                    break;
+                }
                 let host_addr = match get_other_addr().await.clone() {
                     Some(addr) => addr,
                     None => {
@@ -249,7 +898,8 @@ pub fn client_network_loop(socket: tokio::net::UdpSocket, pings: usize) {
                     }
                 };
                 if let Some((data, id)) = queue::pop_outgoing_queue().await {
-                    println!("Sending Packet with ID {}... ({:?})", id, data);
+                    trace!("Sending packet with ID {}... ({:?})", id, data);
+                    queue::track_sent_packet(&data).await;
                     send_p2p_packet(&new_sock, data, host_addr).await.unwrap();
                 } else {
                     tokio::time::sleep(Duration::from_millis(50)).await;
@@ -259,10 +909,13 @@ pub fn client_network_loop(socket: tokio::net::UdpSocket, pings: usize) {
     });
     // Handle incoming responses
     tokio::spawn({
-        println!("Starting Client Handle incoming responses");
+        debug!("Starting client incoming packet task");
         let new_sock = socket.clone();
         async move {
             loop {
+                if is_stale(generation).await {
+                    break;
+                }
                 let timeout_result = tokio::time::timeout(
                     Duration::from_millis(REQUEST_TIMEOUT_MS as u64),
                     recieve_p2p_packet(&new_sock),
@@ -273,48 +926,118 @@ pub fn client_network_loop(socket: tokio::net::UdpSocket, pings: usize) {
                     Ok(Ok(packet)) => packet,
                     _ => continue,
                 };
-                if addr != get_other_addr().await.unwrap() {
+                if get_other_addr().await != Some(addr) {
                     continue;
                 }
+                update_last_packet_time().await;
                 if let P2pPacket::Request(req) = incoming_packet {
+                    // The response went lost, so the host sent the request again
+                    if let Some(response) = queue::get_sent_response(req.transaction_id).await {
+                        if let Err(err) = send_p2p_packet(&new_sock, response, addr).await {
+                            warn!("Failed to resend response to {:?}: {:#}", addr, err);
+                        }
+                        continue;
+                    }
+
+                    if !queue::check_sequence(req.sequence).await {
+                        info!(
+                            "Dropped request {} from the host, since it was already handled",
+                            req.sequence
+                        );
+                        // The game action isn't handled again, but the host still waits for its
+                        // acknowledgement
+                        if matches!(req.packet, P2pRequestPacket::GameAction { .. }) {
+                            let response = P2pResponse::new(
+                                req.session_id,
+                                req.transaction_id,
+                                P2pResponsePacket::Acknowledge,
+                            );
+                            if let Err(err) = send_p2p_packet(&new_sock, response, addr).await {
+                                warn!("Failed to acknowledge {:?}: {:#}", addr, err);
+                            }
+                        }
+                        continue;
+                    }
+
+                    let is_retransmitted = req.packet.is_retransmitted();
                     let packet = match req.packet {
+                        P2pRequestPacket::GameAction { action, .. }
+                            if is_stale_action(req.sequence, &action).await =>
+                        {
+                            P2pResponsePacket::Acknowledge
+                        }
                         P2pRequestPacket::Ping => P2pResponsePacket::Pong,
-                        P2pRequestPacket::GameAction { action } => {
+                        P2pRequestPacket::GameAction { action, .. }
+                            if is_refused_after_game(&action).await =>
+                        {
+                            P2pResponsePacket::error(P2pError::GameOver)
+                        }
+                        P2pRequestPacket::GameAction { action, .. } if is_incoming_full().await => {
+                            refuse_for_full_queue(&action).await
+                        }
+                        P2pRequestPacket::GameAction { action, board_hash } => {
+                            check_board_hash(&action, board_hash, true).await;
                             match action {
-                                GameAction::Surrender => {
-                                    // TODO: Verify Surrender
-                                    push_incoming_gameaction(action).await;
-                                    println!(
-                                        "Incoming action len: {}",
-                                        get_incoming_gameaction_len().await
-                                    );
-                                    P2pResponsePacket::Acknowledge
+                                GameAction::Surrender | GameAction::TimeExpired => {
+                                    recieve_game_end(action).await
                                 }
-                                GameAction::Stalemate => {
-                                    // TODO: Verify stalemate
-                                    push_incoming_gameaction(action).await;
-                                    println!(
-                                        "Incoming action len: {}",
-                                        get_incoming_gameaction_len().await
-                                    );
-                                    P2pResponsePacket::Acknowledge
+                                GameAction::OfferDraw | GameAction::RespondDraw(_) => {
+                                    recieve_draw_action(action).await
+                                }
+                                GameAction::Rematch | GameAction::RematchAccept => {
+                                    recieve_rematch_action(action).await
                                 }
-                                GameAction::MovePiece(_) => {
-                                    // TODO: Verify move
+                                // The game checks if the moves can still be taken back
+                                GameAction::RequestTakeback(_) | GameAction::RespondTakeback(_) => {
                                     push_incoming_gameaction(action).await;
-                                    println!(
-                                        "Incoming action len: {}",
-                                        get_incoming_gameaction_len().await
-                                    );
                                     P2pResponsePacket::Acknowledge
                                 }
+                                GameAction::MovePiece(ref mov) if !is_remote_turn().await => {
+                                    warn!("Rejected move out of turn from the host: {:?}", mov);
+                                    P2pResponsePacket::error(P2pError::OutOfTurn)
+                                }
+                                GameAction::MovePiece(ref mov) => {
+                                    if is_legal_remote_move(mov).await {
+                                        pass_turn_to_me().await;
+                                        if is_spectating().await {
+                                            apply_shared_move(mov).await;
+                                        }
+                                        push_incoming_gameaction(action).await;
+                                        trace!(
+                                            "Incoming action len: {}",
+                                            get_incoming_gameaction_len().await
+                                        );
+                                        P2pResponsePacket::Acknowledge
+                                    } else {
+                                        warn!("Rejected illegal move from the host: {:?}", mov);
+                                        P2pResponsePacket::error(P2pError::IllegalMove)
+                                    }
+                                }
                             }
                         }
+                        P2pRequestPacket::Chat { message } => {
+                            let sender = get_other_username().await.unwrap_or_default();
+                            push_incoming_chat(sender, message).await;
+                            P2pResponsePacket::Acknowledge
+                        }
+                        P2pRequestPacket::SyncCheck { hash, ply } => {
+                            record_board_hash(ply, hash, false).await;
+                            P2pResponsePacket::Acknowledge
+                        }
+                        P2pRequestPacket::Disconnect { reason } => {
+                            recieve_disconnect(reason).await;
+                            P2pResponsePacket::Acknowledge
+                        }
                         _ => P2pResponsePacket::error(P2pError::WrongDirection),
                     };
                     let response = P2pResponse::new(req.session_id, req.transaction_id, packet);
-                    send_p2p_packet(&new_sock, response, addr).await.unwrap();
-                    println!("Sent package");
+                    if is_retransmitted {
+                        queue::cache_sent_response(&response).await;
+                    }
+                    match send_p2p_packet(&new_sock, response, addr).await {
+                        Ok(_) => trace!("Sent response {}", req.transaction_id),
+                        Err(err) => warn!("Failed to send response to {:?}: {:#}", addr, err),
+                    }
                 } else if let P2pPacket::Response(resp) = incoming_packet {
                     // if !queue::check_transaction_id(resp.transaction_id).await {
                     //     continue;
@@ -325,3 +1048,73 @@ pub fn client_network_loop(socket: tokio::net::UdpSocket, pings: usize) {
         }
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::UdpSocket;
+
+    use super::*;
+    use crate::net::{
+        status::{set_heartbeat_timeout, set_my_color, DEFAULT_HEARTBEAT_TIMEOUT},
+        test_utils::{reset_state, MockPeer, CLIENT_WITHOUT_PINGS, TEST_LOCK},
+    };
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn repeated_move_is_only_made_once() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        set_my_color(PieceColor::Black).await;
+        set_whose_turn(PieceColor::White).await;
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = socket.local_addr().unwrap();
+        let host = MockPeer::bind().await;
+        set_other_addr(host.addr()).await;
+        client_network_loop(socket, CLIENT_WITHOUT_PINGS, 8).await;
+
+        let mov = Move {
+            index: 21,
+            end: 17,
+            promoted: false,
+            captured: None,
+        };
+        let request = |transaction_id| P2pRequest {
+            session_id: 77,
+            transaction_id,
+            sequence: 10,
+            packet: P2pRequestPacket::game_action(GameAction::MovePiece(mov.clone()), 0),
+        };
+
+        // The same request twice, and the same move sent again under a new transaction id
+        for transaction_id in [1, 1, 2] {
+            let response = host.request(request(transaction_id), client).await;
+            assert_eq!(response.transaction_id, transaction_id);
+            assert_eq!(response.packet, P2pResponsePacket::Acknowledge);
+        }
+
+        assert!(matches!(
+            queue::pop_incoming_gameaction().await,
+            Some(GameAction::MovePiece(made)) if made == mov
+        ));
+        assert!(queue::pop_incoming_gameaction().await.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn silent_peer_times_out() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        set_heartbeat_timeout(Duration::from_millis(100)).await;
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let host = MockPeer::bind().await;
+        set_other_addr(host.addr()).await;
+        set_connection_status(ConnectionStatus::connected()).await;
+        update_last_packet_time().await;
+        client_network_loop(socket, CLIENT_WITHOUT_PINGS, 8).await;
+
+        tokio::time::sleep(Duration::from_millis(HEARTBEAT_CHECK_MS * 2)).await;
+        assert!(get_connection_status().await.is_timed_out());
+
+        set_heartbeat_timeout(DEFAULT_HEARTBEAT_TIMEOUT).await;
+    }
+}
@@ -1,27 +1,161 @@
 use std::{
-    collections::{HashMap, VecDeque},
-    sync::Arc,
+    collections::{BTreeSet, HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
 use lazy_static::lazy_static;
-use tokio::sync::Mutex;
+use tokio::{
+    sync::{Mutex, Notify},
+    time::Instant,
+};
+use tracing::warn;
+
+use crate::{game::GameAction, net::net_utils::NetworkError};
+
+use super::{P2pPacket, P2pRequestPacket, P2pResponse};
+
+/// A closure that runs when a request has gotten a response, or when the request is given up on
+/// because it never got one.
+pub type ResponseClosure = Arc<Mutex<dyn FnMut(anyhow::Result<P2pResponse>) + Send + Sync>>;
+
+/// The default time to wait for a response, before a request is sent again
+pub const DEFAULT_RETRANSMIT_INTERVAL: Duration = Duration::from_millis(500);
+/// The default number of times a request is sent again, before it is given up on
+pub const DEFAULT_MAX_RETRANSMITS: u32 = 5;
+/// The default number of packets the outgoing queue can hold, see `set_max_outgoing_len`
+pub const DEFAULT_MAX_OUTGOING_LEN: usize = 256;
+/// The default number of game actions, and of chat messages, the incoming queues can hold, see
+/// `set_max_incoming_len`
+pub const DEFAULT_MAX_INCOMING_LEN: usize = 256;
+/// How many responses are remembered, for answering requests that are recieved twice
+const RESPONSE_CACHE_LEN: usize = 64;
+/// How far behind the highest recieved sequence number a request may be, and still be handled.
+/// Requests which are sent again arrive after newer ones, so they can't just be dropped.
+const SEQUENCE_WINDOW: u32 = 1024;
+
+/// How much of a new round-trip time sample goes into the estimate, as `1 / RTT_SMOOTHING`
+const RTT_SMOOTHING: u32 = 8;
+
+/// Statistics about the packets sent to and recieved from the other peer, for diagnosing a bad
+/// connection
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NetStats {
+    /// The number of packets sent, including requests which were sent again
+    pub packets_sent: u64,
+    /// The number of packets recieved, including requests which were recieved twice
+    pub packets_recieved: u64,
+    /// The number of requests which have gotten a response
+    pub packets_acked: u64,
+    /// The number of times a request was sent again, because it didn't get a response in time
+    pub retransmissions: u64,
+    /// The number of requests which were given up on, because they never got a response
+    pub timeouts: u64,
+    /// The smoothed time between queueing a request and getting its response, or `None` before
+    /// the first response
+    pub rtt: Option<Duration>,
+}
+
+/// The counters behind `NetStats`. Every network task counts its packets, so they are atomics,
+/// which are cheap to count with and never wait for each other
+struct NetCounters {
+    packets_sent: AtomicU64,
+    packets_recieved: AtomicU64,
+    packets_acked: AtomicU64,
+    retransmissions: AtomicU64,
+    timeouts: AtomicU64,
+    /// The smoothed round-trip time in nanoseconds, or `0` before the first response
+    rtt_nanos: AtomicU64,
+}
+
+/// The statistics since the session or the game was started, see `reset_net_stats`
+static NET_STATS: NetCounters = NetCounters {
+    packets_sent: AtomicU64::new(0),
+    packets_recieved: AtomicU64::new(0),
+    packets_acked: AtomicU64::new(0),
+    retransmissions: AtomicU64::new(0),
+    timeouts: AtomicU64::new(0),
+    rtt_nanos: AtomicU64::new(0),
+};
+
+/// Adds a round-trip time sample to the estimate
+fn record_rtt(sample: Duration) {
+    let sample = sample.as_nanos().max(1) as u64;
+    let _ = NET_STATS
+        .rtt_nanos
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |rtt| {
+            Some(match rtt {
+                0 => sample,
+                rtt => rtt - rtt / RTT_SMOOTHING as u64 + sample / RTT_SMOOTHING as u64,
+            })
+        });
+}
+
+/// The number of packets the outgoing queue can hold
+static MAX_OUTGOING_LEN: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_OUTGOING_LEN);
+
+/// The number of game actions, and of chat messages, the incoming queues can hold
+static MAX_INCOMING_LEN: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_INCOMING_LEN);
+
+/// The sequence number of the next request sent, see `P2pRequest::sequence`
+static NEXT_SEQUENCE: AtomicU32 = AtomicU32::new(1);
+
+/// The sequence numbers of the recently recieved requests
+struct SequenceWindow {
+    highest: u32,
+    seen: BTreeSet<u32>,
+    /// The sequence number of the latest request whose game action was handled, or `0` before
+    /// the first one
+    last_action: u32,
+}
+
+/// The sequence numbers of the requests recieved from the other peer in this session
+static RECIEVED_SEQUENCES: Mutex<SequenceWindow> = Mutex::const_new(SequenceWindow {
+    highest: 0,
+    seen: BTreeSet::new(),
+    last_action: 0,
+});
+
+lazy_static! {
+    static ref TRANSACTION_TABLE: Mutex<HashMap<u16, (Option<P2pPacket>, Option<ResponseClosure>)>> =
+        Mutex::const_new(HashMap::new());
+}
+
+/// A sent request which hasn't gotten a response yet
+struct PendingRequest {
+    packet: P2pPacket,
+    sent_at: Instant,
+    retransmits: u32,
+}
 
-use crate::game::GameAction;
+lazy_static! {
+    /// Requests which have been sent, but haven't gotten a response yet, by transaction id.
+    static ref PENDING_REQUESTS: Mutex<HashMap<u16, PendingRequest>> =
+        Mutex::const_new(HashMap::new());
+}
 
-use super::{P2pPacket, P2pResponse};
+lazy_static! {
+    /// When each request waiting for a response was queued, by transaction id, for estimating the
+    /// round-trip time.
+    static ref REQUEST_TIMES: Mutex<HashMap<u16, Instant>> = Mutex::const_new(HashMap::new());
+}
 
 lazy_static! {
-    static ref TRANSACTION_TABLE: Mutex<
-        HashMap<
-            u16,
-            (
-                Option<P2pPacket>,
-                Option<Arc<Mutex<dyn FnMut(P2pResponse) + Send + Sync>>>
-            ),
-        >,
-    > = Mutex::const_new(HashMap::new());
+    /// The latest responses sent to the other peer, so a request which is recieved again, because
+    /// the response went lost, gets the same response without being handled twice.
+    static ref SENT_RESPONSES: Mutex<VecDeque<P2pResponse>> = Mutex::const_new(VecDeque::new());
 }
 
+/// Woken whenever a response is stored in the transaction table, see `wait_for_response`
+static RESPONSE_STORED: Notify = Notify::const_new();
+
+/// How long to wait for a response before sending a request again, and how many times to do so
+static RETRANSMIT_POLICY: Mutex<(Duration, u32)> =
+    Mutex::const_new((DEFAULT_RETRANSMIT_INTERVAL, DEFAULT_MAX_RETRANSMITS));
+
 lazy_static! {
     /// Queue for outgoing packets. Follows First in First out principle.
     /// Each item in the queue is a tuple of two items: The outgoing packet, and a closure that runs when
@@ -31,30 +165,76 @@ lazy_static! {
         Mutex::const_new(VecDeque::new());
 }
 
+lazy_static! {
+    /// A list which holds all chat messages sent from the other user, along with their username.
+    /// Kept apart from `INCOMING_ACTIONS`, so chatting never reorders the game actions.
+    static ref INCOMING_CHAT: Mutex<VecDeque<(String, String)>> =
+        Mutex::const_new(VecDeque::new());
+}
+
 lazy_static! {
     /// A list which holds all `GameActions` send from the other user.
     static ref INCOMING_ACTIONS: Mutex<VecDeque<GameAction>> =
         Mutex::const_new(VecDeque::new());
 }
 
+/// Queues `data` to be sent, and returns its transaction id.
+///
+/// If the queue is full, eg. because the other peer can't be reached, the oldest ping in it is
+/// thrown away to make room. Pings are never queued in place of other packets, so if there is no
+/// ping to throw away, or `data` is a ping, it is refused with `NetworkError::QueueFull`.
 pub async fn push_outgoing_queue(
     data: P2pPacket,
-    closure: Option<Arc<Mutex<(dyn FnMut(P2pResponse) + Send + Sync + 'static)>>>,
-) -> u16 {
+    closure: Option<ResponseClosure>,
+) -> Result<u16, NetworkError> {
     let transaction_id = match &data {
         P2pPacket::Request(req) => req.transaction_id,
         P2pPacket::Response(resp) => resp.transaction_id,
     };
-    OUTGOING_QUEUE
-        .lock()
-        .await
-        .push_back((data, transaction_id));
+
+    let mut outgoing = OUTGOING_QUEUE.lock().await;
+    make_room(&mut outgoing, &data)?;
+    if data.is_request() {
+        REQUEST_TIMES
+            .lock()
+            .await
+            .insert(transaction_id, Instant::now());
+    }
+    outgoing.push_back((data, transaction_id));
+    drop(outgoing);
 
     TRANSACTION_TABLE
         .lock()
         .await
         .insert(transaction_id, (None, closure));
-    transaction_id
+    Ok(transaction_id)
+}
+
+/// Throws away the oldest ping in `outgoing` if it is full, so `data` can be queued, see
+/// `push_outgoing_queue`.
+fn make_room(
+    outgoing: &mut VecDeque<(P2pPacket, u16)>,
+    data: &P2pPacket,
+) -> Result<(), NetworkError> {
+    let capacity = MAX_OUTGOING_LEN.load(Ordering::Relaxed);
+    if outgoing.len() < capacity {
+        return Ok(());
+    }
+
+    let oldest_ping = outgoing.iter().position(|(packet, _)| packet.is_ping());
+    match oldest_ping {
+        Some(index) if !data.is_ping() => {
+            outgoing.remove(index);
+            Ok(())
+        }
+        _ => Err(NetworkError::QueueFull { capacity }),
+    }
+}
+
+/// Sets how many packets the outgoing queue can hold, see `push_outgoing_queue`.
+/// Packets which are already queued are kept, even if there are more of them.
+pub fn set_max_outgoing_len(len: usize) {
+    MAX_OUTGOING_LEN.store(len, Ordering::Relaxed);
 }
 
 /// Pops and returns the next item in the outgoing network queue.
@@ -70,15 +250,22 @@ pub async fn get_outgoing_queue_len() -> usize {
 /// If the transaction has a closure, this will run that closure, and then remove the request and
 /// its response.
 pub async fn set_response(transaction_id: u16, response: Option<P2pPacket>) {
+    PENDING_REQUESTS.lock().await.remove(&transaction_id);
+    if let Some(queued_at) = REQUEST_TIMES.lock().await.remove(&transaction_id) {
+        NET_STATS.packets_acked.fetch_add(1, Ordering::Relaxed);
+        record_rtt(queued_at.elapsed());
+    }
+
     let table = &mut TRANSACTION_TABLE.lock().await;
     if let Some((_, closure)) = table.get(&transaction_id) {
         if let Some(closure) = closure {
             if let Some(P2pPacket::Response(resp)) = response.clone() {
-                closure.lock().await(resp);
+                closure.lock().await(Ok(resp));
             }
             table.remove(&transaction_id);
         } else {
             table.insert(transaction_id, (response, None));
+            RESPONSE_STORED.notify_waiters();
         };
     }
 }
@@ -99,6 +286,61 @@ pub async fn new_transaction_id() -> u16 {
     transaction_id
 }
 
+/// Returns the packet of the request with `transaction_id`, if it is waiting to be sent or waiting
+/// for a response.
+pub async fn get_outgoing_request(transaction_id: u16) -> Option<P2pRequestPacket> {
+    let request_packet = |packet: &P2pPacket| match packet {
+        P2pPacket::Request(req) if req.transaction_id == transaction_id => Some(req.packet.clone()),
+        _ => None,
+    };
+
+    if let Some(packet) = OUTGOING_QUEUE
+        .lock()
+        .await
+        .iter()
+        .find_map(|(packet, _)| request_packet(packet))
+    {
+        return Some(packet);
+    }
+    PENDING_REQUESTS
+        .lock()
+        .await
+        .get(&transaction_id)
+        .and_then(|pending| request_packet(&pending.packet))
+}
+
+/// Withdraws the request with `transaction_id`, whether it is still waiting to be sent or waiting
+/// for a response. Its closure is dropped without being run, and a response which arrives later
+/// is ignored. Returns false if there was no such request.
+pub async fn cancel_outgoing(transaction_id: u16) -> bool {
+    let mut outgoing = OUTGOING_QUEUE.lock().await;
+    let queued = outgoing.len();
+    outgoing
+        .retain(|(packet, id)| *id != transaction_id || !matches!(packet, P2pPacket::Request(_)));
+    let was_queued = outgoing.len() != queued;
+    drop(outgoing);
+
+    let was_pending = PENDING_REQUESTS
+        .lock()
+        .await
+        .remove(&transaction_id)
+        .is_some();
+    REQUEST_TIMES.lock().await.remove(&transaction_id);
+    let was_tracked = TRANSACTION_TABLE
+        .lock()
+        .await
+        .remove(&transaction_id)
+        .is_some();
+    was_queued || was_pending || was_tracked
+}
+
+/// Forgets the transaction with `transaction_id`, eg. when its request has timed out.
+pub async fn remove_transaction(transaction_id: u16) {
+    TRANSACTION_TABLE.lock().await.remove(&transaction_id);
+    PENDING_REQUESTS.lock().await.remove(&transaction_id);
+    REQUEST_TIMES.lock().await.remove(&transaction_id);
+}
+
 pub async fn check_transaction_id(transaction_id: u16) -> bool {
     TRANSACTION_TABLE
         .lock()
@@ -122,36 +364,44 @@ pub async fn check_for_response(transaction_id: u16) -> Option<P2pPacket> {
     response.0
 }
 
-/// Wait for the transaction ID to get a response
+/// Wait for the transaction ID to get a response.
+/// The task sleeps until a response is stored, instead of checking the transaction table over
+/// and over.
 pub async fn wait_for_response(transaction_id: u16) -> P2pPacket {
     loop {
-        let response = TRANSACTION_TABLE
-            .lock()
-            .await
-            .clone()
-            .get(&transaction_id)
-            .unwrap_or(&(None, None))
-            .clone();
+        // Registered before looking, so a response stored in between still wakes the task
+        let stored = RESPONSE_STORED.notified();
+        tokio::pin!(stored);
+        stored.as_mut().enable();
 
-        if let Some(resp) = response.0 {
-            TRANSACTION_TABLE.lock().await.remove(&transaction_id);
-            return resp.clone();
+        let mut table = TRANSACTION_TABLE.lock().await;
+        if let Some((Some(resp), _)) = table.get(&transaction_id) {
+            let resp = resp.clone();
+            table.remove(&transaction_id);
+            return resp;
         }
+        drop(table);
+        stored.await;
     }
 }
 
-pub async fn get_transaction_table() -> HashMap<
-    u16,
-    (
-        Option<P2pPacket>,
-        Option<Arc<Mutex<dyn FnMut(P2pResponse) + Send + Sync>>>,
-    ),
-> {
+pub async fn get_transaction_table() -> HashMap<u16, (Option<P2pPacket>, Option<ResponseClosure>)> {
     TRANSACTION_TABLE.lock().await.clone()
 }
 
+/// Queues a game action from the other user for the game to handle.
+/// The network loops refuse game actions with `P2pError::QueueFull` while `is_incoming_full`, so
+/// the other user can send them again. An action queued past the capacity anyway is dropped.
 pub async fn push_incoming_gameaction(action: GameAction) {
-    INCOMING_ACTIONS.lock().await.push_back(action);
+    let mut incoming = INCOMING_ACTIONS.lock().await;
+    if incoming.len() >= MAX_INCOMING_LEN.load(Ordering::Relaxed) {
+        warn!(
+            "Dropped incoming game action, since the queue is full: {:?}",
+            action
+        );
+        return;
+    }
+    incoming.push_back(action);
 }
 pub async fn pop_incoming_gameaction() -> Option<GameAction> {
     INCOMING_ACTIONS.lock().await.pop_front()
@@ -159,3 +409,500 @@ pub async fn pop_incoming_gameaction() -> Option<GameAction> {
 pub async fn get_incoming_gameaction_len() -> usize {
     INCOMING_ACTIONS.lock().await.len()
 }
+
+/// Returns true if no more game actions can be queued, because the game hasn't handled the ones
+/// it got.
+pub async fn is_incoming_full() -> bool {
+    INCOMING_ACTIONS.lock().await.len() >= MAX_INCOMING_LEN.load(Ordering::Relaxed)
+}
+
+/// Returns how many game actions and chat messages are waiting to be handled by the game
+pub async fn get_incoming_queue_len() -> usize {
+    INCOMING_ACTIONS.lock().await.len() + INCOMING_CHAT.lock().await.len()
+}
+
+/// Sets how many game actions, and how many chat messages, the incoming queues can hold.
+/// Items which are already queued are kept, even if there are more of them.
+pub fn set_max_incoming_len(len: usize) {
+    MAX_INCOMING_LEN.store(len, Ordering::Relaxed);
+}
+
+/// Queues a chat message from `sender`. If the queue is full, the oldest unread message is thrown
+/// away to make room.
+pub async fn push_incoming_chat(sender: String, message: String) {
+    let mut incoming = INCOMING_CHAT.lock().await;
+    if incoming.len() >= MAX_INCOMING_LEN.load(Ordering::Relaxed) {
+        warn!("Dropped the oldest unread chat message, since the queue is full");
+        incoming.pop_front();
+    }
+    incoming.push_back((sender, message));
+}
+/// Returns the username of the sender and the text of the oldest unread chat message.
+pub async fn pop_incoming_chat() -> Option<(String, String)> {
+    INCOMING_CHAT.lock().await.pop_front()
+}
+
+/// Remembers that `packet` was just sent, so it can be sent again if it doesn't get a response.
+/// Only requests are tracked, see `P2pRequestPacket::is_retransmitted`.
+pub async fn track_sent_packet(packet: &P2pPacket) {
+    let P2pPacket::Request(req) = packet else {
+        return;
+    };
+    if !req.packet.is_retransmitted() {
+        return;
+    }
+
+    PENDING_REQUESTS
+        .lock()
+        .await
+        .entry(req.transaction_id)
+        .or_insert_with(|| PendingRequest {
+            packet: packet.clone(),
+            sent_at: Instant::now(),
+            retransmits: 0,
+        });
+}
+
+/// Queues the requests which have waited too long for a response to be sent again.
+/// Requests which have been sent the maximum number of times are given up on, and their closure
+/// is run with an error.
+///
+/// The outgoing queue is kept within its cap like in `push_outgoing_queue`. A request which
+/// doesn't fit isn't sent this time, but it still counts as a try, as if the packet was lost.
+pub async fn retransmit_pending_requests() {
+    let (interval, max_retransmits) = *RETRANSMIT_POLICY.lock().await;
+
+    let mut failed = vec![];
+    for (transaction_id, pending) in PENDING_REQUESTS.lock().await.iter_mut() {
+        if pending.sent_at.elapsed() < interval {
+            continue;
+        }
+
+        if pending.retransmits >= max_retransmits {
+            failed.push((*transaction_id, pending.retransmits + 1));
+            continue;
+        }
+
+        pending.retransmits += 1;
+        pending.sent_at = Instant::now();
+        let mut outgoing = OUTGOING_QUEUE.lock().await;
+        if let Err(err) = make_room(&mut outgoing, &pending.packet) {
+            warn!("Didn't send request {} again: {}", transaction_id, err);
+            continue;
+        }
+        NET_STATS.retransmissions.fetch_add(1, Ordering::Relaxed);
+        outgoing.push_back((pending.packet.clone(), *transaction_id));
+    }
+
+    for (transaction_id, tries) in failed {
+        count_timeout();
+        PENDING_REQUESTS.lock().await.remove(&transaction_id);
+        REQUEST_TIMES.lock().await.remove(&transaction_id);
+
+        let entry = TRANSACTION_TABLE.lock().await.remove(&transaction_id);
+        if let Some((_, Some(closure))) = entry {
+            closure.lock().await(Err(NetworkError::NoResponse {
+                transaction_id,
+                tries,
+            }
+            .into()));
+        }
+    }
+}
+
+/// Sets how long to wait for a response before a request is sent again, and how many times it is
+/// sent again before it is given up on.
+pub async fn set_retransmit_policy(interval: Duration, max_retransmits: u32) {
+    *RETRANSMIT_POLICY.lock().await = (interval, max_retransmits);
+}
+
+/// Remembers `response`, so it can be sent again if its request is recieved twice.
+pub async fn cache_sent_response(response: &P2pResponse) {
+    let mut responses = SENT_RESPONSES.lock().await;
+    if responses.len() >= RESPONSE_CACHE_LEN {
+        responses.pop_front();
+    }
+    responses.push_back(response.clone());
+}
+
+/// Returns the response already sent to the request with `transaction_id`, if any.
+pub async fn get_sent_response(transaction_id: u16) -> Option<P2pResponse> {
+    SENT_RESPONSES
+        .lock()
+        .await
+        .iter()
+        .find(|resp| resp.transaction_id == transaction_id)
+        .cloned()
+}
+
+/// Throws away the packets waiting to be sent, and the requests waiting for a response, eg. when
+/// the connection is closed. Their closures aren't run.
+pub async fn clear_outgoing() {
+    OUTGOING_QUEUE.lock().await.clear();
+    PENDING_REQUESTS.lock().await.clear();
+    TRANSACTION_TABLE.lock().await.clear();
+    SENT_RESPONSES.lock().await.clear();
+    REQUEST_TIMES.lock().await.clear();
+}
+
+/// Returns the statistics about the packets sent and recieved since the session or the game was
+/// started.
+pub fn get_net_stats() -> NetStats {
+    let rtt_nanos = NET_STATS.rtt_nanos.load(Ordering::Relaxed);
+    NetStats {
+        packets_sent: NET_STATS.packets_sent.load(Ordering::Relaxed),
+        packets_recieved: NET_STATS.packets_recieved.load(Ordering::Relaxed),
+        packets_acked: NET_STATS.packets_acked.load(Ordering::Relaxed),
+        retransmissions: NET_STATS.retransmissions.load(Ordering::Relaxed),
+        timeouts: NET_STATS.timeouts.load(Ordering::Relaxed),
+        rtt: (rtt_nanos != 0).then(|| Duration::from_nanos(rtt_nanos)),
+    }
+}
+
+/// Counts a packet sent to the other peer, see `NetStats::packets_sent`.
+pub fn count_sent_packet() {
+    NET_STATS.packets_sent.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a packet recieved from the other peer, see `NetStats::packets_recieved`.
+pub fn count_recieved_packet() {
+    NET_STATS.packets_recieved.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a request which was given up on, see `NetStats::timeouts`.
+pub fn count_timeout() {
+    NET_STATS.timeouts.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Starts counting the statistics returned by `get_net_stats` from zero, eg. when a new session
+/// or game starts.
+pub fn reset_net_stats() {
+    for counter in [
+        &NET_STATS.packets_sent,
+        &NET_STATS.packets_recieved,
+        &NET_STATS.packets_acked,
+        &NET_STATS.retransmissions,
+        &NET_STATS.timeouts,
+        &NET_STATS.rtt_nanos,
+    ] {
+        counter.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Returns the sequence number for a new request.
+pub fn next_sequence() -> u32 {
+    NEXT_SEQUENCE.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Remembers that the request with `sequence` has been recieved. Returns false if it has already
+/// been recieved, or is too old to tell, in which case it should be dropped.
+pub async fn check_sequence(sequence: u32) -> bool {
+    let mut window = RECIEVED_SEQUENCES.lock().await;
+    if sequence.saturating_add(SEQUENCE_WINDOW) <= window.highest {
+        return false;
+    }
+    if !window.seen.insert(sequence) {
+        return false;
+    }
+
+    window.highest = window.highest.max(sequence);
+    let oldest = window.highest.saturating_sub(SEQUENCE_WINDOW);
+    window.seen = window.seen.split_off(&oldest);
+    true
+}
+
+/// Remembers that the game action in the request with `sequence` is handled. Returns false if a
+/// game action sent after it has already been handled, in which case it is stale, and mustn't
+/// change the game.
+pub async fn check_action_sequence(sequence: u32) -> bool {
+    let mut window = RECIEVED_SEQUENCES.lock().await;
+    if sequence <= window.last_action {
+        return false;
+    }
+    window.last_action = sequence;
+    true
+}
+
+/// Forgets the recieved sequence numbers, eg. when a new peer connects, since its requests are
+/// counted from the start.
+pub async fn reset_sequences() {
+    let mut window = RECIEVED_SEQUENCES.lock().await;
+    window.highest = 0;
+    window.seen.clear();
+    window.last_action = 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+
+    use super::*;
+    use crate::net::{
+        p2p::{P2pRequest, P2pResponsePacket},
+        test_utils::{reset_state, TEST_LOCK},
+    };
+
+    #[tokio::test]
+    async fn unanswered_request_is_retransmitted_until_answered() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        set_retransmit_policy(Duration::from_millis(20), 3).await;
+
+        let delivered = Arc::new(AtomicBool::new(false));
+        let closure_delivered = delivered.clone();
+        let request = P2pRequest::new(1, 40, P2pRequestPacket::Resync);
+        push_outgoing_queue(
+            P2pPacket::Request(request),
+            Some(Arc::new(Mutex::new(
+                move |resp: anyhow::Result<P2pResponse>| {
+                    closure_delivered.store(resp.is_ok(), Ordering::Relaxed);
+                },
+            ))),
+        )
+        .await
+        .unwrap();
+
+        // The first send is lost
+        let (packet, _) = pop_outgoing_queue().await.unwrap();
+        track_sent_packet(&packet).await;
+        retransmit_pending_requests().await;
+        assert!(pop_outgoing_queue().await.is_none());
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        retransmit_pending_requests().await;
+        let (resent, transaction_id) = pop_outgoing_queue().await.unwrap();
+        assert_eq!(transaction_id, 40);
+        assert!(matches!(
+            resent,
+            P2pPacket::Request(P2pRequest {
+                packet: P2pRequestPacket::Resync,
+                ..
+            })
+        ));
+        track_sent_packet(&resent).await;
+
+        let response = P2pResponse::new(1, 40, P2pResponsePacket::Acknowledge);
+        set_response(40, Some(P2pPacket::Response(response))).await;
+        assert!(delivered.load(Ordering::Relaxed));
+
+        // Once answered, it isn't sent again
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        retransmit_pending_requests().await;
+        assert!(pop_outgoing_queue().await.is_none());
+
+        set_retransmit_policy(DEFAULT_RETRANSMIT_INTERVAL, DEFAULT_MAX_RETRANSMITS).await;
+    }
+
+    #[tokio::test]
+    async fn cancelled_request_is_neither_sent_nor_answered() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        set_retransmit_policy(Duration::from_millis(20), 3).await;
+
+        let answered = Arc::new(AtomicBool::new(false));
+        for transaction_id in [41, 42] {
+            let closure_answered = answered.clone();
+            let request = P2pRequest::new(1, transaction_id, P2pRequestPacket::Resync);
+            push_outgoing_queue(
+                P2pPacket::Request(request),
+                Some(Arc::new(Mutex::new(
+                    move |_: anyhow::Result<P2pResponse>| {
+                        closure_answered.store(true, Ordering::Relaxed);
+                    },
+                ))),
+            )
+            .await
+            .unwrap();
+        }
+
+        // 41 is cancelled before it is sent, and 42 while it waits for a response
+        assert!(cancel_outgoing(41).await);
+        let (sent, transaction_id) = pop_outgoing_queue().await.unwrap();
+        assert_eq!(transaction_id, 42);
+        track_sent_packet(&sent).await;
+        assert!(pop_outgoing_queue().await.is_none());
+        assert!(cancel_outgoing(42).await);
+        assert!(!cancel_outgoing(42).await);
+
+        let response = P2pResponse::new(1, 42, P2pResponsePacket::Acknowledge);
+        set_response(42, Some(P2pPacket::Response(response))).await;
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        retransmit_pending_requests().await;
+        assert!(pop_outgoing_queue().await.is_none());
+        assert!(!answered.load(Ordering::Relaxed));
+
+        set_retransmit_policy(DEFAULT_RETRANSMIT_INTERVAL, DEFAULT_MAX_RETRANSMITS).await;
+    }
+
+    #[tokio::test]
+    async fn pushing_past_the_cap_is_rejected() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        set_max_outgoing_len(2);
+        let request =
+            |transaction_id, packet| P2pPacket::Request(P2pRequest::new(1, transaction_id, packet));
+
+        push_outgoing_queue(request(50, P2pRequestPacket::Ping), None)
+            .await
+            .unwrap();
+        push_outgoing_queue(request(51, P2pRequestPacket::Resync), None)
+            .await
+            .unwrap();
+        // The ping makes room for a more important packet, but nothing else does
+        push_outgoing_queue(request(52, P2pRequestPacket::Resync), None)
+            .await
+            .unwrap();
+        for packet in [P2pRequestPacket::Resync, P2pRequestPacket::Ping] {
+            let err = push_outgoing_queue(request(53, packet), None)
+                .await
+                .unwrap_err();
+            assert!(matches!(err, NetworkError::QueueFull { capacity: 2 }));
+        }
+
+        assert_eq!(pop_outgoing_queue().await.unwrap().1, 51);
+        assert_eq!(pop_outgoing_queue().await.unwrap().1, 52);
+        assert!(pop_outgoing_queue().await.is_none());
+        set_max_outgoing_len(DEFAULT_MAX_OUTGOING_LEN);
+    }
+
+    #[tokio::test]
+    async fn retransmits_stay_within_the_cap() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        set_retransmit_policy(Duration::from_millis(20), 1).await;
+        set_max_outgoing_len(1);
+
+        let failed = Arc::new(AtomicBool::new(false));
+        let closure_failed = failed.clone();
+        let request = P2pRequest::new(1, 54, P2pRequestPacket::Resync);
+        push_outgoing_queue(
+            P2pPacket::Request(request),
+            Some(Arc::new(Mutex::new(
+                move |resp: anyhow::Result<P2pResponse>| {
+                    closure_failed.store(resp.is_err(), Ordering::Relaxed);
+                },
+            ))),
+        )
+        .await
+        .unwrap();
+        let (sent, _) = pop_outgoing_queue().await.unwrap();
+        track_sent_packet(&sent).await;
+
+        // The queue fills up before the request is due again
+        let resync = P2pRequest::new(1, 55, P2pRequestPacket::Resync);
+        push_outgoing_queue(P2pPacket::Request(resync), None)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        retransmit_pending_requests().await;
+        assert_eq!(get_outgoing_queue_len().await, 1);
+
+        // The skipped send counts as a try, so the request is given up on
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        retransmit_pending_requests().await;
+        assert!(failed.load(Ordering::Relaxed));
+        assert_eq!(pop_outgoing_queue().await.unwrap().1, 55);
+
+        set_max_outgoing_len(DEFAULT_MAX_OUTGOING_LEN);
+        set_retransmit_policy(DEFAULT_RETRANSMIT_INTERVAL, DEFAULT_MAX_RETRANSMITS).await;
+    }
+
+    #[tokio::test]
+    async fn waiting_task_wakes_up_on_its_response() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+
+        for transaction_id in [56, 57] {
+            let request = P2pRequest::new(1, transaction_id, P2pRequestPacket::Ping);
+            push_outgoing_queue(P2pPacket::Request(request), None)
+                .await
+                .unwrap();
+        }
+        let waiter = tokio::spawn(wait_for_response(57));
+
+        // Another response doesn't end the wait
+        let pong = |transaction_id| {
+            P2pPacket::Response(P2pResponse::new(1, transaction_id, P2pResponsePacket::Pong))
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        set_response(56, Some(pong(56))).await;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiter.is_finished());
+
+        set_response(57, Some(pong(57))).await;
+        let response = tokio::time::timeout(Duration::from_millis(500), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(response, P2pPacket::Response(resp) if resp.transaction_id == 57));
+        assert!(!check_transaction_id(57).await);
+    }
+
+    #[tokio::test]
+    async fn delayed_response_updates_rtt_and_acks() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        reset_net_stats();
+
+        let request = P2pRequest::new(1, 60, P2pRequestPacket::Resync);
+        push_outgoing_queue(P2pPacket::Request(request), None)
+            .await
+            .unwrap();
+        let (sent, _) = pop_outgoing_queue().await.unwrap();
+        track_sent_packet(&sent).await;
+        assert_eq!(get_net_stats().rtt, None);
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        let response = P2pResponse::new(1, 60, P2pResponsePacket::Acknowledge);
+        set_response(60, Some(P2pPacket::Response(response.clone()))).await;
+        // The same response again isn't a new ack
+        set_response(60, Some(P2pPacket::Response(response))).await;
+
+        let stats = get_net_stats();
+        assert_eq!(stats.packets_acked, 1);
+        let rtt = stats.rtt.unwrap();
+        assert!(rtt >= Duration::from_millis(30) && rtt < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn incoming_queues_stay_within_capacity() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        set_max_incoming_len(2);
+
+        // Game actions past the capacity are dropped, the network loops refuse them before that
+        for action in [
+            GameAction::OfferDraw,
+            GameAction::RespondDraw(false),
+            GameAction::Surrender,
+        ] {
+            push_incoming_gameaction(action).await;
+        }
+        assert_eq!(get_incoming_gameaction_len().await, 2);
+        assert!(is_incoming_full().await);
+
+        // Chat throws away the oldest message instead
+        for message in ["one", "two", "three"] {
+            push_incoming_chat("bob".to_owned(), message.to_owned()).await;
+        }
+        assert_eq!(get_incoming_queue_len().await, 4);
+
+        assert!(matches!(
+            pop_incoming_gameaction().await,
+            Some(GameAction::OfferDraw)
+        ));
+        assert!(matches!(
+            pop_incoming_gameaction().await,
+            Some(GameAction::RespondDraw(false))
+        ));
+        assert!(pop_incoming_gameaction().await.is_none());
+        assert!(!is_incoming_full().await);
+        let messages = [pop_incoming_chat().await, pop_incoming_chat().await];
+        assert_eq!(
+            messages.map(|chat| chat.map(|(_, message)| message)),
+            [Some("two".to_owned()), Some("three".to_owned())]
+        );
+        assert!(pop_incoming_chat().await.is_none());
+        set_max_incoming_len(DEFAULT_MAX_INCOMING_LEN);
+    }
+}
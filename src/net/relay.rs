@@ -0,0 +1,207 @@
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
+
+use anyhow::anyhow;
+use tokio::{net::UdpSocket, time::Instant};
+use tracing::{info, warn};
+
+use super::net_utils::PacketError;
+
+/// The length of the codes the relay host registers under
+pub const RELAY_CODE_LEN: usize = 6;
+/// How many peers can be registered under one code, the host and its client
+pub const MAX_RELAY_PEERS: usize = 2;
+/// How long a code is kept after its last frame. The peers ping each other every second, so a
+/// session this quiet has been left.
+pub const RELAY_SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The peers registered under a code
+struct RelaySession {
+    peers: Vec<SocketAddr>,
+    last_frame: Instant,
+}
+
+/// Puts the relay header in front of `payload`, which is a serialized `P2pPacket`.
+/// The header is the length of `code` as one byte, followed by `code`.
+/// A frame with an empty payload only registers the sender under `code` with the relay.
+pub fn wrap_frame(code: &str, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(1 + code.len() + payload.len());
+    frame.push(code.len() as u8);
+    frame.extend_from_slice(code.as_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Splits a relay frame into the session code and the payload, see `wrap_frame`.
+pub fn unwrap_frame(frame: &[u8]) -> anyhow::Result<(String, &[u8])> {
+    let Some(&code_len) = frame.first() else {
+        return Err(PacketError::Empty.into());
+    };
+    let code_end = 1 + code_len as usize;
+    if frame.len() < code_end {
+        return Err(PacketError::invalid_length(code_end, frame.len()).into());
+    }
+    let code = String::from_utf8(frame[1..code_end].to_vec())
+        .map_err(|_| PacketError::data_error("Invalid UFT8 encoded values for relay code"))?;
+
+    Ok((code, &frame[code_end..]))
+}
+
+/// Creates a random session code for a relay host, from letters and digits which are hard to mix
+/// up.
+pub fn new_relay_code() -> String {
+    const CHARS: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+    (0..RELAY_CODE_LEN)
+        .map(|_| CHARS[rand::random::<usize>() % CHARS.len()] as char)
+        .collect()
+}
+
+/// Runs a relay server on `bind_addr`. Only returns if the socket can't be bound.
+///
+/// Every peer sending a frame is registered under the code in its header, and the payload is
+/// forwarded to every other peer registered under the same code. Peers behind a NAT can then
+/// play, as long as both can reach the relay.
+/// At most `MAX_RELAY_PEERS` peers are registered under a code, and codes which haven't gotten a
+/// frame for `RELAY_SESSION_TIMEOUT` are forgotten.
+pub async fn run_relay_server(bind_addr: SocketAddr) -> anyhow::Result<()> {
+    let socket = UdpSocket::bind(bind_addr)
+        .await
+        .map_err(|err| anyhow!("Failed to bind relay socket to {}: {}", bind_addr, err))?;
+    info!("Relay server listening on {}", bind_addr);
+
+    relay_frames(socket, RELAY_SESSION_TIMEOUT).await;
+    Ok(())
+}
+
+/// Relays the frames recieved on `socket` forever, see `run_relay_server`.
+async fn relay_frames(socket: UdpSocket, session_timeout: Duration) {
+    let mut sessions: HashMap<String, RelaySession> = HashMap::new();
+    let mut buffer = vec![0; 2048];
+    loop {
+        let (len, addr) = match socket.recv_from(&mut buffer).await {
+            Ok(recieved) => recieved,
+            Err(err) => {
+                warn!("Failed to recieve relay frame: {}", err);
+                continue;
+            }
+        };
+        let (code, payload) = match unwrap_frame(&buffer[..len]) {
+            Ok(frame) => frame,
+            Err(err) => {
+                warn!("Dropped invalid relay frame from {:?}: {:#}", addr, err);
+                continue;
+            }
+        };
+
+        sessions.retain(|code, session| {
+            let is_active = session.last_frame.elapsed() < session_timeout;
+            if !is_active {
+                info!("Relay code {} expired", code);
+            }
+            is_active
+        });
+
+        let session = sessions
+            .entry(code.clone())
+            .or_insert_with(|| RelaySession {
+                peers: vec![],
+                last_frame: Instant::now(),
+            });
+        if !session.peers.contains(&addr) {
+            if session.peers.len() >= MAX_RELAY_PEERS {
+                warn!(
+                    "Dropped frame from {:?}, since relay code {} is full",
+                    addr, code
+                );
+                continue;
+            }
+            info!("{:?} registered under relay code {}", addr, code);
+            session.peers.push(addr);
+        }
+        session.last_frame = Instant::now();
+        if payload.is_empty() {
+            continue;
+        }
+
+        let frame = wrap_frame(&code, payload);
+        for peer in session.peers.iter().filter(|peer| **peer != addr) {
+            if let Err(err) = socket.send_to(&frame, peer).await {
+                warn!("Failed to relay frame to {:?}: {}", peer, err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Starts a relay with `session_timeout`, and returns its address
+    async fn start_relay(session_timeout: Duration) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(relay_frames(socket, session_timeout));
+        addr
+    }
+
+    /// Returns the payload of the next frame `socket` recieves, or `None` if none arrives
+    async fn recieve_payload(socket: &UdpSocket) -> Option<Vec<u8>> {
+        let mut buffer = vec![0; 2048];
+        let recieved =
+            tokio::time::timeout(Duration::from_millis(200), socket.recv(&mut buffer)).await;
+        let len = recieved.ok()?.unwrap();
+        let (_, payload) = unwrap_frame(&buffer[..len]).unwrap();
+        Some(payload.to_vec())
+    }
+
+    #[tokio::test]
+    async fn third_peer_is_refused() {
+        let relay = start_relay(RELAY_SESSION_TIMEOUT).await;
+        let mut peers = vec![];
+        for _ in 0..3 {
+            let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            peer.send_to(&wrap_frame("ABC234", &[]), relay)
+                .await
+                .unwrap();
+            peers.push(peer);
+        }
+
+        peers[2]
+            .send_to(&wrap_frame("ABC234", b"intruder"), relay)
+            .await
+            .unwrap();
+        assert_eq!(recieve_payload(&peers[0]).await, None);
+
+        peers[0]
+            .send_to(&wrap_frame("ABC234", b"move"), relay)
+            .await
+            .unwrap();
+        assert_eq!(recieve_payload(&peers[1]).await, Some(b"move".to_vec()));
+        assert_eq!(recieve_payload(&peers[2]).await, None);
+    }
+
+    #[tokio::test]
+    async fn idle_code_expires() {
+        let relay = start_relay(Duration::from_millis(50)).await;
+        let host = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        host.send_to(&wrap_frame("ABC234", &[]), relay)
+            .await
+            .unwrap();
+        client
+            .send_to(&wrap_frame("ABC234", &[]), relay)
+            .await
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let newcomer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        newcomer
+            .send_to(&wrap_frame("ABC234", &[]), relay)
+            .await
+            .unwrap();
+        host.send_to(&wrap_frame("ABC234", b"move"), relay)
+            .await
+            .unwrap();
+        assert_eq!(recieve_payload(&newcomer).await, Some(b"move".to_vec()));
+        assert_eq!(recieve_payload(&client).await, None);
+    }
+}
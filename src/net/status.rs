@@ -1,15 +1,112 @@
-use std::net::SocketAddr;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs, io,
+    net::SocketAddr,
+    path::PathBuf,
+    time::Duration,
+};
+
+use anyhow::Context;
+use lazy_static::lazy_static;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{watch, Mutex},
+    time::Instant,
+};
+use tracing::{error, warn};
 
-use tokio::sync::Mutex;
+use crate::{
+    game::{
+        bitboard::BitBoard,
+        clock::{MoveClock, TimeControl},
+        GameResult, Move, PieceColor, PieceData,
+    },
+    net::p2p::queue::{self, NetStats},
+};
 
 pub const CONNECT_SESSION_ID: u16 = 0x15f4;
 
+/// How long the other peer may be silent before the connection is considered dead, unless
+/// changed with `set_heartbeat_timeout`.
+pub const DEFAULT_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How the client network loop keeps track of the connection to the host
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClientConfig {
+    /// How many times a second the client pings the host. With 0, the client doesn't ping, and
+    /// only finds out that the connection is lost when the host's pings stop arriving
+    pub pings_per_second: u32,
+    /// How long the client waits for the host to answer a ping, before the ping counts as lost
+    pub connect_timeout: Duration,
+}
+
+impl ClientConfig {
+    /// One ping a second, which the host has half a second to answer
+    pub const DEFAULT: Self = Self {
+        pings_per_second: 1,
+        connect_timeout: Duration::from_millis(500),
+    };
+
+    /// Returns the time between two pings, or `None` if the client doesn't ping
+    pub fn ping_interval(&self) -> Option<Duration> {
+        (self.pings_per_second != 0).then(|| Duration::from_secs(1) / self.pings_per_second)
+    }
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// How the host decides which colors the players get. The client always gets the opposite color
+/// of the host, which the host sends in `P2pResponsePacket::Connect`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorAssignment {
+    /// The host plays White, and moves first
+    #[default]
+    HostWhite,
+    /// The host plays Black, and the client moves first
+    HostBlack,
+    /// The host's color is picked at random
+    Random,
+}
+
+impl ColorAssignment {
+    /// Returns the color the host plays. `rng` is only used by `ColorAssignment::Random`, so a
+    /// seeded RNG, eg. `rand::rngs::StdRng::seed_from_u64`, always picks the same color.
+    pub fn host_color(&self, rng: &mut impl Rng) -> PieceColor {
+        match self {
+            Self::HostWhite => PieceColor::White,
+            Self::HostBlack => PieceColor::Black,
+            Self::Random => {
+                if rng.gen() {
+                    PieceColor::White
+                } else {
+                    PieceColor::Black
+                }
+            }
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub enum ConnectionStatus {
     Disconnected,
     PendingConnection,
-    Reconnecting { tries: u8 },
-    Connected { ping: u128 },
+    Reconnecting {
+        tries: u8,
+    },
+    Connected {
+        ping: u128,
+    },
+    /// Nothing has been recieved from the other peer for longer than the heartbeat timeout
+    TimedOut,
+    /// Still connected, but the boards of the peers have stopped matching
+    Desynced {
+        ping: u128,
+    },
 }
 
 impl ConnectionStatus {
@@ -21,21 +118,67 @@ impl ConnectionStatus {
     pub fn reconnecting() -> Self {
         Self::Reconnecting { tries: 0 }
     }
+    /// Returns true if the peers can talk to each other, even if they are desynced
     pub fn is_connected(&self) -> bool {
-        matches!(self, Self::Connected { ping: _ })
+        matches!(
+            self,
+            Self::Connected { ping: _ } | Self::Desynced { ping: _ }
+        )
     }
     pub fn is_reconnecting(&self) -> bool {
         matches!(self, Self::Reconnecting { tries: _ })
     }
+    pub fn is_timed_out(&self) -> bool {
+        matches!(self, Self::TimedOut)
+    }
+    pub fn is_desynced(&self) -> bool {
+        matches!(self, Self::Desynced { ping: _ })
+    }
     pub fn can_send(&self) -> bool {
         match self {
             Self::Disconnected => false,
             Self::PendingConnection => true,
             Self::Reconnecting { tries: _ } => true,
             Self::Connected { ping: _ } => true,
+            Self::TimedOut => false,
+            Self::Desynced { ping: _ } => true,
         }
     }
 }
+/// How many pings the latency is averaged over
+const LATENCY_SAMPLES: usize = 5;
+
+/// The round-trip times of the latest pings
+struct LatencySamples {
+    samples: VecDeque<Duration>,
+    /// When the ping of the newest sample was sent
+    last_sent: Option<std::time::Instant>,
+}
+
+/// The name of the file `save_session` writes to in the temporary directory, unless another file
+/// is set with `set_session_file`
+const SESSION_FILE_NAME: &str = "the_checker_mater_session.json";
+
+/// The connection to the other peer, as written by `save_session`, so the game can offer to
+/// reconnect after it was restarted
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub session_id: u16,
+    pub other_addr: SocketAddr,
+    pub join_code: Option<String>,
+    pub my_username: Option<String>,
+    pub other_username: Option<String>,
+    /// The address of the relay server and the code of the session on it, if the peers play
+    /// through a relay
+    pub relay: Option<(SocketAddr, String)>,
+}
+
+/// A callback registered with `subscribe`
+type StatusCallback = Box<dyn Fn(ConnectionStatus) + Send>;
+
+/// The board hashes of the local and the other peer, by the number of moves made
+type BoardHashes = BTreeMap<u32, (Option<u64>, Option<u64>)>;
+
 pub struct ConnectionData {
     status: Mutex<ConnectionStatus>,
     other_addr: Mutex<Option<SocketAddr>>,
@@ -43,6 +186,62 @@ pub struct ConnectionData {
     my_username: Mutex<Option<String>>,
     join_code: Mutex<Option<String>>,
     session_id: Mutex<u16>,
+    /// When the last packet was recieved from the other peer
+    last_packet_time: Mutex<Option<Instant>>,
+    heartbeat_timeout: Mutex<Duration>,
+    /// The config of the latest client network loop, which is reused when the client reconnects
+    client_config: Mutex<ClientConfig>,
+    /// The latest game board in logical indices, sent to the client when it resyncs or reconnects
+    board_state: Mutex<Vec<PieceData>>,
+    /// The color of the local player's pieces in `board_state`
+    my_color: Mutex<Option<PieceColor>>,
+    /// The color whose turn it is. Passed on to the other side after every move
+    whose_turn: Mutex<PieceColor>,
+    /// The color the host assigned the client when it joined, which the game hasn't set up its
+    /// board with yet
+    assigned_color: Mutex<Option<PieceColor>>,
+    /// A board recieved from the host, which the game hasn't replaced its own board with yet
+    resynced_board: Mutex<Option<Vec<PieceData>>>,
+    /// The time limits of the game, set by the host and sent to the client when it joins
+    time_control: Mutex<Option<TimeControl>>,
+    /// The seed of the game's random opening, set by the host and sent to the client when it joins
+    opening_seed: Mutex<Option<u64>>,
+    /// The clock of the game, shared by the game after every move
+    turn_timer: Mutex<Option<MoveClock>>,
+    /// Counts the started network loops. The tasks of a loop stop when a newer one is started
+    loop_generation: Mutex<u32>,
+    /// Set while a draw offer, from either peer, hasn't been answered
+    draw_offer_pending: Mutex<bool>,
+    /// Set while the other peer's rematch request hasn't been accepted
+    rematch_offered: Mutex<bool>,
+    /// How the game ended, or `None` while it is being played. Only a rematch can be asked for
+    /// once the game has ended
+    game_result: Mutex<Option<GameResult>>,
+    /// The board hashes of the local and the other peer, by the number of moves made, which
+    /// haven't been compared yet
+    board_hashes: Mutex<BoardHashes>,
+    /// The addresses of the peers watching the hosts game
+    spectators: Mutex<Vec<SocketAddr>>,
+    /// Set when the local peer watches a game instead of playing
+    is_spectating: Mutex<bool>,
+    /// The address of the relay server and the code of the session on it, when packets go
+    /// through a relay instead of straight to the other peer
+    relay: Mutex<Option<(SocketAddr, String)>>,
+    /// The reason the other peer gave when it closed the connection
+    disconnect_reason: Mutex<Option<String>>,
+    latency: Mutex<LatencySamples>,
+    /// The file the session is saved to, or `None` for the default file. Locked while the file is
+    /// written or read, so a session is never read while half written
+    session_file: Mutex<Option<PathBuf>>,
+}
+
+lazy_static! {
+    /// Sends the connection status to the subscribers whenever it changes to another kind of
+    /// status, see `subscribe_status`.
+    static ref STATUS_WATCH: watch::Sender<ConnectionStatus> =
+        watch::channel(ConnectionStatus::Disconnected).0;
+    /// Called whenever the connection status changes to another kind of status, see `subscribe`.
+    static ref STATUS_CALLBACKS: std::sync::Mutex<Vec<StatusCallback>> = Default::default();
 }
 
 static CONNECTION_DATA: ConnectionData = ConnectionData {
@@ -52,6 +251,31 @@ static CONNECTION_DATA: ConnectionData = ConnectionData {
     my_username: Mutex::const_new(None),
     join_code: Mutex::const_new(None),
     session_id: Mutex::const_new(CONNECT_SESSION_ID),
+    last_packet_time: Mutex::const_new(None),
+    heartbeat_timeout: Mutex::const_new(DEFAULT_HEARTBEAT_TIMEOUT),
+    client_config: Mutex::const_new(ClientConfig::DEFAULT),
+    board_state: Mutex::const_new(Vec::new()),
+    my_color: Mutex::const_new(None),
+    whose_turn: Mutex::const_new(PieceColor::White),
+    assigned_color: Mutex::const_new(None),
+    resynced_board: Mutex::const_new(None),
+    time_control: Mutex::const_new(None),
+    opening_seed: Mutex::const_new(None),
+    turn_timer: Mutex::const_new(None),
+    loop_generation: Mutex::const_new(0),
+    draw_offer_pending: Mutex::const_new(false),
+    rematch_offered: Mutex::const_new(false),
+    game_result: Mutex::const_new(None),
+    board_hashes: Mutex::const_new(BTreeMap::new()),
+    spectators: Mutex::const_new(Vec::new()),
+    is_spectating: Mutex::const_new(false),
+    relay: Mutex::const_new(None),
+    disconnect_reason: Mutex::const_new(None),
+    latency: Mutex::const_new(LatencySamples {
+        samples: VecDeque::new(),
+        last_sent: None,
+    }),
+    session_file: Mutex::const_new(None),
 };
 
 pub async fn get_other_addr() -> Option<SocketAddr> {
@@ -91,25 +315,113 @@ pub async fn get_connection_status() -> ConnectionStatus {
 }
 
 pub async fn set_connection_status(status: ConnectionStatus) {
-    *CONNECTION_DATA.status.lock().await = status
+    *CONNECTION_DATA.status.lock().await = status;
+    publish_status(status);
+}
+
+/// Returns a reciever which is notified whenever the connection status changes to another kind of
+/// status, eg. from `ConnectionStatus::PendingConnection` to `ConnectionStatus::Connected`, so
+/// the status doesn't have to be polled. Changes to the ping or the reconnect tries update the
+/// value, without notifying.
+pub fn subscribe_status() -> watch::Receiver<ConnectionStatus> {
+    STATUS_WATCH.subscribe()
+}
+
+/// Registers `f`, which is called with the new status whenever the connection status changes to
+/// another kind of status, like the recievers of `subscribe_status` are notified.
+/// The callbacks are called from the task which changed the status, so they should return
+/// quickly, and must not call `subscribe` themselves.
+pub fn subscribe(f: impl Fn(ConnectionStatus) + Send + 'static) {
+    STATUS_CALLBACKS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .push(Box::new(f));
+}
+
+/// Updates the status seen by the subscribers, and notifies them if the kind of status changed.
+fn publish_status(status: ConnectionStatus) {
+    let changed = STATUS_WATCH.send_if_modified(|current| {
+        let changed = std::mem::discriminant(current) != std::mem::discriminant(&status);
+        *current = status;
+        changed
+    });
+    if !changed {
+        return;
+    }
+
+    let callbacks = STATUS_CALLBACKS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner());
+    for callback in callbacks.iter() {
+        callback(status);
+    }
 }
 
 pub async fn get_connection_ping() -> Option<u128> {
     match *CONNECTION_DATA.status.lock().await {
-        ConnectionStatus::Connected { ping } => Some(ping),
+        ConnectionStatus::Connected { ping } | ConnectionStatus::Desynced { ping } => Some(ping),
         _ => None,
     }
 }
 
 pub async fn set_connection_ping(new_ping: u128) {
-    if let ConnectionStatus::Connected { ping } = &mut *CONNECTION_DATA.status.lock().await {
+    let mut status = CONNECTION_DATA.status.lock().await;
+    if let ConnectionStatus::Connected { ping } | ConnectionStatus::Desynced { ping } = &mut *status
+    {
         *ping = new_ping;
     }
+    publish_status(*status);
+}
+
+/// Records the round-trip time of the ping sent at `sent_at`, which has just gotten its pong, and
+/// sets the ping of the connection status to the average of the latest samples.
+/// A pong which arrives after the pong of a newer ping is ignored, so it can't skew the average.
+pub async fn record_latency(sent_at: std::time::Instant) {
+    let round_trip = sent_at.elapsed();
+    let mut latency = CONNECTION_DATA.latency.lock().await;
+    if latency
+        .last_sent
+        .is_some_and(|last_sent| sent_at < last_sent)
+    {
+        return;
+    }
+    latency.last_sent = Some(sent_at);
+
+    if latency.samples.len() >= LATENCY_SAMPLES {
+        latency.samples.pop_front();
+    }
+    latency.samples.push_back(round_trip);
+    let average = latency.samples.iter().sum::<Duration>() / latency.samples.len() as u32;
+    drop(latency);
+
+    set_connection_ping(average.as_millis()).await;
 }
+
+/// Returns the average round-trip time of the latest pings, or `None` if there is no connection
+/// or no ping has gotten a pong yet.
+pub async fn get_latency() -> Option<Duration> {
+    if !get_connection_status().await.is_connected() {
+        return None;
+    }
+    let latency = CONNECTION_DATA.latency.lock().await;
+    if latency.samples.is_empty() {
+        return None;
+    }
+    Some(latency.samples.iter().sum::<Duration>() / latency.samples.len() as u32)
+}
+
+/// Returns the counts of the packets sent to and recieved from the other peer since the session
+/// or the game was started. The counters are atomics, so this never waits for the network tasks.
+pub fn net_stats() -> NetStats {
+    queue::get_net_stats()
+}
+
 pub async fn set_reconnect_tries(new_tries: u8) {
-    if let ConnectionStatus::Reconnecting { tries } = &mut *CONNECTION_DATA.status.lock().await {
+    let mut status = CONNECTION_DATA.status.lock().await;
+    if let ConnectionStatus::Reconnecting { tries } = &mut *status {
         *tries = new_tries;
     }
+    publish_status(*status);
 }
 
 pub async fn get_join_code() -> Option<String> {
@@ -127,3 +439,473 @@ pub async fn get_session_id() -> u16 {
 pub async fn set_session_id(session_id: u16) {
     *CONNECTION_DATA.session_id.lock().await = session_id
 }
+
+pub async fn get_last_packet_time() -> Option<Instant> {
+    *CONNECTION_DATA.last_packet_time.lock().await
+}
+
+/// Marks that a packet was just recieved from the other peer
+pub async fn update_last_packet_time() {
+    *CONNECTION_DATA.last_packet_time.lock().await = Some(Instant::now())
+}
+
+pub async fn get_heartbeat_timeout() -> Duration {
+    *CONNECTION_DATA.heartbeat_timeout.lock().await
+}
+
+pub async fn set_heartbeat_timeout(timeout: Duration) {
+    *CONNECTION_DATA.heartbeat_timeout.lock().await = timeout
+}
+
+pub async fn get_client_config() -> ClientConfig {
+    *CONNECTION_DATA.client_config.lock().await
+}
+
+pub async fn set_client_config(config: ClientConfig) {
+    *CONNECTION_DATA.client_config.lock().await = config
+}
+
+pub async fn get_board_state() -> Vec<PieceData> {
+    CONNECTION_DATA.board_state.lock().await.clone()
+}
+
+pub async fn set_board_state(board: Vec<PieceData>) {
+    *CONNECTION_DATA.board_state.lock().await = board
+}
+
+/// Returns the board shared with `set_board_state` as a `BitBoard` in logical indices, where
+/// White's pieces are the "player" pieces. `None` if no board has been shared yet.
+pub async fn get_shared_bitboard() -> Option<BitBoard> {
+    let pieces = CONNECTION_DATA.board_state.lock().await;
+    if pieces.len() != 32 {
+        return None;
+    }
+    Some(BitBoard::from_pieces(&pieces, PieceColor::White))
+}
+
+/// Stores `board`, recieved from the host, as the shared board, and keeps it until the game
+/// takes it with `take_resynced_board`.
+pub async fn set_resynced_board(board: Vec<PieceData>) {
+    *CONNECTION_DATA.board_state.lock().await = board.clone();
+    *CONNECTION_DATA.resynced_board.lock().await = Some(board);
+}
+
+pub async fn take_resynced_board() -> Option<Vec<PieceData>> {
+    CONNECTION_DATA.resynced_board.lock().await.take()
+}
+
+pub async fn get_time_control() -> Option<TimeControl> {
+    *CONNECTION_DATA.time_control.lock().await
+}
+
+pub async fn set_time_control(time_control: Option<TimeControl>) {
+    *CONNECTION_DATA.time_control.lock().await = time_control
+}
+
+pub async fn get_opening_seed() -> Option<u64> {
+    *CONNECTION_DATA.opening_seed.lock().await
+}
+
+pub async fn set_opening_seed(seed: Option<u64>) {
+    *CONNECTION_DATA.opening_seed.lock().await = seed
+}
+
+pub async fn get_turn_timer() -> Option<MoveClock> {
+    CONNECTION_DATA.turn_timer.lock().await.clone()
+}
+
+pub async fn set_turn_timer(timer: Option<MoveClock>) {
+    *CONNECTION_DATA.turn_timer.lock().await = timer
+}
+
+pub async fn get_my_color() -> Option<PieceColor> {
+    *CONNECTION_DATA.my_color.lock().await
+}
+
+pub async fn set_my_color(color: PieceColor) {
+    *CONNECTION_DATA.my_color.lock().await = Some(color)
+}
+
+/// Forgets the color of the local player, like before the first game started
+#[cfg(test)]
+pub(crate) async fn clear_my_color() {
+    *CONNECTION_DATA.my_color.lock().await = None
+}
+
+/// Stores the color the host assigned the client, and keeps it until the game takes it with
+/// `take_assigned_color`.
+pub async fn set_assigned_color(color: PieceColor) {
+    *CONNECTION_DATA.assigned_color.lock().await = Some(color)
+}
+
+pub async fn take_assigned_color() -> Option<PieceColor> {
+    CONNECTION_DATA.assigned_color.lock().await.take()
+}
+
+/// Returns the color whose turn it is. White moves first in every game
+pub async fn whose_turn() -> PieceColor {
+    *CONNECTION_DATA.whose_turn.lock().await
+}
+
+pub async fn set_whose_turn(color: PieceColor) {
+    *CONNECTION_DATA.whose_turn.lock().await = color
+}
+
+pub async fn get_loop_generation() -> u32 {
+    *CONNECTION_DATA.loop_generation.lock().await
+}
+
+/// Starts a new network loop generation, which makes the tasks of the running loop stop.
+/// Returns the new generation.
+pub async fn new_loop_generation() -> u32 {
+    let mut generation = CONNECTION_DATA.loop_generation.lock().await;
+    *generation = generation.wrapping_add(1);
+    *generation
+}
+
+pub async fn is_draw_offer_pending() -> bool {
+    *CONNECTION_DATA.draw_offer_pending.lock().await
+}
+
+pub async fn set_draw_offer_pending(pending: bool) {
+    *CONNECTION_DATA.draw_offer_pending.lock().await = pending
+}
+
+pub async fn get_spectators() -> Vec<SocketAddr> {
+    CONNECTION_DATA.spectators.lock().await.clone()
+}
+
+pub async fn is_spectator(addr: SocketAddr) -> bool {
+    CONNECTION_DATA.spectators.lock().await.contains(&addr)
+}
+
+/// Adds `addr` to the spectators of the game, unless it already is one
+pub async fn add_spectator(addr: SocketAddr) {
+    let mut spectators = CONNECTION_DATA.spectators.lock().await;
+    if !spectators.contains(&addr) {
+        spectators.push(addr);
+    }
+}
+
+pub async fn get_spectator_count() -> usize {
+    CONNECTION_DATA.spectators.lock().await.len()
+}
+
+pub async fn is_spectating() -> bool {
+    *CONNECTION_DATA.is_spectating.lock().await
+}
+
+pub async fn set_spectating(spectating: bool) {
+    *CONNECTION_DATA.is_spectating.lock().await = spectating
+}
+
+/// Applies `mov` to the shared board, for spectators which don't have a game of their own to
+/// share the board from. Does nothing if no board has been shared yet.
+pub async fn apply_shared_move(mov: &Move) {
+    let mut pieces = CONNECTION_DATA.board_state.lock().await;
+    if pieces.len() != 32 {
+        return;
+    }
+    let mut board = BitBoard::from_pieces(&pieces, PieceColor::White);
+    board.apply_move(mov);
+    *pieces = (0..32)
+        .map(|index| board.piece_data(index, PieceColor::White))
+        .collect();
+}
+
+pub async fn get_relay() -> Option<(SocketAddr, String)> {
+    CONNECTION_DATA.relay.lock().await.clone()
+}
+
+/// Sends all packets through the relay server at `relay_addr`, under the session `code`, or
+/// straight to the other peer if `None`.
+pub async fn set_relay(relay: Option<(SocketAddr, String)>) {
+    *CONNECTION_DATA.relay.lock().await = relay
+}
+
+pub async fn get_disconnect_reason() -> Option<String> {
+    CONNECTION_DATA.disconnect_reason.lock().await.clone()
+}
+
+pub async fn set_disconnect_reason(reason: Option<String>) {
+    *CONNECTION_DATA.disconnect_reason.lock().await = reason
+}
+
+pub async fn remove_spectator(addr: SocketAddr) {
+    CONNECTION_DATA
+        .spectators
+        .lock()
+        .await
+        .retain(|spectator| *spectator != addr);
+}
+
+pub async fn is_rematch_offered() -> bool {
+    *CONNECTION_DATA.rematch_offered.lock().await
+}
+
+pub async fn set_rematch_offered(offered: bool) {
+    *CONNECTION_DATA.rematch_offered.lock().await = offered
+}
+
+pub async fn get_game_result() -> Option<GameResult> {
+    *CONNECTION_DATA.game_result.lock().await
+}
+
+pub async fn set_game_result(result: Option<GameResult>) {
+    *CONNECTION_DATA.game_result.lock().await = result
+}
+
+/// Records the board hash of the local peer if `is_mine` is true, or of the other peer otherwise,
+/// after `ply` moves have been made. Once the hashes of both peers are known for `ply` they are
+/// compared, and the status is set to `ConnectionStatus::Desynced` if they differ.
+/// Returns false if the hashes differ.
+pub async fn record_board_hash(ply: u32, hash: u64, is_mine: bool) -> bool {
+    let mut hashes = CONNECTION_DATA.board_hashes.lock().await;
+    let entry = hashes.entry(ply).or_default();
+    if is_mine {
+        entry.0 = Some(hash);
+    } else {
+        entry.1 = Some(hash);
+    }
+
+    let (Some(my_hash), Some(other_hash)) = *entry else {
+        return true;
+    };
+    hashes.remove(&ply);
+    drop(hashes);
+
+    if my_hash == other_hash {
+        return true;
+    }
+
+    error!(
+        "Boards are out of sync after {} moves. Local hash: {:#018x}, other hash: {:#018x}",
+        ply, my_hash, other_hash
+    );
+    set_desynced(true).await;
+    false
+}
+
+/// Switches between `ConnectionStatus::Connected` and `ConnectionStatus::Desynced`, keeping the
+/// ping. Does nothing if the peers aren't connected.
+pub async fn set_desynced(desynced: bool) {
+    let mut status = CONNECTION_DATA.status.lock().await;
+    *status = match *status {
+        ConnectionStatus::Connected { ping } | ConnectionStatus::Desynced { ping } => {
+            if desynced {
+                ConnectionStatus::Desynced { ping }
+            } else {
+                ConnectionStatus::Connected { ping }
+            }
+        }
+        other => other,
+    };
+    publish_status(*status);
+}
+
+/// Forgets the board hashes which haven't been compared, eg. when a new game is started.
+pub async fn clear_board_hashes() {
+    CONNECTION_DATA.board_hashes.lock().await.clear()
+}
+
+/// Writes sessions to `path` instead of the default file in the temporary directory.
+pub async fn set_session_file(path: PathBuf) {
+    *CONNECTION_DATA.session_file.lock().await = Some(path)
+}
+
+/// Writes the current session to the session file, so it can be read by `restore_session` if the
+/// game is restarted. Does nothing if there is no other peer.
+pub async fn save_session() {
+    let Some(other_addr) = get_other_addr().await else {
+        return;
+    };
+    let session = SessionInfo {
+        session_id: get_session_id().await,
+        other_addr,
+        join_code: get_join_code().await,
+        my_username: get_my_username().await,
+        other_username: get_other_username().await,
+        relay: get_relay().await,
+    };
+
+    let file = CONNECTION_DATA.session_file.lock().await;
+    let path = file.clone().unwrap_or_else(default_session_file);
+    let written = serde_json::to_string(&session)
+        .context("Failed to serialize the session")
+        .and_then(|json| {
+            fs::write(&path, json).with_context(|| format!("Failed to write {:?}", path))
+        });
+    if let Err(err) = written {
+        warn!("Couldn't save the session: {:#}", err);
+    }
+}
+
+/// Reads the session written by `save_session`.
+/// Returns `None` if no session was saved, or the file can't be read.
+pub async fn restore_session() -> Option<SessionInfo> {
+    let file = CONNECTION_DATA.session_file.lock().await;
+    let path = file.clone().unwrap_or_else(default_session_file);
+    let json = match fs::read_to_string(&path) {
+        Ok(json) => json,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return None,
+        Err(err) => {
+            warn!("Couldn't read the session file {:?}: {}", path, err);
+            return None;
+        }
+    };
+
+    match serde_json::from_str(&json) {
+        Ok(session) => Some(session),
+        Err(err) => {
+            warn!("The session file {:?} is invalid: {}", path, err);
+            None
+        }
+    }
+}
+
+/// Removes the session file, once the session has ended and can't be reconnected to.
+pub async fn clear_session() {
+    let file = CONNECTION_DATA.session_file.lock().await;
+    let path = file.clone().unwrap_or_else(default_session_file);
+    match fs::remove_file(&path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+            warn!("Couldn't remove the session file {:?}: {}", path, err);
+        }
+        _ => (),
+    }
+}
+
+fn default_session_file() -> PathBuf {
+    std::env::temp_dir().join(SESSION_FILE_NAME)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::net::test_utils::{reset_state, TEST_LOCK};
+
+    #[tokio::test]
+    async fn subscriber_sees_connecting_once() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        set_connection_status(ConnectionStatus::Disconnected).await;
+        let mut statuses = subscribe_status();
+        statuses.mark_unchanged();
+
+        set_connection_status(ConnectionStatus::PendingConnection).await;
+        assert!(statuses.has_changed().unwrap());
+        assert!(matches!(
+            *statuses.borrow_and_update(),
+            ConnectionStatus::PendingConnection
+        ));
+
+        // Only the ping changes after the first status, which isn't a transition
+        for ping in [0, 12, 30] {
+            set_connection_status(ConnectionStatus::Connected { ping }).await;
+        }
+        assert!(statuses.has_changed().unwrap());
+        assert!(statuses.borrow_and_update().is_connected());
+        assert!(!statuses.has_changed().unwrap());
+    }
+
+    #[test]
+    fn ping_interval_follows_the_config() {
+        let config = ClientConfig {
+            pings_per_second: 4,
+            connect_timeout: Duration::from_millis(200),
+        };
+        assert_eq!(config.ping_interval(), Some(Duration::from_millis(250)));
+        assert_eq!(
+            ClientConfig::default().ping_interval(),
+            Some(Duration::from_secs(1))
+        );
+        let silent = ClientConfig {
+            pings_per_second: 0,
+            ..config
+        };
+        assert_eq!(silent.ping_interval(), None);
+    }
+
+    #[tokio::test]
+    async fn callbacks_fire_on_each_transition() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        set_connection_status(ConnectionStatus::Disconnected).await;
+
+        let first = Arc::new(std::sync::Mutex::new(vec![]));
+        let second = Arc::new(std::sync::Mutex::new(vec![]));
+        for seen in [first.clone(), second.clone()] {
+            subscribe(move |status| seen.lock().unwrap().push(status));
+        }
+
+        set_connection_status(ConnectionStatus::PendingConnection).await;
+        set_connection_status(ConnectionStatus::Connected { ping: 0 }).await;
+        set_connection_status(ConnectionStatus::Connected { ping: 25 }).await;
+        set_connection_status(ConnectionStatus::Disconnected).await;
+
+        for seen in [first, second] {
+            let seen = seen.lock().unwrap();
+            assert_eq!(seen.len(), 3, "{:?}", seen);
+            assert!(matches!(seen[0], ConnectionStatus::PendingConnection));
+            assert!(seen[1].is_connected());
+            assert!(matches!(seen[2], ConnectionStatus::Disconnected));
+        }
+    }
+
+    #[test]
+    fn seeded_random_colors_are_deterministic() {
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let colors = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..16)
+                .map(|_| ColorAssignment::Random.host_color(&mut rng))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(colors(7), colors(7));
+        // Both colors get picked
+        let picked = colors(7);
+        assert!(picked.contains(&PieceColor::White) && picked.contains(&PieceColor::Black));
+
+        let mut rng = StdRng::seed_from_u64(7);
+        assert_eq!(
+            ColorAssignment::HostWhite.host_color(&mut rng),
+            PieceColor::White
+        );
+        assert_eq!(
+            ColorAssignment::HostBlack.host_color(&mut rng),
+            PieceColor::Black
+        );
+    }
+
+    #[tokio::test]
+    async fn saved_session_is_restored() {
+        let _lock = TEST_LOCK.lock().await;
+        reset_state().await;
+        clear_session().await;
+        assert_eq!(restore_session().await, None);
+
+        let other_addr: SocketAddr = "192.168.0.12:6001".parse().unwrap();
+        set_session_id(4242).await;
+        set_other_addr(other_addr).await;
+        set_join_code("c0a8000a1770").await;
+        set_my_username("alice").await;
+        set_other_username("bob").await;
+        save_session().await;
+
+        let expected = SessionInfo {
+            session_id: 4242,
+            other_addr,
+            join_code: Some("c0a8000a1770".to_owned()),
+            my_username: Some("alice".to_owned()),
+            other_username: Some("bob".to_owned()),
+            relay: None,
+        };
+        assert_eq!(restore_session().await, Some(expected));
+
+        clear_session().await;
+        assert_eq!(restore_session().await, None);
+    }
+}
@@ -0,0 +1,119 @@
+//! Helpers for the tests of the network layer, which talk to the network loops over loopback
+//! sockets.
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::net::UdpSocket;
+
+use crate::game::PieceColor;
+
+use super::{
+    p2p::{
+        communicate::{recieve_p2p_packet, send_p2p_packet},
+        queue, P2pPacket, P2pRequest, P2pRequestPacket, P2pResponse, P2pResponsePacket,
+    },
+    status::{self, ClientConfig},
+};
+
+/// How long a test waits for a packet before it fails
+const PACKET_WAIT: Duration = Duration::from_secs(5);
+
+/// Held by the tests which use the global network state, so they don't run at the same time
+pub(crate) static TEST_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// A client which only hears from the host when the test sends something
+pub(crate) const CLIENT_WITHOUT_PINGS: ClientConfig = ClientConfig {
+    pings_per_second: 0,
+    ..ClientConfig::DEFAULT
+};
+
+/// Forgets what earlier tests left in the global network state
+pub(crate) async fn reset_state() {
+    // Stops the network loops started by earlier tests
+    status::new_loop_generation().await;
+    let session_file = format!("checker_mater_test_session_{}.json", std::process::id());
+    status::set_session_file(std::env::temp_dir().join(session_file)).await;
+    queue::clear_outgoing().await;
+    queue::reset_sequences().await;
+    while queue::pop_incoming_gameaction().await.is_some() {}
+    while queue::pop_incoming_chat().await.is_some() {}
+    status::set_relay(None).await;
+    status::remove_other_addr().await;
+    status::remove_other_username().await;
+    status::set_session_id(status::CONNECT_SESSION_ID).await;
+    status::set_game_result(None).await;
+    status::set_board_state(vec![]).await;
+    status::clear_my_color().await;
+    status::set_whose_turn(PieceColor::White).await;
+    status::set_draw_offer_pending(false).await;
+    status::set_rematch_offered(false).await;
+    status::set_spectating(false).await;
+    status::set_desynced(false).await;
+    status::clear_board_hashes().await;
+    status::take_resynced_board().await;
+}
+
+/// The other peer of a network loop under test, which sends and answers packets as the test
+/// tells it to
+pub(crate) struct MockPeer {
+    pub(crate) socket: Arc<UdpSocket>,
+}
+
+impl MockPeer {
+    pub(crate) async fn bind() -> Self {
+        Self {
+            socket: Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()),
+        }
+    }
+
+    pub(crate) fn addr(&self) -> SocketAddr {
+        self.socket.local_addr().unwrap()
+    }
+
+    /// Returns the next packet recieved, and its sender, or `None` if nothing arrives in time
+    pub(crate) async fn recieve(&self) -> Option<(P2pPacket, SocketAddr)> {
+        tokio::time::timeout(PACKET_WAIT, recieve_p2p_packet(&self.socket))
+            .await
+            .ok()?
+            .ok()
+    }
+
+    /// Returns the next request recieved, and its sender, or `None` if none arrives in time.
+    /// Responses and pings recieved before it are thrown away.
+    pub(crate) async fn next_request(&self) -> Option<(P2pRequest, SocketAddr)> {
+        loop {
+            if let (P2pPacket::Request(request), addr) = self.recieve().await? {
+                if !matches!(request.packet, P2pRequestPacket::Ping) {
+                    return Some((request, addr));
+                }
+            }
+        }
+    }
+
+    /// Sends `request` to `to`, and returns the response to it.
+    /// Requests recieved before the response are thrown away.
+    pub(crate) async fn request(&self, request: P2pRequest, to: SocketAddr) -> P2pResponse {
+        let transaction_id = request.transaction_id;
+        send_p2p_packet(&self.socket, request, to).await.unwrap();
+        loop {
+            let (packet, _) = self.recieve().await.expect("No response recieved");
+            match packet {
+                P2pPacket::Response(response) if response.transaction_id == transaction_id => {
+                    return response
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    /// Answers `request`, which was recieved from `to`, with `packet`
+    pub(crate) async fn respond(
+        &self,
+        request: &P2pRequest,
+        packet: P2pResponsePacket,
+        to: SocketAddr,
+    ) {
+        let response = P2pResponse::new(request.session_id, request.transaction_id, packet);
+        send_p2p_packet(&self.socket, response, to).await.unwrap();
+    }
+}